@@ -0,0 +1,63 @@
+//! Compares the latency of rendering a single tile when the source image is read through
+//! opendal's asynchronous stream against reading it directly from a local file.
+
+use std::path::Path;
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use futures::StreamExt;
+use kaduceus::KakaduContext;
+use laya::iiif::service::{ImageService, ImageServiceResponseKind};
+use laya::iiif::{Format, ImageServiceRequest, Quality, Region, Rotation, Scale, Size};
+use laya::image::codec::KaduceusImageReader;
+use laya::storage::StorageProvider;
+use laya::storage::local::LocalStorageProvider;
+use laya::storage::opendal::OpenDalStorageProvider;
+use tokio::runtime::Runtime;
+use tower::Service;
+
+const TEST_DATA: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test-data");
+const IMAGE: &str = "iiif-validator-reference.jp2";
+
+async fn render_tile(mut service: ImageService) {
+    let request = ImageServiceRequest::image(
+        IMAGE,
+        Region::Absolute { x: 0, y: 0, width: 256, height: 256 },
+        Size::new(Scale::Max),
+        Rotation::new(0.0),
+        Quality::Default,
+        Format::Jpg,
+    );
+
+    let response = service.call(request).await.expect("failed to render tile");
+    let ImageServiceResponseKind::Image(mut image) = response.kind else {
+        panic!("expected an image response");
+    };
+
+    while let Some(chunk) = image.data.next().await {
+        chunk.expect("failed to encode tile");
+    }
+}
+
+fn tile_latency(c: &mut Criterion) {
+    let rt = Runtime::new().expect("failed to create benchmark runtime");
+    let mut group = c.benchmark_group("tile_latency");
+    let providers: [(&str, Box<dyn StorageProvider>); 2] = [
+        (
+            "opendal_stream",
+            Box::new(OpenDalStorageProvider::fs(Path::new(TEST_DATA)).unwrap()),
+        ),
+        ("local_file", Box::new(LocalStorageProvider::new(TEST_DATA.into()))),
+    ];
+
+    for (name, storage) in providers {
+        let service =
+            ImageService::new(storage, KaduceusImageReader::new(KakaduContext::default()));
+
+        group.bench_function(name, |b| b.to_async(&rt).iter(|| render_tile(service.clone())));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, tile_latency);
+criterion_main!(benches);
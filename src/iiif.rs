@@ -1,5 +1,7 @@
 use std::num::NonZero;
 
+pub mod cache;
+pub(crate) mod coalesce;
 pub mod http;
 pub(crate) mod parse;
 pub mod service;
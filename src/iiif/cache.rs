@@ -0,0 +1,798 @@
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use bytes::{Bytes, BytesMut};
+use futures::{Stream, StreamExt};
+use mediatype::MediaTypeBuf;
+use opentelemetry::KeyValue;
+use opentelemetry::metrics::Counter;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio_util::io::ReaderStream;
+use tracing::{debug, info, warn};
+
+use super::service::ImageParameters;
+use crate::image::ImageStream;
+use crate::image::color::ColorManagement;
+use crate::image::transcoding::plan::OutputPlan;
+use crate::storage::StorageObject;
+
+/// The extension of files holding cached derivatives.
+const ENTRY_EXTENSION: &str = "derivative";
+
+/// The extension of files holding derivatives that are still being written.
+const PARTIAL_EXTENSION: &str = "partial";
+
+/// The size of the largest derivative kept in memory, so that a single full-size render can't
+/// take a large share of the memory tier while it is buffered.
+const MAX_MEMORY_ENTRY_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The chunks of a derivative that can wait to be written to disk. A derivative that is served
+/// faster than it is written isn't cached on disk, rather than being buffered in memory.
+const DISK_WRITE_QUEUE: usize = 64;
+
+/// A cache of encoded images, keyed on the identifier of the source image and the canonical form
+/// of the request parameters, resolved against the source image.
+///
+/// Entries are recorded against the ETag and modification time of the source image they were
+/// rendered from, and are invalidated when a request finds that the source has changed.
+///
+/// The cache has a memory tier and an optional disk tier, each bounded by the total size of the
+/// entries it holds and evicting the least recently used entries once full.
+#[derive(Clone)]
+pub struct DerivativeCache {
+    memory: Arc<Mutex<Tier<Bytes>>>,
+    /// The size of the largest derivative kept in memory.
+    max_memory_entry: u64,
+    disk: Option<Arc<DiskTier>>,
+    metrics: Arc<CacheMetrics>,
+}
+
+struct DiskTier {
+    directory: PathBuf,
+    index: Mutex<Tier<PathBuf>>,
+    writes: AtomicU64,
+}
+
+/// Identifies a derivative of a source image.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct DerivativeKey(String);
+
+impl DerivativeKey {
+    /// The key of the derivative that `params` produce from the source image named
+    /// `identifier`, once planned as `output` and with colours handled according to `color`.
+    pub fn new(
+        identifier: &str,
+        params: &ImageParameters,
+        output: &OutputPlan,
+        color: ColorManagement,
+    ) -> Self {
+        let color = match color {
+            ColorManagement::Convert => "srgb",
+            ColorManagement::Preserve => "preserved",
+        };
+
+        Self(format!("{identifier}/{}#{color}", params.canonical(output)))
+    }
+}
+
+/// The version of a source image that a derivative was rendered from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceVersion {
+    etag: Option<String>,
+    last_modified: Option<SystemTime>,
+}
+
+impl SourceVersion {
+    pub fn new(etag: Option<String>, last_modified: Option<SystemTime>) -> Self {
+        Self { etag, last_modified }
+    }
+
+    /// Get the version of a stored object, if it has an ETag or modification time that changes
+    /// are detectable from.
+    pub fn of(object: &StorageObject) -> Option<Self> {
+        match (&object.etag, object.last_modified) {
+            (None, None) => None,
+            (etag, last_modified) => Some(Self { etag: etag.clone(), last_modified }),
+        }
+    }
+}
+
+impl DerivativeCache {
+    /// Create a cache that keeps at most `memory_capacity` bytes of derivatives in memory.
+    pub fn new(memory_capacity: u64) -> Self {
+        Self {
+            memory: Arc::new(Mutex::new(Tier::new(memory_capacity))),
+            max_memory_entry: memory_capacity.min(MAX_MEMORY_ENTRY_SIZE),
+            disk: None,
+            metrics: Arc::new(CacheMetrics::new()),
+        }
+    }
+
+    /// Also keep at most `capacity` bytes of derivatives in `directory`.
+    ///
+    /// The index of the disk tier is held in memory, so any entries left behind by a previous
+    /// process are removed.
+    pub fn with_disk_tier(self, directory: PathBuf, capacity: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&directory)?;
+
+        for entry in std::fs::read_dir(&directory)? {
+            let path = entry?.path();
+            let extension = path.extension().and_then(|extension| extension.to_str());
+
+            if matches!(extension, Some(ENTRY_EXTENSION | PARTIAL_EXTENSION)) {
+                std::fs::remove_file(&path)?;
+            }
+        }
+
+        info!(directory = %directory.display(), capacity, "using derivative disk cache");
+
+        Ok(Self {
+            disk: Some(Arc::new(DiskTier {
+                directory,
+                index: Mutex::new(Tier::new(capacity)),
+                writes: AtomicU64::new(0),
+            })),
+            ..self
+        })
+    }
+
+    /// Find a cached derivative rendered from the current version of its source image.
+    pub async fn get(&self, key: &DerivativeKey, version: &SourceVersion) -> Option<ImageStream> {
+        let memory_hit = {
+            let mut memory = self.memory.lock().expect("derivative cache was poisoned");
+            memory.remove_stale(key, version);
+            memory.get(key)
+        };
+
+        if let Some((media_type, data)) = memory_hit {
+            self.metrics.record_lookup("memory");
+
+            return Some(ImageStream {
+                media_type,
+                data: Box::new(futures::stream::iter([Ok(data)])),
+            });
+        }
+
+        if let Some(disk) = &self.disk {
+            let (disk_hit, stale) = {
+                let mut index = disk.index.lock().expect("derivative cache was poisoned");
+                let stale = index.remove_stale(key, version);
+
+                (index.get(key), stale)
+            };
+
+            remove_files(stale.into_iter().collect());
+
+            if let Some((media_type, path)) = disk_hit {
+                match tokio::fs::File::open(&path).await {
+                    Ok(file) => {
+                        self.metrics.record_lookup("disk");
+
+                        return Some(ImageStream {
+                            media_type,
+                            data: Box::new(ReaderStream::new(file)),
+                        });
+                    }
+                    Err(error) => {
+                        warn!(%error, path = %path.display(), "cached derivative is unreadable");
+                        disk.index
+                            .lock()
+                            .expect("derivative cache was poisoned")
+                            .remove(key);
+                    }
+                }
+            }
+        }
+
+        self.metrics.record_lookup("miss");
+        None
+    }
+
+    /// Wrap an encoded image that is being served so that it is added to the cache once it has
+    /// been streamed in full.
+    ///
+    /// Small derivatives are copied into memory as they pass through, and every derivative is
+    /// written to the disk tier as it passes through, so no derivative is buffered in full on its
+    /// way to disk. Nothing is cached if the stream fails, is dropped before it completes, or is
+    /// larger than every tier of the cache.
+    pub fn tee(
+        &self,
+        key: DerivativeKey,
+        version: SourceVersion,
+        stream: ImageStream,
+    ) -> ImageStream {
+        let media_type = stream.media_type.clone();
+
+        ImageStream { media_type, data: Box::new(self.tee_stream(key, version, stream)) }
+    }
+
+    fn tee_stream(
+        &self,
+        key: DerivativeKey,
+        version: SourceVersion,
+        stream: ImageStream,
+    ) -> TeeStream {
+        let ImageStream { media_type, data } = stream;
+
+        let disk = self.disk.as_ref().map(|disk| {
+            let capacity = disk
+                .index
+                .lock()
+                .expect("derivative cache was poisoned")
+                .capacity;
+            let (sender, receiver) = mpsc::channel(DISK_WRITE_QUEUE);
+            tokio::spawn(write_to_disk(
+                disk.clone(),
+                self.metrics.clone(),
+                (key.clone(), version.clone(), media_type.clone()),
+                receiver,
+            ));
+
+            (sender, capacity)
+        });
+
+        TeeStream {
+            inner: data,
+            size: 0,
+            memory: Some(BytesMut::new()),
+            memory_limit: self.max_memory_entry,
+            disk,
+            entry: Some((self.clone(), key, version, media_type)),
+        }
+    }
+
+    /// Add a derivative to the memory tier.
+    fn insert(
+        &self,
+        key: DerivativeKey,
+        version: SourceVersion,
+        media_type: MediaTypeBuf,
+        data: Bytes,
+    ) {
+        let size = data.len() as u64;
+
+        let evicted = self
+            .memory
+            .lock()
+            .expect("derivative cache was poisoned")
+            .insert(key, version, media_type, size, data);
+        self.metrics.record_evictions("memory", evicted.len());
+    }
+}
+
+/// A write of a derivative to the disk tier.
+enum DiskWrite {
+    Chunk(Bytes),
+    /// The derivative was streamed in full, so it can be added to the disk tier.
+    Finish,
+}
+
+/// Write the chunks of a derivative to a file of its own as they arrive, and add it to the disk
+/// tier once it is finished. The file is removed if the derivative is abandoned first.
+async fn write_to_disk(
+    disk: Arc<DiskTier>,
+    metrics: Arc<CacheMetrics>,
+    (key, version, media_type): (DerivativeKey, SourceVersion, MediaTypeBuf),
+    mut writes: mpsc::Receiver<DiskWrite>,
+) {
+    // Nothing is written for streams that are dropped before producing anything.
+    let Some(mut write) = writes.recv().await else {
+        return;
+    };
+
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+
+    let name = format!("{:016x}", hasher.finish());
+    let write_number = disk.writes.fetch_add(1, Ordering::Relaxed);
+    let partial = disk
+        .directory
+        .join(format!("{name}.{write_number}.{PARTIAL_EXTENSION}"));
+    // Every write is renamed to a path of its own, so that removing a stale or evicted entry
+    // never removes one written concurrently for the same key.
+    let path = disk
+        .directory
+        .join(format!("{name}.{write_number}.{ENTRY_EXTENSION}"));
+
+    let written = async {
+        let mut file = tokio::fs::File::create(&partial).await?;
+        let mut size = 0;
+
+        loop {
+            match write {
+                DiskWrite::Chunk(data) => {
+                    file.write_all(&data).await?;
+                    size += data.len() as u64;
+                }
+                DiskWrite::Finish => {
+                    file.flush().await?;
+                    tokio::fs::rename(&partial, &path).await?;
+
+                    return Ok::<_, std::io::Error>(Some(size));
+                }
+            }
+
+            match writes.recv().await {
+                Some(next) => write = next,
+                None => return Ok(None),
+            }
+        }
+    };
+
+    let size = match written.await {
+        Ok(Some(size)) => size,
+        Ok(None) => {
+            debug!("derivative was abandoned before it was written to disk");
+            let _ = tokio::fs::remove_file(&partial).await;
+            return;
+        }
+        Err(error) => {
+            warn!(%error, "unable to write derivative to disk");
+            let _ = tokio::fs::remove_file(&partial).await;
+            return;
+        }
+    };
+
+    debug!(size, path = %path.display(), "cached derivative on disk");
+
+    let evicted = disk
+        .index
+        .lock()
+        .expect("derivative cache was poisoned")
+        .insert(key, version, media_type, size, path);
+
+    metrics.record_evictions("disk", evicted.len());
+    remove_files(evicted);
+}
+
+fn remove_files(paths: Vec<PathBuf>) {
+    if paths.is_empty() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        for path in paths {
+            if let Err(error) = tokio::fs::remove_file(&path).await {
+                warn!(%error, path = %path.display(), "unable to remove cached derivative");
+            }
+        }
+    });
+}
+
+/// A stream that passes through encoded image data while copying it into the cache.
+struct TeeStream {
+    inner: Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + Unpin>,
+    /// The size of the data that has passed through so far.
+    size: u64,
+    /// A copy of the data for the memory tier, until it grows larger than `memory_limit`.
+    memory: Option<BytesMut>,
+    memory_limit: u64,
+    /// Where the data is sent to be written to disk, and the capacity of the disk tier, until the
+    /// data outgrows it or the writes fall behind.
+    disk: Option<(mpsc::Sender<DiskWrite>, u64)>,
+    entry: Option<(DerivativeCache, DerivativeKey, SourceVersion, MediaTypeBuf)>,
+}
+
+impl Stream for TeeStream {
+    type Item = Result<Bytes, std::io::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let item = std::task::ready!(this.inner.poll_next_unpin(cx));
+
+        match &item {
+            Some(Ok(data)) => {
+                this.size += data.len() as u64;
+
+                if this.size > this.memory_limit {
+                    this.memory = None;
+                } else if let Some(buffer) = &mut this.memory {
+                    buffer.extend_from_slice(data);
+                }
+
+                let written = this.disk.as_ref().is_some_and(|(writes, capacity)| {
+                    this.size <= *capacity
+                        && writes.try_send(DiskWrite::Chunk(data.clone())).is_ok()
+                });
+                if !written {
+                    this.disk = None;
+                }
+            }
+            Some(Err(_)) => {
+                this.memory = None;
+                this.disk = None;
+            }
+            None => {
+                if let Some((writes, _)) = this.disk.take() {
+                    // The queue may be full of chunks still being written.
+                    tokio::spawn(async move { writes.send(DiskWrite::Finish).await });
+                }
+
+                if let Some((buffer, (cache, key, version, media_type))) =
+                    this.memory.take().zip(this.entry.take())
+                {
+                    cache.insert(key, version, media_type, buffer.freeze());
+                }
+            }
+        }
+
+        Poll::Ready(item)
+    }
+}
+
+/// A set of cache entries bounded by their total size, evicting the least recently used entries
+/// once full.
+struct Tier<V> {
+    capacity: u64,
+    entries: HashMap<DerivativeKey, TierEntry<V>>,
+    size: u64,
+    clock: u64,
+}
+
+struct TierEntry<V> {
+    version: SourceVersion,
+    media_type: MediaTypeBuf,
+    size: u64,
+    value: V,
+    last_access: u64,
+}
+
+impl<V: Clone> Tier<V> {
+    fn new(capacity: u64) -> Self {
+        Self { capacity, entries: HashMap::new(), size: 0, clock: 0 }
+    }
+
+    /// Get the entry for `key`, marking it as recently used.
+    fn get(&mut self, key: &DerivativeKey) -> Option<(MediaTypeBuf, V)> {
+        self.clock += 1;
+
+        let entry = self.entries.get_mut(key)?;
+        entry.last_access = self.clock;
+
+        Some((entry.media_type.clone(), entry.value.clone()))
+    }
+
+    /// Remove the entry for `key` if it wasn't rendered from `version` of the source image.
+    fn remove_stale(&mut self, key: &DerivativeKey, version: &SourceVersion) -> Option<V> {
+        if self
+            .entries
+            .get(key)
+            .is_some_and(|entry| &entry.version != version)
+        {
+            debug!(key = key.0, "source image changed, invalidating derivative");
+            return self.remove(key);
+        }
+
+        None
+    }
+
+    fn remove(&mut self, key: &DerivativeKey) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.size -= entry.size;
+
+        Some(entry.value)
+    }
+
+    /// Add an entry, returning the values of any entries that were replaced or evicted to make
+    /// room for it.
+    fn insert(
+        &mut self,
+        key: DerivativeKey,
+        version: SourceVersion,
+        media_type: MediaTypeBuf,
+        size: u64,
+        value: V,
+    ) -> Vec<V> {
+        if size > self.capacity {
+            return vec![];
+        }
+
+        let mut evicted: Vec<V> = self.remove(&key).into_iter().collect();
+        while self.size + size > self.capacity {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_access)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+
+            evicted.extend(self.remove(&oldest));
+        }
+
+        self.clock += 1;
+        self.size += size;
+        self.entries
+            .insert(key, TierEntry { version, media_type, size, value, last_access: self.clock });
+
+        evicted
+    }
+}
+
+struct CacheMetrics {
+    lookups: Counter<u64>,
+    evictions: Counter<u64>,
+}
+
+impl CacheMetrics {
+    fn new() -> Self {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+
+        Self {
+            lookups: meter
+                .u64_counter("derivative_cache.lookups")
+                .with_description("Derivative cache lookups, by result (memory, disk or miss)")
+                .build(),
+            evictions: meter
+                .u64_counter("derivative_cache.evictions")
+                .with_description(
+                    "Derivatives evicted from a cache tier to stay within its capacity",
+                )
+                .build(),
+        }
+    }
+
+    fn record_lookup(&self, result: &'static str) {
+        self.lookups.add(1, &[KeyValue::new("result", result)]);
+    }
+
+    fn record_evictions(&self, tier: &'static str, count: usize) {
+        if count > 0 {
+            self.evictions
+                .add(count as u64, &[KeyValue::new("tier", tier)]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::TryStreamExt;
+    use mediatype::names::{IMAGE, JPEG};
+
+    use super::*;
+    use crate::iiif::ImageServiceRequest;
+    use crate::iiif::service::ImageServiceRequestKind;
+    use crate::image::info::ImageInfo;
+    use crate::image::transcoding::plan::plan_output;
+
+    fn key(name: &str) -> DerivativeKey {
+        DerivativeKey(format!("{name}/full/max/0/default.jpg"))
+    }
+
+    fn version(etag: &str) -> SourceVersion {
+        SourceVersion { etag: Some(etag.to_string()), last_modified: None }
+    }
+
+    fn image(chunks: Vec<Result<Bytes, std::io::Error>>) -> ImageStream {
+        ImageStream {
+            media_type: MediaTypeBuf::new(IMAGE, JPEG),
+            data: Box::new(futures::stream::iter(chunks)),
+        }
+    }
+
+    fn data(chunks: &[&'static [u8]]) -> Vec<Result<Bytes, std::io::Error>> {
+        chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::from_static(chunk)))
+            .collect()
+    }
+
+    async fn drain(stream: ImageStream) -> std::io::Result<Vec<u8>> {
+        let chunks: Vec<Bytes> = stream.data.try_collect().await?;
+        Ok(chunks.concat())
+    }
+
+    /// Wait for derivatives to be written to the disk tier in the background.
+    async fn wait_for_disk(cache: &DerivativeCache, entries: usize) {
+        let disk = cache.disk.as_ref().unwrap();
+
+        for _ in 0..100 {
+            if disk.index.lock().unwrap().entries.len() == entries {
+                return;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        panic!("derivatives were not written to disk");
+    }
+
+    #[tokio::test]
+    async fn caches_streamed_derivatives_in_memory() {
+        let cache = DerivativeCache::new(1024);
+        assert!(cache.get(&key("a"), &version("1")).await.is_none());
+
+        let stream = cache.tee(key("a"), version("1"), image(data(&[b"abc", b"def"])));
+        assert_eq!(drain(stream).await.unwrap(), b"abcdef");
+
+        let hit = cache.get(&key("a"), &version("1")).await.unwrap();
+        assert_eq!(hit.media_type, MediaTypeBuf::new(IMAGE, JPEG));
+        assert_eq!(drain(hit).await.unwrap(), b"abcdef");
+    }
+
+    #[tokio::test]
+    async fn invalidates_derivatives_when_source_changes() {
+        let cache = DerivativeCache::new(1024);
+        drain(cache.tee(key("a"), version("1"), image(data(&[b"abc"]))))
+            .await
+            .unwrap();
+
+        assert!(cache.get(&key("a"), &version("2")).await.is_none());
+        assert!(cache.get(&key("a"), &version("1")).await.is_none());
+        assert_eq!(cache.memory.lock().unwrap().size, 0);
+    }
+
+    #[tokio::test]
+    async fn ignores_incomplete_derivatives() {
+        let cache = DerivativeCache::new(1024);
+
+        let mut failed = data(&[b"abc"]);
+        failed.push(Err(std::io::Error::other("decoding failed")));
+        assert!(
+            drain(cache.tee(key("a"), version("1"), image(failed)))
+                .await
+                .is_err()
+        );
+
+        let mut dropped = cache.tee(key("b"), version("1"), image(data(&[b"abc", b"def"])));
+        dropped.data.next().await.unwrap().unwrap();
+        drop(dropped);
+
+        assert!(cache.get(&key("a"), &version("1")).await.is_none());
+        assert!(cache.get(&key("b"), &version("1")).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used_derivatives() {
+        let cache = DerivativeCache::new(8);
+
+        drain(cache.tee(key("a"), version("1"), image(data(&[b"aaa"]))))
+            .await
+            .unwrap();
+        drain(cache.tee(key("b"), version("1"), image(data(&[b"bbb"]))))
+            .await
+            .unwrap();
+        assert!(cache.get(&key("a"), &version("1")).await.is_some());
+        drain(cache.tee(key("c"), version("1"), image(data(&[b"ccc"]))))
+            .await
+            .unwrap();
+
+        assert!(cache.get(&key("a"), &version("1")).await.is_some());
+        assert!(cache.get(&key("b"), &version("1")).await.is_none());
+        assert!(cache.get(&key("c"), &version("1")).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn never_buffers_derivatives_larger_than_a_memory_entry() {
+        let cache = DerivativeCache { max_memory_entry: 4, ..DerivativeCache::new(1024) };
+
+        let mut stream = cache.tee_stream(key("a"), version("1"), image(data(&[b"abc", b"def"])));
+        stream.next().await.unwrap().unwrap();
+        assert_eq!(stream.memory.as_deref(), Some(b"abc".as_slice()));
+
+        stream.next().await.unwrap().unwrap();
+        assert!(stream.memory.is_none());
+        assert!(stream.next().await.is_none());
+
+        assert!(cache.get(&key("a"), &version("1")).await.is_none());
+        assert_eq!(cache.memory.lock().unwrap().size, 0);
+    }
+
+    #[tokio::test]
+    async fn removes_abandoned_derivatives_from_disk() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = DerivativeCache::new(0)
+            .with_disk_tier(directory.path().to_path_buf(), 1024)
+            .unwrap();
+
+        let mut dropped = cache.tee(key("a"), version("1"), image(data(&[b"abc", b"def"])));
+        dropped.data.next().await.unwrap().unwrap();
+        drop(dropped);
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 0);
+        assert!(cache.get(&key("a"), &version("1")).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn serves_derivatives_from_disk() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = DerivativeCache::new(0)
+            .with_disk_tier(directory.path().to_path_buf(), 1024)
+            .unwrap();
+
+        drain(cache.tee(key("a"), version("1"), image(data(&[b"abc", b"def"]))))
+            .await
+            .unwrap();
+        wait_for_disk(&cache, 1).await;
+
+        let hit = cache.get(&key("a"), &version("1")).await.unwrap();
+        assert_eq!(drain(hit).await.unwrap(), b"abcdef");
+
+        assert!(cache.get(&key("a"), &version("2")).await.is_none());
+        wait_for_disk(&cache, 0).await;
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn keeps_derivatives_rewritten_for_a_new_version() {
+        let directory = tempfile::tempdir().unwrap();
+        let cache = DerivativeCache::new(0)
+            .with_disk_tier(directory.path().to_path_buf(), 1024)
+            .unwrap();
+
+        drain(cache.tee(key("a"), version("1"), image(data(&[b"abc"]))))
+            .await
+            .unwrap();
+        wait_for_disk(&cache, 1).await;
+
+        // The new version is written to a file of its own, and the file it replaces is removed.
+        drain(cache.tee(key("a"), version("2"), image(data(&[b"def"]))))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let hit = cache.get(&key("a"), &version("2")).await.unwrap();
+        assert_eq!(drain(hit).await.unwrap(), b"def");
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 1);
+    }
+
+    /// The key of an image request for a 400x300 source image.
+    fn planned_key(request: &str, color: ColorManagement) -> DerivativeKey {
+        let info = ImageInfo {
+            width: 400,
+            height: 300,
+            max_width: None,
+            max_height: None,
+            max_area: None,
+            sizes: None,
+            tiles: None,
+            preferred_formats: None,
+            rights: None,
+        };
+
+        let request: ImageServiceRequest = request.parse().unwrap();
+        let ImageServiceRequestKind::Image(params) = request.kind else {
+            panic!("expected an image request");
+        };
+
+        let output = plan_output(&params, &info).unwrap();
+        DerivativeKey::new(&request.identifier, &params, &output, color)
+    }
+
+    #[test]
+    fn keys_equivalent_requests_identically() {
+        let key = |request| planned_key(request, ColorManagement::Convert);
+
+        let full = key("i/full/max/0/default.jpg");
+        assert_eq!(key("i/0,0,400,300/400,/0/default.jpg"), full);
+        assert_eq!(
+            key("i/pct:25,50,50,50/pct:50/0/default.jpg"),
+            key("i/100,150,200,150/100,/0/default.jpg")
+        );
+        assert_eq!(key("i/square/150,/0/default.jpg"), key("i/50,0,300,300/150,150/0/default.jpg"));
+        assert_ne!(key("i/full/200,/0/default.jpg"), full);
+    }
+
+    #[test]
+    fn keys_derivatives_on_color_management() {
+        assert_ne!(
+            planned_key("i/full/max/0/default.jpg", ColorManagement::Convert),
+            planned_key("i/full/max/0/default.jpg", ColorManagement::Preserve)
+        );
+    }
+}
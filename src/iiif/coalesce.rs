@@ -0,0 +1,683 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use bytes::Bytes;
+use futures::future::{BoxFuture, Shared};
+use futures::{FutureExt, Stream, StreamExt};
+use mediatype::MediaTypeBuf;
+use opentelemetry::metrics::Counter;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+use tracing::debug;
+
+use super::service::{ImageServiceError, ImageServiceResponse, ImageServiceResponseKind};
+use crate::image::ImageStream;
+use crate::image::info::ImageInfo;
+
+/// The most encoded image data kept for replay to requests that join an in-flight request late.
+/// Waiters may fall up to four times as far behind the fastest waiter before they are detached,
+/// and the request runs up to this far ahead of the fastest waiter.
+const REPLAY_LIMIT: usize = 1024 * 1024;
+
+type SharedFuture = Shared<BoxFuture<'static, Result<SharedResponse, ImageServiceError>>>;
+
+type Registry = Arc<Mutex<HashMap<String, Flight>>>;
+
+/// A request in progress, and the encoded image data it has produced so far.
+struct Flight {
+    response: SharedFuture,
+    data: Arc<FanOut>,
+}
+
+/// Deduplicates identical image service requests that are in progress at the same time.
+///
+/// The first request for a canonical key (the leader) is handled on its own task, and any
+/// identical requests that arrive before it has finished streaming its response (the followers)
+/// wait for the leader's response instead of opening storage and decoding the image themselves.
+/// Encoded image data is buffered as it is produced and replayed to every waiter, so followers
+/// that join late still receive the whole response. Once more data has been produced than can be
+/// replayed, no more followers join, and data is only kept until every waiter has received it.
+///
+/// The request produces data as fast as its fastest waiter receives it. A waiter that falls too
+/// far behind is detached with an error, rather than holding back every other waiter or growing
+/// the buffered data without bound.
+///
+/// Waiters that disconnect only stop receiving data. The shared work is abandoned once every
+/// waiter has disconnected.
+pub(crate) struct InFlightRequests {
+    flights: Registry,
+    replay_limit: usize,
+    coalesced: Counter<u64>,
+}
+
+impl InFlightRequests {
+    pub(crate) fn new() -> Self {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+
+        Self {
+            flights: Arc::new(Mutex::new(HashMap::new())),
+            replay_limit: REPLAY_LIMIT,
+            coalesced: meter
+                .u64_counter("image_service.coalesced_requests")
+                .with_description("Requests served by waiting for an identical request in progress")
+                .build(),
+        }
+    }
+
+    /// Keep at most `replay_limit` bytes of encoded image data for requests that join late.
+    #[cfg(test)]
+    fn with_replay_limit(self, replay_limit: usize) -> Self {
+        Self { replay_limit, ..self }
+    }
+
+    /// Handle the request identified by `key` with `work`, unless an identical request is already
+    /// in progress.
+    pub(crate) fn call<F>(
+        &self,
+        key: String,
+        work: F,
+    ) -> BoxFuture<'static, Result<ImageServiceResponse, ImageServiceError>>
+    where
+        F: Future<Output = Result<ImageServiceResponse, ImageServiceError>> + Send + 'static,
+    {
+        let mut flights = self
+            .flights
+            .lock()
+            .expect("in-flight requests were poisoned");
+
+        // Waiters subscribe while the registry is locked, so that none joins a flight as it is
+        // removed.
+        let (response, subscriber) = match flights.get(&key) {
+            Some(flight) => {
+                debug!(key, "waiting for identical request in progress");
+                self.coalesced.add(1, &[]);
+                (flight.response.clone(), FanOut::subscribe(&flight.data))
+            }
+            None => {
+                let data = FanOut::new(self.flights.clone(), key.clone(), self.replay_limit);
+                let subscriber = FanOut::subscribe(&data);
+
+                let producer = data.clone();
+                let task = tokio::spawn(async move {
+                    match work.await {
+                        Ok(response) => Ok(SharedResponse::new(response, producer)),
+                        Err(error) => {
+                            producer.close();
+                            Err(error)
+                        }
+                    }
+                });
+
+                let failed = data.clone();
+                let response = task
+                    .map(move |result| {
+                        result.unwrap_or_else(|error| {
+                            failed.close();
+                            Err(ImageServiceError::Internal(format!(
+                                "request task failed: {error}"
+                            )))
+                        })
+                    })
+                    .boxed()
+                    .shared();
+
+                flights.insert(key, Flight { response: response.clone(), data });
+                (response, subscriber)
+            }
+        };
+
+        Box::pin(async move {
+            response
+                .await
+                .map(|shared| shared.into_response(subscriber))
+        })
+    }
+}
+
+/// A response that can be handed to each waiter of an in-flight request.
+#[derive(Clone)]
+struct SharedResponse {
+    kind: SharedResponseKind,
+    last_modified_time: Option<std::time::SystemTime>,
+    etag: Option<String>,
+}
+
+#[derive(Clone)]
+enum SharedResponseKind {
+    Info(ImageInfo),
+    Image { media_type: MediaTypeBuf },
+    CacheHit,
+}
+
+impl SharedResponse {
+    /// Share a response, streaming its image data through `data`.
+    fn new(response: ImageServiceResponse, data: Arc<FanOut>) -> Self {
+        let ImageServiceResponse { kind, last_modified_time, etag } = response;
+        let kind = match kind {
+            ImageServiceResponseKind::Info(info) => {
+                data.close();
+                SharedResponseKind::Info(info)
+            }
+            ImageServiceResponseKind::CacheHit => {
+                data.close();
+                SharedResponseKind::CacheHit
+            }
+            ImageServiceResponseKind::Image(ImageStream { media_type, data: stream }) => {
+                data.spawn(stream);
+                SharedResponseKind::Image { media_type }
+            }
+        };
+
+        Self { kind, last_modified_time, etag }
+    }
+
+    fn into_response(self, subscriber: Subscriber) -> ImageServiceResponse {
+        let kind = match self.kind {
+            SharedResponseKind::Info(info) => ImageServiceResponseKind::Info(info),
+            SharedResponseKind::CacheHit => ImageServiceResponseKind::CacheHit,
+            SharedResponseKind::Image { media_type } => {
+                ImageServiceResponseKind::Image(ImageStream {
+                    media_type,
+                    data: Box::new(subscriber),
+                })
+            }
+        };
+
+        ImageServiceResponse { kind, last_modified_time: self.last_modified_time, etag: self.etag }
+    }
+}
+
+type ByteStream = Box<dyn Stream<Item = Result<Bytes, std::io::Error>> + Send + Sync + Unpin>;
+
+/// Encoded image data produced by a single request, replayed to each of its waiters.
+struct FanOut {
+    state: Mutex<FanOutState>,
+    abandoned: CancellationToken,
+    /// Notified when a waiter receives data or disconnects, which may let the request produce
+    /// more.
+    received: Notify,
+    registry: Registry,
+    key: String,
+    replay_limit: usize,
+}
+
+#[derive(Default)]
+struct FanOutState {
+    /// The data that hasn't yet been received by every waiter, starting with chunk `first`.
+    chunks: VecDeque<Bytes>,
+    first: usize,
+    buffered: usize,
+    end: Option<Result<(), String>>,
+    /// Whether the flight has been removed from the registry, so that no more waiters join.
+    closed: bool,
+    /// The position of each waiter in the data.
+    positions: HashMap<u64, usize>,
+    /// Waiters that fell too far behind, which haven't yet been told so.
+    detached: HashSet<u64>,
+    next_subscriber: u64,
+    wakers: Vec<Waker>,
+}
+
+impl FanOutState {
+    /// Drop the data that every waiter has received, once no more waiters can join.
+    fn trim(&mut self) -> bool {
+        if !self.closed {
+            return false;
+        }
+
+        let received = self
+            .positions
+            .values()
+            .copied()
+            .min()
+            .unwrap_or(self.first + self.chunks.len());
+        let trimmed = received > self.first;
+
+        while self.first < received {
+            let chunk = self
+                .chunks
+                .pop_front()
+                .expect("waiters are within the data");
+            self.buffered -= chunk.len();
+            self.first += 1;
+        }
+
+        trimmed
+    }
+
+    /// The position just past the last chunk produced so far.
+    fn end_position(&self) -> usize {
+        self.first + self.chunks.len()
+    }
+
+    /// The bytes produced that a waiter at `position` hasn't received yet.
+    fn lag(&self, position: usize) -> usize {
+        self.chunks
+            .iter()
+            .skip(position - self.first)
+            .map(Bytes::len)
+            .sum()
+    }
+
+    /// The bytes produced that even the fastest waiter hasn't received yet.
+    fn lead(&self) -> usize {
+        self.positions
+            .values()
+            .max()
+            .map_or(0, |&position| self.lag(position))
+    }
+}
+
+impl FanOut {
+    fn new(registry: Registry, key: String, replay_limit: usize) -> Arc<Self> {
+        Arc::new(FanOut {
+            state: Mutex::default(),
+            abandoned: CancellationToken::new(),
+            received: Notify::new(),
+            registry,
+            key,
+            replay_limit,
+        })
+    }
+
+    /// Drive `data` to completion on its own task, so it isn't cancelled along with any one
+    /// waiter.
+    fn spawn(self: Arc<Self>, mut data: ByteStream) {
+        tokio::spawn(async move {
+            loop {
+                let next = tokio::select! {
+                    _ = self.abandoned.cancelled() => {
+                        debug!("every waiter disconnected, abandoning request");
+                        self.finish(Err("request was abandoned".into()));
+                        break;
+                    }
+                    next = async {
+                        // Wait for a waiter to catch up rather than buffering the whole image.
+                        self.wait_for_waiters().await;
+                        data.next().await
+                    } => next,
+                };
+
+                match next {
+                    Some(Ok(chunk)) => self.push(chunk),
+                    Some(Err(error)) => {
+                        self.finish(Err(error.to_string()));
+                        break;
+                    }
+                    None => {
+                        self.finish(Ok(()));
+                        break;
+                    }
+                }
+            }
+
+            self.close();
+        });
+    }
+
+    /// Wait until the fastest waiter is no more than the replay limit behind the data produced.
+    async fn wait_for_waiters(&self) {
+        loop {
+            let received = self.received.notified();
+            if self.state.lock().expect("fan-out was poisoned").lead() <= self.replay_limit {
+                return;
+            }
+
+            received.await;
+        }
+    }
+
+    fn push(&self, chunk: Bytes) {
+        let exceeded = {
+            let mut state = self.state.lock().expect("fan-out was poisoned");
+            state.buffered += chunk.len();
+            state.chunks.push_back(chunk);
+            self.detach_lagging(&mut state);
+            state.wakers.drain(..).for_each(Waker::wake);
+
+            !state.closed && state.buffered > self.replay_limit
+        };
+
+        if exceeded {
+            debug!(key = self.key, "replay limit exceeded, no longer coalescing request");
+            self.close();
+        }
+    }
+
+    /// Detach the waiters that have fallen too far behind the fastest, so that the data they
+    /// haven't received isn't kept for them.
+    fn detach_lagging(&self, state: &mut FanOutState) {
+        let lag_limit = self.replay_limit * 4;
+        let Some(&fastest) = state.positions.values().max() else {
+            return;
+        };
+
+        let lagging: Vec<u64> = state
+            .positions
+            .iter()
+            .filter(|&(_, &position)| position < fastest && state.lag(position) > lag_limit)
+            .map(|(&id, _)| id)
+            .collect();
+
+        for id in lagging {
+            debug!(key = self.key, "detaching a waiter that fell too far behind");
+            state.positions.remove(&id);
+            state.detached.insert(id);
+        }
+
+        state.trim();
+    }
+
+    fn finish(&self, result: Result<(), String>) {
+        let mut state = self.state.lock().expect("fan-out was poisoned");
+        state.end = Some(result);
+        state.wakers.drain(..).for_each(Waker::wake);
+    }
+
+    /// Remove the flight from the registry, so that no more waiters join it.
+    fn close(&self) {
+        self.unregister(
+            &mut self
+                .registry
+                .lock()
+                .expect("in-flight requests were poisoned"),
+        );
+
+        let mut state = self.state.lock().expect("fan-out was poisoned");
+        state.closed = true;
+        state.trim();
+    }
+
+    fn unregister(&self, flights: &mut HashMap<String, Flight>) {
+        let registered = flights
+            .get(&self.key)
+            .is_some_and(|flight| std::ptr::eq(Arc::as_ptr(&flight.data), self));
+
+        if registered {
+            flights.remove(&self.key);
+        }
+    }
+
+    fn subscribe(fan_out: &Arc<Self>) -> Subscriber {
+        let mut state = fan_out.state.lock().expect("fan-out was poisoned");
+        let id = state.next_subscriber;
+        state.next_subscriber += 1;
+
+        let position = state.first;
+        state.positions.insert(id, position);
+
+        Subscriber { fan_out: fan_out.clone(), id, position, detached: false }
+    }
+}
+
+/// A waiter's view of the encoded image data of an in-flight request.
+struct Subscriber {
+    fan_out: Arc<FanOut>,
+    id: u64,
+    position: usize,
+    /// Whether the waiter fell too far behind and no longer receives data.
+    detached: bool,
+}
+
+impl Stream for Subscriber {
+    type Item = Result<Bytes, std::io::Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.detached {
+            return Poll::Ready(None);
+        }
+
+        let mut state = this.fan_out.state.lock().expect("fan-out was poisoned");
+        if state.detached.remove(&this.id) {
+            this.detached = true;
+            return Poll::Ready(Some(Err(std::io::Error::other(
+                "fell too far behind an identical request in progress",
+            ))));
+        }
+
+        if let Some(chunk) = state.chunks.get(this.position - state.first).cloned() {
+            this.position += 1;
+            state.positions.insert(this.id, this.position);
+            state.trim();
+            this.fan_out.received.notify_waiters();
+
+            return Poll::Ready(Some(Ok(chunk)));
+        }
+
+        let end = state.end_position();
+        match &state.end {
+            Some(Ok(())) => Poll::Ready(None),
+            Some(Err(error)) if this.position == end => {
+                // Report the error once, then end the stream.
+                this.position += 1;
+                Poll::Ready(Some(Err(std::io::Error::other(error.clone()))))
+            }
+            Some(Err(_)) => Poll::Ready(None),
+            None => {
+                state.wakers.push(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        let mut flights = self
+            .fan_out
+            .registry
+            .lock()
+            .expect("in-flight requests were poisoned");
+        let mut state = self.fan_out.state.lock().expect("fan-out was poisoned");
+        state.positions.remove(&self.id);
+        state.detached.remove(&self.id);
+
+        if state.positions.is_empty() && state.end.is_none() {
+            // Remove the flight before abandoning it, so that no request joins it only to fail.
+            self.fan_out.unregister(&mut flights);
+            self.fan_out.abandoned.cancel();
+        } else {
+            state.trim();
+            self.fan_out.received.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use futures::TryStreamExt;
+    use mediatype::names::{IMAGE, JPEG};
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+
+    use super::*;
+
+    /// An image response whose data is sent through the returned channel.
+    fn streamed_response() -> (mpsc::Sender<Result<Bytes, std::io::Error>>, ImageServiceResponse) {
+        let (sender, receiver) = mpsc::channel(16);
+        let response = ImageServiceResponse {
+            kind: ImageServiceResponseKind::Image(ImageStream {
+                media_type: MediaTypeBuf::new(IMAGE, JPEG),
+                data: Box::new(ReceiverStream::new(receiver)),
+            }),
+            last_modified_time: None,
+            etag: None,
+        };
+
+        (sender, response)
+    }
+
+    fn image_data(response: ImageServiceResponse) -> ByteStream {
+        match response.kind {
+            ImageServiceResponseKind::Image(stream) => stream.data,
+            _ => panic!("expected an image response"),
+        }
+    }
+
+    #[tokio::test]
+    async fn identical_requests_share_one_response() {
+        let requests = InFlightRequests::new();
+        let handled = Arc::new(AtomicUsize::new(0));
+
+        let waiters: Vec<_> = (0..5)
+            .map(|_| {
+                let handled = handled.clone();
+                requests.call("a/full/max/0/default.jpg".into(), async move {
+                    handled.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+
+                    let (sender, response) = streamed_response();
+                    tokio::spawn(async move {
+                        for chunk in [&b"abc"[..], b"def"] {
+                            sender.send(Ok(Bytes::from_static(chunk))).await.unwrap();
+                        }
+                    });
+
+                    Ok(response)
+                })
+            })
+            .collect();
+
+        for response in futures::future::join_all(waiters).await {
+            let chunks: Vec<Bytes> = image_data(response.unwrap()).try_collect().await.unwrap();
+            assert_eq!(chunks.concat(), b"abcdef");
+        }
+
+        assert_eq!(handled.load(Ordering::SeqCst), 1);
+        assert!(requests.flights.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn followers_disconnecting_do_not_cancel_the_leader() {
+        let requests = InFlightRequests::new();
+        let (sender, response) = streamed_response();
+
+        let leader = requests.call("a".into(), async move { Ok(response) });
+        let follower = requests.call("a".into(), async { unreachable!() });
+
+        let leader = image_data(leader.await.unwrap());
+        let mut follower = image_data(follower.await.unwrap());
+
+        sender.send(Ok(Bytes::from_static(b"abc"))).await.unwrap();
+        assert_eq!(follower.next().await.unwrap().unwrap(), "abc");
+        drop(follower);
+
+        sender.send(Ok(Bytes::from_static(b"def"))).await.unwrap();
+        drop(sender);
+
+        let chunks: Vec<Bytes> = leader.try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), b"abcdef");
+    }
+
+    #[tokio::test]
+    async fn abandons_work_once_every_waiter_disconnects() {
+        let requests = InFlightRequests::new();
+        let (sender, response) = streamed_response();
+
+        let waiter = requests.call("a".into(), async move { Ok(response) });
+        let mut data = image_data(waiter.await.unwrap());
+
+        sender.send(Ok(Bytes::from_static(b"abc"))).await.unwrap();
+        data.next().await.unwrap().unwrap();
+        drop(data);
+
+        // The receiving end of the image data is dropped once the request is abandoned.
+        tokio::time::timeout(Duration::from_secs(1), sender.closed())
+            .await
+            .expect("request was not abandoned");
+        tokio::task::yield_now().await;
+
+        assert!(requests.flights.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn removes_abandoned_requests_before_cancelling_them() {
+        let requests = InFlightRequests::new();
+        let (_sender, response) = streamed_response();
+
+        let waiter = requests.call("a".into(), async move { Ok(response) });
+        let data = image_data(waiter.await.unwrap());
+        drop(data);
+
+        // A request arriving as the last waiter disconnects starts afresh.
+        assert!(requests.flights.lock().unwrap().is_empty());
+
+        let (sender, response) = streamed_response();
+        let waiter = requests.call("a".into(), async move { Ok(response) });
+        sender.send(Ok(Bytes::from_static(b"abc"))).await.unwrap();
+        drop(sender);
+
+        let chunks: Vec<Bytes> = image_data(waiter.await.unwrap())
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(chunks.concat(), b"abc");
+    }
+
+    #[tokio::test]
+    async fn stops_coalescing_once_the_replay_limit_is_exceeded() {
+        let requests = InFlightRequests::new().with_replay_limit(4);
+        let (sender, response) = streamed_response();
+
+        let leader = requests.call("a".into(), async move { Ok(response) });
+        let mut leader = image_data(leader.await.unwrap());
+
+        sender.send(Ok(Bytes::from_static(b"abc"))).await.unwrap();
+        assert_eq!(leader.next().await.unwrap().unwrap(), "abc");
+        let follower = requests.call("a".into(), async { unreachable!() });
+        let follower = image_data(follower.await.unwrap());
+
+        sender.send(Ok(Bytes::from_static(b"def"))).await.unwrap();
+        assert_eq!(leader.next().await.unwrap().unwrap(), "def");
+
+        // Data that every waiter has received is no longer kept, so new requests aren't joined.
+        assert!(requests.flights.lock().unwrap().is_empty());
+        let (other, response) = streamed_response();
+        let late = requests.call("a".into(), async move { Ok(response) });
+        drop(other);
+        let chunks: Vec<Bytes> = image_data(late.await.unwrap()).try_collect().await.unwrap();
+        assert!(chunks.is_empty());
+
+        sender.send(Ok(Bytes::from_static(b"ghi"))).await.unwrap();
+        drop(sender);
+
+        let chunks: Vec<Bytes> = follower.try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), b"abcdefghi");
+        let chunks: Vec<Bytes> = leader.try_collect().await.unwrap();
+        assert_eq!(chunks.concat(), b"ghi");
+    }
+
+    #[tokio::test]
+    async fn detaches_followers_that_fall_too_far_behind() {
+        let requests = InFlightRequests::new().with_replay_limit(4);
+        let (sender, response) = streamed_response();
+
+        let leader = requests.call("a".into(), async move { Ok(response) });
+        let follower = requests.call("a".into(), async { unreachable!() });
+        let leader = image_data(leader.await.unwrap());
+        let mut follower = image_data(follower.await.unwrap());
+
+        tokio::spawn(async move {
+            for _ in 0..10 {
+                sender.send(Ok(Bytes::from_static(b"abcd"))).await.unwrap();
+            }
+        });
+
+        // The follower never reads, but the leader still receives the whole image.
+        let chunks: Vec<Bytes> = tokio::time::timeout(Duration::from_secs(1), leader.try_collect())
+            .await
+            .expect("the leader was held back by the follower")
+            .unwrap();
+        assert_eq!(chunks.concat().len(), 40);
+
+        assert!(follower.next().await.unwrap().is_err());
+        assert!(follower.next().await.is_none());
+    }
+}
@@ -1,27 +1,37 @@
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
 use std::future::Future;
+use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::Poll;
+use std::time::Duration;
 
 use futures::{Stream, StreamExt};
 use http_body::Frame;
 use http_body_util::combinators::BoxBody;
 use http_body_util::{BodyExt, Empty, Full, StreamBody};
-use hyper::body::{Bytes, Incoming};
-use hyper::header::{CONTENT_TYPE, HeaderValue, LAST_MODIFIED};
+use hyper::body::Bytes;
+use hyper::header::{CONTENT_TYPE, ETAG, HeaderName, HeaderValue, LAST_MODIFIED, RETRY_AFTER};
 use hyper::{Request, Response, StatusCode};
 use serde_json::{Value, json, to_string_pretty};
 use tower::Service;
-use tracing::{Instrument, error};
+use tracing::{Instrument, debug, error, warn};
 
 use super::service::{
     ImageServiceError, ImageServiceRequestKind, ImageServiceResponse, ImageServiceResponseKind,
 };
 use crate::iiif::ImageServiceRequest;
-use crate::iiif::parse::ParseError as ImageRequestParseError;
+use crate::iiif::parse::{ParseError as ImageRequestParseError, parse_quality_layers};
+use crate::image::codec::CodecError;
+use crate::image::transcoding::admission::PriorityClass;
 use crate::storage::StorageError;
 
+/// The address of the client on the other end of a connection, added to each request by the
+/// runtime that accepted it.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientAddress(pub SocketAddr);
+
 #[derive(Clone)]
 pub struct HttpImageService<S>
 where
@@ -29,15 +39,91 @@ where
 {
     inner: S,
     prefix: String,
+    priority_header: Option<HeaderName>,
+    /// The header identifying clients, and the number of proxies that append to it.
+    client_header: Option<(HeaderName, usize)>,
+    quality_layers_parameter: bool,
 }
 
 impl<S: Clone> HttpImageService<S> {
-    pub(crate) fn new_with_prefix(image_service: S, prefix: &str) -> Self {
-        Self { inner: image_service, prefix: prefix.to_string() }
+    pub fn new_with_prefix(image_service: S, prefix: &str) -> Self {
+        Self {
+            inner: image_service,
+            prefix: prefix.to_string(),
+            priority_header: None,
+            client_header: None,
+            quality_layers_parameter: false,
+        }
+    }
+
+    /// Let requests choose their priority class with the `name` header, such as one set by a
+    /// trusted proxy.
+    pub fn with_priority_header(self, name: HeaderName) -> Self {
+        Self { priority_header: Some(name), ..self }
+    }
+
+    /// Identify clients by an address in the `name` header, such as `X-Forwarded-For`, rather
+    /// than by the address they connected from. Each of the `trusted_proxies` in front of the
+    /// server appends an address, so the client is the one added by the outermost proxy and any
+    /// earlier addresses, which clients can set themselves, are ignored.
+    pub fn with_client_header(self, name: HeaderName, trusted_proxies: usize) -> Self {
+        Self { client_header: Some((name, trusted_proxies.max(1))), ..self }
+    }
+
+    /// Let clients limit the quality layers decoded for an image request with the non-standard
+    /// `layers` query parameter, which is otherwise ignored.
+    pub fn with_quality_layers_parameter(self) -> Self {
+        Self { quality_layers_parameter: true, ..self }
+    }
+
+    /// The most quality layers a request asks to decode, if the service lets it choose.
+    fn quality_layers<B>(&self, req: &Request<B>) -> Result<Option<u16>, ImageRequestParseError> {
+        match req.uri().query() {
+            Some(query) if self.quality_layers_parameter => parse_quality_layers(query),
+            _ => Ok(None),
+        }
+    }
+
+    /// The priority class a request asks for, if the service lets it choose one.
+    fn priority<B>(&self, req: &Request<B>) -> Option<PriorityClass> {
+        let value = req.headers().get(self.priority_header.as_ref()?)?;
+
+        match value
+            .to_str()
+            .map_err(|e| e.to_string())
+            .and_then(str::parse)
+        {
+            Ok(priority) => Some(priority),
+            Err(e) => {
+                debug!("ignoring an invalid request priority: {e}");
+                None
+            }
+        }
+    }
+
+    /// Identifies the client that made a request.
+    fn client<B>(&self, req: &Request<B>) -> Option<Arc<str>> {
+        let forwarded = self
+            .client_header
+            .as_ref()
+            .and_then(|(name, trusted_proxies)| {
+                let value = req.headers().get(name)?.to_str().ok()?;
+                value.rsplit(',').nth(trusted_proxies - 1)
+            })
+            .map(str::trim)
+            .filter(|client| !client.is_empty());
+
+        match forwarded {
+            Some(client) => Some(Arc::from(client)),
+            None => {
+                let ClientAddress(address) = req.extensions().get()?;
+                Some(Arc::from(address.ip().to_string()))
+            }
+        }
     }
 }
 
-impl<S> tower::Service<Request<Incoming>> for HttpImageService<S>
+impl<S, B> tower::Service<Request<B>> for HttpImageService<S>
 where
     S: Service<ImageServiceRequest, Response = ImageServiceResponse, Error = ImageServiceError>
         + Send
@@ -45,15 +131,28 @@ where
         + Clone
         + 'static,
     S::Future: Send + Unpin,
+    B: Send + 'static,
 {
     type Response = Response<BoxBody<Bytes, std::io::Error>>;
     type Error = hyper::http::Error;
     type Future = Pin<Box<dyn Send + Future<Output = Result<Self::Response, Self::Error>>>>;
 
-    fn call(&mut self, req: Request<Incoming>) -> Self::Future {
-        Box::pin(Self::decode_request(req, self.prefix.clone(), self.inner.clone()))
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let priority = self.priority(&req);
+        let client = self.client(&req);
+        let quality_layers = self.quality_layers(&req);
+
+        Box::pin(Self::decode_request(
+            req,
+            self.prefix.clone(),
+            priority,
+            client,
+            quality_layers,
+            self.inner.clone(),
+        ))
     }
 
+    /// Always ready, as the image service sheds the image requests it has no room for.
     fn poll_ready(
         &mut self,
         _cx: &mut std::task::Context<'_>,
@@ -82,6 +181,9 @@ const IMAGE_REQUEST_ROUTE: &str =
     "/<prefix>/<identifier>/<region>/<size>/<rotation>/<quality>.<format>";
 const INFO_REQUEST_ROUTE: &str = "/<prefix>/<identifier>/info.json";
 
+/// How long clients are asked to wait before retrying a request the image decoder was too busy for.
+const DECODER_RETRY_AFTER: Duration = Duration::from_secs(1);
+
 impl<S> HttpImageService<S>
 where
     S: Service<ImageServiceRequest, Response = ImageServiceResponse, Error = ImageServiceError>
@@ -91,9 +193,12 @@ where
         + 'static,
     S::Future: Send,
 {
-    pub async fn decode_request(
-        req: Request<Incoming>,
+    pub async fn decode_request<B>(
+        req: Request<B>,
         prefix: String,
+        priority: Option<PriorityClass>,
+        client: Option<Arc<str>>,
+        quality_layers: Result<Option<u16>, ImageRequestParseError>,
         mut inner: S,
     ) -> Result<HttpImageServiceResponse, hyper::http::Error> {
         let request_path = req
@@ -106,11 +211,13 @@ where
         let request_method = req.method().to_string();
         let request = match request_path.as_str() {
             "/" => return ok_response("OK!"),
-            _ => req.try_into(),
+            _ => ImageServiceRequest::try_from(req)
+                .and_then(|request| Ok(request.with_quality_layers(quality_layers?))),
         };
 
         match request {
             Ok(request) => {
+                let request = request.with_priority(priority).with_client(client);
                 let route = match &request {
                     ImageServiceRequest { kind: ImageServiceRequestKind::Info, .. } => {
                         INFO_REQUEST_ROUTE
@@ -124,16 +231,7 @@ where
 
                 match inner.call(request).await {
                     Ok(response) => response.try_into(),
-                    Err(ImageServiceError::Storage(StorageError::NotFound)) => Response::builder()
-                        .status(StatusCode::NOT_FOUND)
-                        .body(text_body("Image file not found")),
-                    Err(e) => {
-                        error!("failed to handle an image service request: {e:?}");
-
-                        Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(text_body("An internal error occurred"))
-                    }
+                    Err(error) => error_response(error),
                 }
             }
             Err(e) => Response::builder()
@@ -143,6 +241,65 @@ where
     }
 }
 
+/// The response to an image service request that failed with `error`.
+pub(crate) fn error_response(
+    error: ImageServiceError,
+) -> Result<HttpImageServiceResponse, hyper::http::Error> {
+    match error {
+        ImageServiceError::Storage(StorageError::NotFound) => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(text_body("Image file not found")),
+        ImageServiceError::Storage(StorageError::InvalidIdentifier(reason)) => Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(text_body(format!("Invalid image identifier: {reason}"))),
+        ImageServiceError::Storage(StorageError::AccessDenied) => Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(text_body("Access to the image file was denied")),
+        ImageServiceError::Codec(CodecError::UnsupportedFormat(reason)) => Response::builder()
+            .status(StatusCode::UNSUPPORTED_MEDIA_TYPE)
+            .body(text_body(format!("Unsupported image format: {reason}"))),
+        ImageServiceError::Codec(CodecError::Malformed(reason)) => {
+            error!("unable to decode an image file: {reason}");
+
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(text_body("The image file is corrupt or truncated"))
+        }
+        ImageServiceError::Codec(CodecError::Exhausted(reason)) => {
+            warn!("unable to decode an image file: {reason}");
+
+            Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(RETRY_AFTER, DECODER_RETRY_AFTER.as_secs())
+                .body(text_body("The image decoder is busy, please try again later"))
+        }
+        ImageServiceError::Overloaded { retry_after } => {
+            warn!("shedding an image request, the server is too busy");
+
+            Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .header(RETRY_AFTER, retry_after.as_secs())
+                .body(text_body("The server is busy, please try again later"))
+        }
+        ImageServiceError::TooLarge(reason) => Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(text_body(format!("The requested image is too large: {reason}"))),
+        ImageServiceError::InvalidRequest(reason) => Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(text_body(format!("Invalid image request: {reason}"))),
+        ImageServiceError::Unsupported(reason) => Response::builder()
+            .status(StatusCode::NOT_IMPLEMENTED)
+            .body(text_body(format!("Unsupported image request: {reason}"))),
+        e => {
+            error!("failed to handle an image service request: {e:?}");
+
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(text_body("An internal error occurred"))
+        }
+    }
+}
+
 impl TryInto<HttpImageServiceResponse> for ImageServiceResponse {
     type Error = hyper::http::Error;
 
@@ -158,6 +315,10 @@ impl TryInto<HttpImageServiceResponse> for ImageServiceResponse {
             headers.append(LAST_MODIFIED, value);
         }
 
+        if let Some(Ok(value)) = self.etag.as_deref().map(HeaderValue::from_str) {
+            headers.append(ETAG, value);
+        }
+
         match self.kind {
             ImageServiceResponseKind::CacheHit => Response::builder()
                 .status(StatusCode::NOT_MODIFIED)
@@ -272,3 +433,89 @@ impl From<ImageRequestParseError> for IiifRequestError {
         IiifRequestError::ParseError(value)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::iiif::service::ImageService;
+    use crate::image::codec::{CompositeImageReader, PngImageReader, SourceFormat};
+    use crate::image::transcoding::admission::{Admission, Requester};
+    use crate::storage::local::LocalStorageProvider;
+
+    fn request(uri: &str) -> Request<()> {
+        Request::builder().uri(uri).body(()).unwrap()
+    }
+
+    #[test]
+    fn ignores_the_layers_parameter_unless_enabled() {
+        let service = HttpImageService::new_with_prefix((), "/");
+        let invalid = request("/image/full/max/0/default.jpg?layers=abc");
+
+        assert_eq!(service.quality_layers(&invalid), Ok(None));
+
+        let service = service.with_quality_layers_parameter();
+        let valid = request("/image/full/max/0/default.jpg?layers=2");
+
+        assert_eq!(service.quality_layers(&valid), Ok(Some(2)));
+        assert!(service.quality_layers(&invalid).is_err());
+    }
+
+    #[tokio::test]
+    async fn sheds_image_requests_once_the_queue_is_full() {
+        let directory = tempfile::tempdir().unwrap();
+        let mut image = vec![];
+        let mut encoder = png::Encoder::new(&mut image, 4, 4);
+        encoder.set_color(png::ColorType::Rgb);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[0; 4 * 4 * 3]).unwrap();
+        writer.finish().unwrap();
+        std::fs::write(directory.path().join("image.png"), image).unwrap();
+
+        let storage = LocalStorageProvider::new(directory.path().to_path_buf());
+        let admission = Admission::new(1, 0, Duration::from_secs(5));
+        let reader = CompositeImageReader::new().with_reader(SourceFormat::Png, PngImageReader);
+        let image_service = ImageService::new(storage, reader).with_admission(admission.clone());
+        let mut service = HttpImageService::new_with_prefix(image_service, "/");
+
+        // With no capacity free and no room in the queue, image requests are shed at once.
+        let _running = admission.admit(1, &Requester::default()).await.unwrap();
+        let response = tokio::time::timeout(
+            Duration::from_secs(1),
+            service.call(request("/image.png/full/max/0/default.jpg")),
+        )
+        .await
+        .expect("the image request waited for admission")
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().contains_key(RETRY_AFTER));
+
+        // Requests that don't decode anything are still served.
+        let response = service.call(request("/image.png/info.json")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn identifies_clients_by_the_address_added_by_trusted_proxies() {
+        let forwarded = |value: &str| {
+            let mut request = request("/image/info.json");
+            let address = ClientAddress("10.0.0.1:443".parse().unwrap());
+            request.extensions_mut().insert(address);
+            request
+                .headers_mut()
+                .insert("x-forwarded-for", value.parse().unwrap());
+            request
+        };
+        let header = HeaderName::from_static("x-forwarded-for");
+
+        let service =
+            HttpImageService::new_with_prefix((), "/").with_client_header(header.clone(), 1);
+        let spoofed = forwarded("192.0.2.1, 198.51.100.7");
+        assert_eq!(service.client(&spoofed).as_deref(), Some("198.51.100.7"));
+
+        let service = HttpImageService::new_with_prefix((), "/").with_client_header(header, 2);
+        assert_eq!(service.client(&spoofed).as_deref(), Some("192.0.2.1"));
+
+        // Headers with fewer addresses than proxies fall back to the connecting address.
+        assert_eq!(service.client(&forwarded("192.0.2.1")).as_deref(), Some("10.0.0.1"));
+    }
+}
@@ -10,6 +10,9 @@ use crate::iiif::{Dimension, Format, Quality, Region, Rotation, Scale, Size};
 const PERCENT_PREFIX: &str = "pct:";
 const REGION_SELECTOR_COUNT: usize = 4;
 
+/// The non-standard query parameter that limits the quality layers decoded for an image request.
+const QUALITY_LAYERS_PARAMETER: &str = "layers=";
+
 impl FromStr for Region {
     type Err = ParseError;
 
@@ -88,6 +91,22 @@ impl FromStr for ImageServiceRequest {
     }
 }
 
+/// Parse the number of quality layers requested in the query string of an image request, if
+/// there is one.
+pub(crate) fn parse_quality_layers(query: &str) -> Result<Option<u16>, ParseError> {
+    let Some(input) = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix(QUALITY_LAYERS_PARAMETER))
+    else {
+        return Ok(None);
+    };
+
+    match input.parse::<u16>() {
+        Ok(layers) if layers > 0 => Ok(Some(layers)),
+        _ => Err(ParseError::QualityLayersUnparsable(input.into())),
+    }
+}
+
 fn parse_region_selectors<T: SpatialSelector>(
     input: &str,
 ) -> Result<[T; REGION_SELECTOR_COUNT], ParseError> {
@@ -281,6 +300,71 @@ impl FromStr for Format {
     }
 }
 
+impl Display for Region {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Region::Full => write!(f, "full"),
+            Region::Square => write!(f, "square"),
+            Region::Absolute { x, y, width, height } => write!(f, "{x},{y},{width},{height}"),
+            Region::Percentage { x, y, width, height } => {
+                write!(f, "{PERCENT_PREFIX}{x},{y},{width},{height}")
+            }
+        }
+    }
+}
+
+impl Display for Size {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.upscale {
+            write!(f, "^")?;
+        }
+
+        match self.scale {
+            Scale::Max => write!(f, "max"),
+            Scale::Percentage(scale) => write!(f, "{PERCENT_PREFIX}{scale}"),
+            Scale::FixedWidth(width) => write!(f, "{width},"),
+            Scale::FixedHeight(height) => write!(f, ",{height}"),
+            Scale::Fixed { width, height } => write!(f, "{width},{height}"),
+            Scale::AspectPreserving { width, height } => write!(f, "!{width},{height}"),
+        }
+    }
+}
+
+impl Display for Rotation {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.mirror {
+            write!(f, "!")?;
+        }
+
+        write!(f, "{}", self.degrees)
+    }
+}
+
+impl Display for Quality {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Quality::Color => write!(f, "color"),
+            Quality::Gray => write!(f, "gray"),
+            Quality::Bitonal => write!(f, "bitonal"),
+            Quality::Default => write!(f, "default"),
+        }
+    }
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Format::Jpg => write!(f, "jpg"),
+            Format::Tif => write!(f, "tif"),
+            Format::Png => write!(f, "png"),
+            Format::Gif => write!(f, "gif"),
+            Format::Jp2 => write!(f, "jp2"),
+            Format::Pdf => write!(f, "pdf"),
+            Format::Webp => write!(f, "webp"),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum ParseError {
     /// If a spatial selector (x, y, width, height) could not be parsed.
@@ -324,6 +408,9 @@ pub enum ParseError {
 
     /// If the requested format is unrecognised.
     UnrecognisedFormat(String),
+
+    /// If the number of quality layers to decode could not be parsed as a positive integer.
+    QualityLayersUnparsable(String),
 }
 
 impl ParseError {
@@ -384,6 +471,9 @@ impl Display for ParseError {
             }
             ParseError::UnrecognisedQuality(s) => write!(f, "Unrecognised quality '{s}'."),
             ParseError::UnrecognisedFormat(s) => write!(f, "Unrecognised format '{s}'."),
+            ParseError::QualityLayersUnparsable(s) => {
+                write!(f, "Quality layers '{s}' could not be parsed (expected positive integer).")
+            }
         }
     }
 }
@@ -391,6 +481,17 @@ impl Display for ParseError {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::iiif::service::ImageServiceRequestKind;
+
+    #[test]
+    fn quality_layers() {
+        assert_eq!(parse_quality_layers(""), Ok(None));
+        assert_eq!(parse_quality_layers("foo=bar&layers=3"), Ok(Some(3)));
+        assert_eq!(
+            parse_quality_layers("layers=0"),
+            Err(ParseError::QualityLayersUnparsable("0".into()))
+        );
+    }
 
     #[test]
     fn full_region() {
@@ -611,6 +712,25 @@ mod test {
         assert_eq!(result, Err(ParseError::UnrecognisedQuality("TRaFoaMP20230922".into())));
     }
 
+    #[test]
+    fn parameters_format_in_canonical_form() {
+        for input in [
+            "/id/full/max/0/default.jpg",
+            "/id/square/^pct:150/!90.5/gray.png",
+            "/id/0,10,256,256/256,/0/color.jpg",
+            "/id/pct:10,10,50,50/,128/180/bitonal.tif",
+            "/id/full/!200,200/0/default.webp",
+            "/id/full/^1024,768/270/default.jpg",
+        ] {
+            let request = input.parse::<ImageServiceRequest>().unwrap();
+            let ImageServiceRequestKind::Image(params) = request.kind else {
+                panic!("expected an image request")
+            };
+
+            assert_eq!(format!("/id/{params}"), input);
+        }
+    }
+
     #[test]
     fn unrecognised_format_err() {
         let result = "TRaFoaMP20230922".parse::<Format>();
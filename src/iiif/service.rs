@@ -4,21 +4,30 @@ use std::future::Future;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::Poll;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use futures::FutureExt;
 use hyper::Request;
-use hyper::body::Incoming;
 use hyper::header::IF_MODIFIED_SINCE;
 use tower::Service;
 use tracing::{Instrument, info_span};
 
+use super::cache::{DerivativeCache, DerivativeKey, SourceVersion};
+use super::coalesce::InFlightRequests;
 use super::http::IiifRequestError;
-use super::{Format, Quality, Region, Rotation, Size};
+use super::{Dimension, Format, Quality, Region, Rotation, Size};
+use crate::image::cache::ImageCache;
+use crate::image::codec::CodecError;
+use crate::image::color::ColorManagement;
 use crate::image::info::ImageInfo;
-use crate::image::transcoding::TranscodingPipeline;
+use crate::image::transcoding::admission::{Admission, PriorityClass, Requester};
+use crate::image::transcoding::plan::{OutputPlan, plan_output};
+use crate::image::transcoding::resample::ResamplingFilter;
+use crate::image::transcoding::{
+    PreviewLayers, TranscodingError, TranscodingOptions, TranscodingPipeline,
+};
 use crate::image::{BoxedImage, Image, ImageReader, ImageStream};
-use crate::storage::{StorageError, StorageProvider};
+use crate::storage::{FileOrStream, StorageError, StorageProvider};
 
 pub enum ImageServiceResponseKind {
     Info(ImageInfo),
@@ -29,6 +38,7 @@ pub enum ImageServiceResponseKind {
 pub struct ImageServiceResponse {
     pub kind: ImageServiceResponseKind,
     pub last_modified_time: Option<SystemTime>,
+    pub etag: Option<String>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -36,6 +46,10 @@ pub struct ImageServiceRequest {
     pub(crate) identifier: String,
     pub(crate) kind: ImageServiceRequestKind,
     pub(crate) last_access_time: Option<SystemTime>,
+    /// The priority class asked for by the client, rather than one chosen by the service.
+    pub(crate) priority: Option<PriorityClass>,
+    /// Identifies the client that made the request, to share capacity fairly between clients.
+    pub(crate) client: Option<Arc<str>>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -48,15 +62,48 @@ pub enum ImageServiceRequestKind {
 pub struct ImageParameters {
     pub region: Region,
     pub size: Size,
-    rotation: Rotation,
-    quality: Quality,
-    format: Format,
+    pub rotation: Rotation,
+    pub quality: Quality,
+    pub format: Format,
+    /// The most quality layers to decode, from the non-standard `layers` query parameter.
+    pub quality_layers: Option<u16>,
 }
 
-impl TryFrom<Request<Incoming>> for ImageServiceRequest {
+impl Display for ImageParameters {
+    /// Formats the parameters as the path segments of an image request, in a canonical form
+    /// that is identical for requests that parse to the same parameters.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { region, size, rotation, quality, format, quality_layers } = self;
+        write!(f, "{region}/{size}/{rotation}/{quality}.{format}")?;
+
+        match quality_layers {
+            Some(layers) => write!(f, "?layers={layers}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ImageParameters {
+    /// Formats the parameters in the canonical form of an image request, with the region and
+    /// size resolved against the source image by `output`, so that requests for the same image
+    /// format identically however they were written.
+    pub(crate) fn canonical(&self, output: &OutputPlan) -> String {
+        let OutputPlan { region, size: (width, height), .. } = output;
+        let Self { rotation, quality, format, quality_layers, .. } = self;
+
+        let mut canonical = format!("{region}/{width},{height}/{rotation}/{quality}.{format}");
+        if let Some(layers) = quality_layers {
+            canonical.push_str(&format!("?layers={layers}"));
+        }
+
+        canonical
+    }
+}
+
+impl<B> TryFrom<Request<B>> for ImageServiceRequest {
     type Error = IiifRequestError;
 
-    fn try_from(req: Request<Incoming>) -> Result<Self, Self::Error> {
+    fn try_from(req: Request<B>) -> Result<Self, Self::Error> {
         let last_access_time = req
             .headers()
             .get(IF_MODIFIED_SINCE)
@@ -76,6 +123,8 @@ impl ImageServiceRequest {
             identifier: identifier.into(),
             kind: ImageServiceRequestKind::Info,
             last_access_time: None,
+            priority: None,
+            client: None,
         }
     }
 
@@ -90,12 +139,15 @@ impl ImageServiceRequest {
         ImageServiceRequest {
             identifier: identifier.into(),
             last_access_time: None,
+            priority: None,
+            client: None,
             kind: ImageServiceRequestKind::Image(ImageParameters {
                 region,
                 size,
                 rotation,
                 quality,
                 format,
+                quality_layers: None,
             }),
         }
     }
@@ -103,17 +155,88 @@ impl ImageServiceRequest {
     pub fn with_last_access_time(self, last_access_time: Option<SystemTime>) -> Self {
         Self { last_access_time, ..self }
     }
+
+    /// Admit the request with `priority`, rather than the priority the service would choose.
+    pub fn with_priority(self, priority: Option<PriorityClass>) -> Self {
+        Self { priority, ..self }
+    }
+
+    /// Identify the client that made the request.
+    pub fn with_client(self, client: Option<Arc<str>>) -> Self {
+        Self { client, ..self }
+    }
+
+    /// Limit the quality layers decoded for an image request, or remove the limit.
+    pub fn with_quality_layers(mut self, quality_layers: Option<u16>) -> Self {
+        if let ImageServiceRequestKind::Image(params) = &mut self.kind {
+            params.quality_layers = quality_layers;
+        }
+
+        self
+    }
+
+    /// A key that is identical for requests that produce the same response.
+    pub(crate) fn canonical_key(&self) -> String {
+        let mut key = match &self.kind {
+            ImageServiceRequestKind::Info => format!("{}/info.json", self.identifier),
+            ImageServiceRequestKind::Image(params) => format!("{}/{params}", self.identifier),
+        };
+
+        if let Some(last_access_time) = self.last_access_time {
+            key.push_str(&format!("?since={}", httpdate::fmt_http_date(last_access_time)));
+        }
+
+        key
+    }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum ImageServiceError {
     Storage(StorageError),
+    Codec(CodecError),
+    /// The requested image is too large to produce within the configured limits.
+    TooLarge(String),
+    /// The request can't be produced from the image it names.
+    InvalidRequest(String),
+    /// The request asks for a feature that isn't implemented.
+    Unsupported(String),
+    /// The server is too busy to handle the request, which can be retried after a while.
+    Overloaded {
+        retry_after: Duration,
+    },
+    Internal(String),
 }
 
 impl Error for ImageServiceError {}
+
+impl From<TranscodingError> for ImageServiceError {
+    fn from(value: TranscodingError) -> Self {
+        match value {
+            TranscodingError::Codec(e) => ImageServiceError::Codec(e),
+            e @ (TranscodingError::MemoryBudgetExceeded { .. }
+            | TranscodingError::LimitExceeded(_)) => ImageServiceError::TooLarge(e.to_string()),
+            TranscodingError::InvalidRequest(reason) => ImageServiceError::InvalidRequest(reason),
+            TranscodingError::Unsupported(reason) => ImageServiceError::Unsupported(reason),
+            TranscodingError::Overloaded { retry_after } => {
+                ImageServiceError::Overloaded { retry_after }
+            }
+            e => ImageServiceError::Internal(e.to_string()),
+        }
+    }
+}
 impl Display for ImageServiceError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        todo!()
+        match self {
+            ImageServiceError::Storage(e) => write!(f, "storage error: {e}"),
+            ImageServiceError::Codec(e) => write!(f, "codec error: {e}"),
+            ImageServiceError::TooLarge(reason) => write!(f, "image too large: {reason}"),
+            ImageServiceError::InvalidRequest(reason) => write!(f, "invalid request: {reason}"),
+            ImageServiceError::Unsupported(reason) => write!(f, "unsupported request: {reason}"),
+            ImageServiceError::Overloaded { retry_after } => {
+                write!(f, "overloaded, retry after {retry_after:?}")
+            }
+            ImageServiceError::Internal(reason) => write!(f, "internal error: {reason}"),
+        }
     }
 }
 
@@ -121,6 +244,13 @@ impl Display for ImageServiceError {
 pub struct ImageService {
     storage: Arc<dyn StorageProvider>,
     reader: Arc<dyn ImageReader>,
+    derivatives: Option<DerivativeCache>,
+    images: Option<Arc<ImageCache>>,
+    in_flight: Arc<InFlightRequests>,
+    transcoding: TranscodingOptions,
+    admission: Option<Admission>,
+    /// Identifier prefixes and the priority class of the image requests they match.
+    priority_prefixes: Arc<Vec<(String, PriorityClass)>>,
 }
 
 impl ImageService {
@@ -129,7 +259,91 @@ impl ImageService {
         S: StorageProvider + 'static,
         R: ImageReader + 'static,
     {
-        Self { storage: Arc::new(storage), reader: Arc::from(reader) }
+        Self {
+            storage: Arc::new(storage),
+            reader: Arc::from(reader),
+            derivatives: None,
+            images: None,
+            in_flight: Arc::new(InFlightRequests::new()),
+            transcoding: TranscodingOptions::default(),
+            admission: None,
+            priority_prefixes: Arc::default(),
+        }
+    }
+
+    /// Serve repeated image requests from `cache` rather than decoding the source image again.
+    pub fn with_derivative_cache(self, cache: DerivativeCache) -> Self {
+        Self { derivatives: Some(cache), ..self }
+    }
+
+    /// Reuse the image info and parsed headers of source images from `cache` rather than reading
+    /// them again.
+    pub fn with_image_cache(self, cache: Arc<ImageCache>) -> Self {
+        Self { images: Some(cache), ..self }
+    }
+
+    /// Handle the colours of source images with an embedded ICC profile according to `color`,
+    /// rather than converting them to sRGB.
+    pub fn with_color_management(self, color: ColorManagement) -> Self {
+        Self { transcoding: TranscodingOptions { color, ..self.transcoding }, ..self }
+    }
+
+    /// Reject image requests that need more than `budget` bytes of memory for decoding, before
+    /// decoding starts.
+    pub fn with_memory_budget(self, budget: u64) -> Self {
+        let transcoding = TranscodingOptions { memory_budget: Some(budget), ..self.transcoding };
+        Self { transcoding, ..self }
+    }
+
+    /// Resample decoded regions to the requested size with `filter`, in linear light if
+    /// `linear_light` is set.
+    pub fn with_resampling(self, filter: ResamplingFilter, linear_light: bool) -> Self {
+        let transcoding =
+            TranscodingOptions { resampling: filter, linear_light, ..self.transcoding };
+        Self { transcoding, ..self }
+    }
+
+    /// Decode at most `layers` quality layers for images no larger than `max_size` pixels on
+    /// either side, such as previews and thumbnails.
+    pub fn with_preview_layers(self, max_size: Dimension, layers: u16) -> Self {
+        let preview_layers = Some(PreviewLayers { max_size, layers });
+        let transcoding = TranscodingOptions { preview_layers, ..self.transcoding };
+        Self { transcoding, ..self }
+    }
+
+    /// Limit the image requests that decode and encode at once with `admission`, queueing or
+    /// shedding the rest.
+    pub fn with_admission(self, admission: Admission) -> Self {
+        Self { admission: Some(admission), ..self }
+    }
+
+    /// Admit image requests for identifiers starting with `prefix` with `priority`, unless the
+    /// request asks for a priority itself. The longest matching prefix wins.
+    pub fn with_priority_prefix<S: Into<String>>(self, prefix: S, priority: PriorityClass) -> Self {
+        let mut priority_prefixes = Vec::clone(&self.priority_prefixes);
+        priority_prefixes.push((prefix.into(), priority));
+
+        Self { priority_prefixes: Arc::new(priority_prefixes), ..self }
+    }
+
+    /// Who an image request is run for, and the priority it is admitted with.
+    fn requester(&self, req: &ImageServiceRequest) -> Requester {
+        let priority = req.priority.unwrap_or_else(|| {
+            self.priority_prefixes
+                .iter()
+                .filter(|(prefix, _)| req.identifier.starts_with(prefix.as_str()))
+                .max_by_key(|(prefix, _)| prefix.len())
+                .map_or(PriorityClass::default(), |(_, priority)| *priority)
+        });
+
+        Requester { priority, client: req.client.clone() }
+    }
+
+    /// Decode regions one resolution level below the requested size and enlarge them, for
+    /// interactive deep zoom viewers that favour speed over sharpness.
+    pub fn with_fast_decoding(self) -> Self {
+        let transcoding = TranscodingOptions { fast_decoding: true, ..self.transcoding };
+        Self { transcoding, ..self }
     }
 }
 
@@ -141,9 +355,16 @@ impl Service<ImageServiceRequest> for ImageService {
     fn call(&mut self, req: ImageServiceRequest) -> Self::Future {
         let storage = self.storage.clone();
         let reader = self.reader.clone();
+        let derivatives = self.derivatives.clone();
+        let images = self.images.clone();
+        let transcoding = self.transcoding;
+        let admission = self.admission.clone();
+        let requester = self.requester(&req);
         let span = info_span!("handle_image_request");
+        let key = req.canonical_key();
 
-        Box::pin(
+        self.in_flight.call(
+            key,
             async move {
                 let data = storage
                     .open(&req.identifier)
@@ -158,25 +379,108 @@ impl Service<ImageServiceRequest> for ImageService {
                     return Ok(ImageServiceResponse {
                         kind: ImageServiceResponseKind::CacheHit,
                         last_modified_time: data.last_modified,
+                        etag: data.etag,
                     });
                 }
 
-                let image = reader.read(data.name, data.content).await;
+                let version = SourceVersion::of(&data);
+                let cached_images = images.as_ref().zip(version.as_ref());
+
+                if let (ImageServiceRequestKind::Info, Some((cache, version))) =
+                    (&req.kind, cached_images)
+                {
+                    if let Some(info) = cache.info(&req.identifier, version) {
+                        return Ok(ImageServiceResponse {
+                            kind: ImageServiceResponseKind::Info(info),
+                            last_modified_time: data.last_modified,
+                            etag: data.etag,
+                        });
+                    }
+                }
+
+                let mut source = Some((data.name, data.content));
+                let mut image = None;
+
+                // Derivatives are keyed on the region and size resolved against the source image,
+                // so that equivalent requests share them.
+                let derivative = match (&req.kind, derivatives, &version) {
+                    (ImageServiceRequestKind::Image(params), Some(cache), Some(version)) => {
+                        let cached_info = cached_images
+                            .and_then(|(images, version)| images.info(&req.identifier, version));
+                        let info = match cached_info {
+                            Some(info) => info,
+                            None => {
+                                let opened = image.insert(
+                                    open_image(
+                                        &*reader,
+                                        cached_images,
+                                        &req.identifier,
+                                        &mut source,
+                                    )
+                                    .await?,
+                                );
+                                opened.info().map_err(ImageServiceError::Codec)?
+                            }
+                        };
+
+                        // Requests that can't be planned aren't cached, and fail in the pipeline.
+                        plan_output(params, &info).ok().map(|output| {
+                            let key = DerivativeKey::new(
+                                &req.identifier,
+                                params,
+                                &output,
+                                transcoding.color,
+                            );
+                            (cache, key, version.clone())
+                        })
+                    }
+                    _ => None,
+                };
+
+                if let Some((cache, key, version)) = &derivative {
+                    if let Some(stream) = cache.get(key, version).await {
+                        return Ok(ImageServiceResponse {
+                            kind: ImageServiceResponseKind::Image(stream),
+                            last_modified_time: data.last_modified,
+                            etag: data.etag,
+                        });
+                    }
+                }
+
+                let image = match image {
+                    Some(image) => image,
+                    None => {
+                        open_image(&*reader, cached_images, &req.identifier, &mut source).await?
+                    }
+                };
                 let kind = match req.kind {
                     ImageServiceRequestKind::Info => handle_info_request(image)
                         .await
                         .map(ImageServiceResponseKind::Info),
-                    ImageServiceRequestKind::Image(params) => handle_image_request(image, params)
-                        .await
-                        .map(ImageServiceResponseKind::Image),
+                    ImageServiceRequestKind::Image(params) => {
+                        handle_image_request(image, params, transcoding, admission, requester)
+                            .await
+                            .map(|stream| match derivative {
+                                Some((cache, key, version)) => cache.tee(key, version, stream),
+                                None => stream,
+                            })
+                            .map(ImageServiceResponseKind::Image)
+                    }
                 }?;
 
-                Ok(ImageServiceResponse { kind, last_modified_time: data.last_modified })
+                Ok(ImageServiceResponse {
+                    kind,
+                    last_modified_time: data.last_modified,
+                    etag: data.etag,
+                })
             }
             .instrument(span),
         )
     }
 
+    /// Always ready. Only image requests that decode wait for admission, and those that can't
+    /// be queued are shed with [ImageServiceError::Overloaded] rather than holding back requests
+    /// for image information or cached images.
     fn poll_ready(
         &mut self,
         _cx: &mut std::task::Context<'_>,
@@ -185,18 +489,283 @@ impl Service<ImageServiceRequest> for ImageService {
     }
 }
 
+/// Check out an image of the source from `images`, or read it from `source` if none is cached.
+async fn open_image(
+    reader: &dyn ImageReader,
+    images: Option<(&Arc<ImageCache>, &SourceVersion)>,
+    identifier: &str,
+    source: &mut Option<(Option<String>, FileOrStream)>,
+) -> Result<BoxedImage, ImageServiceError> {
+    if let Some(image) = images.and_then(|(cache, version)| cache.checkout(identifier, version)) {
+        return Ok(image);
+    }
+
+    let (name, content) = source.take().expect("the source is only read once");
+    let image = reader
+        .read(name, content)
+        .await
+        .map_err(ImageServiceError::Codec)?;
+
+    Ok(match images {
+        Some((cache, version)) => cache.track(identifier, version.clone(), image),
+        None => image,
+    })
+}
+
 #[tracing::instrument(err, skip(image))]
 async fn handle_info_request(mut image: BoxedImage) -> Result<ImageInfo, ImageServiceError> {
-    let info = image.info();
-    Ok(info)
+    image.info().map_err(ImageServiceError::Codec)
 }
 
-#[tracing::instrument(err, skip(image))]
+#[tracing::instrument(err, skip(image, admission))]
 async fn handle_image_request(
     image: BoxedImage,
     params: ImageParameters,
+    options: TranscodingOptions,
+    admission: Option<Admission>,
+    requester: Requester,
 ) -> Result<ImageStream, ImageServiceError> {
-    let pipeline = TranscodingPipeline { image, params };
+    let pipeline = TranscodingPipeline { image, params, options, admission, requester };
+
+    // Requests that can't be produced are rejected while planning, before the response starts.
+    let planned = pipeline.plan().await?;
+
+    Ok(planned.execute())
+}
+
+#[cfg(test)]
+mod test {
+    use std::num::NonZero;
+
+    use futures::StreamExt;
+    use hyper::StatusCode;
+
+    use super::*;
+    use crate::iiif::Scale;
+    use crate::iiif::http::error_response;
+    use crate::image::codec::{CompositeImageReader, PngImageReader, SourceFormat};
+    use crate::storage::local::LocalStorageProvider;
+
+    /// A service reading PNG images from a temporary directory holding the given files.
+    fn service(files: &[(&str, &[u8])]) -> (tempfile::TempDir, ImageService) {
+        let reader = CompositeImageReader::new().with_reader(SourceFormat::Png, PngImageReader);
+        service_with_reader(files, reader)
+    }
+
+    fn service_with_reader<R: ImageReader + 'static>(
+        files: &[(&str, &[u8])],
+        reader: R,
+    ) -> (tempfile::TempDir, ImageService) {
+        let directory = tempfile::tempdir().unwrap();
+        for (name, data) in files {
+            std::fs::write(directory.path().join(name), data).unwrap();
+        }
+
+        let storage = LocalStorageProvider::new(directory.path().to_path_buf());
+
+        (directory, ImageService::new(storage, reader))
+    }
+
+    /// The status of the HTTP response to a request that failed with `error`.
+    fn status(error: ImageServiceError) -> StatusCode {
+        error_response(error).unwrap().status()
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = vec![];
+        let mut encoder = png::Encoder::new(&mut data, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+
+        // Noise doesn't compress, so truncating the file truncates the image data.
+        let pixels: Vec<u8> = (0..width * height * 3)
+            .map(|i| (i * 7919 % 251) as u8)
+            .collect();
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&pixels).unwrap();
+        writer.finish().unwrap();
+
+        data
+    }
+
+    fn full_image(identifier: &str) -> ImageServiceRequest {
+        ImageServiceRequest::image(
+            identifier,
+            Region::Full,
+            Size::new(Scale::Max),
+            Rotation::new(0.0),
+            Quality::Default,
+            Format::Jpg,
+        )
+    }
+
+    #[tokio::test]
+    async fn reports_corrupt_images() {
+        let mut corrupt = png(8, 8);
+        corrupt[12..16].copy_from_slice(b"JUNK");
+        let (_directory, mut service) = service(&[("corrupt.png", &corrupt)]);
+
+        let result = service.call(ImageServiceRequest::info("corrupt.png")).await;
+        assert!(matches!(result, Err(ImageServiceError::Codec(CodecError::Malformed(_)))));
+        assert_eq!(status(result.err().unwrap()), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[cfg(feature = "openjp2")]
+    #[tokio::test]
+    async fn reports_corrupt_and_truncated_jp2_images() {
+        use crate::image::codec::OpenJpegImageReader;
+
+        let image = std::fs::read("test-data/iiif-validator-reference.jp2").unwrap();
+
+        // The tile width in the SIZ marker of the codestream is zero.
+        let mut corrupt = image.clone();
+        assert_eq!(corrupt[111..115], [0xFF, 0x4F, 0xFF, 0x51]);
+        corrupt[135..139].fill(0);
 
-    Ok(pipeline.run())
+        // The file ends within the main header of the codestream.
+        let truncated = &image[..200];
+
+        let reader =
+            CompositeImageReader::new().with_reader(SourceFormat::Jpeg2000, OpenJpegImageReader);
+        let (_directory, mut service) =
+            service_with_reader(&[("corrupt.jp2", &corrupt), ("truncated.jp2", truncated)], reader);
+
+        for identifier in ["corrupt.jp2", "truncated.jp2"] {
+            let error = service
+                .call(ImageServiceRequest::info(identifier))
+                .await
+                .err()
+                .unwrap();
+            assert!(
+                matches!(error, ImageServiceError::Codec(CodecError::Malformed(_))),
+                "{identifier}: {error}"
+            );
+            assert_eq!(status(error), StatusCode::INTERNAL_SERVER_ERROR);
+
+            let error = service.call(full_image(identifier)).await.err().unwrap();
+            assert_eq!(status(error), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_unsupported_images() {
+        let (_directory, mut service) = service(&[("notes.txt", b"not an image")]);
+
+        let result = service.call(ImageServiceRequest::info("notes.txt")).await;
+        assert!(matches!(
+            result,
+            Err(ImageServiceError::Codec(CodecError::UnsupportedFormat(_)))
+        ));
+        assert_eq!(status(result.err().unwrap()), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn reports_truncated_images() {
+        let image = png(64, 64);
+        let (_directory, mut service) = service(&[
+            ("header.png", &image[..20]),
+            ("data.png", &image[..image.len() / 2]),
+        ]);
+
+        let result = service.call(ImageServiceRequest::info("header.png")).await;
+        assert!(matches!(result, Err(ImageServiceError::Codec(CodecError::Malformed(_)))));
+
+        // The header of the image is intact, so the error is only found once decoding starts.
+        let response = service.call(full_image("data.png")).await.unwrap();
+        let ImageServiceResponseKind::Image(stream) = response.kind else {
+            panic!("expected an image response");
+        };
+
+        let chunks: Vec<_> = stream.data.collect().await;
+        assert!(chunks.iter().any(Result::is_err));
+    }
+
+    #[tokio::test]
+    async fn enforces_the_memory_budget() {
+        let image = png(64, 64);
+        let (_directory, service) = service(&[("image.png", &image)]);
+
+        // Six strips of 16 rows of 64 RGB pixels are needed.
+        let mut constrained = service.clone().with_memory_budget(6 * 16 * 64 * 3 - 1);
+        let result = constrained.call(full_image("image.png")).await;
+        assert!(matches!(result, Err(ImageServiceError::TooLarge(_))));
+
+        let mut sufficient = service.with_memory_budget(6 * 16 * 64 * 3);
+        let response = sufficient.call(full_image("image.png")).await.unwrap();
+        let ImageServiceResponseKind::Image(stream) = response.kind else {
+            panic!("expected an image response");
+        };
+
+        let chunks: Vec<_> = stream.data.collect().await;
+        let encoded: Vec<u8> = chunks.into_iter().flat_map(Result::unwrap).collect();
+        assert_eq!(encoded[..2], [0xFF, 0xD8]);
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_while_planning() {
+        let image = png(64, 48);
+        let (_directory, mut service) = service(&[("image.png", &image)]);
+
+        let mut request = |region, format| {
+            let request = ImageServiceRequest::image(
+                "image.png",
+                region,
+                Size::new(Scale::Max),
+                Rotation::new(0.0),
+                Quality::Default,
+                format,
+            );
+            service.call(request)
+        };
+
+        let outside = Region::Absolute { x: 64, y: 0, width: 16, height: 16 };
+        let result = request(outside, Format::Jpg).await;
+        assert!(matches!(result, Err(ImageServiceError::InvalidRequest(_))));
+
+        let result = request(Region::Full, Format::Png).await;
+        assert!(matches!(result, Err(ImageServiceError::Unsupported(_))));
+    }
+
+    #[test]
+    fn prioritises_requests_by_identifier_prefix() {
+        let (_directory, service) = service(&[]);
+        let service = service
+            .with_priority_prefix("harvest/", PriorityClass::Bulk)
+            .with_priority_prefix("harvest/viewer/", PriorityClass::Interactive);
+
+        let priority = |identifier| service.requester(&full_image(identifier)).priority;
+        assert_eq!(priority("harvest/a.jp2"), PriorityClass::Bulk);
+        assert_eq!(priority("harvest/viewer/a.jp2"), PriorityClass::Interactive);
+        assert_eq!(priority("a.jp2"), PriorityClass::Normal);
+
+        // A priority chosen by the request takes precedence.
+        let request = full_image("harvest/a.jp2").with_priority(Some(PriorityClass::Normal));
+        assert_eq!(service.requester(&request).priority, PriorityClass::Normal);
+    }
+
+    #[tokio::test]
+    async fn resamples_with_the_chosen_filter() {
+        let image = png(64, 48);
+        let (_directory, service) = service(&[("image.png", &image)]);
+        let mut service = service.with_resampling(ResamplingFilter::Lanczos3, true);
+
+        let size =
+            Scale::Fixed { width: NonZero::new(20).unwrap(), height: NonZero::new(15).unwrap() };
+        let request = ImageServiceRequest::image(
+            "image.png",
+            Region::Full,
+            Size::new(size),
+            Rotation::new(0.0),
+            Quality::Default,
+            Format::Jpg,
+        );
+        let response = service.call(request).await.unwrap();
+        let ImageServiceResponseKind::Image(stream) = response.kind else {
+            panic!("expected an image response");
+        };
+
+        let chunks: Vec<_> = stream.data.collect().await;
+        let encoded: Vec<u8> = chunks.into_iter().flat_map(Result::unwrap).collect();
+        let decompress = mozjpeg::Decompress::new_mem(&encoded).unwrap();
+        assert_eq!(decompress.size(), (20, 15));
+    }
 }
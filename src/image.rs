@@ -1,21 +1,27 @@
 use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
 
 use bytes::{Bytes, BytesMut};
 use futures::Stream;
 use mediatype::MediaTypeBuf;
+use tokio::sync::Semaphore;
 
+pub mod cache;
 pub mod codec;
+pub mod color;
 pub mod info;
+pub mod pixel;
 pub mod transcoding;
 
-pub use codec::ImageReader;
+pub use codec::{CodecError, ImageReader};
 use info::ImageInfo;
+use pixel::PixelFormat;
 
 use crate::iiif::{Dimension, Region};
 
 pub type Dimensions = (Dimension, Dimension);
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct AbsoluteRegion {
     x: Dimension,
     y: Dimension,
@@ -23,6 +29,14 @@ pub struct AbsoluteRegion {
     height: Dimension,
 }
 
+impl std::fmt::Display for AbsoluteRegion {
+    /// Formats the region as the `x,y,w,h` region of an image request.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { x, y, width, height } = self;
+        write!(f, "{x},{y},{width},{height}")
+    }
+}
+
 /// An asynchronous sequential stream of encoded image data and the associated
 /// [mediatype::MediaType]
 pub struct ImageStream {
@@ -32,7 +46,31 @@ pub struct ImageStream {
 
 pub trait ImageDecoder {
     fn output_size(&self) -> Dimensions;
-    fn decode_to(&mut self, buffer: &mut BytesMut) -> bool;
+
+    /// The layout of the pixels written by [ImageDecoder::decode_to].
+    fn pixel_format(&self) -> PixelFormat;
+
+    /// The ICC profile describing the colours written by [ImageDecoder::decode_to], if the
+    /// source image embeds one.
+    fn icc_profile(&self) -> Option<Arc<[u8]>> {
+        None
+    }
+
+    /// An estimate of the memory the decoder holds to decode the region, beyond the buffers it
+    /// decodes into, such as the tiles or reconstructed components it decodes the region from.
+    fn working_memory(&self) -> u64 {
+        0
+    }
+
+    /// The most rows a single call to [ImageDecoder::decode_to] appends to its buffer, if the
+    /// decoder can append more than a strip of rows at once.
+    fn rows_per_decode(&self) -> Option<Dimension> {
+        None
+    }
+
+    /// Decode the next scanlines of the region into `buffer`, returning `true` once the whole
+    /// region has been decoded.
+    fn decode_to(&mut self, buffer: &mut BytesMut) -> Result<bool, CodecError>;
 }
 
 pub trait Image {
@@ -43,13 +81,31 @@ pub trait Image {
         BoxedImage(Box::new(self))
     }
 
-    fn info(&mut self) -> ImageInfo;
+    fn info(&mut self) -> Result<ImageInfo, CodecError>;
 
     fn open_region(
         &mut self,
         region: AbsoluteRegion,
         scaled_to: Dimensions,
-    ) -> Box<dyn ImageDecoder>;
+    ) -> Result<Box<dyn ImageDecoder>, CodecError>;
+
+    /// Open a region like [Image::open_region], decoding at most `layers` of the quality layers
+    /// that progressively refine the source image. Formats without quality layers decode the
+    /// region in full.
+    fn open_region_with_layers(
+        &mut self,
+        region: AbsoluteRegion,
+        scaled_to: Dimensions,
+        _layers: u16,
+    ) -> Result<Box<dyn ImageDecoder>, CodecError> {
+        self.open_region(region, scaled_to)
+    }
+
+    /// The slots that limit the regions decoded at once by every image from the same reader, for
+    /// readers that limit them. A slot is acquired before decoding starts and held until it ends.
+    fn decoder_slots(&self) -> Option<Arc<Semaphore>> {
+        None
+    }
 }
 
 pub struct BoxedImage(Box<dyn Image + Send>);
@@ -69,7 +125,7 @@ impl DerefMut for BoxedImage {
 }
 
 impl Image for BoxedImage {
-    fn info(&mut self) -> ImageInfo {
+    fn info(&mut self) -> Result<ImageInfo, CodecError> {
         self.0.info()
     }
 
@@ -77,7 +133,20 @@ impl Image for BoxedImage {
         &mut self,
         region: AbsoluteRegion,
         scaled_to: (Dimension, Dimension),
-    ) -> Box<dyn ImageDecoder> {
+    ) -> Result<Box<dyn ImageDecoder>, CodecError> {
         self.0.open_region(region, scaled_to)
     }
+
+    fn open_region_with_layers(
+        &mut self,
+        region: AbsoluteRegion,
+        scaled_to: Dimensions,
+        layers: u16,
+    ) -> Result<Box<dyn ImageDecoder>, CodecError> {
+        self.0.open_region_with_layers(region, scaled_to, layers)
+    }
+
+    fn decoder_slots(&self) -> Option<Arc<Semaphore>> {
+        self.0.decoder_slots()
+    }
 }
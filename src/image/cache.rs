@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use opentelemetry::KeyValue;
+use opentelemetry::metrics::Counter;
+use tokio::sync::Semaphore;
+use tracing::debug;
+
+use super::info::ImageInfo;
+use super::{AbsoluteRegion, BoxedImage, CodecError, Dimensions, Image, ImageDecoder};
+use crate::iiif::cache::SourceVersion;
+
+/// The maximum number of idle images kept for each source image.
+const MAX_IDLE_IMAGES: usize = 2;
+
+/// A cache of image metadata and opened images, keyed on the identifier and version of the
+/// source image.
+///
+/// Each entry holds the [ImageInfo] of a source image, so info requests can be answered without
+/// reading the image, along with images whose headers have already been parsed. Images are
+/// checked out for the duration of a request and returned to the cache when dropped, so later
+/// requests for the same source image can skip reading and parsing its headers.
+///
+/// The cache holds a bounded number of entries, evicting the least recently used entry once full.
+/// Idle images hold open the file or connection they read from, so their number is bounded
+/// separately across all entries, closing the least recently used once full while keeping the
+/// [ImageInfo] of their source. Entries are invalidated when a request finds that the source image
+/// has changed.
+pub struct ImageCache {
+    state: Mutex<CacheState>,
+    capacity: usize,
+    idle_capacity: usize,
+    lookups: Counter<u64>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, CacheEntry>,
+    /// The number of idle images held by all entries.
+    idle: usize,
+    clock: u64,
+}
+
+struct CacheEntry {
+    version: SourceVersion,
+    info: Option<ImageInfo>,
+    idle: Vec<BoxedImage>,
+    last_access: u64,
+}
+
+impl ImageCache {
+    /// Create a cache that holds at most `capacity` source images, and at most `idle_capacity`
+    /// idle images of them.
+    pub fn new(capacity: usize, idle_capacity: usize) -> Arc<Self> {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+
+        Arc::new(Self {
+            state: Mutex::new(CacheState::default()),
+            capacity,
+            idle_capacity,
+            lookups: meter
+                .u64_counter("image_cache.lookups")
+                .with_description(
+                    "Image metadata cache lookups, by kind (info or image) and result",
+                )
+                .build(),
+        })
+    }
+
+    /// Get the cached [ImageInfo] of a source image.
+    pub fn info(&self, id: &str, version: &SourceVersion) -> Option<ImageInfo> {
+        let info = self
+            .lock()
+            .entry(id, version)
+            .and_then(|entry| entry.info.clone());
+
+        self.record_lookup("info", info.is_some());
+        info
+    }
+
+    /// Check out an image of a source whose headers have already been read, returning it to the
+    /// cache once it is dropped.
+    pub fn checkout(self: &Arc<Self>, id: &str, version: &SourceVersion) -> Option<BoxedImage> {
+        let image = {
+            let mut state = self.lock();
+            let image = state.entry(id, version).and_then(|entry| entry.idle.pop());
+            state.idle -= usize::from(image.is_some());
+            image
+        };
+
+        self.record_lookup("image", image.is_some());
+        image.map(|image| self.track(id, version.clone(), image))
+    }
+
+    /// Wrap an image that was read from storage, so that its [ImageInfo] is recorded and the image
+    /// is returned to the cache once dropped.
+    pub fn track(
+        self: &Arc<Self>,
+        id: &str,
+        version: SourceVersion,
+        image: BoxedImage,
+    ) -> BoxedImage {
+        CachedImage { image: Some(image), id: id.to_string(), version, cache: self.clone() }.boxed()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, CacheState> {
+        self.state.lock().expect("image cache was poisoned")
+    }
+
+    fn record_lookup(&self, kind: &'static str, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.lookups
+            .add(1, &[KeyValue::new("kind", kind), KeyValue::new("result", result)]);
+    }
+
+    /// Update the entry for a source image, creating it if necessary. Nothing is updated if the
+    /// cache holds a different version of the source image.
+    fn update<F: FnOnce(&mut CacheEntry)>(&self, id: &str, version: &SourceVersion, update: F) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if let Some(entry) = self.lock().upsert(id, version, self.capacity) {
+            update(entry);
+        }
+    }
+
+    /// Return an idle image to the entry for its source image, closing the least recently used
+    /// idle images if there are too many.
+    fn release(&self, id: &str, version: &SourceVersion, image: BoxedImage) {
+        if self.capacity == 0 || self.idle_capacity == 0 {
+            return;
+        }
+
+        let closed = {
+            let mut state = self.lock();
+            if let Some(entry) = state.upsert(id, version, self.capacity) {
+                if entry.idle.len() < MAX_IDLE_IMAGES {
+                    entry.idle.push(image);
+                    state.idle += 1;
+                }
+            }
+
+            let mut closed = vec![];
+            while state.idle > self.idle_capacity {
+                match state.close_oldest_idle() {
+                    Some(image) => closed.push(image),
+                    None => break,
+                }
+            }
+
+            closed
+        };
+
+        // Closing images may close files or connections, which is done outside of the lock.
+        drop(closed);
+    }
+}
+
+impl CacheState {
+    /// Get the entry for a source image, creating it if necessary and marking it as recently
+    /// used. Nothing is returned if the cache holds a different version of the source image.
+    fn upsert(
+        &mut self,
+        id: &str,
+        version: &SourceVersion,
+        capacity: usize,
+    ) -> Option<&mut CacheEntry> {
+        match self.entries.get(id) {
+            Some(entry) if &entry.version != version => return None,
+            Some(_) => {}
+            None => {
+                if self.entries.len() >= capacity {
+                    self.evict_oldest();
+                }
+
+                self.entries.insert(
+                    id.to_string(),
+                    CacheEntry {
+                        version: version.clone(),
+                        info: None,
+                        idle: vec![],
+                        last_access: 0,
+                    },
+                );
+            }
+        }
+
+        self.clock += 1;
+
+        let clock = self.clock;
+        let entry = self.entries.get_mut(id).expect("entry was inserted");
+        entry.last_access = clock;
+
+        Some(entry)
+    }
+
+    /// Get the entry for a source image if it is current, marking it as recently used. Entries
+    /// for other versions of the source image are removed.
+    fn entry(&mut self, id: &str, version: &SourceVersion) -> Option<&mut CacheEntry> {
+        if self
+            .entries
+            .get(id)
+            .is_some_and(|entry| &entry.version != version)
+        {
+            debug!(id, "source image changed, invalidating cached metadata");
+            self.remove(id);
+        }
+
+        self.clock += 1;
+
+        let clock = self.clock;
+        let entry = self.entries.get_mut(id)?;
+        entry.last_access = clock;
+
+        Some(entry)
+    }
+
+    fn evict_oldest(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_access)
+            .map(|(id, _)| id.clone());
+
+        if let Some(id) = oldest {
+            self.remove(&id);
+        }
+    }
+
+    /// Take an idle image from the least recently used entry that has one.
+    fn close_oldest_idle(&mut self) -> Option<BoxedImage> {
+        let image = self
+            .entries
+            .values_mut()
+            .filter(|entry| !entry.idle.is_empty())
+            .min_by_key(|entry| entry.last_access)?
+            .idle
+            .pop();
+
+        self.idle -= usize::from(image.is_some());
+        image
+    }
+
+    fn remove(&mut self, id: &str) {
+        if let Some(entry) = self.entries.remove(id) {
+            self.idle -= entry.idle.len();
+        }
+    }
+}
+
+/// An image checked out from an [ImageCache].
+struct CachedImage {
+    image: Option<BoxedImage>,
+    id: String,
+    version: SourceVersion,
+    cache: Arc<ImageCache>,
+}
+
+impl CachedImage {
+    fn image(&mut self) -> &mut BoxedImage {
+        self.image
+            .as_mut()
+            .expect("image is only taken when dropped")
+    }
+}
+
+impl Image for CachedImage {
+    fn info(&mut self) -> Result<ImageInfo, CodecError> {
+        let info = self.image().info()?;
+
+        self.cache
+            .update(&self.id, &self.version, |entry| entry.info = Some(info.clone()));
+
+        Ok(info)
+    }
+
+    fn open_region(
+        &mut self,
+        region: AbsoluteRegion,
+        scaled_to: Dimensions,
+    ) -> Result<Box<dyn ImageDecoder>, CodecError> {
+        self.image().open_region(region, scaled_to)
+    }
+
+    fn open_region_with_layers(
+        &mut self,
+        region: AbsoluteRegion,
+        scaled_to: Dimensions,
+        layers: u16,
+    ) -> Result<Box<dyn ImageDecoder>, CodecError> {
+        self.image()
+            .open_region_with_layers(region, scaled_to, layers)
+    }
+
+    fn decoder_slots(&self) -> Option<Arc<Semaphore>> {
+        self.image.as_ref().and_then(|image| image.decoder_slots())
+    }
+}
+
+impl Drop for CachedImage {
+    fn drop(&mut self) {
+        let Some(image) = self.image.take() else {
+            return;
+        };
+
+        self.cache.release(&self.id, &self.version, image);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, SystemTime};
+
+    use bytes::BytesMut;
+
+    use super::*;
+    use crate::image::pixel::PixelFormat;
+
+    /// An image that counts how many times its headers have been read.
+    struct CountingImage {
+        header_reads: Arc<AtomicUsize>,
+    }
+
+    impl Image for CountingImage {
+        fn info(&mut self) -> Result<ImageInfo, CodecError> {
+            self.header_reads.fetch_add(1, Ordering::SeqCst);
+
+            Ok(ImageInfo {
+                width: 100,
+                height: 50,
+                max_width: None,
+                max_height: None,
+                max_area: None,
+                sizes: None,
+                tiles: None,
+                preferred_formats: None,
+                rights: None,
+            })
+        }
+
+        fn open_region(
+            &mut self,
+            _: AbsoluteRegion,
+            _: Dimensions,
+        ) -> Result<Box<dyn ImageDecoder>, CodecError> {
+            struct EmptyDecoder;
+
+            impl ImageDecoder for EmptyDecoder {
+                fn output_size(&self) -> Dimensions {
+                    (0, 0)
+                }
+
+                fn pixel_format(&self) -> PixelFormat {
+                    PixelFormat::RGB8
+                }
+
+                fn decode_to(&mut self, _: &mut BytesMut) -> Result<bool, CodecError> {
+                    Ok(true)
+                }
+            }
+
+            Ok(Box::new(EmptyDecoder))
+        }
+    }
+
+    fn version(seconds: u64) -> SourceVersion {
+        SourceVersion::new(None, Some(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds)))
+    }
+
+    fn image(header_reads: &Arc<AtomicUsize>) -> BoxedImage {
+        CountingImage { header_reads: header_reads.clone() }.boxed()
+    }
+
+    #[test]
+    fn caches_info_of_read_images() {
+        let cache = ImageCache::new(8, 8);
+        let header_reads = Arc::new(AtomicUsize::new(0));
+        assert!(cache.info("a", &version(1)).is_none());
+
+        let mut tracked = cache.track("a", version(1), image(&header_reads));
+        assert_eq!(tracked.info().unwrap().width, 100);
+        drop(tracked);
+
+        assert_eq!(cache.info("a", &version(1)).unwrap().height, 50);
+        assert!(cache.info("a", &version(2)).is_none());
+        assert!(cache.info("a", &version(1)).is_none());
+        assert_eq!(header_reads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn reuses_idle_images() {
+        let cache = ImageCache::new(8, 8);
+        let header_reads = Arc::new(AtomicUsize::new(0));
+        assert!(cache.checkout("a", &version(1)).is_none());
+
+        drop(cache.track("a", version(1), image(&header_reads)));
+
+        let first = cache
+            .checkout("a", &version(1))
+            .expect("image was returned to the cache");
+        assert!(cache.checkout("a", &version(1)).is_none());
+        drop(first);
+
+        assert!(cache.checkout("a", &version(1)).is_some());
+        assert!(cache.checkout("a", &version(2)).is_none());
+    }
+
+    #[test]
+    fn evicts_least_recently_used_images() {
+        let cache = ImageCache::new(2, 2);
+        let header_reads = Arc::new(AtomicUsize::new(0));
+
+        for id in ["a", "b"] {
+            cache
+                .track(id, version(1), image(&header_reads))
+                .info()
+                .unwrap();
+        }
+
+        cache.info("a", &version(1));
+        cache
+            .track("c", version(1), image(&header_reads))
+            .info()
+            .unwrap();
+
+        assert!(cache.info("a", &version(1)).is_some());
+        assert!(cache.info("b", &version(1)).is_none());
+        assert!(cache.info("c", &version(1)).is_some());
+    }
+
+    #[test]
+    fn closes_least_recently_used_idle_images() {
+        let cache = ImageCache::new(8, 2);
+        let header_reads = Arc::new(AtomicUsize::new(0));
+
+        for id in ["a", "b", "c"] {
+            let mut tracked = cache.track(id, version(1), image(&header_reads));
+            tracked.info().unwrap();
+        }
+
+        // The idle image of "a" is closed, but its info is kept.
+        assert!(cache.checkout("a", &version(1)).is_none());
+        assert!(cache.info("a", &version(1)).is_some());
+        assert!(cache.checkout("b", &version(1)).is_some());
+        assert!(cache.checkout("c", &version(1)).is_some());
+    }
+}
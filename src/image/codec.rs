@@ -1,29 +1,56 @@
+use std::error::Error;
+use std::fmt::Display;
 use std::future::Future;
+use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::pin::Pin;
+use std::sync::{Arc, Mutex};
 
-use crate::storage::FileOrStream;
+use futures::{AsyncReadExt, AsyncSeekExt};
+use tokio::runtime::Handle;
 
+use crate::iiif::Dimension;
+use crate::storage::local::PositionalFileReader;
+use crate::storage::{AsyncSeekableRead, FileOrStream};
+
+mod composite;
+#[cfg(any(feature = "kaduceus", feature = "openjp2"))]
+mod jp2;
+mod jpeg;
+#[cfg(feature = "kaduceus")]
 mod kaduceus;
+#[cfg(feature = "openjp2")]
+mod openjp2;
+mod png;
+mod scale;
+mod tiff;
+pub use composite::{CompositeImageReader, SourceFormat};
+pub use jpeg::JpegImageReader;
+#[cfg(feature = "kaduceus")]
 pub use kaduceus::KaduceusImageReader;
+#[cfg(feature = "openjp2")]
+pub use openjp2::OpenJpegImageReader;
+pub use png::PngImageReader;
+pub use tiff::TiffImageReader;
 
 use super::BoxedImage;
+use super::info::{ImageInfo, PreferredSize, Tile};
 
 /// The [`ImageReader`] trait defines functionality for reading and decoding various image formats.
 ///
 /// This trait provides an asynchronous mechanism for reading image data from a file or stream and
 /// converting it into a usable representation. Implementations are expected to handle specific
 /// image formats and return a boxed [`Image`](super::Image) object that can be used for further
-/// processing.
+/// processing, or a [`CodecError`] if the image can't be read.
 ///
 /// # Example
 ///
 /// ```rust
-/// use laya::image::{BoxedImage, ImageReader};
+/// use laya::image::{Image, ImageReader};
 /// use laya::storage::FileOrStream;
 ///
 /// async fn decode_image(reader: impl ImageReader, file_stream: FileOrStream) {
-///     let image = reader.read(None, file_stream).await;
-///     let info = image.info();
+///     let mut image = reader.read(None, file_stream).await.expect("unreadable image");
+///     let info = image.info().expect("unreadable image info");
 ///     println!("Image dimensions: {}x{}", info.width, info.height);
 /// }
 /// ```
@@ -32,7 +59,7 @@ pub trait ImageReader: Send + Sync {
         &'a self,
         name: Option<String>,
         location: FileOrStream,
-    ) -> Pin<Box<dyn Future<Output = BoxedImage> + Send + 'a>>;
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedImage, CodecError>> + Send + 'a>>;
 }
 
 impl ImageReader for Box<dyn ImageReader> {
@@ -40,7 +67,7 @@ impl ImageReader for Box<dyn ImageReader> {
         &'a self,
         name: Option<String>,
         location: FileOrStream,
-    ) -> Pin<Box<dyn Future<Output = BoxedImage> + Send + 'a>> {
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedImage, CodecError>> + Send + 'a>> {
         <dyn ImageReader>::read(self, name, location)
     }
 }
@@ -50,7 +77,203 @@ impl<T: ImageReader> ImageReader for Box<T> {
         &'a self,
         name: Option<String>,
         location: FileOrStream,
-    ) -> Pin<Box<dyn Future<Output = BoxedImage> + Send + 'a>> {
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedImage, CodecError>> + Send + 'a>> {
         T::read(self, name, location)
     }
 }
+
+/// An error raised while reading a source image.
+#[derive(Clone, Debug)]
+pub enum CodecError {
+    /// The source image is not in a format that any registered reader can decode.
+    UnsupportedFormat(String),
+
+    /// The source image is corrupt or truncated.
+    Malformed(String),
+
+    /// The source image could not be read from storage.
+    Io(String),
+
+    /// The decoder failed for a reason unrelated to the source image.
+    Internal(String),
+
+    /// The decoder doesn't have the resources to decode the image right now, but may once other
+    /// requests have finished.
+    Exhausted(String),
+}
+
+impl Error for CodecError {}
+
+impl From<std::io::Error> for CodecError {
+    fn from(value: std::io::Error) -> Self {
+        match value.kind() {
+            // Decoders report truncated and invalid data through their readers.
+            std::io::ErrorKind::UnexpectedEof | std::io::ErrorKind::InvalidData => {
+                CodecError::Malformed(value.to_string())
+            }
+            _ => CodecError::Io(value.to_string()),
+        }
+    }
+}
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CodecError::UnsupportedFormat(reason) => write!(f, "unsupported format: {reason}"),
+            CodecError::Malformed(reason) => write!(f, "malformed image: {reason}"),
+            CodecError::Io(reason) => write!(f, "i/o error: {reason}"),
+            CodecError::Internal(reason) => write!(f, "internal error: {reason}"),
+            CodecError::Exhausted(reason) => write!(f, "resources exhausted: {reason}"),
+        }
+    }
+}
+
+/// Run a blocking decoder operation on the blocking thread pool within the current span.
+///
+/// A panic raised by the decoder is returned as an error, rather than being propagated to the
+/// task awaiting the operation.
+pub(crate) async fn run_blocking<T, F>(operation: F) -> Result<T, CodecError>
+where
+    F: FnOnce() -> Result<T, CodecError> + Send + 'static,
+    T: Send + 'static,
+{
+    let span = tracing::Span::current();
+
+    tokio::task::spawn_blocking(move || span.in_scope(operation))
+        .await
+        .map_err(|e| CodecError::Internal(format!("decoder failed: {e}")))?
+}
+
+/// A source image that can be read synchronously, for decoders that don't support asynchronous
+/// I/O.
+pub(crate) trait BlockingSource: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> BlockingSource for T {}
+
+/// Open a source image for synchronous reads. Local files are read directly, and streams are
+/// driven on the current runtime.
+///
+/// Must be called from a thread where blocking is allowed, such as a
+/// [tokio::task::spawn_blocking] task.
+pub(crate) fn open_blocking(location: FileOrStream) -> std::io::Result<Box<dyn BlockingSource>> {
+    match location {
+        FileOrStream::File(file) => {
+            Ok(Box::new(BufReader::new(PositionalFileReader::for_file(file.file)?)))
+        }
+        FileOrStream::Stream(stream) => Ok(Box::new(BufReader::new(BlockingStream {
+            runtime: Handle::current(),
+            stream: Box::into_pin(stream),
+        }))),
+    }
+}
+
+/// An asynchronous stream read synchronously by blocking on the runtime that drives it.
+struct BlockingStream {
+    runtime: Handle,
+    stream: Pin<Box<dyn AsyncSeekableRead + Send>>,
+}
+
+impl Read for BlockingStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.runtime.block_on(self.stream.read(buf))
+    }
+}
+
+impl Seek for BlockingStream {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.runtime.block_on(self.stream.seek(pos))
+    }
+}
+
+/// A [BlockingSource] shared between an image and its decoders, each of which reads from its own
+/// position in the source.
+#[derive(Clone)]
+pub(crate) struct SharedSource {
+    shared: Arc<Mutex<(Box<dyn BlockingSource>, u64)>>,
+    position: u64,
+}
+
+impl SharedSource {
+    pub(crate) fn new(source: Box<dyn BlockingSource>) -> Self {
+        Self { shared: Arc::new(Mutex::new((source, 0))), position: 0 }
+    }
+}
+
+impl Read for SharedSource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut shared = self.shared.lock().expect("shared source was poisoned");
+        let (source, position) = &mut *shared;
+
+        // Avoid seeking when this reader is the only one reading, so buffered reads are kept.
+        if *position != self.position {
+            *position = source.seek(SeekFrom::Start(self.position))?;
+        }
+
+        let read = source.read(buf)?;
+        *position += read as u64;
+        self.position = *position;
+
+        Ok(read)
+    }
+}
+
+impl Seek for SharedSource {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::Current(offset) => self
+                .position
+                .checked_add_signed(offset)
+                .ok_or_else(|| std::io::Error::other("seek before start of source"))?,
+            SeekFrom::End(_) => {
+                let mut shared = self.shared.lock().expect("shared source was poisoned");
+                let (source, position) = &mut *shared;
+                *position = source.seek(pos)?;
+                *position
+            }
+        };
+
+        Ok(self.position)
+    }
+}
+
+/// The size of the tiles advertised for images that aren't stored as tiles.
+pub(crate) const SYNTHETIC_TILE_SIZE: Dimension = 512;
+
+/// Describe an image that isn't stored as tiles or at multiple resolutions, advertising tiles and
+/// reduced sizes at power of two scale factors so viewers can request it piece by piece.
+pub(crate) fn synthetic_info(width: Dimension, height: Dimension) -> ImageInfo {
+    let largest = width.max(height);
+    let scale_factors: Vec<u16> = (0..16)
+        .map(|level| 1 << level)
+        .take_while(|&factor| {
+            factor == 1 || largest.div_ceil(factor as u32 / 2) > SYNTHETIC_TILE_SIZE
+        })
+        .collect();
+
+    let sizes = scale_factors
+        .iter()
+        .skip(1)
+        .rev()
+        .map(|&factor| PreferredSize {
+            width: width.div_ceil(factor as u32),
+            height: height.div_ceil(factor as u32),
+        })
+        .collect();
+
+    ImageInfo {
+        width,
+        height,
+        max_width: Some(width),
+        max_height: Some(height),
+        max_area: None,
+        sizes: Some(sizes),
+        tiles: Some(vec![Tile {
+            width: SYNTHETIC_TILE_SIZE.min(width),
+            height: Some(SYNTHETIC_TILE_SIZE.min(height)),
+            scale_factors,
+        }]),
+        preferred_formats: None,
+        rights: None,
+    }
+}
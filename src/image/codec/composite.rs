@@ -0,0 +1,255 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::pin::Pin;
+
+use futures::{AsyncReadExt, AsyncSeekExt};
+use tracing::debug;
+
+use super::{CodecError, ImageReader};
+use crate::image::BoxedImage;
+use crate::storage::FileOrStream;
+use crate::storage::local::PositionalFileReader;
+
+/// The number of leading bytes needed to identify every [SourceFormat].
+const HEADER_LENGTH: u64 = 12;
+
+/// The formats of source images that readers can be registered for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SourceFormat {
+    /// JPEG 2000, either as a JP2 file or a raw codestream.
+    Jpeg2000,
+    Tiff,
+    Jpeg,
+    Png,
+}
+
+impl SourceFormat {
+    /// Identify the format of an image from its leading bytes.
+    pub fn sniff(header: &[u8]) -> Option<Self> {
+        const JP2_SIGNATURE: &[u8] = &[
+            0, 0, 0, 0x0C, b'j', b'P', b' ', b' ', 0x0D, 0x0A, 0x87, 0x0A,
+        ];
+        const J2K_SOC_SIZ: &[u8] = &[0xFF, 0x4F, 0xFF, 0x51];
+        const PNG_SIGNATURE: &[u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+        const JPEG_SOI: &[u8] = &[0xFF, 0xD8, 0xFF];
+
+        match header {
+            _ if header.starts_with(JP2_SIGNATURE) || header.starts_with(J2K_SOC_SIZ) => {
+                Some(SourceFormat::Jpeg2000)
+            }
+            // Classic TIFF has a version of 42, and BigTIFF a version of 43.
+            [b'I', b'I', 42 | 43, 0, ..] | [b'M', b'M', 0, 42 | 43, ..] => Some(SourceFormat::Tiff),
+            _ if header.starts_with(JPEG_SOI) => Some(SourceFormat::Jpeg),
+            _ if header.starts_with(PNG_SIGNATURE) => Some(SourceFormat::Png),
+            _ => None,
+        }
+    }
+
+    /// Guess the format of an image from the extension of its name.
+    pub fn from_extension(name: &str) -> Option<Self> {
+        let extension = Path::new(name).extension()?.to_str()?.to_ascii_lowercase();
+
+        match extension.as_str() {
+            "jp2" | "j2k" | "j2c" | "jpf" | "jpx" => Some(SourceFormat::Jpeg2000),
+            "tif" | "tiff" | "ptif" => Some(SourceFormat::Tiff),
+            "jpg" | "jpeg" => Some(SourceFormat::Jpeg),
+            "png" => Some(SourceFormat::Png),
+            _ => None,
+        }
+    }
+}
+
+impl Display for SourceFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SourceFormat::Jpeg2000 => write!(f, "JPEG 2000"),
+            SourceFormat::Tiff => write!(f, "TIFF"),
+            SourceFormat::Jpeg => write!(f, "JPEG"),
+            SourceFormat::Png => write!(f, "PNG"),
+        }
+    }
+}
+
+/// An [ImageReader] that identifies the format of each source image and dispatches it to the
+/// reader registered for that format.
+///
+/// Formats are identified by their leading bytes, falling back to the extension of the image's
+/// name when those aren't recognised.
+#[derive(Default)]
+pub struct CompositeImageReader {
+    readers: Vec<(SourceFormat, Box<dyn ImageReader>)>,
+}
+
+impl CompositeImageReader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Read images of the given `format` with `reader`, replacing any reader previously
+    /// registered for it.
+    pub fn with_reader<R: ImageReader + 'static>(
+        mut self,
+        format: SourceFormat,
+        reader: R,
+    ) -> Self {
+        self.readers.retain(|(registered, _)| *registered != format);
+        self.readers.push((format, Box::new(reader)));
+        self
+    }
+
+    fn reader(&self, format: SourceFormat) -> Option<&dyn ImageReader> {
+        self.readers
+            .iter()
+            .find(|(registered, _)| *registered == format)
+            .map(|(_, reader)| reader.as_ref())
+    }
+}
+
+impl ImageReader for CompositeImageReader {
+    fn read<'a>(
+        &'a self,
+        name: Option<String>,
+        location: FileOrStream,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedImage, CodecError>> + Send + 'a>> {
+        Box::pin(async move {
+            let (header, location) = peek(location).await?;
+            let path = match &location {
+                FileOrStream::File(file) => file.path.to_str().map(str::to_owned),
+                FileOrStream::Stream(_) => None,
+            };
+
+            let format = SourceFormat::sniff(&header)
+                .or_else(|| name.as_deref().and_then(SourceFormat::from_extension))
+                .or_else(|| path.as_deref().and_then(SourceFormat::from_extension))
+                .ok_or_else(|| {
+                    CodecError::UnsupportedFormat("the image format was not recognised".into())
+                })?;
+
+            let reader = self.reader(format).ok_or_else(|| {
+                CodecError::UnsupportedFormat(format!("{format} images are not supported"))
+            })?;
+
+            debug!(%format, "reading source image");
+            reader.read(name, location).await
+        })
+    }
+}
+
+/// Read the leading bytes of a source image, returning them along with a location that reads the
+/// image from the start.
+async fn peek(location: FileOrStream) -> std::io::Result<(Vec<u8>, FileOrStream)> {
+    let mut header = Vec::with_capacity(HEADER_LENGTH as usize);
+
+    match location {
+        FileOrStream::File(file) => {
+            let reader = PositionalFileReader::for_file(file.file.clone())?;
+            let header = tokio::task::spawn_blocking(move || {
+                std::io::Read::read_to_end(
+                    &mut std::io::Read::take(reader, HEADER_LENGTH),
+                    &mut header,
+                )
+                .map(|_| header)
+            })
+            .await??;
+
+            Ok((header, FileOrStream::File(file)))
+        }
+        FileOrStream::Stream(stream) => {
+            let mut stream = Box::into_pin(stream);
+            stream
+                .as_mut()
+                .take(HEADER_LENGTH)
+                .read_to_end(&mut header)
+                .await?;
+            stream.seek(SeekFrom::Start(0)).await?;
+
+            Ok((header, FileOrStream::Stream(Box::new(stream))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::io::Cursor;
+
+    use super::*;
+    use crate::image::codec::PngImageReader;
+
+    fn stream(data: Vec<u8>) -> FileOrStream {
+        FileOrStream::Stream(Box::new(Cursor::new(data)))
+    }
+
+    fn png() -> Vec<u8> {
+        let mut data = vec![];
+        let mut encoder = png::Encoder::new(&mut data, 2, 2);
+        encoder.set_color(png::ColorType::Rgb);
+
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[0; 12]).unwrap();
+        writer.finish().unwrap();
+
+        data
+    }
+
+    #[test]
+    fn sniffs_formats_from_leading_bytes() {
+        let jp2 = std::fs::read("test-data/iiif-validator-reference.jp2").unwrap();
+
+        assert_eq!(SourceFormat::sniff(&jp2[..12]), Some(SourceFormat::Jpeg2000));
+        assert_eq!(SourceFormat::sniff(&[0xFF, 0x4F, 0xFF, 0x51]), Some(SourceFormat::Jpeg2000));
+        assert_eq!(SourceFormat::sniff(b"II*\0\x08\0\0\0"), Some(SourceFormat::Tiff));
+        assert_eq!(SourceFormat::sniff(b"MM\0+\0\x08\0\0"), Some(SourceFormat::Tiff));
+        assert_eq!(SourceFormat::sniff(&[0xFF, 0xD8, 0xFF, 0xE0]), Some(SourceFormat::Jpeg));
+        assert_eq!(SourceFormat::sniff(&png()), Some(SourceFormat::Png));
+        assert_eq!(SourceFormat::sniff(b"GIF89a"), None);
+        assert_eq!(SourceFormat::sniff(&[]), None);
+    }
+
+    #[test]
+    fn guesses_formats_from_extensions() {
+        assert_eq!(SourceFormat::from_extension("a/b.JP2"), Some(SourceFormat::Jpeg2000));
+        assert_eq!(SourceFormat::from_extension("scan.ptif"), Some(SourceFormat::Tiff));
+        assert_eq!(SourceFormat::from_extension("photo.jpeg"), Some(SourceFormat::Jpeg));
+        assert_eq!(SourceFormat::from_extension("scan"), None);
+    }
+
+    #[tokio::test]
+    async fn dispatches_to_registered_readers() {
+        let reader = CompositeImageReader::new().with_reader(SourceFormat::Png, PngImageReader);
+
+        let mut image = reader.read(None, stream(png())).await.unwrap();
+        assert_eq!(image.info().unwrap().width, 2);
+
+        let tiff = b"II*\0\x08\0\0\0".to_vec();
+        let result = reader.read(Some("scan.png".into()), stream(tiff)).await;
+        assert!(matches!(result, Err(CodecError::UnsupportedFormat(_))));
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_extensions() {
+        /// Fails every read, to show which images were dispatched to it.
+        struct Failing;
+
+        impl ImageReader for Failing {
+            fn read<'a>(
+                &'a self,
+                _: Option<String>,
+                _: FileOrStream,
+            ) -> Pin<Box<dyn Future<Output = Result<BoxedImage, CodecError>> + Send + 'a>>
+            {
+                Box::pin(async { Err(CodecError::Io("dispatched".into())) })
+            }
+        }
+
+        let reader = CompositeImageReader::new().with_reader(SourceFormat::Tiff, Failing);
+        let unknown = || stream(b"unknown".to_vec());
+
+        let result = reader.read(Some("scan.tif".into()), unknown()).await;
+        assert!(matches!(result, Err(CodecError::Io(_))));
+
+        let result = reader.read(Some("notes.txt".into()), unknown()).await;
+        assert!(matches!(result, Err(CodecError::UnsupportedFormat(_))));
+    }
+}
@@ -0,0 +1,225 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::image::pixel::{BitDepth, ColorSpace, PixelFormat};
+
+/// The signature box that starts every JP2 file.
+pub(crate) const SIGNATURE: [u8; 12] = [
+    0, 0, 0, 0x0C, b'j', b'P', b' ', b' ', 0x0D, 0x0A, 0x87, 0x0A,
+];
+
+/// The `colr` box methods that carry an ICC profile rather than an enumerated colour space.
+const METHOD_RESTRICTED_ICC: u8 = 2;
+const METHOD_ANY_ICC: u8 = 3;
+
+/// Read the ICC profile from the first colour specification box of a JP2 file, if it has one.
+///
+/// Raw codestreams have no boxes, and so never carry a profile.
+pub(crate) fn icc_profile<R: Read + Seek>(reader: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+    reader.seek(SeekFrom::Start(0))?;
+
+    let mut signature = [0; 12];
+    if reader.read_exact(&mut signature).is_err() || signature != SIGNATURE {
+        return Ok(None);
+    }
+
+    // The colour specification is found in the JP2 header superbox, before the codestream.
+    let Some(header_length) = find_box(reader, b"jp2h", u64::MAX)? else {
+        return Ok(None);
+    };
+    let Some(colr_length) = find_box(reader, b"colr", header_length)? else {
+        return Ok(None);
+    };
+
+    let mut colr = vec![];
+    reader.take(colr_length).read_to_end(&mut colr)?;
+
+    match colr.first() {
+        Some(&(METHOD_RESTRICTED_ICC | METHOD_ANY_ICC)) if colr.len() > 3 => {
+            Ok(Some(colr.split_off(3)))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The markers that start a codestream: the start of codestream marker, followed by the image and
+/// tile size (SIZ) marker.
+const CODESTREAM_START: [u8; 4] = [0xFF, 0x4F, 0xFF, 0x51];
+
+/// Read the layout of the pixels decoded from a JP2 file or raw codestream, from the number of
+/// components and their precision in the SIZ marker of the codestream.
+///
+/// Only the first four components are used, and samples of more than 8 bits are kept at 16 bits.
+pub(crate) fn pixel_format<R: Read + Seek>(reader: &mut R) -> std::io::Result<PixelFormat> {
+    reader.seek(SeekFrom::Start(0))?;
+
+    let mut signature = [0; 12];
+    let is_jp2 = reader.read_exact(&mut signature).is_ok() && signature == SIGNATURE;
+    if is_jp2 {
+        if find_box(reader, b"jp2c", u64::MAX)?.is_none() {
+            return Err(invalid_codestream("JP2 file has no codestream"));
+        }
+    } else {
+        reader.seek(SeekFrom::Start(0))?;
+    }
+
+    // The start of codestream and SIZ markers, the length and capabilities of the SIZ marker,
+    // and the eight 32-bit sizes and offsets of the image and its tiles.
+    let mut siz = [0; 4 + 4 + 32];
+    reader.read_exact(&mut siz)?;
+    if siz[..4] != CODESTREAM_START {
+        return Err(invalid_codestream("codestream doesn't start with a SIZ marker"));
+    }
+
+    let mut count = [0; 2];
+    reader.read_exact(&mut count)?;
+    let count = u16::from_be_bytes(count) as usize;
+    if count == 0 {
+        return Err(invalid_codestream("codestream has no components"));
+    }
+
+    // Each component has its sample size and signedness, then its subsampling.
+    let mut components = vec![0; count.min(4) * 3];
+    reader.read_exact(&mut components)?;
+    let precision = components
+        .chunks_exact(3)
+        .map(|component| (component[0] & 0x7F) + 1)
+        .max()
+        .unwrap_or(8);
+
+    let (color, alpha) = match count {
+        1 => (ColorSpace::Gray, false),
+        2 => (ColorSpace::Gray, true),
+        3 => (ColorSpace::Rgb, false),
+        _ => (ColorSpace::Rgb, true),
+    };
+    let depth = if precision > 8 {
+        BitDepth::Sixteen
+    } else {
+        BitDepth::Eight
+    };
+
+    Ok(PixelFormat::new(color, alpha, depth))
+}
+
+fn invalid_codestream(reason: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, reason)
+}
+
+/// Skip boxes until one of the given type within the next `limit` bytes, leaving the reader at
+/// the start of its contents and returning the length of those contents.
+///
+/// The contents of a box that extends to the end of the file are limited only by `limit`.
+fn find_box<R: Read + Seek>(
+    reader: &mut R,
+    kind: &[u8; 4],
+    mut limit: u64,
+) -> std::io::Result<Option<u64>> {
+    while limit >= 8 {
+        let mut header = [0; 8];
+        if reader.read_exact(&mut header).is_err() {
+            return Ok(None);
+        }
+
+        let (header_size, length) =
+            match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+                0 => (8, limit),
+                1 => {
+                    let mut extended = [0; 8];
+                    reader.read_exact(&mut extended)?;
+                    (16, u64::from_be_bytes(extended))
+                }
+                length => (8, length as u64),
+            };
+
+        let Some(contents) = length.checked_sub(header_size) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "JP2 box is shorter than its header",
+            ));
+        };
+
+        if &header[4..] == kind {
+            return Ok(Some(contents.min(limit.saturating_sub(header_size))));
+        }
+
+        reader.seek(SeekFrom::Current(contents as i64))?;
+        limit = limit.saturating_sub(length);
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn jp2_box(kind: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+        let length = (contents.len() + 8) as u32;
+        [&length.to_be_bytes()[..], kind, contents].concat()
+    }
+
+    fn jp2(colr: &[u8]) -> Vec<u8> {
+        jp2_with_codestream(colr, &CODESTREAM_START)
+    }
+
+    fn jp2_with_codestream(colr: &[u8], codestream: &[u8]) -> Vec<u8> {
+        let header = [jp2_box(b"ihdr", &[0; 14]), jp2_box(b"colr", colr)].concat();
+
+        [
+            SIGNATURE.to_vec(),
+            jp2_box(b"ftyp", b"jp2 \0\0\0\0jp2 "),
+            jp2_box(b"jp2h", &header),
+            jp2_box(b"jp2c", codestream),
+        ]
+        .concat()
+    }
+
+    /// The start of a codestream with components of the given precisions, in bits.
+    fn codestream(precisions: &[u8]) -> Vec<u8> {
+        let mut codestream = CODESTREAM_START.to_vec();
+        codestream.extend((38 + 3 * precisions.len() as u16).to_be_bytes());
+        codestream.extend([0; 2 + 32]);
+        codestream.extend((precisions.len() as u16).to_be_bytes());
+        for precision in precisions {
+            codestream.extend([precision - 1, 1, 1]);
+        }
+
+        codestream
+    }
+
+    #[test]
+    fn reads_embedded_profiles() {
+        let data = jp2(&[METHOD_RESTRICTED_ICC, 0, 0, 1, 2, 3]);
+        assert_eq!(icc_profile(&mut Cursor::new(data)).unwrap(), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn ignores_enumerated_colour_spaces() {
+        let srgb = jp2(&[1, 0, 0, 0, 0, 0, 16]);
+        assert_eq!(icc_profile(&mut Cursor::new(srgb)).unwrap(), None);
+
+        let codestream = vec![0xFF, 0x4F, 0xFF, 0x51, 0, 0];
+        assert_eq!(icc_profile(&mut Cursor::new(codestream)).unwrap(), None);
+    }
+
+    #[test]
+    fn reads_the_pixel_format_of_codestreams() {
+        let format = |data: Vec<u8>| pixel_format(&mut Cursor::new(data)).unwrap();
+
+        assert_eq!(format(codestream(&[8])), PixelFormat::GRAY8);
+        assert_eq!(format(codestream(&[8, 8, 8])), PixelFormat::RGB8);
+        assert_eq!(
+            format(codestream(&[16])),
+            PixelFormat::new(ColorSpace::Gray, false, BitDepth::Sixteen)
+        );
+        assert_eq!(
+            format(jp2_with_codestream(&[1, 0, 0, 0, 0, 0, 16], &codestream(&[12, 12, 12, 12]))),
+            PixelFormat::new(ColorSpace::Rgb, true, BitDepth::Sixteen)
+        );
+
+        let truncated = codestream(&[8])[..20].to_vec();
+        assert!(pixel_format(&mut Cursor::new(truncated)).is_err());
+    }
+}
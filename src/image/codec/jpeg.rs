@@ -0,0 +1,264 @@
+use std::future::Future;
+use std::io::BufReader;
+use std::ops::Range;
+use std::pin::Pin;
+
+use bytes::BytesMut;
+use mozjpeg::decompress::DecompressStarted;
+use mozjpeg::{ColorSpace, ColorSpaceExt, Decompress};
+use tracing::debug;
+
+use super::scale::{RowScaler, reduce_region};
+use super::{CodecError, ImageReader, SharedSource, open_blocking, run_blocking, synthetic_info};
+use crate::iiif::Dimension;
+use crate::image::info::ImageInfo;
+use crate::image::pixel::PixelFormat;
+use crate::image::{AbsoluteRegion, BoxedImage, Dimensions, Image, ImageDecoder};
+use crate::storage::FileOrStream;
+
+/// The number of source scanlines decoded by each call to [ImageDecoder::decode_to].
+const SCANLINES_PER_BATCH: usize = 16;
+
+/// Reads baseline and progressive JPEG images.
+#[derive(Default)]
+pub struct JpegImageReader;
+
+impl ImageReader for JpegImageReader {
+    fn read<'a>(
+        &'a self,
+        _name: Option<String>,
+        location: FileOrStream,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedImage, CodecError>> + Send + 'a>> {
+        Box::pin(run_blocking(move || {
+            let source = open_blocking(location)?;
+
+            Ok(JpegImage::open(SharedSource::new(source))?.boxed())
+        }))
+    }
+}
+
+/// A JPEG image, decoded afresh for each region that is opened.
+pub struct JpegImage {
+    source: SharedSource,
+    width: Dimension,
+    height: Dimension,
+    cmyk: bool,
+    gray: bool,
+}
+
+impl JpegImage {
+    fn open(source: SharedSource) -> std::io::Result<Self> {
+        let decompress = Decompress::builder().from_reader(BufReader::new(source.clone()))?;
+        let (width, height) = decompress.size();
+        let cmyk = matches!(decompress.color_space(), ColorSpace::JCS_CMYK | ColorSpace::JCS_YCCK);
+        let gray = decompress.color_space() == ColorSpace::JCS_GRAYSCALE;
+
+        Ok(Self { source, width: width as Dimension, height: height as Dimension, cmyk, gray })
+    }
+}
+
+impl Image for JpegImage {
+    fn info(&mut self) -> Result<ImageInfo, CodecError> {
+        Ok(synthetic_info(self.width, self.height))
+    }
+
+    fn open_region(
+        &mut self,
+        region: AbsoluteRegion,
+        scaled_to: Dimensions,
+    ) -> Result<Box<dyn ImageDecoder>, CodecError> {
+        let (scaled_width, scaled_height) = scaled_to;
+
+        // Use the smallest DCT scaling that doesn't need to be enlarged afterwards.
+        let numerator = [1, 2, 4]
+            .into_iter()
+            .find(|&numerator| {
+                region.width as u64 * numerator >= scaled_width as u64 * 8
+                    && region.height as u64 * numerator >= scaled_height as u64 * 8
+            })
+            .unwrap_or(8);
+
+        let mut source = self.source.clone();
+        std::io::Seek::rewind(&mut source)?;
+
+        let mut decompress = Decompress::builder().from_reader(BufReader::new(source))?;
+        decompress.scale(numerator as u8);
+
+        // CMYK is converted to RGB as each scanline is read, and grayscale is left as it is.
+        let (colorspace, format) = if self.cmyk {
+            (ColorSpace::JCS_CMYK, PixelFormat::RGB8)
+        } else if self.gray {
+            (ColorSpace::JCS_GRAYSCALE, PixelFormat::GRAY8)
+        } else {
+            (ColorSpace::JCS_EXT_RGB, PixelFormat::RGB8)
+        };
+        let decompress = decompress.to_colorspace(colorspace)?;
+
+        let scaled = (decompress.width() as Dimension, decompress.height() as Dimension);
+        let (columns, rows) = reduce_region(&region, (self.width, self.height), scaled);
+
+        debug!(numerator, ?columns, ?rows, "decoding region of JPEG image");
+
+        Ok(Box::new(JpegRegionDecoder {
+            scanline: vec![0; scaled.0 as usize * colorspace.num_components()],
+            row: Vec::with_capacity(columns.len() * format.bytes_per_pixel()),
+            scaler: RowScaler::new(
+                (columns.len() as Dimension, rows.len() as Dimension),
+                scaled_to,
+                format,
+            ),
+            decompress: Some(decompress),
+            columns,
+            rows,
+            next_row: 0,
+            cmyk: self.cmyk,
+            format,
+            output_size: scaled_to,
+        }))
+    }
+}
+
+/// Decodes a region of a JPEG image a batch of scanlines at a time.
+struct JpegRegionDecoder {
+    decompress: Option<DecompressStarted<BufReader<SharedSource>>>,
+    scanline: Vec<u8>,
+    row: Vec<u8>,
+    columns: Range<Dimension>,
+    rows: Range<Dimension>,
+    next_row: Dimension,
+    scaler: RowScaler,
+    cmyk: bool,
+    format: PixelFormat,
+    output_size: Dimensions,
+}
+
+impl ImageDecoder for JpegRegionDecoder {
+    fn output_size(&self) -> Dimensions {
+        self.output_size
+    }
+
+    fn pixel_format(&self) -> PixelFormat {
+        self.format
+    }
+
+    fn rows_per_decode(&self) -> Option<Dimension> {
+        Some(
+            self.scaler
+                .max_output_rows(SCANLINES_PER_BATCH as Dimension),
+        )
+    }
+
+    fn decode_to(&mut self, buffer: &mut BytesMut) -> Result<bool, CodecError> {
+        let Some(decompress) = self.decompress.as_mut() else {
+            return Ok(true);
+        };
+
+        let mut decoded = 0;
+        while decoded < SCANLINES_PER_BATCH && self.next_row < self.rows.end {
+            decompress.read_scanlines_into(&mut self.scanline)?;
+
+            let y = self.next_row;
+            self.next_row += 1;
+
+            // JPEG images can only be decoded from the top, so rows above the region are skipped.
+            if y < self.rows.start {
+                continue;
+            }
+
+            let columns = self.columns.start as usize..self.columns.end as usize;
+            self.row.clear();
+            if self.cmyk {
+                // Adobe applications write inverted CMYK, so each channel is already 255 - ink.
+                self.row.extend(
+                    self.scanline[columns.start * 4..columns.end * 4]
+                        .chunks_exact(4)
+                        .flat_map(|p| {
+                            [p[0], p[1], p[2]].map(|ink| (ink as u32 * p[3] as u32 / 255) as u8)
+                        }),
+                );
+            } else {
+                let pixel_size = self.format.bytes_per_pixel();
+                self.row.extend_from_slice(
+                    &self.scanline[columns.start * pixel_size..columns.end * pixel_size],
+                );
+            }
+
+            self.scaler.push(&self.row, buffer);
+            decoded += 1;
+        }
+
+        // Stop decoding once the region has been read, without reading the rest of the image.
+        if self.next_row >= self.rows.end {
+            self.decompress = None;
+        }
+
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Encode a JPEG image with a horizontal gradient in the red channel.
+    fn gradient(width: usize, height: usize) -> Vec<u8> {
+        let pixels: Vec<u8> = (0..width * height)
+            .flat_map(|i| [(i % width * 255 / (width - 1)) as u8, 0, 0])
+            .collect();
+
+        let mut compress = mozjpeg::Compress::new(ColorSpace::JCS_RGB);
+        compress.set_size(width, height);
+        compress.set_quality(95.0);
+
+        let mut started = compress.start_compress(vec![]).unwrap();
+        started.write_scanlines(&pixels).unwrap();
+        started.finish().unwrap()
+    }
+
+    fn open(data: Vec<u8>) -> JpegImage {
+        JpegImage::open(SharedSource::new(Box::new(Cursor::new(data)))).unwrap()
+    }
+
+    fn decode(image: &mut JpegImage, region: AbsoluteRegion, scaled_to: Dimensions) -> Vec<u8> {
+        let mut decoder = image.open_region(region, scaled_to).unwrap();
+        let mut output = BytesMut::new();
+        while !decoder.decode_to(&mut output).unwrap() {}
+
+        output.to_vec()
+    }
+
+    #[test]
+    fn synthesises_tiles_and_sizes() {
+        let info = open(gradient(2000, 1000)).info().unwrap();
+        assert_eq!((info.width, info.height), (2000, 1000));
+
+        let sizes: Vec<_> = info
+            .sizes
+            .unwrap()
+            .iter()
+            .map(|s| (s.width, s.height))
+            .collect();
+        assert_eq!(sizes, [(500, 250), (1000, 500)]);
+
+        let tiles = info.tiles.unwrap();
+        assert_eq!((tiles[0].width, tiles[0].height), (512, Some(512)));
+        assert_eq!(tiles[0].scale_factors, [1, 2, 4]);
+    }
+
+    #[test]
+    fn decodes_reduced_regions() {
+        let mut image = open(gradient(256, 128));
+        let full = AbsoluteRegion { x: 0, y: 0, width: 256, height: 128 };
+
+        let pixels = decode(&mut image, full, (32, 16));
+        assert_eq!(pixels.len(), 32 * 16 * 3);
+
+        let right_half = AbsoluteRegion { x: 128, y: 64, width: 128, height: 64 };
+        let pixels = decode(&mut image, right_half, (64, 32));
+        assert_eq!(pixels.len(), 64 * 32 * 3);
+        assert!(pixels[0] >= 120 && pixels[0] <= 136, "red was {}", pixels[0]);
+        assert!(pixels[pixels.len() - 3] > 240);
+    }
+}
@@ -1,36 +1,152 @@
 use std::future::Future;
-use std::io::Write;
+use std::io::{BufReader, Seek, SeekFrom, Write};
 use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use bytes::{BufMut, BytesMut};
 use kaduceus::{KakaduContext, KakaduDecompressor, KakaduImage};
-use tokio::runtime::{Builder, Runtime};
+use opentelemetry::metrics::ObservableGauge;
+use tokio::runtime::{Builder, Handle, Runtime};
+use tokio::sync::Semaphore;
 use tracing::info;
 
-use super::ImageReader;
+use super::{BlockingStream, CodecError, ImageReader, jp2, run_blocking};
 use crate::iiif::{Dimension, Region};
 use crate::image::info::{ImageInfo, PreferredSize, Tile};
-use crate::image::{AbsoluteRegion, BoxedImage, Image, ImageDecoder};
+use crate::image::pixel::PixelFormat;
+use crate::image::{AbsoluteRegion, BoxedImage, Dimensions, Image, ImageDecoder};
 use crate::storage::FileOrStream;
+use crate::storage::local::PositionalFileReader;
+
+/// The number of bytes in each sample Kakadu reconstructs, before they are converted to the pixel
+/// format of the image.
+const WORKING_SAMPLE_SIZE: u64 = size_of::<i32>() as u64;
 
 pub struct KaduceusImageReader {
     context: KakaduContext,
     executor: Arc<Runtime>,
+    memory: Arc<MemoryUsage>,
+    /// Limits the regions decoded at once by every request combined. Regions are decoded on the
+    /// blocking threads of the pipelines that request them rather than on Kakadu's executor, so
+    /// pipelines wait for a slot before they take a thread.
+    slots: Arc<Semaphore>,
+    _memory_gauge: ObservableGauge<u64>,
 }
 
 impl KaduceusImageReader {
+    /// Create a reader that decodes as many regions at once as there are CPU cores.
     pub fn new(context: KakaduContext) -> Self {
+        let threads = std::thread::available_parallelism().map_or(1, usize::from);
+        Self::new_with_decoder_threads(context, threads)
+    }
+
+    /// Create a reader that opens images on an executor with `threads` threads, and decodes at
+    /// most `threads` regions at once.
+    pub fn new_with_decoder_threads(context: KakaduContext, threads: usize) -> Self {
+        let threads = threads.max(1);
+        let mut executor = Builder::new_multi_thread();
+        executor.worker_threads(threads);
+
+        Self::with_executor(context, executor, threads)
+    }
+
+    fn with_executor(context: KakaduContext, mut executor: Builder, threads: usize) -> Self {
+        let executor = executor
+            .thread_name("kakadu-decoder")
+            .enable_all()
+            .build()
+            .expect("unable to start the Kakadu executor");
+
+        let memory =
+            Arc::new(MemoryUsage { limit: AtomicU64::new(u64::MAX), used: AtomicU64::new(0) });
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+        let observed = memory.clone();
+
         Self {
             context,
-            executor: Arc::new(Builder::new_multi_thread().enable_all().build().unwrap()),
+            executor: Arc::new(executor),
+            memory,
+            slots: Arc::new(Semaphore::new(threads)),
+            _memory_gauge: meter
+                .u64_observable_gauge("kakadu.memory.used")
+                .with_description("Memory reserved by Kakadu decoders for the regions they decode")
+                .with_unit("By")
+                .with_callback(move |gauge| gauge.observe(observed.used(), &[]))
+                .build(),
         }
     }
+
+    /// Limit the memory reserved by the decoders of every request combined to `limit` bytes.
+    ///
+    /// The memory a decoder reserves is an estimate from the region it decodes, as Kakadu doesn't
+    /// report or limit the memory it allocates, so this bounds rather than caps its usage.
+    /// Regions that would take the reserved memory over the limit fail to open with
+    /// [CodecError::Exhausted] until other requests finish decoding.
+    pub fn with_memory_limit(self, limit: u64) -> Self {
+        self.memory.limit.store(limit, Ordering::Relaxed);
+        self
+    }
+}
+
+/// The memory reserved by Kakadu decoders, shared between every image read by a reader.
+struct MemoryUsage {
+    limit: AtomicU64,
+    used: AtomicU64,
+}
+
+impl MemoryUsage {
+    fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Reserve `bytes` until the returned reservation is dropped, failing if that would take the
+    /// reserved memory over the limit.
+    fn reserve(self: &Arc<Self>, bytes: u64) -> Result<MemoryReservation, CodecError> {
+        let limit = self.limit.load(Ordering::Relaxed);
+
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|&total| total <= limit)
+            })
+            .map_err(|used| {
+                CodecError::Exhausted(format!(
+                    "decoding the region needs {bytes} bytes, but {used} of the {limit} bytes \
+                     available to Kakadu are in use"
+                ))
+            })?;
+
+        Ok(MemoryReservation { usage: self.clone(), bytes })
+    }
 }
 
-impl Image for KakaduImage {
-    fn info(&mut self) -> ImageInfo {
-        let info = KakaduImage::info(self);
+/// Memory reserved for a decoder, returned once it is dropped.
+struct MemoryReservation {
+    usage: Arc<MemoryUsage>,
+    bytes: u64,
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.usage.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+/// A JPEG 2000 image decoded by Kakadu, along with the ICC profile from its JP2 header.
+///
+/// Regions are always decoded from every quality layer, as kaduceus doesn't take a limit on the
+/// layers a region is decoded from.
+pub struct KaduceusImage {
+    image: KakaduImage,
+    format: PixelFormat,
+    icc_profile: Option<Arc<[u8]>>,
+    memory: Arc<MemoryUsage>,
+    slots: Arc<Semaphore>,
+}
+
+impl Image for KaduceusImage {
+    fn info(&mut self) -> Result<ImageInfo, CodecError> {
+        let info = self.image.info();
         let tiles = vec![Tile {
             width: info.tile_width,
             height: Some(info.tile_height),
@@ -47,7 +163,7 @@ impl Image for KakaduImage {
             });
         }
 
-        ImageInfo {
+        Ok(ImageInfo {
             width: info.width,
             height: info.height,
             max_width: Some(info.width),
@@ -57,15 +173,14 @@ impl Image for KakaduImage {
             tiles: Some(tiles),
             preferred_formats: None,
             rights: None,
-        }
+        })
     }
 
     fn open_region(
         &mut self,
         region: AbsoluteRegion,
         scaled_to: (Dimension, Dimension),
-    ) -> Box<dyn ImageDecoder> {
-        let info = self.info();
+    ) -> Result<Box<dyn ImageDecoder>, CodecError> {
         let (scaled_width, scaled_height) = scaled_to;
         let kdu_region = kaduceus::Region {
             x: region.x,
@@ -74,14 +189,62 @@ impl Image for KakaduImage {
             height: region.height,
         };
 
-        let decompressor = KakaduImage::open_region(self, kdu_region, scaled_width, scaled_height);
+        let reservation =
+            self.memory
+                .reserve(working_memory(&self.image, self.format, &region, scaled_to))?;
+        let decompressor = self
+            .image
+            .open_region(kdu_region, scaled_width, scaled_height);
 
-        Box::new(decompressor)
+        Ok(Box::new(KakaduRegionDecoder {
+            decompressor,
+            output_size: scaled_to,
+            format: self.format,
+            icc_profile: self.icc_profile.clone(),
+            reservation,
+        }))
     }
+
+    fn decoder_slots(&self) -> Option<Arc<Semaphore>> {
+        Some(self.slots.clone())
+    }
+}
+
+/// An estimate of the memory Kakadu needs to decode `region`, scaled to `scaled_to`: a stripe of
+/// working samples for each component, as wide as the region and as tall as a tile, at the
+/// resolution level the region is reconstructed at.
+fn working_memory(
+    image: &KakaduImage,
+    format: PixelFormat,
+    region: &AbsoluteRegion,
+    scaled_to: Dimensions,
+) -> u64 {
+    let info = image.info();
+    let reduction = (region.width / scaled_to.0.max(1))
+        .min(region.height / scaled_to.1.max(1))
+        .max(1)
+        .ilog2()
+        .min(info.dwt_levels);
+
+    let width = region.width.div_ceil(1 << reduction) as u64;
+    let height = info.tile_height.min(region.height).div_ceil(1 << reduction) as u64;
+
+    width * height * format.channels() as u64 * WORKING_SAMPLE_SIZE
 }
 
-impl ImageDecoder for KakaduDecompressor {
-    fn decode_to(&mut self, buffer: &mut BytesMut) -> bool {
+/// Decodes a region of a JPEG 2000 image with Kakadu, scaled to a fixed output size.
+struct KakaduRegionDecoder {
+    decompressor: KakaduDecompressor,
+    output_size: Dimensions,
+    format: PixelFormat,
+    icc_profile: Option<Arc<[u8]>>,
+    reservation: MemoryReservation,
+}
+
+impl ImageDecoder for KakaduRegionDecoder {
+    fn decode_to(&mut self, buffer: &mut BytesMut) -> Result<bool, CodecError> {
+        // Leave room for at least one row of the region.
+        buffer.reserve(self.output_size.0 as usize * self.format.bytes_per_pixel());
         let uninit = buffer.spare_capacity_mut();
 
         // SAFETY: the buffer is never read by `process`
@@ -89,19 +252,35 @@ impl ImageDecoder for KakaduDecompressor {
             std::mem::transmute::<&mut [std::mem::MaybeUninit<u8>], &mut [u8]>(&mut uninit[..])
         };
 
-        let region = self.process(uninit_buf).unwrap();
+        let region = self
+            .decompressor
+            .process(uninit_buf)
+            .map_err(|e| CodecError::Malformed(e.to_string()))?;
 
         unsafe {
-            buffer.set_len(buffer.len() + region.width as usize * region.height as usize * 3);
+            let pixels = region.width as usize * region.height as usize;
+            buffer.set_len(buffer.len() + pixels * self.format.bytes_per_pixel());
         }
 
         info!(region=?region, "processed a region");
 
-        region.width == 0 || region.height == 0
+        Ok(region.width == 0 || region.height == 0)
+    }
+
+    fn output_size(&self) -> Dimensions {
+        self.output_size
     }
 
-    fn output_size(&self) -> crate::image::Dimensions {
-        todo!()
+    fn pixel_format(&self) -> PixelFormat {
+        self.format
+    }
+
+    fn icc_profile(&self) -> Option<Arc<[u8]>> {
+        self.icc_profile.clone()
+    }
+
+    fn working_memory(&self) -> u64 {
+        self.reservation.bytes
     }
 }
 
@@ -110,26 +289,154 @@ impl ImageReader for KaduceusImageReader {
         &'a self,
         name: Option<String>,
         location: FileOrStream,
-    ) -> Pin<Box<dyn Future<Output = BoxedImage> + Send + 'a>> {
-        Box::pin(async move {
-            let executor = self.executor.clone();
-            let context = self.context.clone();
-            let span = tracing::Span::current();
-
-            tokio::task::spawn_blocking(move || {
-                span.in_scope(|| {
-                    let stream = match location {
-                        FileOrStream::File(file) => {
-                            Box::into_pin((file.stream_factory)(&file.path))
-                        }
-                        FileOrStream::Stream(reader) => Box::into_pin(reader),
-                    };
-
-                    KakaduImage::new(executor, context, stream, name).boxed()
-                })
-            })
-            .await
-            .unwrap()
-        })
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedImage, CodecError>> + Send + 'a>> {
+        let executor = self.executor.clone();
+        let context = self.context.clone();
+        let memory = self.memory.clone();
+        let slots = self.slots.clone();
+
+        Box::pin(run_blocking(move || {
+            // Kakadu doesn't expose the JP2 header boxes or the components of the codestream, so
+            // the ICC profile and pixel format are read separately.
+            let (stream, icc_profile, format): (Pin<Box<dyn kaduceus::AsyncSeekableRead>>, _, _) =
+                match location {
+                    // Read local files with positional reads rather than through the provider's
+                    // asynchronous stream.
+                    FileOrStream::File(file) => {
+                        let reader = PositionalFileReader::for_file(file.file)?;
+                        let mut header = BufReader::new(reader);
+                        let icc_profile = jp2::icc_profile(&mut header)?;
+                        let format = jp2::pixel_format(&mut header)?;
+
+                        let mut reader = header.into_inner();
+                        reader.seek(SeekFrom::Start(0))?;
+
+                        (Box::pin(reader), icc_profile, format)
+                    }
+                    FileOrStream::Stream(reader) => {
+                        let mut reader = BlockingStream {
+                            runtime: Handle::current(),
+                            stream: Box::into_pin(reader),
+                        };
+                        let icc_profile = jp2::icc_profile(&mut reader)?;
+                        let format = jp2::pixel_format(&mut reader)?;
+                        reader.seek(SeekFrom::Start(0))?;
+
+                        (Box::pin(reader.stream), icc_profile, format)
+                    }
+                };
+
+            let image = KakaduImage::new(executor, context, stream, name);
+            let icc_profile = icc_profile.map(Arc::from);
+            Ok(KaduceusImage { image, format, icc_profile, memory, slots }.boxed())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn enforces_the_memory_limit() {
+        let memory = Arc::new(MemoryUsage { limit: AtomicU64::new(100), used: AtomicU64::new(0) });
+
+        let first = memory.reserve(60).unwrap();
+        assert!(matches!(memory.reserve(50), Err(CodecError::Exhausted(_))));
+        assert_eq!(memory.used(), 60);
+
+        // Memory is returned once the decoder holding it is dropped.
+        drop(first);
+        let _second = memory.reserve(100).unwrap();
+        assert_eq!(memory.used(), 100);
+    }
+
+    fn jp2_box(kind: &[u8; 4], contents: &[u8]) -> Vec<u8> {
+        let length = (contents.len() + 8) as u32;
+        [&length.to_be_bytes()[..], kind, contents].concat()
+    }
+
+    /// A single-tile JP2 image with components of the given precisions, in bits, whose packets are
+    /// all empty, so every sample decodes to the middle of its range.
+    fn blank_jp2(width: u32, height: u32, precisions: &[u8]) -> Vec<u8> {
+        let components = precisions.len() as u16;
+        let mut codestream = vec![0xFF, 0x4F, 0xFF, 0x51];
+        codestream.extend((38 + 3 * components).to_be_bytes());
+        codestream.extend([0; 2]);
+        for value in [width, height, 0, 0, width, height, 0, 0] {
+            codestream.extend(value.to_be_bytes());
+        }
+        codestream.extend(components.to_be_bytes());
+        for precision in precisions {
+            codestream.extend([precision - 1, 1, 1]);
+        }
+
+        // One quality layer and no decomposition levels, coded losslessly in 64x64 code-blocks.
+        codestream.extend([0xFF, 0x52, 0, 12, 0, 0, 0, 1, 0, 0, 4, 4, 0, 1]);
+        let precision = precisions.iter().copied().max().unwrap_or(8);
+        codestream.extend([0xFF, 0x5C, 0, 4, 0x40, precision << 3]);
+
+        // One tile part holding an empty packet for each component.
+        let tile_length = 14 + u32::from(components);
+        codestream.extend([0xFF, 0x90, 0, 10, 0, 0]);
+        codestream.extend(tile_length.to_be_bytes());
+        codestream.extend([0, 1, 0xFF, 0x93]);
+        codestream.extend(std::iter::repeat_n(0, precisions.len()));
+        codestream.extend([0xFF, 0xD9]);
+
+        let mut ihdr = [height.to_be_bytes(), width.to_be_bytes()].concat();
+        ihdr.extend(components.to_be_bytes());
+        ihdr.extend([precision - 1, 7, 0, 0]);
+        let colour_space: u32 = if components < 3 { 17 } else { 16 };
+        let colr = [&[1, 0, 0][..], &colour_space.to_be_bytes()].concat();
+        let header = [jp2_box(b"ihdr", &ihdr), jp2_box(b"colr", &colr)].concat();
+
+        [
+            vec![0, 0, 0, 12, b'j', b'P', b' ', b' ', 0x0D, 0x0A, 0x87, 0x0A],
+            jp2_box(b"ftyp", b"jp2 \0\0\0\0jp2 "),
+            jp2_box(b"jp2h", &header),
+            jp2_box(b"jp2c", &codestream),
+        ]
+        .concat()
+    }
+
+    /// Decode the whole of `data`, returning the pixel format reported for it and its pixels.
+    fn decode(data: Vec<u8>) -> (PixelFormat, Dimensions, Vec<u8>) {
+        let reader = KaduceusImageReader::new_with_decoder_threads(KakaduContext::default(), 1);
+        let runtime = Builder::new_current_thread().enable_all().build().unwrap();
+        let location = FileOrStream::Stream(Box::new(futures::io::Cursor::new(data)));
+        let mut image = runtime.block_on(reader.read(None, location)).unwrap();
+
+        let info = image.info().unwrap();
+        let region = AbsoluteRegion { x: 0, y: 0, width: info.width, height: info.height };
+        let mut decoder = image
+            .open_region(region, (info.width, info.height))
+            .unwrap();
+
+        let mut buffer = BytesMut::new();
+        while !decoder.decode_to(&mut buffer).unwrap() {}
+
+        (decoder.pixel_format(), decoder.output_size(), buffer.to_vec())
+    }
+
+    #[test]
+    fn decodes_pixels_in_the_reported_format() {
+        use crate::image::pixel::{BitDepth, ColorSpace};
+
+        let gray16 = PixelFormat::new(ColorSpace::Gray, false, BitDepth::Sixteen);
+        let rgb16 = PixelFormat::new(ColorSpace::Rgb, false, BitDepth::Sixteen);
+
+        for (precisions, expected) in [
+            (&[8][..], PixelFormat::GRAY8),
+            (&[16][..], gray16),
+            (&[8, 8, 8][..], PixelFormat::RGB8),
+            (&[16, 16, 16][..], rgb16),
+        ] {
+            let (format, (width, height), pixels) = decode(blank_jp2(7, 5, precisions));
+
+            assert_eq!(format, expected);
+            assert_eq!((width, height), (7, 5));
+            assert_eq!(pixels.len(), 7 * 5 * format.bytes_per_pixel(), "{format:?}");
+        }
     }
 }
@@ -0,0 +1,514 @@
+use std::ffi::c_void;
+use std::future::Future;
+use std::io::{Read, Seek, SeekFrom};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use openjp2::openjpeg::*;
+use tracing::debug;
+
+use super::scale::RowScaler;
+use super::{
+    CodecError, ImageReader, SYNTHETIC_TILE_SIZE, SharedSource, jp2, open_blocking, run_blocking,
+};
+use crate::iiif::Dimension;
+use crate::image::info::{ImageInfo, PreferredSize, Tile};
+use crate::image::pixel::{BitDepth, ColorSpace, PixelFormat};
+use crate::image::{AbsoluteRegion, BoxedImage, Dimensions, Image, ImageDecoder};
+use crate::storage::FileOrStream;
+
+/// The size of the buffer OpenJPEG reads the codestream through.
+const STREAM_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// The number of rows converted by each call to [ImageDecoder::decode_to].
+const ROWS_PER_BATCH: Dimension = 16;
+
+/// The quality layer limit that OpenJPEG takes to mean every layer.
+const ALL_LAYERS: u32 = 0;
+
+/// Reads JPEG 2000 images with openjp2, a Rust port of OpenJPEG.
+///
+/// Unlike the Kakadu reader, regions are decoded in full before any scanlines are produced, but
+/// only the requested resolution level and the code-blocks that intersect the region are decoded.
+#[derive(Default)]
+pub struct OpenJpegImageReader;
+
+impl ImageReader for OpenJpegImageReader {
+    fn read<'a>(
+        &'a self,
+        _name: Option<String>,
+        location: FileOrStream,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedImage, CodecError>> + Send + 'a>> {
+        Box::pin(run_blocking(move || {
+            let source = open_blocking(location)?;
+
+            Ok(OpenJpegImage::open(SharedSource::new(source))?.boxed())
+        }))
+    }
+}
+
+/// A JPEG 2000 image, decoded afresh for each region that is opened.
+pub struct OpenJpegImage {
+    source: SharedSource,
+    format: OPJ_CODEC_FORMAT,
+    origin: (Dimension, Dimension),
+    width: Dimension,
+    height: Dimension,
+    tile_width: Dimension,
+    tile_height: Dimension,
+    resolutions: u32,
+    icc_profile: Option<Arc<[u8]>>,
+}
+
+impl OpenJpegImage {
+    fn open(mut source: SharedSource) -> Result<Self, CodecError> {
+        let mut signature = [0; 12];
+        source.read_exact(&mut signature)?;
+
+        // Raw codestreams start with the SOC marker rather than a JP2 signature box.
+        let format = if signature == jp2::SIGNATURE {
+            OPJ_CODEC_JP2
+        } else {
+            OPJ_CODEC_J2K
+        };
+        let icc_profile = jp2::icc_profile(&mut source.clone())?.map(Arc::from);
+        let decompression = Decompression::new(source.clone(), format, 0, ALL_LAYERS)?;
+        let image = decompression.image();
+
+        // SAFETY: the codec has read the main header, so codestream info is available.
+        let (tile_width, tile_height, resolutions) = unsafe {
+            let mut info = opj_get_cstr_info(decompression.codec);
+            if info.is_null() {
+                return Err(CodecError::Malformed("codestream information is unavailable".into()));
+            }
+
+            let tile_info = &(*info).m_default_tile_info;
+            let resolutions = (*tile_info.tccp_info).numresolutions;
+            let tile_size = ((*info).tdx, (*info).tdy);
+            opj_destroy_cstr_info(&mut info);
+
+            (tile_size.0, tile_size.1, resolutions)
+        };
+
+        Ok(Self {
+            source,
+            format,
+            origin: (image.x0, image.y0),
+            width: image.x1 - image.x0,
+            height: image.y1 - image.y0,
+            tile_width,
+            tile_height,
+            resolutions,
+            icc_profile,
+        })
+    }
+}
+
+impl Image for OpenJpegImage {
+    fn info(&mut self) -> Result<ImageInfo, CodecError> {
+        let (tile_width, tile_height) = if self.tile_width < self.width {
+            (self.tile_width, self.tile_height)
+        } else {
+            (SYNTHETIC_TILE_SIZE.min(self.width), SYNTHETIC_TILE_SIZE.min(self.height))
+        };
+
+        let sizes = (1..self.resolutions)
+            .rev()
+            .map(|level| PreferredSize {
+                width: self.width.div_ceil(1 << level),
+                height: self.height.div_ceil(1 << level),
+            })
+            .collect();
+
+        Ok(ImageInfo {
+            width: self.width,
+            height: self.height,
+            max_width: Some(self.width),
+            max_height: Some(self.height),
+            max_area: None,
+            sizes: Some(sizes),
+            tiles: Some(vec![Tile {
+                width: tile_width,
+                height: Some(tile_height),
+                scale_factors: (0..self.resolutions).map(|level| 1 << level).collect(),
+            }]),
+            preferred_formats: None,
+            rights: None,
+        })
+    }
+
+    fn open_region(
+        &mut self,
+        region: AbsoluteRegion,
+        scaled_to: Dimensions,
+    ) -> Result<Box<dyn ImageDecoder>, CodecError> {
+        self.open(region, scaled_to, ALL_LAYERS)
+    }
+
+    fn open_region_with_layers(
+        &mut self,
+        region: AbsoluteRegion,
+        scaled_to: Dimensions,
+        layers: u16,
+    ) -> Result<Box<dyn ImageDecoder>, CodecError> {
+        self.open(region, scaled_to, layers as u32)
+    }
+}
+
+impl OpenJpegImage {
+    /// Open a region for decoding from at most `layers` quality layers, or every layer if
+    /// `layers` is [ALL_LAYERS].
+    fn open(
+        &mut self,
+        region: AbsoluteRegion,
+        scaled_to: Dimensions,
+        layers: u32,
+    ) -> Result<Box<dyn ImageDecoder>, CodecError> {
+        let (scaled_width, scaled_height) = scaled_to;
+
+        // Discard the most resolution levels that still don't need to be enlarged afterwards.
+        let reduce = (0..self.resolutions)
+            .rev()
+            .find(|&level| {
+                region.width.div_ceil(1 << level) >= scaled_width
+                    && region.height.div_ceil(1 << level) >= scaled_height
+            })
+            .unwrap_or(0);
+
+        debug!(reduce, layers, ?scaled_to, "decoding region of JPEG 2000 image");
+
+        let mut decompression =
+            Decompression::new(self.source.clone(), self.format, reduce, layers)?;
+
+        let (x, y) = self.origin;
+        decompression.decode(
+            x + region.x,
+            y + region.y,
+            x + region.x + region.width,
+            y + region.y + region.height,
+        )?;
+
+        let components = decompression.components();
+        let decoded = (components[0].w, components[0].h);
+        let format = pixel_format(components);
+
+        Ok(Box::new(OpenJpegRegionDecoder {
+            decompression,
+            decoded,
+            next_row: 0,
+            row: Vec::with_capacity(decoded.0 as usize * format.bytes_per_pixel()),
+            scaler: RowScaler::new(decoded, scaled_to, format),
+            format,
+            icc_profile: self.icc_profile.clone(),
+            output_size: scaled_to,
+        }))
+    }
+}
+
+/// Produces the scanlines of a region decoded by OpenJPEG.
+struct OpenJpegRegionDecoder {
+    decompression: Decompression,
+    decoded: Dimensions,
+    next_row: Dimension,
+    row: Vec<u8>,
+    scaler: RowScaler,
+    format: PixelFormat,
+    icc_profile: Option<Arc<[u8]>>,
+    output_size: Dimensions,
+}
+
+impl ImageDecoder for OpenJpegRegionDecoder {
+    fn output_size(&self) -> Dimensions {
+        self.output_size
+    }
+
+    fn pixel_format(&self) -> PixelFormat {
+        self.format
+    }
+
+    fn icc_profile(&self) -> Option<Arc<[u8]>> {
+        self.icc_profile.clone()
+    }
+
+    fn working_memory(&self) -> u64 {
+        // OpenJPEG reconstructs the whole region as a plane of 32-bit samples for each component.
+        self.decompression
+            .components()
+            .iter()
+            .map(|component| component.w as u64 * component.h as u64)
+            .sum::<u64>()
+            * size_of::<i32>() as u64
+    }
+
+    fn rows_per_decode(&self) -> Option<Dimension> {
+        Some(self.scaler.max_output_rows(ROWS_PER_BATCH))
+    }
+
+    fn decode_to(&mut self, buffer: &mut BytesMut) -> Result<bool, CodecError> {
+        let (width, height) = self.decoded;
+        if self.next_row >= height {
+            return Ok(true);
+        }
+
+        let components = &self.decompression.components()[..self.format.channels()];
+
+        let rows = self.next_row..height.min(self.next_row + ROWS_PER_BATCH);
+        for y in rows.clone() {
+            self.row.clear();
+
+            for x in 0..width {
+                for component in components {
+                    let value = sample(component, (x, y), self.decoded, self.format.depth);
+                    match self.format.depth {
+                        BitDepth::Eight => self.row.push(value as u8),
+                        BitDepth::Sixteen => self.row.extend_from_slice(&value.to_ne_bytes()),
+                    }
+                }
+            }
+
+            self.scaler.push(&self.row, buffer);
+        }
+
+        self.next_row = rows.end;
+        Ok(false)
+    }
+}
+
+/// The layout of the pixels produced from an image's components.
+///
+/// Only the first four components are used, and samples of more than 8 bits are kept at 16 bits.
+fn pixel_format(components: &[opj_image_comp_t]) -> PixelFormat {
+    let (color, alpha) = match components.len() {
+        1 => (ColorSpace::Gray, false),
+        2 => (ColorSpace::Gray, true),
+        3 => (ColorSpace::Rgb, false),
+        _ => (ColorSpace::Rgb, true),
+    };
+    let depth = if components.iter().take(4).any(|c| c.prec > 8) {
+        BitDepth::Sixteen
+    } else {
+        BitDepth::Eight
+    };
+
+    PixelFormat::new(color, alpha, depth)
+}
+
+/// Read the sample of a component at a position in the decoded region, scaled to `depth`.
+fn sample(
+    component: &opj_image_comp_t,
+    position: Dimensions,
+    decoded: Dimensions,
+    depth: BitDepth,
+) -> u16 {
+    // Components may be subsampled relative to the first component.
+    let x = position.0 as u64 * component.w as u64 / decoded.0 as u64;
+    let y = position.1 as u64 * component.h as u64 / decoded.1 as u64;
+
+    // SAFETY: the component data holds `w * h` samples once the region has been decoded.
+    let mut value = unsafe { *component.data.add((y * component.w as u64 + x) as usize) };
+    if component.sgnd != 0 {
+        value += 1 << (component.prec - 1);
+    }
+
+    let bits = match depth {
+        BitDepth::Eight => 8,
+        BitDepth::Sixteen => 16,
+    };
+    let value = if component.prec > bits {
+        value >> (component.prec - bits)
+    } else {
+        value << (bits - component.prec)
+    };
+
+    value.clamp(0, (1 << bits) - 1) as u16
+}
+
+/// An OpenJPEG decompressor along with the stream it reads from and the image it decodes to.
+struct Decompression {
+    codec: *mut opj_codec_t,
+    stream: *mut opj_stream_t,
+    image: *mut opj_image_t,
+}
+
+impl Decompression {
+    /// Create a decompressor that discards `reduce` resolution levels and decodes at most `layers`
+    /// quality layers, and read the main header.
+    fn new(
+        mut source: SharedSource,
+        format: OPJ_CODEC_FORMAT,
+        reduce: u32,
+        layers: u32,
+    ) -> Result<Self, CodecError> {
+        let length = source.seek(SeekFrom::End(0))?;
+        source.rewind()?;
+
+        // SAFETY: each pointer is checked before use, and owned by `this` so that it is released
+        // on every path.
+        unsafe {
+            let codec = opj_create_decompress(format);
+            if codec.is_null() {
+                return Err(CodecError::Internal("unable to create decompressor".into()));
+            }
+
+            let mut this = Self { codec, stream: ptr::null_mut(), image: ptr::null_mut() };
+
+            let mut parameters: opj_dparameters_t = std::mem::zeroed();
+            opj_set_default_decoder_parameters(&mut parameters);
+            parameters.cp_reduce = reduce;
+            parameters.cp_layer = layers;
+            check(opj_setup_decoder(codec, &mut parameters), "set up decoder")?;
+
+            this.stream = opj_stream_create(STREAM_BUFFER_SIZE, 1);
+            if this.stream.is_null() {
+                return Err(CodecError::Internal("unable to create stream".into()));
+            }
+
+            let user_data = Box::into_raw(Box::new(source)) as *mut c_void;
+            opj_stream_set_user_data(this.stream, user_data, Some(free_source));
+            opj_stream_set_user_data_length(this.stream, length);
+            opj_stream_set_read_function(this.stream, Some(read_source));
+            opj_stream_set_skip_function(this.stream, Some(skip_source));
+            opj_stream_set_seek_function(this.stream, Some(seek_source));
+
+            check(opj_read_header(this.stream, codec, &mut this.image), "read header")?;
+
+            Ok(this)
+        }
+    }
+
+    /// Decode an area of the image, given in reference grid coordinates.
+    fn decode(&mut self, x0: u32, y0: u32, x1: u32, y1: u32) -> Result<(), CodecError> {
+        // SAFETY: the header has been read, so the codec, stream and image are all valid.
+        unsafe {
+            check(
+                opj_set_decode_area(
+                    self.codec, self.image, x0 as i32, y0 as i32, x1 as i32, y1 as i32,
+                ),
+                "set decode area",
+            )?;
+            check(opj_decode(self.codec, self.stream, self.image), "decode")?;
+            check(opj_end_decompress(self.codec, self.stream), "finish decoding")
+        }
+    }
+
+    fn image(&self) -> &opj_image_t {
+        // SAFETY: the image is created when the header is read and lives as long as `self`.
+        unsafe { &*self.image }
+    }
+
+    fn components(&self) -> &[opj_image_comp_t] {
+        let image = self.image();
+
+        // SAFETY: OpenJPEG allocates `numcomps` components for the image.
+        unsafe { std::slice::from_raw_parts(image.comps, image.numcomps as usize) }
+    }
+}
+
+impl Drop for Decompression {
+    fn drop(&mut self) {
+        // SAFETY: each pointer is either null or owned by this decompressor.
+        unsafe {
+            if !self.image.is_null() {
+                opj_image_destroy(self.image);
+            }
+            if !self.stream.is_null() {
+                opj_stream_destroy(self.stream);
+            }
+            opj_destroy_codec(self.codec);
+        }
+    }
+}
+
+/// Check the result of an OpenJPEG operation, which fails when the codestream is invalid.
+fn check(result: OPJ_BOOL, operation: &str) -> Result<(), CodecError> {
+    if result == 0 {
+        Err(CodecError::Malformed(format!("unable to {operation}")))
+    } else {
+        Ok(())
+    }
+}
+
+/// Get the source that a stream was created with from its user data.
+///
+/// # Safety
+///
+/// `user_data` must be the pointer passed to `opj_stream_set_user_data` by
+/// [Decompression::new].
+unsafe fn source<'a>(user_data: *mut c_void) -> &'a mut SharedSource {
+    unsafe { &mut *(user_data as *mut SharedSource) }
+}
+
+unsafe extern "C" fn read_source(
+    buffer: *mut c_void,
+    length: usize,
+    user_data: *mut c_void,
+) -> usize {
+    let source = unsafe { source(user_data) };
+    let buffer = unsafe { std::slice::from_raw_parts_mut(buffer as *mut u8, length) };
+
+    match source.read(buffer) {
+        // OpenJPEG expects (OPJ_SIZE_T)-1 at the end of the stream.
+        Ok(0) | Err(_) => usize::MAX,
+        Ok(read) => read,
+    }
+}
+
+unsafe extern "C" fn skip_source(offset: i64, user_data: *mut c_void) -> i64 {
+    let source = unsafe { source(user_data) };
+
+    match source.seek(SeekFrom::Current(offset)) {
+        Ok(_) => offset,
+        Err(_) => -1,
+    }
+}
+
+unsafe extern "C" fn seek_source(offset: i64, user_data: *mut c_void) -> OPJ_BOOL {
+    let source = unsafe { source(user_data) };
+
+    source.seek(SeekFrom::Start(offset as u64)).is_ok() as OPJ_BOOL
+}
+
+unsafe extern "C" fn free_source(user_data: *mut c_void) {
+    drop(unsafe { Box::from_raw(user_data as *mut SharedSource) });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn open() -> OpenJpegImage {
+        let file = std::fs::File::open("test-data/iiif-validator-reference.jp2").unwrap();
+        OpenJpegImage::open(SharedSource::new(Box::new(std::io::BufReader::new(file)))).unwrap()
+    }
+
+    fn decode(image: &mut OpenJpegImage, region: AbsoluteRegion, scaled_to: Dimensions) -> Vec<u8> {
+        let mut decoder = image.open_region(region, scaled_to).unwrap();
+        let mut output = BytesMut::new();
+        while !decoder.decode_to(&mut output).unwrap() {}
+
+        output.to_vec()
+    }
+
+    #[test]
+    fn reports_resolution_levels() {
+        let info = open().info().unwrap();
+        assert_eq!((info.width, info.height), (1000, 1000));
+
+        let sizes: Vec<_> = info.sizes.unwrap().iter().map(|s| s.width).collect();
+        assert_eq!(sizes, [63, 125, 250, 500]);
+        assert_eq!(info.tiles.unwrap()[0].scale_factors, [1, 2, 4, 8, 16]);
+    }
+
+    #[test]
+    fn decodes_reduced_regions() {
+        let mut image = open();
+
+        let full = AbsoluteRegion { x: 0, y: 0, width: 1000, height: 1000 };
+        assert_eq!(decode(&mut image, full, (100, 100)).len(), 100 * 100 * 3);
+
+        let quarter = AbsoluteRegion { x: 500, y: 500, width: 500, height: 500 };
+        assert_eq!(decode(&mut image, quarter, (250, 250)).len(), 250 * 250 * 3);
+    }
+}
@@ -0,0 +1,306 @@
+use std::future::Future;
+use std::io::{BufReader, Seek};
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use bytes::BytesMut;
+use png::{BitDepth, ColorType, Decoder, Reader, Transformations};
+use tracing::debug;
+
+use super::scale::RowScaler;
+use super::{CodecError, ImageReader, SharedSource, open_blocking, run_blocking, synthetic_info};
+use crate::iiif::Dimension;
+use crate::image::info::ImageInfo;
+use crate::image::pixel::{self, PixelFormat};
+use crate::image::{AbsoluteRegion, BoxedImage, Dimensions, Image, ImageDecoder};
+use crate::storage::FileOrStream;
+
+/// The number of source rows decoded by each call to [ImageDecoder::decode_to].
+const ROWS_PER_BATCH: usize = 16;
+
+/// Reads PNG images, including interlaced and paletted images.
+#[derive(Default)]
+pub struct PngImageReader;
+
+impl ImageReader for PngImageReader {
+    fn read<'a>(
+        &'a self,
+        _name: Option<String>,
+        location: FileOrStream,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedImage, CodecError>> + Send + 'a>> {
+        Box::pin(run_blocking(move || {
+            let source = open_blocking(location)?;
+
+            Ok(PngImage::open(SharedSource::new(source))?.boxed())
+        }))
+    }
+}
+
+/// A PNG image, decoded afresh for each region that is opened.
+pub struct PngImage {
+    source: SharedSource,
+    width: Dimension,
+    height: Dimension,
+}
+
+impl PngImage {
+    fn open(source: SharedSource) -> Result<Self, png::DecodingError> {
+        let mut decoder = Decoder::new(BufReader::new(source.clone()));
+        let header = decoder.read_header_info()?;
+        let (width, height) = (header.width, header.height);
+
+        Ok(Self { source, width, height })
+    }
+
+    fn reader(&self) -> Result<Reader<BufReader<SharedSource>>, png::DecodingError> {
+        let mut source = self.source.clone();
+        source.rewind()?;
+
+        let mut decoder = Decoder::new(BufReader::new(source));
+        decoder.set_transformations(Transformations::EXPAND);
+        decoder.read_info()
+    }
+}
+
+impl Image for PngImage {
+    fn info(&mut self) -> Result<ImageInfo, CodecError> {
+        Ok(synthetic_info(self.width, self.height))
+    }
+
+    fn open_region(
+        &mut self,
+        region: AbsoluteRegion,
+        scaled_to: Dimensions,
+    ) -> Result<Box<dyn ImageDecoder>, CodecError> {
+        let reader = self.reader()?;
+        let format = pixel_format(reader.output_color_type());
+        let columns = region.x..region.x + region.width;
+        let rows = region.y..region.y + region.height;
+
+        debug!(%format, ?columns, ?rows, "decoding region of PNG image");
+
+        Ok(Box::new(PngRegionDecoder {
+            frame: None,
+            row: Vec::with_capacity(region.width as usize * format.bytes_per_pixel()),
+            scaler: RowScaler::new((region.width, region.height), scaled_to, format),
+            format,
+            icc_profile: reader.info().icc_profile.as_deref().map(Arc::from),
+            reader: Some(reader),
+            columns,
+            rows,
+            next_row: 0,
+            output_size: scaled_to,
+        }))
+    }
+}
+
+/// Decodes a region of a PNG image a batch of rows at a time.
+struct PngRegionDecoder {
+    reader: Option<Reader<BufReader<SharedSource>>>,
+    /// The whole decoded image, for interlaced images that can't be decoded a row at a time.
+    frame: Option<Vec<u8>>,
+    row: Vec<u8>,
+    columns: Range<Dimension>,
+    rows: Range<Dimension>,
+    next_row: Dimension,
+    format: PixelFormat,
+    icc_profile: Option<Arc<[u8]>>,
+    scaler: RowScaler,
+    output_size: Dimensions,
+}
+
+impl ImageDecoder for PngRegionDecoder {
+    fn output_size(&self) -> Dimensions {
+        self.output_size
+    }
+
+    fn pixel_format(&self) -> PixelFormat {
+        self.format
+    }
+
+    fn icc_profile(&self) -> Option<Arc<[u8]>> {
+        self.icc_profile.clone()
+    }
+
+    fn working_memory(&self) -> u64 {
+        // Interlaced images are decoded whole before any rows are produced.
+        match &self.reader {
+            Some(reader) if reader.info().interlaced => reader.output_buffer_size() as u64,
+            _ => 0,
+        }
+    }
+
+    fn rows_per_decode(&self) -> Option<Dimension> {
+        Some(self.scaler.max_output_rows(ROWS_PER_BATCH as Dimension))
+    }
+
+    fn decode_to(&mut self, buffer: &mut BytesMut) -> Result<bool, CodecError> {
+        let Some(reader) = self.reader.as_mut() else {
+            return Ok(true);
+        };
+
+        if reader.info().interlaced && self.frame.is_none() {
+            let mut frame = vec![0; reader.output_buffer_size()];
+            reader.next_frame(&mut frame)?;
+            self.frame = Some(frame);
+        }
+
+        let pixel_size = self.format.bytes_per_pixel();
+        let row_size = reader.output_line_size(reader.info().width);
+        let columns =
+            self.columns.start as usize * pixel_size..self.columns.end as usize * pixel_size;
+
+        let mut decoded = 0;
+        while decoded < ROWS_PER_BATCH && self.next_row < self.rows.end {
+            let y = self.next_row;
+            self.next_row += 1;
+
+            let data = match &self.frame {
+                Some(frame) => &frame[y as usize * row_size..(y as usize + 1) * row_size],
+                None => reader
+                    .next_row()?
+                    .ok_or_else(|| CodecError::Malformed("PNG image ended early".into()))?
+                    .data(),
+            };
+
+            // Rows can only be decoded from the top, so rows above the region are skipped.
+            if y < self.rows.start {
+                continue;
+            }
+
+            self.row.clear();
+            match self.format.depth {
+                pixel::BitDepth::Eight => self.row.extend_from_slice(&data[columns.clone()]),
+                // PNG stores 16-bit samples in big-endian order.
+                pixel::BitDepth::Sixteen => {
+                    self.row
+                        .extend(data[columns.clone()].chunks_exact(2).flat_map(|sample| {
+                            u16::from_be_bytes([sample[0], sample[1]]).to_ne_bytes()
+                        }))
+                }
+            }
+
+            self.scaler.push(&self.row, buffer);
+            decoded += 1;
+        }
+
+        if self.next_row >= self.rows.end {
+            self.reader = None;
+        }
+
+        Ok(false)
+    }
+}
+
+/// The layout of the pixels produced by a reader, once paletted and low bit depth images have
+/// been expanded.
+fn pixel_format((color, depth): (ColorType, BitDepth)) -> PixelFormat {
+    let (space, alpha) = match color {
+        ColorType::Grayscale => (pixel::ColorSpace::Gray, false),
+        ColorType::GrayscaleAlpha => (pixel::ColorSpace::Gray, true),
+        ColorType::Rgba => (pixel::ColorSpace::Rgb, true),
+        ColorType::Rgb | ColorType::Indexed => (pixel::ColorSpace::Rgb, false),
+    };
+    let depth = match depth {
+        BitDepth::Sixteen => pixel::BitDepth::Sixteen,
+        _ => pixel::BitDepth::Eight,
+    };
+
+    PixelFormat::new(space, alpha, depth)
+}
+
+impl From<png::DecodingError> for CodecError {
+    fn from(value: png::DecodingError) -> Self {
+        match value {
+            png::DecodingError::IoError(e) => e.into(),
+            png::DecodingError::Parameter(e) => CodecError::Internal(e.to_string()),
+            e => CodecError::Malformed(e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn encode(width: u32, height: u32, color: ColorType, pixels: &[u8]) -> Vec<u8> {
+        let mut data = vec![];
+        let mut encoder = png::Encoder::new(&mut data, width, height);
+        encoder.set_color(color);
+
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(pixels).unwrap();
+        writer.finish().unwrap();
+
+        data
+    }
+
+    fn open(data: Vec<u8>) -> PngImage {
+        PngImage::open(SharedSource::new(Box::new(Cursor::new(data)))).unwrap()
+    }
+
+    fn decode(image: &mut PngImage, region: AbsoluteRegion, scaled_to: Dimensions) -> Vec<u8> {
+        let mut decoder = image.open_region(region, scaled_to).unwrap();
+        let mut output = BytesMut::new();
+        while !decoder.decode_to(&mut output).unwrap() {}
+
+        output.to_vec()
+    }
+
+    #[test]
+    fn decodes_regions() {
+        let pixels: Vec<u8> = (0..40 * 40)
+            .flat_map(|i| [(i % 40) as u8, (i / 40) as u8, 0, 255])
+            .collect();
+        let mut image = open(encode(40, 40, ColorType::Rgba, &pixels));
+
+        let region = AbsoluteRegion { x: 10, y: 12, width: 5, height: 10 };
+        let decoded = decode(&mut image, region, (5, 10));
+
+        assert_eq!(decoded.len(), 5 * 10 * 4);
+        assert_eq!(&decoded[..4], [10, 12, 0, 255]);
+        assert_eq!(&decoded[decoded.len() - 4..], [14, 21, 0, 255]);
+
+        // The same image can be decoded again.
+        let full = AbsoluteRegion { x: 0, y: 0, width: 40, height: 40 };
+        assert_eq!(decode(&mut image, full, (20, 20)).len(), 20 * 20 * 4);
+    }
+
+    #[test]
+    fn decodes_grayscale_images() {
+        let mut image = open(encode(4, 4, ColorType::Grayscale, &[128; 16]));
+        let full = AbsoluteRegion { x: 0, y: 0, width: 4, height: 4 };
+
+        let decoder = image.open_region(full, (2, 2)).unwrap();
+        assert_eq!(decoder.pixel_format(), PixelFormat::GRAY8);
+        assert_eq!(decode(&mut image, full, (2, 2)), [128; 4]);
+        assert_eq!(image.info().unwrap().tiles.unwrap()[0].scale_factors, [1]);
+    }
+    #[test]
+    fn keeps_16_bit_samples() {
+        let pixels: Vec<u8> = [0x1234u16, 0xABCD]
+            .iter()
+            .flat_map(|s| s.to_be_bytes())
+            .collect();
+        let mut data = vec![];
+        let mut encoder = png::Encoder::new(&mut data, 2, 1);
+        encoder.set_color(ColorType::Grayscale);
+        encoder.set_depth(BitDepth::Sixteen);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&pixels).unwrap();
+        writer.finish().unwrap();
+
+        let mut image = open(data);
+        let full = AbsoluteRegion { x: 0, y: 0, width: 2, height: 1 };
+        let decoded = decode(&mut image, full, (2, 1));
+
+        let samples: Vec<u16> = decoded
+            .chunks_exact(2)
+            .map(|s| u16::from_ne_bytes([s[0], s[1]]))
+            .collect();
+        assert_eq!(samples, [0x1234, 0xABCD]);
+    }
+}
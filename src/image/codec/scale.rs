@@ -0,0 +1,221 @@
+use std::ops::Range;
+
+use bytes::{BufMut, BytesMut};
+
+use crate::iiif::Dimension;
+use crate::image::pixel::{BitDepth, PixelFormat};
+use crate::image::{AbsoluteRegion, Dimensions};
+
+/// Scales scanlines to a fixed output size as they are decoded, averaging the source pixels
+/// covered by each output pixel.
+///
+/// Source rows are pushed in order and output rows are written as soon as every source row that
+/// contributes to them has been seen, so only a single row of intermediate sums is held in memory.
+pub(super) struct RowScaler {
+    columns: Vec<Range<usize>>,
+    format: PixelFormat,
+    input_height: Dimension,
+    output_height: Dimension,
+    input_row: Dimension,
+    output_row: Dimension,
+    sums: Vec<u64>,
+    rows: u64,
+}
+
+impl RowScaler {
+    pub(super) fn new(
+        input: (Dimension, Dimension),
+        output: (Dimension, Dimension),
+        format: PixelFormat,
+    ) -> Self {
+        let (input_width, input_height) = input;
+        let (output_width, output_height) = output;
+
+        Self {
+            columns: (0..output_width)
+                .map(|x| {
+                    let span = span(x, input_width, output_width);
+                    span.start as usize..span.end as usize
+                })
+                .collect(),
+            format,
+            input_height,
+            output_height,
+            input_row: 0,
+            output_row: 0,
+            sums: vec![0; output_width as usize * format.channels()],
+            rows: 0,
+        }
+    }
+
+    /// The most output rows that pushing `rows` consecutive source rows can write.
+    pub(super) fn max_output_rows(&self, rows: Dimension) -> Dimension {
+        let scaled =
+            (rows as u64 * self.output_height as u64).div_ceil(self.input_height.max(1) as u64);
+
+        // When enlarging, the rows written can straddle the ends of the batch.
+        let straddling = (self.output_height > self.input_height) as u64;
+        (scaled + straddling).min(self.output_height as u64) as Dimension
+    }
+
+    /// Returns `true` once every output row has been written.
+    pub(super) fn is_complete(&self) -> bool {
+        self.output_row >= self.output_height
+    }
+
+    /// Push the next source row, writing any output rows it completes to `output`.
+    pub(super) fn push(&mut self, row: &[u8], output: &mut BytesMut) {
+        let y = self.input_row;
+        self.input_row += 1;
+
+        if self.is_complete()
+            || y < span(self.output_row, self.input_height, self.output_height).start
+        {
+            return;
+        }
+
+        let channels = self.format.channels();
+        let pixel_size = self.format.bytes_per_pixel();
+        let sample_size = self.format.bytes_per_sample();
+
+        for (sum, column) in self.sums.chunks_exact_mut(channels).zip(&self.columns) {
+            for pixel in
+                row[column.start * pixel_size..column.end * pixel_size].chunks_exact(pixel_size)
+            {
+                for (sum, sample) in sum.iter_mut().zip(pixel.chunks_exact(sample_size)) {
+                    *sum += match self.format.depth {
+                        BitDepth::Eight => sample[0] as u64,
+                        BitDepth::Sixteen => u16::from_ne_bytes([sample[0], sample[1]]) as u64,
+                    };
+                }
+            }
+        }
+        self.rows += 1;
+
+        // When enlarging, several output rows can be produced from the same source row.
+        while !self.is_complete()
+            && span(self.output_row, self.input_height, self.output_height).end <= y + 1
+        {
+            for (sum, column) in self.sums.chunks_exact(channels).zip(&self.columns) {
+                let count = self.rows * column.len() as u64;
+                for channel in sum {
+                    let average = (channel + count / 2) / count;
+
+                    match self.format.depth {
+                        BitDepth::Eight => output.put_u8(average as u8),
+                        BitDepth::Sixteen => output.put_u16_ne(average as u16),
+                    }
+                }
+            }
+
+            self.output_row += 1;
+            if self.is_complete()
+                || span(self.output_row, self.input_height, self.output_height).start > y
+            {
+                self.sums.fill(0);
+                self.rows = 0;
+            }
+        }
+    }
+}
+
+/// Map a region of the full image onto a reduced copy of it, rounding outwards to cover every
+/// pixel the region touches.
+pub(super) fn reduce_region(
+    region: &AbsoluteRegion,
+    full: Dimensions,
+    reduced: Dimensions,
+) -> (Range<Dimension>, Range<Dimension>) {
+    let reduce = |start: Dimension, length: Dimension, full: Dimension, reduced: Dimension| {
+        let end = (start + length) as u64 * reduced as u64;
+        let start = (start as u64 * reduced as u64 / full as u64) as Dimension;
+
+        start..(end.div_ceil(full as u64) as Dimension).min(reduced)
+    };
+
+    (
+        reduce(region.x, region.width, full.0, reduced.0),
+        reduce(region.y, region.height, full.1, reduced.1),
+    )
+}
+
+/// The range of source pixels that contribute to the output pixel at `index`.
+fn span(index: Dimension, input: Dimension, output: Dimension) -> Range<Dimension> {
+    let start = (index as u64 * input as u64 / output as u64) as Dimension;
+    let end = ((index as u64 + 1) * input as u64 / output as u64) as Dimension;
+
+    start..end.max(start + 1).min(input)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::image::pixel::ColorSpace;
+
+    fn scale(input: &[u8], from: (Dimension, Dimension), to: (Dimension, Dimension)) -> Vec<u8> {
+        let mut scaler = RowScaler::new(from, to, PixelFormat::RGB8);
+        let mut output = BytesMut::new();
+
+        for row in input.chunks_exact(from.0 as usize * 3) {
+            scaler.push(row, &mut output);
+        }
+
+        assert!(scaler.is_complete());
+        output.to_vec()
+    }
+
+    #[test]
+    fn averages_pixels_when_reducing() {
+        let input = [0, 0, 0, 100, 100, 100, 50, 50, 50, 250, 250, 250];
+
+        assert_eq!(scale(&input, (2, 2), (1, 1)), [100, 100, 100]);
+        assert_eq!(scale(&input, (2, 2), (2, 1)), [25, 25, 25, 175, 175, 175]);
+    }
+
+    #[test]
+    fn repeats_pixels_when_enlarging() {
+        let input = [10, 20, 30, 40, 50, 60];
+
+        assert_eq!(
+            scale(&input, (2, 1), (4, 2)),
+            [
+                10, 20, 30, 10, 20, 30, 40, 50, 60, 40, 50, 60, 10, 20, 30, 10, 20, 30, 40, 50, 60,
+                40, 50, 60
+            ]
+        );
+    }
+
+    #[test]
+    fn averages_16_bit_samples() {
+        let format = PixelFormat::new(ColorSpace::Gray, true, BitDepth::Sixteen);
+        let input: Vec<u8> = [1000u16, 65535, 3000, 65535, 5000, 0, 7000, 0]
+            .iter()
+            .flat_map(|sample| sample.to_ne_bytes())
+            .collect();
+
+        let mut scaler = RowScaler::new((2, 2), (1, 1), format);
+        let mut output = BytesMut::new();
+        for row in input.chunks_exact(8) {
+            scaler.push(row, &mut output);
+        }
+
+        assert_eq!(output.as_ref(), [4000u16, 32768].map(u16::to_ne_bytes).concat());
+    }
+
+    #[test]
+    fn bounds_the_rows_written_by_each_batch() {
+        for (input, output) in [(16, 16), (100, 7), (7, 100), (33, 32), (32, 33), (1, 5)] {
+            for batch in [1, 3, 16] {
+                let mut scaler = RowScaler::new((1, input), (1, output), PixelFormat::GRAY8);
+                let limit = scaler.max_output_rows(batch);
+                let mut written = BytesMut::new();
+
+                for rows in (0..input).collect::<Vec<_>>().chunks(batch as usize) {
+                    written.clear();
+                    rows.iter().for_each(|_| scaler.push(&[0], &mut written));
+                    assert!(written.len() <= limit as usize, "{input} -> {output} by {batch}");
+                }
+            }
+        }
+    }
+}
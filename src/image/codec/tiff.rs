@@ -0,0 +1,667 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+use tiff::decoder::{ChunkType, Decoder, DecodingResult, Limits};
+use tiff::tags::{PlanarConfiguration, Tag};
+use tiff::{ColorType, TiffError, TiffResult, TiffUnsupportedError};
+use tracing::{debug, warn};
+
+use super::scale::{RowScaler, reduce_region};
+use super::{
+    BlockingSource, CodecError, ImageReader, SYNTHETIC_TILE_SIZE, open_blocking, run_blocking,
+};
+use crate::iiif::Dimension;
+use crate::image::info::{ImageInfo, PreferredSize, Tile};
+use crate::image::pixel::{BitDepth, ColorSpace, PixelFormat};
+use crate::image::{AbsoluteRegion, BoxedImage, Dimensions, Image, ImageDecoder};
+use crate::storage::FileOrStream;
+
+/// The bit in the `NewSubfileType` tag marking an image as a transparency mask.
+const SUBFILE_MASK: u32 = 4;
+
+/// Reads tiled or stripped TIFF and BigTIFF images, including pyramidal TIFFs that store
+/// reduced-resolution copies of the image as additional directories.
+#[derive(Default)]
+pub struct TiffImageReader;
+
+impl ImageReader for TiffImageReader {
+    fn read<'a>(
+        &'a self,
+        _name: Option<String>,
+        location: FileOrStream,
+    ) -> Pin<Box<dyn Future<Output = Result<BoxedImage, CodecError>> + Send + 'a>> {
+        Box::pin(run_blocking(move || {
+            let source = open_blocking(location)?;
+
+            Ok(TiffImage::open(source)?.boxed())
+        }))
+    }
+}
+
+/// A resolution level of a TIFF image, stored in its own image file directory.
+#[derive(Clone, Copy, Debug)]
+struct Level {
+    directory: usize,
+    width: Dimension,
+    height: Dimension,
+    chunk_width: Dimension,
+    chunk_height: Dimension,
+    tiled: bool,
+    color: ColorType,
+    format: PixelFormat,
+}
+
+impl Level {
+    fn read(decoder: &mut Decoder<Box<dyn BlockingSource>>, directory: usize) -> TiffResult<Self> {
+        let (width, height) = decoder.dimensions()?;
+        let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+        let color = decoder.colortype()?;
+
+        let planar = decoder.find_tag_unsigned::<u16>(Tag::PlanarConfiguration)?;
+        if planar == Some(PlanarConfiguration::Planar.to_u16()) {
+            return Err(TiffError::UnsupportedError(
+                TiffUnsupportedError::UnsupportedPlanarConfig(Some(PlanarConfiguration::Planar)),
+            ));
+        }
+
+        let Some(format) = pixel_format(color) else {
+            return Err(TiffError::UnsupportedError(TiffUnsupportedError::UnsupportedColorType(
+                color,
+            )));
+        };
+
+        Ok(Self {
+            directory,
+            width,
+            height,
+            chunk_width,
+            chunk_height,
+            tiled: decoder.get_chunk_type() == ChunkType::Tile,
+            color,
+            format,
+        })
+    }
+
+    /// The number of chunks in each row of chunks.
+    fn chunks_across(&self) -> Dimension {
+        self.width.div_ceil(self.chunk_width)
+    }
+
+    /// Returns `true` if this level is a reduced-resolution copy of `full`, rather than an
+    /// unrelated image such as a label or macro photograph.
+    fn is_reduction_of(&self, full: &Level) -> bool {
+        if self.width >= full.width || self.height >= full.height {
+            return false;
+        }
+
+        // Empty directories aren't reductions of anything, and would divide by zero below.
+        if self.width == 0 || self.height == 0 {
+            return false;
+        }
+
+        // Allow for the reduced dimensions having been rounded in either direction.
+        let expected_height = full.height as f64 * self.width as f64 / full.width as f64;
+        (expected_height - self.height as f64).abs() <= (full.width / self.width) as f64 + 1.0
+    }
+}
+
+/// A TIFF image, made up of the full-resolution image and any reduced-resolution levels.
+pub struct TiffImage {
+    decoder: Arc<Mutex<Decoder<Box<dyn BlockingSource>>>>,
+    levels: Vec<Level>,
+    icc_profile: Option<Arc<[u8]>>,
+}
+
+impl TiffImage {
+    fn open(source: Box<dyn BlockingSource>) -> TiffResult<Self> {
+        // Only a single chunk is decoded at a time, so the default limits are sufficient.
+        let mut decoder = Decoder::new(source)?.with_limits(Limits::default());
+        let mut levels = vec![Level::read(&mut decoder, 0)?];
+        let mut directory = 0;
+
+        // Reduced-resolution levels share the colours of the full-resolution image.
+        let icc_profile = match decoder.find_tag(Tag::IccProfile)? {
+            Some(value) => Some(Arc::from(value.into_u8_vec()?)),
+            None => None,
+        };
+
+        while decoder.more_images() {
+            decoder.next_image()?;
+            directory += 1;
+
+            let subfile_type = decoder.find_tag_unsigned::<u32>(Tag::NewSubfileType)?;
+            if subfile_type.is_some_and(|kind| kind & SUBFILE_MASK != 0) {
+                continue;
+            }
+
+            let level = match Level::read(&mut decoder, directory) {
+                Ok(level) => level,
+                Err(e) => {
+                    warn!(directory, "skipping unreadable TIFF directory: {e}");
+                    continue;
+                }
+            };
+
+            let previous = levels
+                .last()
+                .expect("full resolution level is always present");
+            if level.width < previous.width && level.is_reduction_of(&levels[0]) {
+                levels.push(level);
+            }
+        }
+
+        debug!(?levels, "read TIFF resolution levels");
+
+        Ok(Self { decoder: Arc::new(Mutex::new(decoder)), levels, icc_profile })
+    }
+
+    /// Find the smallest level that can be scaled to `scaled_to` without enlarging it.
+    fn level_for(&self, region: &AbsoluteRegion, scaled_to: Dimensions) -> &Level {
+        let full = &self.levels[0];
+        let (scaled_width, scaled_height) = scaled_to;
+
+        self.levels
+            .iter()
+            .rev()
+            .find(|level| {
+                region.width as u64 * level.width as u64 >= scaled_width as u64 * full.width as u64
+                    && region.height as u64 * level.height as u64
+                        >= scaled_height as u64 * full.height as u64
+            })
+            .unwrap_or(full)
+    }
+}
+
+impl Image for TiffImage {
+    fn info(&mut self) -> Result<ImageInfo, CodecError> {
+        let full = self.levels[0];
+
+        let sizes = self
+            .levels
+            .iter()
+            .skip(1)
+            .rev()
+            .map(|level| PreferredSize { width: level.width, height: level.height })
+            .collect();
+
+        // Striped images can be read in any region, but viewers still need to be told of a tile
+        // size to request.
+        let (tile_width, tile_height) = if full.tiled {
+            (full.chunk_width, full.chunk_height)
+        } else {
+            (full.width.min(SYNTHETIC_TILE_SIZE), full.height.min(SYNTHETIC_TILE_SIZE))
+        };
+
+        let mut scale_factors: Vec<u16> = self
+            .levels
+            .iter()
+            .map(|level| (full.width as f64 / level.width as f64).round() as u16)
+            .collect();
+        scale_factors.dedup();
+
+        Ok(ImageInfo {
+            width: full.width,
+            height: full.height,
+            max_width: Some(full.width),
+            max_height: Some(full.height),
+            max_area: None,
+            sizes: Some(sizes),
+            tiles: Some(vec![Tile { width: tile_width, height: Some(tile_height), scale_factors }]),
+            preferred_formats: None,
+            rights: None,
+        })
+    }
+
+    fn open_region(
+        &mut self,
+        region: AbsoluteRegion,
+        scaled_to: Dimensions,
+    ) -> Result<Box<dyn ImageDecoder>, CodecError> {
+        let full = self.levels[0];
+        let level = *self.level_for(&region, scaled_to);
+
+        let (columns, rows) =
+            reduce_region(&region, (full.width, full.height), (level.width, level.height));
+
+        debug!(?level, ?columns, ?rows, "decoding region of TIFF level");
+
+        Ok(Box::new(TiffRegionDecoder {
+            decoder: self.decoder.clone(),
+            level,
+            icc_profile: self.icc_profile.clone(),
+            scaler: RowScaler::new(
+                (columns.len() as Dimension, rows.len() as Dimension),
+                scaled_to,
+                level.format,
+            ),
+            columns,
+            next_row: rows.start,
+            bottom: rows.end,
+            output_size: scaled_to,
+            finished: false,
+        }))
+    }
+}
+
+/// Decodes a region of a TIFF level one row of chunks at a time, reading only the chunks that
+/// intersect the region.
+struct TiffRegionDecoder {
+    decoder: Arc<Mutex<Decoder<Box<dyn BlockingSource>>>>,
+    level: Level,
+    icc_profile: Option<Arc<[u8]>>,
+    columns: std::ops::Range<Dimension>,
+    next_row: Dimension,
+    bottom: Dimension,
+    scaler: RowScaler,
+    output_size: Dimensions,
+    finished: bool,
+}
+
+impl TiffRegionDecoder {
+    fn decode_chunk_row(&mut self, buffer: &mut BytesMut) -> TiffResult<()> {
+        let level = self.level;
+        let mut decoder = self
+            .decoder
+            .lock()
+            .map_err(|_| TiffError::IoError(std::io::Error::other("TIFF decoder was poisoned")))?;
+        decoder.seek_to_image(level.directory)?;
+
+        let chunk_row = self.next_row / level.chunk_height;
+        let chunk_top = chunk_row * level.chunk_height;
+        let rows = self.next_row..self.bottom.min(chunk_top + level.chunk_height);
+
+        let first_chunk = self.columns.start / level.chunk_width;
+        let last_chunk = (self.columns.end - 1) / level.chunk_width;
+
+        let mut chunks = Vec::with_capacity((last_chunk - first_chunk + 1) as usize);
+        for chunk_column in first_chunk..=last_chunk {
+            let index = chunk_row * level.chunks_across() + chunk_column;
+            let (chunk_width, _) = decoder.chunk_data_dimensions(index);
+            let pixels = to_native(level.color, decoder.read_chunk(index)?)?;
+
+            chunks.push((chunk_column * level.chunk_width, chunk_width, pixels));
+        }
+
+        drop(decoder);
+
+        let pixel_size = level.format.bytes_per_pixel();
+        let mut row = Vec::with_capacity(self.columns.len() * pixel_size);
+        for y in rows.clone() {
+            row.clear();
+
+            for (chunk_left, chunk_width, pixels) in &chunks {
+                let start = self.columns.start.max(*chunk_left) - chunk_left;
+                let end = self.columns.end.min(chunk_left + chunk_width) - chunk_left;
+                let offset = ((y - chunk_top) * chunk_width) as usize;
+
+                row.extend_from_slice(
+                    &pixels[(offset + start as usize) * pixel_size
+                        ..(offset + end as usize) * pixel_size],
+                );
+            }
+
+            self.scaler.push(&row, buffer);
+        }
+
+        self.next_row = rows.end;
+        Ok(())
+    }
+}
+
+impl ImageDecoder for TiffRegionDecoder {
+    fn output_size(&self) -> Dimensions {
+        self.output_size
+    }
+
+    fn pixel_format(&self) -> PixelFormat {
+        self.level.format
+    }
+
+    fn icc_profile(&self) -> Option<Arc<[u8]>> {
+        self.icc_profile.clone()
+    }
+
+    fn working_memory(&self) -> u64 {
+        let level = self.level;
+        let first_chunk = self.columns.start / level.chunk_width;
+        let last_chunk = (self.columns.end - 1) / level.chunk_width;
+        let chunk_size = level.chunk_width as u64
+            * level.chunk_height as u64
+            * level.format.bytes_per_pixel() as u64;
+
+        // Each chunk of a row of chunks is read whole, then converted to native pixels.
+        (last_chunk - first_chunk + 1) as u64 * chunk_size * 2
+    }
+
+    fn rows_per_decode(&self) -> Option<Dimension> {
+        // A whole row of chunks is decoded with each call, which can be the whole image.
+        Some(self.scaler.max_output_rows(self.level.chunk_height))
+    }
+
+    fn decode_to(&mut self, buffer: &mut BytesMut) -> Result<bool, CodecError> {
+        if self.finished {
+            return Ok(true);
+        }
+
+        self.decode_chunk_row(buffer)?;
+
+        self.finished = self.next_row >= self.bottom || self.scaler.is_complete();
+        Ok(false)
+    }
+}
+
+impl From<TiffError> for CodecError {
+    fn from(value: TiffError) -> Self {
+        match value {
+            TiffError::IoError(e) => e.into(),
+            TiffError::UnsupportedError(e) => CodecError::UnsupportedFormat(e.to_string()),
+            TiffError::UsageError(e) => CodecError::Internal(e.to_string()),
+            e => CodecError::Malformed(e.to_string()),
+        }
+    }
+}
+
+/// The layout of the pixels produced for a TIFF colour type, or `None` if it can't be decoded.
+///
+/// YCbCr and CMYK images are converted to RGB, while other colour types are kept as they are.
+fn pixel_format(color: ColorType) -> Option<PixelFormat> {
+    let depth = |bits| match bits {
+        8 => Some(BitDepth::Eight),
+        16 => Some(BitDepth::Sixteen),
+        _ => None,
+    };
+
+    match color {
+        ColorType::Gray(bits) => Some(PixelFormat::new(ColorSpace::Gray, false, depth(bits)?)),
+        ColorType::GrayA(bits) => Some(PixelFormat::new(ColorSpace::Gray, true, depth(bits)?)),
+        ColorType::RGB(bits) => Some(PixelFormat::new(ColorSpace::Rgb, false, depth(bits)?)),
+        ColorType::RGBA(bits) => Some(PixelFormat::new(ColorSpace::Rgb, true, depth(bits)?)),
+        ColorType::YCbCr(8) | ColorType::CMYK(8) => Some(PixelFormat::RGB8),
+        _ => None,
+    }
+}
+
+/// Convert decoded chunk samples to packed pixels in the [pixel_format] of `color`.
+fn to_native(color: ColorType, data: DecodingResult) -> TiffResult<Vec<u8>> {
+    let unsupported =
+        || TiffError::UnsupportedError(TiffUnsupportedError::UnsupportedColorType(color));
+
+    let samples = match data {
+        DecodingResult::U8(samples) => samples,
+        DecodingResult::U16(samples) => {
+            return Ok(samples.into_iter().flat_map(u16::to_ne_bytes).collect());
+        }
+        _ => return Err(unsupported()),
+    };
+
+    let pixels = match color {
+        ColorType::YCbCr(_) => samples
+            .chunks_exact(3)
+            .flat_map(|p| ycbcr_to_rgb(p[0], p[1], p[2]))
+            .collect(),
+        ColorType::CMYK(_) => samples
+            .chunks_exact(4)
+            .flat_map(|p| {
+                let k = 255 - p[3] as u32;
+                [p[0], p[1], p[2]].map(|ink| ((255 - ink as u32) * k / 255) as u8)
+            })
+            .collect(),
+        _ if pixel_format(color).is_some() => samples,
+        _ => return Err(unsupported()),
+    };
+
+    Ok(pixels)
+}
+
+/// Convert a JPEG (full-range BT.601) YCbCr pixel to RGB.
+fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8) -> [u8; 3] {
+    let y = y as f32;
+    let cb = cb as f32 - 128.0;
+    let cr = cr as f32 - 128.0;
+
+    [
+        y + 1.402 * cr,
+        y - 0.344_136 * cb - 0.714_136 * cr,
+        y + 1.772 * cb,
+    ]
+    .map(|channel| channel.round().clamp(0.0, 255.0) as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use tiff::encoder::colortype::RGB8;
+    use tiff::encoder::{Compression, TiffEncoder};
+
+    use super::*;
+
+    /// Encode a pyramid of solid colour levels, halving the size of each level.
+    fn pyramid(width: u32, height: u32, colors: &[[u8; 3]], compression: Compression) -> Vec<u8> {
+        let mut data = Cursor::new(vec![]);
+        let mut encoder = TiffEncoder::new(&mut data)
+            .unwrap()
+            .with_compression(compression);
+
+        for (level, color) in colors.iter().enumerate() {
+            let (width, height) = (width >> level, height >> level);
+            let pixels: Vec<u8> = color.repeat((width * height) as usize);
+
+            let mut image = encoder.new_image::<RGB8>(width, height).unwrap();
+            image.rows_per_strip(16).unwrap();
+            image.write_data(&pixels).unwrap();
+        }
+
+        data.into_inner()
+    }
+
+    /// Encode a tiled TIFF of 2x2 JPEG-compressed tiles of solid colours, which the tiff encoder
+    /// can't write.
+    fn jpeg_tiled(tile: u32, colors: [[u8; 3]; 4]) -> Vec<u8> {
+        const SHORT: u16 = 3;
+        const LONG: u16 = 4;
+
+        let tiles: Vec<Vec<u8>> = colors
+            .iter()
+            .map(|color| {
+                let mut compress = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+                compress.set_size(tile as usize, tile as usize);
+                compress.set_quality(95.0);
+                compress.set_chroma_sampling_pixel_sizes((1, 1), (1, 1));
+
+                let mut started = compress.start_compress(vec![]).unwrap();
+                started
+                    .write_scanlines(&color.repeat((tile * tile) as usize))
+                    .unwrap();
+                started.finish().unwrap()
+            })
+            .collect();
+
+        let mut data = b"II\x2a\x00\0\0\0\0".to_vec();
+        let mut offsets = vec![];
+        for tile in &tiles {
+            offsets.push(data.len() as u32);
+            data.extend_from_slice(tile);
+        }
+
+        let arrays = data.len() as u32;
+        offsets
+            .iter()
+            .for_each(|offset| data.extend(offset.to_le_bytes()));
+        tiles
+            .iter()
+            .for_each(|tile| data.extend((tile.len() as u32).to_le_bytes()));
+
+        let bits_per_sample = data.len() as u32;
+        data.extend([8u8, 0, 8, 0, 8, 0, 0]);
+
+        let ifd = data.len() as u32 & !1;
+        data.truncate(ifd as usize);
+        data[4..8].copy_from_slice(&ifd.to_le_bytes());
+
+        // Values of one or two shorts are stored in the entry itself.
+        let entries: [(u16, u16, u32, u32); 12] = [
+            (Tag::ImageWidth.to_u16(), LONG, 1, 2 * tile),
+            (Tag::ImageLength.to_u16(), LONG, 1, 2 * tile),
+            (Tag::BitsPerSample.to_u16(), SHORT, 3, bits_per_sample),
+            (Tag::Compression.to_u16(), SHORT, 1, 7),
+            (Tag::PhotometricInterpretation.to_u16(), SHORT, 1, 6),
+            (Tag::SamplesPerPixel.to_u16(), SHORT, 1, 3),
+            (Tag::PlanarConfiguration.to_u16(), SHORT, 1, 1),
+            (Tag::TileWidth.to_u16(), SHORT, 1, tile),
+            (Tag::TileLength.to_u16(), SHORT, 1, tile),
+            (Tag::TileOffsets.to_u16(), LONG, 4, arrays),
+            (Tag::TileByteCounts.to_u16(), LONG, 4, arrays + 16),
+            (Tag::Unknown(530).to_u16(), SHORT, 2, 1 | 1 << 16),
+        ];
+
+        data.extend((entries.len() as u16).to_le_bytes());
+        for (tag, kind, count, value) in entries {
+            data.extend(tag.to_le_bytes());
+            data.extend(kind.to_le_bytes());
+            data.extend(count.to_le_bytes());
+            data.extend(value.to_le_bytes());
+        }
+        data.extend(0u32.to_le_bytes());
+
+        data
+    }
+
+    fn open(data: Vec<u8>) -> TiffImage {
+        TiffImage::open(Box::new(Cursor::new(data))).unwrap()
+    }
+
+    fn decode(image: &mut TiffImage, region: AbsoluteRegion, scaled_to: Dimensions) -> Vec<u8> {
+        let mut decoder = image.open_region(region, scaled_to).unwrap();
+        let mut output = BytesMut::new();
+        while !decoder.decode_to(&mut output).unwrap() {}
+
+        output.to_vec()
+    }
+
+    #[test]
+    fn reports_pyramid_levels() {
+        let data = pyramid(200, 100, &[[255, 0, 0], [0, 255, 0], [0, 0, 255]], Compression::Lzw);
+        let info = open(data).info().unwrap();
+
+        assert_eq!((info.width, info.height), (200, 100));
+
+        let sizes: Vec<_> = info
+            .sizes
+            .unwrap()
+            .iter()
+            .map(|s| (s.width, s.height))
+            .collect();
+        assert_eq!(sizes, [(50, 25), (100, 50)]);
+        assert_eq!(info.tiles.unwrap()[0].scale_factors, [1, 2, 4]);
+    }
+
+    #[tokio::test]
+    async fn reads_images_from_streams() {
+        let data = pyramid(64, 64, &[[255, 0, 0], [0, 255, 0]], Compression::Uncompressed);
+        let stream = FileOrStream::Stream(Box::new(futures::io::Cursor::new(data)));
+        let mut image = TiffImageReader.read(None, stream).await.unwrap();
+
+        assert_eq!(image.info().unwrap().width, 64);
+    }
+
+    #[test]
+    fn decodes_from_the_closest_level() {
+        let data = pyramid(
+            200,
+            100,
+            &[[255, 0, 0], [0, 255, 0], [0, 0, 255]],
+            Compression::Deflate(Default::default()),
+        );
+        let mut image = open(data);
+        let full = AbsoluteRegion { x: 0, y: 0, width: 200, height: 100 };
+
+        assert_eq!(&decode(&mut image, full, (200, 100))[..3], [255, 0, 0]);
+        assert_eq!(&decode(&mut image, full, (80, 40))[..3], [0, 255, 0]);
+
+        let pixels = decode(&mut image, full, (50, 25));
+        assert_eq!(pixels.len(), 50 * 25 * 3);
+        assert!(pixels.chunks(3).all(|pixel| pixel == [0, 0, 255]));
+    }
+
+    #[test]
+    fn decodes_regions_across_chunks() {
+        let width = 40;
+        let pixels: Vec<u8> = (0..width * 40)
+            .flat_map(|i| [(i % width) as u8, (i / width) as u8, 0])
+            .collect();
+
+        let mut data = Cursor::new(vec![]);
+        let mut encoder = TiffEncoder::new(&mut data).unwrap();
+        let mut tiff = encoder.new_image::<RGB8>(width, 40).unwrap();
+        tiff.rows_per_strip(16).unwrap();
+        tiff.write_data(&pixels).unwrap();
+
+        let mut image = open(data.into_inner());
+        let region = AbsoluteRegion { x: 10, y: 12, width: 5, height: 10 };
+        let decoded = decode(&mut image, region, (5, 10));
+
+        assert_eq!(decoded.len(), 5 * 10 * 3);
+        assert_eq!(&decoded[..3], [10, 12, 0]);
+        assert_eq!(&decoded[decoded.len() - 3..], [14, 21, 0]);
+    }
+
+    #[test]
+    fn decodes_jpeg_compressed_tiles() {
+        let colors = [[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]];
+        let mut image = open(jpeg_tiled(16, colors));
+
+        let info = image.info().unwrap();
+        assert_eq!((info.width, info.height), (32, 32));
+        assert_eq!(info.tiles.unwrap()[0].width, 16);
+
+        let full = AbsoluteRegion { x: 0, y: 0, width: 32, height: 32 };
+        let pixels = decode(&mut image, full, (32, 32));
+        assert_eq!(pixels.len(), 32 * 32 * 3);
+
+        for (tile, color) in colors.iter().enumerate() {
+            let (x, y) = (tile % 2 * 16 + 8, tile / 2 * 16 + 8);
+            let pixel = &pixels[(y * 32 + x) * 3..][..3];
+
+            let difference = pixel.iter().zip(color).map(|(a, b)| a.abs_diff(*b));
+            assert!(difference.max().unwrap() <= 4, "tile {tile} decoded as {pixel:?}");
+        }
+    }
+
+    #[test]
+    fn empty_directories_are_not_reductions() {
+        let level = |width, height| Level {
+            directory: 0,
+            width,
+            height,
+            chunk_width: 16,
+            chunk_height: 16,
+            tiled: true,
+            color: ColorType::RGB(8),
+            format: PixelFormat::RGB8,
+        };
+
+        assert!(level(50, 25).is_reduction_of(&level(100, 50)));
+        assert!(!level(0, 25).is_reduction_of(&level(100, 50)));
+        assert!(!level(50, 0).is_reduction_of(&level(100, 50)));
+    }
+
+    #[test]
+    fn estimates_the_memory_of_whole_strips() {
+        let pixels = [0; 64 * 64 * 3];
+
+        let mut data = Cursor::new(vec![]);
+        let mut encoder = TiffEncoder::new(&mut data).unwrap();
+        let mut tiff = encoder.new_image::<RGB8>(64, 64).unwrap();
+        tiff.rows_per_strip(64).unwrap();
+        tiff.write_data(&pixels).unwrap();
+
+        // A single strip is read whole, however little of it is decoded.
+        let mut image = open(data.into_inner());
+        let region = AbsoluteRegion { x: 0, y: 0, width: 8, height: 8 };
+        let decoder = image.open_region(region, (8, 8)).unwrap();
+        assert!(decoder.working_memory() >= 64 * 64 * 3);
+    }
+}
@@ -0,0 +1,428 @@
+use palette::chromatic_adaptation::AdaptIntoUnclamped;
+use palette::convert::FromColorUnclamped;
+use palette::white_point::{D50, D65};
+use palette::{LinSrgb, Srgb, Xyz};
+
+use super::CodecError;
+use super::pixel::{BitDepth, ColorSpace, PixelFormat};
+
+/// The number of entries in the lookup tables that approximate tone curves.
+const LUT_SIZE: usize = 4096;
+
+/// How the colours of source images with an embedded ICC profile are handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorManagement {
+    /// Convert colours to sRGB, so they display correctly without colour management.
+    #[default]
+    Convert,
+
+    /// Keep the original colours and embed the source image's profile in the output.
+    Preserve,
+}
+
+/// A tone reproduction curve, mapping encoded samples in `[0, 1]` to linear light.
+#[derive(Clone, Debug)]
+enum ToneCurve {
+    /// The parameters `[g, a, b, c, d, e, f]` of the ICC parametric curve
+    /// `Y = (aX + b)^g + e` for `X >= d`, and `Y = cX + f` otherwise.
+    Parametric([f32; 7]),
+    /// Samples of the curve spaced evenly across its input range.
+    Table(Vec<f32>),
+}
+
+impl ToneCurve {
+    fn eval(&self, x: f32) -> f32 {
+        match self {
+            ToneCurve::Parametric([g, a, b, _, d, e, _]) if x >= *d => {
+                (a * x + b).max(0.0).powf(*g) + e
+            }
+            ToneCurve::Parametric([.., c, _, _, f]) => c * x + f,
+            ToneCurve::Table(table) => interpolate(table, x),
+        }
+    }
+}
+
+/// The parts of a matrix/TRC ICC profile needed to convert its colours to sRGB.
+///
+/// Profiles that describe colours with lookup tables, such as most CMYK and device link
+/// profiles, aren't supported.
+#[derive(Clone, Debug)]
+pub struct IccProfile {
+    color: ColorSpace,
+    curves: Vec<ToneCurve>,
+    /// The D50 XYZ colorants of the red, green and blue channels of RGB profiles.
+    colorants: [[f32; 3]; 3],
+}
+
+impl IccProfile {
+    pub fn parse(data: &[u8]) -> Result<Self, CodecError> {
+        let Some(color) = profile_color_space(data)? else {
+            return Err(CodecError::UnsupportedFormat(format!(
+                "{} ICC profiles are not supported",
+                String::from_utf8_lossy(&data[16..20]).trim_end()
+            )));
+        };
+
+        let tag = |signature: &[u8; 4]| find_tag(data, signature);
+
+        let curves = match color {
+            ColorSpace::Gray => vec![parse_curve(tag(b"kTRC")?)?],
+            ColorSpace::Rgb => [b"rTRC", b"gTRC", b"bTRC"]
+                .into_iter()
+                .map(|signature| parse_curve(tag(signature)?))
+                .collect::<Result<_, _>>()?,
+        };
+
+        let colorants = match color {
+            ColorSpace::Gray => [[0.0; 3]; 3],
+            ColorSpace::Rgb => [
+                parse_xyz(tag(b"rXYZ")?)?,
+                parse_xyz(tag(b"gXYZ")?)?,
+                parse_xyz(tag(b"bXYZ")?)?,
+            ],
+        };
+
+        Ok(Self { color, curves, colorants })
+    }
+}
+
+/// Read the colour space of the pixels an ICC profile describes from its header, or `None` if
+/// it describes neither grayscale nor RGB pixels.
+pub fn profile_color_space(data: &[u8]) -> Result<Option<ColorSpace>, CodecError> {
+    if data.len() < 132 || &data[36..40] != b"acsp" {
+        return Err(CodecError::Malformed("invalid ICC profile header".into()));
+    }
+
+    Ok(match &data[16..20] {
+        b"GRAY" => Some(ColorSpace::Gray),
+        b"RGB " => Some(ColorSpace::Rgb),
+        _ => None,
+    })
+}
+
+/// Converts decoded pixels from the colour space of an ICC profile to sRGB, in place.
+///
+/// Alpha channels are left untouched, and grayscale pixels stay grayscale, encoded with the sRGB
+/// tone curve.
+#[derive(Clone, Debug)]
+pub struct ColorTransform {
+    format: PixelFormat,
+    /// The tone curves of each colour channel, sampled into lookup tables.
+    linearize: Vec<Vec<f32>>,
+    /// Converts linear profile colours to linear sRGB.
+    matrix: [[f32; 3]; 3],
+    /// The sRGB tone curve, sampled into a lookup table.
+    encode: Vec<f32>,
+}
+
+impl ColorTransform {
+    /// Create a transform from pixels of `format` described by `profile` to sRGB.
+    pub fn to_srgb(profile: &IccProfile, format: PixelFormat) -> Result<Self, CodecError> {
+        if profile.color != format.color {
+            return Err(CodecError::UnsupportedFormat(format!(
+                "the ICC profile doesn't describe {format} pixels"
+            )));
+        }
+
+        let linearize = profile
+            .curves
+            .iter()
+            .map(|curve| sample(|x| curve.eval(x)))
+            .collect();
+
+        // Each colorant, adapted from the D50 profile connection space to D65, becomes a column
+        // of the matrix from the profile's linear RGB to linear sRGB.
+        let mut matrix = [[0.0; 3]; 3];
+        for (channel, [x, y, z]) in profile.colorants.into_iter().enumerate() {
+            let xyz: Xyz<D65, f32> = Xyz::<D50, f32>::new(x, y, z).adapt_into_unclamped();
+            let rgb = LinSrgb::from_color_unclamped(xyz);
+
+            matrix[0][channel] = rgb.red;
+            matrix[1][channel] = rgb.green;
+            matrix[2][channel] = rgb.blue;
+        }
+
+        let encode = sample(|x| Srgb::<f32>::from_linear(LinSrgb::new(x, x, x)).red);
+
+        Ok(Self { format, linearize, matrix, encode })
+    }
+
+    /// Convert packed pixels of the transform's format to sRGB.
+    pub fn apply(&self, pixels: &mut [u8]) {
+        let format = self.format;
+        let sample_size = format.bytes_per_sample();
+
+        for pixel in pixels.chunks_exact_mut(format.bytes_per_pixel()) {
+            let mut color = [0.0; 3];
+            for (channel, value) in color.iter_mut().enumerate().take(self.linearize.len()) {
+                let sample = read_sample(&pixel[channel * sample_size..], format.depth);
+                *value = interpolate(&self.linearize[channel], sample);
+            }
+
+            if format.color == ColorSpace::Rgb {
+                color = self
+                    .matrix
+                    .map(|row| row[0] * color[0] + row[1] * color[1] + row[2] * color[2]);
+            }
+
+            for (channel, value) in color.iter().enumerate().take(self.linearize.len()) {
+                let encoded = interpolate(&self.encode, value.clamp(0.0, 1.0));
+                write_sample(&mut pixel[channel * sample_size..], encoded, format.depth);
+            }
+        }
+    }
+}
+
+/// The sRGB tone curve and its inverse, sampled into lookup tables, for working with sRGB
+/// samples in linear light.
+#[derive(Clone, Debug)]
+pub(crate) struct SrgbCurve {
+    linearize: Vec<f32>,
+    encode: Vec<f32>,
+}
+
+impl SrgbCurve {
+    pub(crate) fn new() -> Self {
+        Self {
+            linearize: sample(|x| Srgb::new(x, x, x).into_linear::<f32>().red),
+            encode: sample(|x| Srgb::<f32>::from_linear(LinSrgb::new(x, x, x)).red),
+        }
+    }
+
+    pub(crate) fn linearize(&self, x: f32) -> f32 {
+        interpolate(&self.linearize, x)
+    }
+
+    pub(crate) fn encode(&self, x: f32) -> f32 {
+        interpolate(&self.encode, x)
+    }
+}
+
+/// Sample a function over `[0, 1]` into a lookup table.
+fn sample(function: impl Fn(f32) -> f32) -> Vec<f32> {
+    (0..LUT_SIZE)
+        .map(|i| function(i as f32 / (LUT_SIZE - 1) as f32))
+        .collect()
+}
+
+/// Look up `x` in `[0, 1]` in a table of evenly spaced samples, interpolating between them.
+fn interpolate(table: &[f32], x: f32) -> f32 {
+    let position = x.clamp(0.0, 1.0) * (table.len() - 1) as f32;
+    let index = (position as usize).min(table.len() - 2);
+    let fraction = position - index as f32;
+
+    table[index] + (table[index + 1] - table[index]) * fraction
+}
+
+pub(crate) fn read_sample(data: &[u8], depth: BitDepth) -> f32 {
+    match depth {
+        BitDepth::Eight => data[0] as f32 / 255.0,
+        BitDepth::Sixteen => u16::from_ne_bytes([data[0], data[1]]) as f32 / 65535.0,
+    }
+}
+
+pub(crate) fn write_sample(data: &mut [u8], value: f32, depth: BitDepth) {
+    match depth {
+        BitDepth::Eight => data[0] = (value * 255.0).round() as u8,
+        BitDepth::Sixteen => {
+            data[..2].copy_from_slice(&((value * 65535.0).round() as u16).to_ne_bytes());
+        }
+    }
+}
+
+fn truncated() -> CodecError {
+    CodecError::Malformed("truncated ICC profile".into())
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, CodecError> {
+    let bytes = data.get(offset..offset + 2).ok_or_else(truncated)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, CodecError> {
+    let bytes = data.get(offset..offset + 4).ok_or_else(truncated)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Read an `s15Fixed16Number`.
+fn read_fixed(data: &[u8], offset: usize) -> Result<f32, CodecError> {
+    Ok(read_u32(data, offset)? as i32 as f32 / 65536.0)
+}
+
+/// Find the data of the tag with the given signature in the profile's tag table.
+fn find_tag<'a>(data: &'a [u8], signature: &[u8; 4]) -> Result<&'a [u8], CodecError> {
+    let count = read_u32(data, 128)? as usize;
+
+    for entry in (0..count).map(|i| 132 + i * 12) {
+        if data.get(entry..entry + 4).ok_or_else(truncated)? == signature {
+            let offset = read_u32(data, entry + 4)? as usize;
+            let size = read_u32(data, entry + 8)? as usize;
+
+            return data.get(offset..offset + size).ok_or_else(truncated);
+        }
+    }
+
+    Err(CodecError::UnsupportedFormat(format!(
+        "ICC profiles without a {} tag are not supported",
+        String::from_utf8_lossy(signature)
+    )))
+}
+
+/// Parse an `XYZType` tag holding a single colour.
+fn parse_xyz(tag: &[u8]) -> Result<[f32; 3], CodecError> {
+    if tag.get(..4) != Some(b"XYZ ") {
+        return Err(CodecError::Malformed("invalid ICC colorant tag".into()));
+    }
+
+    Ok([
+        read_fixed(tag, 8)?,
+        read_fixed(tag, 12)?,
+        read_fixed(tag, 16)?,
+    ])
+}
+
+/// Parse a `curveType` or `parametricCurveType` tag.
+fn parse_curve(tag: &[u8]) -> Result<ToneCurve, CodecError> {
+    match tag.get(..4) {
+        Some(b"curv") => {
+            let count = read_u32(tag, 8)? as usize;
+            match count {
+                0 => Ok(ToneCurve::Parametric([1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0])),
+                1 => {
+                    let gamma = read_u16(tag, 12)? as f32 / 256.0;
+                    Ok(ToneCurve::Parametric([gamma, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0]))
+                }
+                _ => (0..count)
+                    .map(|i| Ok(read_u16(tag, 12 + i * 2)? as f32 / 65535.0))
+                    .collect::<Result<_, _>>()
+                    .map(ToneCurve::Table),
+            }
+        }
+        Some(b"para") => {
+            let function = read_u16(tag, 8)?;
+            let parameters = match function {
+                0 => 1,
+                1 => 3,
+                2 => 4,
+                3 => 5,
+                4 => 7,
+                _ => return Err(CodecError::Malformed("invalid ICC parametric curve".into())),
+            };
+
+            let mut p = [0.0; 7];
+            for (i, value) in p.iter_mut().enumerate().take(parameters) {
+                *value = read_fixed(tag, 12 + i * 4)?;
+            }
+
+            // Rewrite every function in the form of the most general one, function 4.
+            let [g, a, b, c, d, e, f] = p;
+            let threshold = if a == 0.0 { 0.0 } else { -b / a };
+            Ok(ToneCurve::Parametric(match function {
+                0 => [g, 1.0, 0.0, 0.0, f32::MIN, 0.0, 0.0],
+                1 => [g, a, b, 0.0, threshold, 0.0, 0.0],
+                2 => [g, a, b, 0.0, threshold, c, c],
+                3 => [g, a, b, c, d, 0.0, 0.0],
+                _ => [g, a, b, c, d, e, f],
+            }))
+        }
+        _ => Err(CodecError::Malformed("invalid ICC tone curve".into())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Encode a minimal ICC profile with the given colour space and tags.
+    fn profile(color: &[u8; 4], tags: &[(&[u8; 4], Vec<u8>)]) -> Vec<u8> {
+        let mut data = vec![0; 128];
+        data[16..20].copy_from_slice(color);
+        data[20..24].copy_from_slice(b"XYZ ");
+        data[36..40].copy_from_slice(b"acsp");
+        data.extend((tags.len() as u32).to_be_bytes());
+
+        let mut offset = 132 + tags.len() * 12;
+        for (signature, tag) in tags {
+            data.extend_from_slice(*signature);
+            data.extend((offset as u32).to_be_bytes());
+            data.extend((tag.len() as u32).to_be_bytes());
+            offset += tag.len();
+        }
+
+        for (_, tag) in tags {
+            data.extend_from_slice(tag);
+        }
+
+        data
+    }
+
+    fn fixed(value: f32) -> [u8; 4] {
+        ((value * 65536.0).round() as i32).to_be_bytes()
+    }
+
+    fn xyz([x, y, z]: [f32; 3]) -> Vec<u8> {
+        [*b"XYZ ", [0; 4], fixed(x), fixed(y), fixed(z)].concat()
+    }
+
+    fn gamma(gamma: f32) -> Vec<u8> {
+        let mut tag = [*b"curv", [0; 4], 1u32.to_be_bytes()].concat();
+        tag.extend(((gamma * 256.0) as u16).to_be_bytes());
+        tag
+    }
+
+    fn srgb_curve() -> Vec<u8> {
+        let parameters = [2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045];
+        let mut tag = [*b"para", [0; 4], [0, 3, 0, 0]].concat();
+        tag.extend(parameters.into_iter().flat_map(fixed));
+        tag
+    }
+
+    fn transform(profile: &[u8], format: PixelFormat, pixels: &[u8]) -> Vec<u8> {
+        let profile = IccProfile::parse(profile).unwrap();
+        let mut pixels = pixels.to_vec();
+        ColorTransform::to_srgb(&profile, format)
+            .unwrap()
+            .apply(&mut pixels);
+
+        pixels
+    }
+
+    #[test]
+    fn keeps_srgb_colours() {
+        let srgb = profile(
+            b"RGB ",
+            &[
+                (b"rXYZ", xyz([0.4361, 0.2225, 0.0139])),
+                (b"gXYZ", xyz([0.3851, 0.7169, 0.0971])),
+                (b"bXYZ", xyz([0.1431, 0.0606, 0.7141])),
+                (b"rTRC", srgb_curve()),
+                (b"gTRC", srgb_curve()),
+                (b"bTRC", srgb_curve()),
+            ],
+        );
+
+        let pixels = [255, 255, 255, 255, 0, 0, 0, 128, 200, 100, 50, 77];
+        let converted = transform(&srgb, PixelFormat::RGBA8, &pixels);
+
+        for (converted, original) in converted.iter().zip(pixels) {
+            assert!(converted.abs_diff(original) <= 1, "{converted} != {original}");
+        }
+    }
+
+    #[test]
+    fn converts_grayscale_tone_curves() {
+        let linear = profile(b"GRAY", &[(b"kTRC", gamma(1.0))]);
+
+        // Linear mid-gray is lighter once encoded with the sRGB tone curve.
+        assert_eq!(transform(&linear, PixelFormat::GRAY8, &[0, 128, 255]), [0, 188, 255]);
+    }
+
+    #[test]
+    fn rejects_mismatched_profiles() {
+        let gray = IccProfile::parse(&profile(b"GRAY", &[(b"kTRC", gamma(2.2))])).unwrap();
+        assert!(ColorTransform::to_srgb(&gray, PixelFormat::RGB8).is_err());
+
+        let cmyk = profile(b"CMYK", &[]);
+        assert!(matches!(IccProfile::parse(&cmyk), Err(CodecError::UnsupportedFormat(_))));
+    }
+}
@@ -1,6 +1,7 @@
 use crate::iiif::Dimension;
 
 #[allow(unused)]
+#[derive(Clone)]
 pub struct ImageInfo {
     // @context: "http://iiif.io/api/image/3/context.json",
     // type: "ImageService3",
@@ -35,6 +36,7 @@ pub struct ImageInfo {
 }
 
 #[allow(unused)]
+#[derive(Clone)]
 pub struct PreferredSize {
     // type: "Size",
     pub width: Dimension,
@@ -42,6 +44,7 @@ pub struct PreferredSize {
 }
 
 #[allow(unused)]
+#[derive(Clone)]
 pub struct Tile {
     // type: "Tile",
     pub scale_factors: Vec<u16>,
@@ -0,0 +1,77 @@
+use std::fmt::Display;
+
+/// The colour channels of a pixel, excluding any alpha channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorSpace {
+    Gray,
+    Rgb,
+}
+
+/// The size of each sample in a pixel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitDepth {
+    Eight,
+    /// 16-bit samples, stored in native byte order.
+    Sixteen,
+}
+
+/// Describes the layout of the pixels in scanlines produced by an
+/// [ImageDecoder](super::ImageDecoder) or accepted by an encoder.
+///
+/// Pixels are packed without padding, with the colour channels followed by the alpha channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelFormat {
+    pub color: ColorSpace,
+    pub alpha: bool,
+    pub depth: BitDepth,
+}
+
+impl PixelFormat {
+    pub const GRAY8: PixelFormat = PixelFormat::new(ColorSpace::Gray, false, BitDepth::Eight);
+    pub const RGB8: PixelFormat = PixelFormat::new(ColorSpace::Rgb, false, BitDepth::Eight);
+    pub const RGBA8: PixelFormat = PixelFormat::new(ColorSpace::Rgb, true, BitDepth::Eight);
+
+    pub const fn new(color: ColorSpace, alpha: bool, depth: BitDepth) -> Self {
+        Self { color, alpha, depth }
+    }
+
+    /// The number of channels in each pixel, including any alpha channel.
+    pub fn channels(&self) -> usize {
+        let color = match self.color {
+            ColorSpace::Gray => 1,
+            ColorSpace::Rgb => 3,
+        };
+
+        color + self.alpha as usize
+    }
+
+    /// The number of bytes in each sample.
+    pub fn bytes_per_sample(&self) -> usize {
+        match self.depth {
+            BitDepth::Eight => 1,
+            BitDepth::Sixteen => 2,
+        }
+    }
+
+    /// The number of bytes in each pixel.
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.channels() * self.bytes_per_sample()
+    }
+}
+
+impl Display for PixelFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let color = match (self.color, self.alpha) {
+            (ColorSpace::Gray, false) => "gray",
+            (ColorSpace::Gray, true) => "gray+alpha",
+            (ColorSpace::Rgb, false) => "rgb",
+            (ColorSpace::Rgb, true) => "rgba",
+        };
+        let depth = match self.depth {
+            BitDepth::Eight => 8,
+            BitDepth::Sixteen => 16,
+        };
+
+        write!(f, "{color}{depth}")
+    }
+}
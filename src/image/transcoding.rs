@@ -3,28 +3,69 @@ use std::fmt::Display;
 use std::io::Write;
 use std::ops::Div;
 use std::pin::pin;
+use std::sync::LazyLock;
 use std::task::Poll;
+use std::time::Duration;
 
+use admission::{Admission, Permit, Requester};
 use bytes::{BufMut, Bytes, BytesMut};
-use decode::decode_task;
+use decode::{DecodeTarget, NegotiatedOutput, decode_task};
 use encode::encode_task;
 use futures::stream::Fuse;
 use futures::{Stream, StreamExt};
-use gcd::Gcd;
-use mediatype::MediaTypeBuf;
-use mediatype::names::{IMAGE, JPEG};
-use tokio::sync::mpsc::{self, Sender};
+use opentelemetry::metrics::Counter;
+use plan::{OutputPlan, plan_output};
+use resample::ResamplingFilter;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
 use tokio::task::JoinSet;
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info, info_span};
+use tracing::field::Empty;
+use tracing::{debug, error, info, info_span};
 
-use super::{AbsoluteRegion, BoxedImage, ImageStream};
+use super::color::ColorManagement;
+use super::info::ImageInfo;
+use super::{BoxedImage, CodecError, Dimensions, ImageStream};
+use crate::iiif::Dimension;
 use crate::iiif::service::ImageParameters;
-use crate::iiif::{Region, Scale};
 
+pub mod admission;
+pub mod convert;
 pub mod decode;
 pub mod encode;
+pub mod plan;
+pub mod pool;
+pub mod resample;
+
+/// The number of strips of scanlines that can be queued for the encoder.
+const QUEUED_STRIPS: usize = 4;
+
+/// Instruments shared by every transcoding pipeline.
+struct TranscodingMetrics {
+    cancelled: Counter<u64>,
+    abandoned_rows: Counter<u64>,
+    shed: Counter<u64>,
+}
+
+static METRICS: LazyLock<TranscodingMetrics> = LazyLock::new(|| {
+    let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+
+    TranscodingMetrics {
+        cancelled: meter
+            .u64_counter("image_transcoding.cancelled")
+            .with_description("Transcoding pipelines cancelled because their stream was dropped")
+            .build(),
+        abandoned_rows: meter
+            .u64_counter("image_transcoding.abandoned_rows")
+            .with_description("Output rows left undecoded by cancelled transcoding pipelines")
+            .build(),
+        shed: meter
+            .u64_counter("image_transcoding.shed")
+            .with_description("Transcoding pipelines rejected because the server was too busy")
+            .build(),
+    }
+});
 
 /// Coordinates the processing of an image according to IIIF parameters.
 ///
@@ -37,27 +78,125 @@ pub mod encode;
 /// 2. An encoder task compresses the image data to the target format
 /// 3. The resulting stream yields compressed image data as it becomes available
 ///
+/// A pipeline is [planned](TranscodingPipeline::plan) before it runs: the region, size and
+/// encoder are resolved from the request, checked against the limits of the source image, and the
+/// decoder opens the region. Requests that can't be produced fail while planning, so they can be
+/// answered with an error rather than a truncated image.
+///
+/// The decoder negotiates the pixel format it will send before any scanlines, converting its
+/// output only when the encoder can't accept the decoder's native format. Colours described by an
+/// ICC profile embedded in the source image are converted to sRGB, or the profile is embedded in
+/// the encoded image, depending on [ColorManagement].
+///
+/// Scanlines are sent in strips, through a small pool of buffers sized for a strip of the output
+/// image. The memory those buffers need is checked against the
+/// [memory budget](TranscodingOptions::memory_budget) before decoding starts.
+///
+/// Decoders scale the region they decode to the output size by averaging pixels. When a
+/// [ResamplingFilter] other than a box filter is chosen, or resampling in linear light, the region
+/// is decoded at the most reduced resolution level of the image that is at least as large as the
+/// output instead, and resampled to the output size as it is decoded.
+/// [Fast decoding](TranscodingOptions::fast_decoding) decodes the level below that, and enlarges
+/// it instead.
+///
+/// Pipelines can be limited by an [Admission], which they must be admitted by after planning
+/// their output and before they start any work. Waiting pipelines are prioritised by their
+/// [Requester] and the size of their output.
+///
+/// Images with quality layers, such as JPEG 2000, can be decoded from fewer of their layers for
+/// previews, or when the request limits them.
+///
 /// # Example
 ///
 /// ```
 /// use laya::iiif::service::ImageParameters;
-/// use laya::image::transcoding::TranscodingPipeline;
+/// use laya::image::BoxedImage;
+/// use laya::image::transcoding::{TranscodingOptions, TranscodingPipeline};
 ///
-/// let pipeline = TranscodingPipeline {
-///     image: source_image,
-///     params: image_parameters,
-/// };
+/// async fn transcode(source_image: BoxedImage, image_parameters: ImageParameters) {
+///     let pipeline = TranscodingPipeline {
+///         image: source_image,
+///         params: image_parameters,
+///         options: TranscodingOptions::default(),
+///         admission: None,
+///         requester: Default::default(),
+///     };
 ///
-/// // Run the pipeline and get a stream of encoded image data
-/// let image_stream = pipeline.run();
+///     // Run the pipeline and get a stream of encoded image data
+///     let image_stream = pipeline.run().await.expect("unreadable image");
+/// }
 /// ```
 pub struct TranscodingPipeline {
     pub image: BoxedImage,
     pub params: ImageParameters,
+    pub options: TranscodingOptions,
+    /// Limits the pipelines that run at once, if any.
+    pub admission: Option<Admission>,
+    /// Who the pipeline is run for, which decides how it is queued for admission.
+    pub requester: Requester,
+}
+
+/// Settings that apply to every image transcoded by a [TranscodingPipeline].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TranscodingOptions {
+    /// How the colours of source images with an embedded ICC profile are handled.
+    pub color: ColorManagement,
+    /// The most memory, in bytes, that a request may use for the scanline buffers passed between
+    /// the decoder and the encoder. Requests that need more are rejected before decoding starts.
+    pub memory_budget: Option<u64>,
+    /// The filter used to resample decoded regions to the output size.
+    pub resampling: ResamplingFilter,
+    /// Whether samples are resampled in linear light, rather than as their sRGB encoded values.
+    pub linear_light: bool,
+    /// The most quality layers decoded for small images, such as previews and thumbnails.
+    pub preview_layers: Option<PreviewLayers>,
+    /// Whether regions are decoded one resolution level below the output size and enlarged,
+    /// trading sharpness for speed.
+    pub fast_decoding: bool,
+}
+
+/// A limit on the quality layers decoded for images no larger than `max_size` pixels on either
+/// side.
+#[derive(Clone, Copy, Debug)]
+pub struct PreviewLayers {
+    pub max_size: Dimension,
+    pub layers: u16,
+}
+
+impl TranscodingOptions {
+    /// The most quality layers to decode for an image of `size`, given the limit requested by the
+    /// client, if any.
+    fn quality_layers(&self, requested: Option<u16>, size: Dimensions) -> Option<u16> {
+        let preview = self
+            .preview_layers
+            .filter(|preview| size.0.max(size.1) <= preview.max_size)
+            .map(|preview| preview.layers);
+
+        match (requested, preview) {
+            (Some(requested), Some(preview)) => Some(requested.min(preview)),
+            (requested, preview) => requested.or(preview),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum TranscodingError {
+    Codec(CodecError),
+    /// The request needs more memory than its budget allows.
+    MemoryBudgetExceeded {
+        required: u64,
+        budget: u64,
+    },
+    /// The request can't be produced from the source image, such as a region outside of it.
+    InvalidRequest(String),
+    /// The request is valid, but asks for something the pipeline can't produce.
+    Unsupported(String),
+    /// The requested size is over the limits of the source image.
+    LimitExceeded(String),
+    /// The server is too busy to start the pipeline, and the request should be retried later.
+    Overloaded {
+        retry_after: Duration,
+    },
     Generic(String),
     Io(std::io::Error),
     Unknown,
@@ -65,6 +204,12 @@ pub enum TranscodingError {
 
 impl Error for TranscodingError {}
 
+impl From<CodecError> for TranscodingError {
+    fn from(value: CodecError) -> Self {
+        TranscodingError::Codec(value)
+    }
+}
+
 impl From<std::io::Error> for TranscodingError {
     fn from(value: std::io::Error) -> Self {
         TranscodingError::Io(value)
@@ -74,6 +219,17 @@ impl From<std::io::Error> for TranscodingError {
 impl Display for TranscodingError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
+            TranscodingError::Codec(err) => write!(f, "codec error: {err}"),
+            TranscodingError::MemoryBudgetExceeded { required, budget } => write!(
+                f,
+                "the request needs {required} bytes, over its memory budget of {budget} bytes"
+            ),
+            TranscodingError::InvalidRequest(reason) => write!(f, "invalid request: {reason}"),
+            TranscodingError::Unsupported(reason) => write!(f, "unsupported request: {reason}"),
+            TranscodingError::LimitExceeded(reason) => write!(f, "{reason}"),
+            TranscodingError::Overloaded { retry_after } => {
+                write!(f, "too busy to transcode the image, retry after {retry_after:?}")
+            }
             TranscodingError::Generic(message) => write!(f, "{message}"),
             TranscodingError::Io(err) => write!(f, "io error: {err}"),
             TranscodingError::Unknown => write!(f, "unknown error"),
@@ -117,68 +273,152 @@ impl Write for SenderWriter {
 }
 
 impl TranscodingPipeline {
-    pub fn run(self) -> ImageStream {
-        let Self { mut image, params } = self;
+    /// Plan the pipeline and execute it straight away.
+    pub async fn run(self) -> Result<ImageStream, TranscodingError> {
+        Ok(self.plan().await?.execute())
+    }
 
-        let info = image.info();
-        let token = CancellationToken::new();
-        let mut task_set = JoinSet::new();
+    /// Resolve the region, size and encoder of the request, check them against the limits of the
+    /// source image, and open the region for decoding.
+    ///
+    /// Nothing is decoded or encoded until the plan is [executed](PlannedPipeline::execute), so
+    /// a request that can't be produced fails here, before any of its response has been sent.
+    /// Pipelines that must wait to be admitted wait here too, and fail if they're shed.
+    pub async fn plan(self) -> Result<PlannedPipeline, TranscodingError> {
+        let Self { mut image, params, options, admission, requester } = self;
+
+        let info = image.info()?;
+        let output = plan_output(&params, &info)?;
+        let OutputPlan { region, size, encoder } = output;
+        info!(?region, ?size, encoder = encoder.name(), "planned image transcoding");
+
+        let permit = match &admission {
+            Some(admission) => Some(admission.admit(admission.cost(size), &requester).await?),
+            None => None,
+        };
 
-        let absolute_region = match params.region {
-            Region::Absolute { x, y, width, height } => AbsoluteRegion { x, y, width, height },
-            Region::Full => AbsoluteRegion { x: 0, y: 0, width: info.width, height: info.height },
-            _ => todo!(),
+        // Decoders that limit the regions decoded at once are waited for before a blocking thread
+        // is taken, and their slot is given back as soon as decoding finishes.
+        let slot = match image.decoder_slots() {
+            Some(slots) => Some(
+                slots
+                    .acquire_owned()
+                    .await
+                    .expect("decoder slots are never closed"),
+            ),
+            None => None,
         };
 
-        let dims_gcd = info.width.gcd(info.height);
-        let ratio_w = info.width / dims_gcd;
-        let ratio_h = info.height / dims_gcd;
+        let token = CancellationToken::new();
+        let mut task_set = JoinSet::new();
 
-        let size = match params.size.scale() {
-            Scale::Max => (absolute_region.width, absolute_region.height),
-            Scale::Fixed { width: scaled_width, height: scaled_height } => {
-                (scaled_width.get(), scaled_height.get())
-            }
-            Scale::FixedWidth(scaled_width) => {
-                (scaled_width.get(), (scaled_width.get() * ratio_h) / ratio_w)
-            }
-            Scale::FixedHeight(scaled_height) => {
-                ((scaled_height.get() * ratio_w) / ratio_h, scaled_height.get())
-            }
-            Scale::Percentage(pct) => {
-                let scaled_x = absolute_region.width as f32 / 100.0 * pct;
-                let scaled_y = absolute_region.height as f32 / 100.0 * pct;
+        let decoder_token = token.clone();
+        let quality_layers = options.quality_layers(params.quality_layers, size);
+        let decoder_span = info_span!(
+            "image_decoder",
+            decoder = "kakadu",
+            scale_factor = Empty,
+            quality_layers = Empty
+        );
+        let (decoded_tx, decoded_rx) = mpsc::channel(QUEUED_STRIPS);
+        let (negotiated_tx, negotiated_rx) = oneshot::channel();
+        let (start_tx, start_rx) = oneshot::channel();
+
+        task_set.spawn_blocking(move || -> Result<(), TranscodingError> {
+            let _slot = slot;
+            decoder_span.in_scope(|| {
+                decode_task(
+                    decoder_token,
+                    image,
+                    DecodeTarget { region, size, quality_layers },
+                    options,
+                    negotiated_tx,
+                    start_rx,
+                    decoded_tx,
+                )
+            })
+        });
 
-                (scaled_x.ceil() as u32, scaled_y.ceil() as u32)
+        // Wait for the decoder to open the region, so that failures to do so are reported
+        // before any of the response is sent.
+        let negotiated = match negotiated_rx.await {
+            Ok(Ok(negotiated)) => negotiated,
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                return Err(match task_set.join_next().await {
+                    Some(Ok(Err(e))) => e,
+                    Some(Err(e)) => TranscodingError::Generic(format!("decoder failed: {e}")),
+                    _ => TranscodingError::Unknown,
+                });
             }
-            Scale::AspectPreserving { .. } => todo!(),
         };
 
-        info!("Calculated dimensions ({size:?}) for scale params: {:?}", params.size.scale());
+        Ok(PlannedPipeline {
+            output,
+            info,
+            token,
+            task_set,
+            negotiated,
+            decoded: decoded_rx,
+            start: start_tx,
+            permit,
+        })
+    }
+}
+
+/// A [TranscodingPipeline] that has been planned, with its decoder waiting to start.
+///
+/// Dropping a plan without executing it closes the decoder without decoding anything.
+pub struct PlannedPipeline {
+    output: OutputPlan,
+    info: ImageInfo,
+    token: CancellationToken,
+    task_set: JoinSet<Result<(), TranscodingError>>,
+    negotiated: NegotiatedOutput,
+    decoded: Receiver<BytesMut>,
+    start: oneshot::Sender<()>,
+    permit: Option<Permit>,
+}
 
-        let decoder_token = token.clone();
-        let decoder_span = info_span!("image_decoder", decoder = "kakadu");
-        let (decoded_tx, decoded_rx) = mpsc::channel(4);
+impl PlannedPipeline {
+    /// The image the pipeline produces.
+    pub fn output(&self) -> &OutputPlan {
+        &self.output
+    }
 
-        task_set.spawn_blocking(move || -> Result<(), TranscodingError> {
-            decoder_span
-                .in_scope(|| decode_task(decoder_token, image, absolute_region, size, decoded_tx))
-        });
+    /// Start decoding and encoding the image, returning a stream of the encoded data.
+    pub fn execute(self) -> ImageStream {
+        let Self { output, info, token, mut task_set, negotiated, decoded, start, permit } = self;
 
         let encoder_token = token.clone();
-        let encoder_span = info_span!("image_encoder", encoder = "mozjpeg");
+        let encoder_span = info_span!("image_encoder", encoder = output.encoder.name());
         let (encoded_tx, encoded_rx) = mpsc::channel(4);
 
         task_set.spawn_blocking(move || -> Result<(), TranscodingError> {
-            encoder_span.in_scope(|| encode_task(encoder_token, size, decoded_rx, encoded_tx, info))
+            encoder_span.in_scope(|| {
+                encode_task(
+                    encoder_token,
+                    output.size,
+                    negotiated.format,
+                    negotiated.pool,
+                    decoded,
+                    encoded_tx,
+                    info,
+                )
+            })
         });
 
+        // The decoder only gives up waiting if it has already failed, which the stream reports.
+        let _ = start.send(());
+
         ImageStream {
-            media_type: MediaTypeBuf::new(IMAGE, JPEG),
+            media_type: output.encoder.media_type(),
             data: Box::new(TranscodedStream {
                 task_set,
                 token,
                 receiver: ReceiverStream::new(encoded_rx).fuse(),
+                finished: false,
+                _permit: permit,
             }),
         }
     }
@@ -194,10 +434,17 @@ impl TranscodingPipeline {
 /// - All transcoding tasks complete successfully and all encoded data is yielded
 /// - An error occurs in any task, causing cancellation of the pipeline
 /// - The associated cancellation token is triggered externally
+///
+/// Dropping the stream before it completes, such as when a client disconnects, cancels the
+/// pipeline so that its tasks stop decoding and encoding.
 pub struct TranscodedStream {
     task_set: JoinSet<Result<(), TranscodingError>>,
     token: CancellationToken,
     receiver: Fuse<ReceiverStream<Bytes>>,
+    finished: bool,
+    /// The share of the admission capacity taken by the pipeline, held until the stream is
+    /// dropped.
+    _permit: Option<Permit>,
 }
 
 impl Stream for TranscodedStream {
@@ -232,10 +479,175 @@ impl Stream for TranscodedStream {
 
         match inner.poll_next(cx) {
             Poll::Ready(Some(data)) => return Poll::Ready(Some(Ok(data))),
-            Poll::Ready(None) if this.task_set.is_empty() => return Poll::Ready(None),
+            Poll::Ready(None) if this.task_set.is_empty() => {
+                this.finished = true;
+                return Poll::Ready(None);
+            }
             _ => {}
         }
 
         Poll::Pending
     }
 }
+
+impl Drop for TranscodedStream {
+    fn drop(&mut self) {
+        // Pipelines that failed have already been cancelled.
+        if !self.finished && !self.token.is_cancelled() {
+            debug!("transcoded stream was dropped before it finished, cancelling the pipeline");
+            self.token.cancel();
+            METRICS.cancelled.add(1, &[]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use tokio::sync::Semaphore;
+
+    use super::*;
+    use crate::iiif::service::{ImageServiceRequest, ImageServiceRequestKind};
+    use crate::image::info::ImageInfo;
+    use crate::image::pixel::PixelFormat;
+    use crate::image::{AbsoluteRegion, Dimensions, Image, ImageDecoder};
+
+    const WIDTH: u32 = 64;
+    const HEIGHT: u32 = 60_000;
+
+    /// A tall grayscale image that records how much of it has been decoded.
+    struct TallImage {
+        strips: Arc<AtomicUsize>,
+        dropped: Arc<AtomicBool>,
+        slots: Option<Arc<Semaphore>>,
+    }
+
+    struct TallImageDecoder {
+        strips: Arc<AtomicUsize>,
+        dropped: Arc<AtomicBool>,
+    }
+
+    impl Image for TallImage {
+        fn info(&mut self) -> Result<ImageInfo, CodecError> {
+            Ok(ImageInfo {
+                width: WIDTH,
+                height: HEIGHT,
+                max_width: None,
+                max_height: None,
+                max_area: None,
+                sizes: None,
+                tiles: None,
+                preferred_formats: None,
+                rights: None,
+            })
+        }
+
+        fn open_region(
+            &mut self,
+            _: AbsoluteRegion,
+            _: Dimensions,
+        ) -> Result<Box<dyn ImageDecoder>, CodecError> {
+            Ok(Box::new(TallImageDecoder {
+                strips: self.strips.clone(),
+                dropped: self.dropped.clone(),
+            }))
+        }
+
+        fn decoder_slots(&self) -> Option<Arc<Semaphore>> {
+            self.slots.clone()
+        }
+    }
+
+    impl ImageDecoder for TallImageDecoder {
+        fn output_size(&self) -> Dimensions {
+            (WIDTH, HEIGHT)
+        }
+
+        fn pixel_format(&self) -> PixelFormat {
+            PixelFormat::GRAY8
+        }
+
+        fn decode_to(&mut self, buffer: &mut BytesMut) -> Result<bool, CodecError> {
+            let strip = self.strips.fetch_add(1, Ordering::SeqCst);
+            if strip * 16 >= HEIGHT as usize {
+                return Ok(true);
+            }
+
+            buffer.put_bytes(128, WIDTH as usize * 16);
+            Ok(false)
+        }
+    }
+
+    impl Drop for TallImageDecoder {
+        fn drop(&mut self) {
+            self.dropped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn pipeline(image: TallImage) -> TranscodingPipeline {
+        let request: ImageServiceRequest = "image/full/max/0/default.jpg".parse().unwrap();
+        let ImageServiceRequestKind::Image(params) = request.kind else {
+            panic!("expected an image request");
+        };
+
+        TranscodingPipeline {
+            image: image.boxed(),
+            params,
+            options: TranscodingOptions::default(),
+            admission: None,
+            requester: Requester::default(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stops_decoding_when_the_stream_is_dropped() {
+        let strips = Arc::new(AtomicUsize::new(0));
+        let dropped = Arc::new(AtomicBool::new(false));
+        let image = TallImage { strips: strips.clone(), dropped: dropped.clone(), slots: None };
+        let stream = pipeline(image).run().await.unwrap();
+
+        while strips.load(Ordering::SeqCst) < 10 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        drop(stream);
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !dropped.load(Ordering::SeqCst) {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("decoding wasn't cancelled");
+
+        assert!(strips.load(Ordering::SeqCst) < HEIGHT as usize / 16);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn waits_for_a_decoder_slot_before_decoding() {
+        let slots = Arc::new(Semaphore::new(1));
+        let image = TallImage {
+            strips: Arc::default(),
+            dropped: Arc::default(),
+            slots: Some(slots.clone()),
+        };
+
+        let held = slots.clone().acquire_owned().await.unwrap();
+        let planning = tokio::spawn(pipeline(image).plan());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!planning.is_finished());
+
+        // The slot is held while the region is decoded, and given back once decoding ends.
+        drop(held);
+        let planned = planning.await.unwrap().unwrap();
+        assert_eq!(slots.available_permits(), 0);
+
+        drop(planned);
+        let _slot = tokio::time::timeout(Duration::from_secs(5), slots.acquire())
+            .await
+            .expect("the decoder slot wasn't given back")
+            .unwrap();
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+use tracing::debug;
+
+use super::{METRICS, TranscodingError};
+use crate::image::Dimensions;
+
+/// The output pixels that cost a single unit of capacity: a 256x256 tile.
+const PIXELS_PER_UNIT: u64 = 256 * 256;
+
+/// The most units a pipeline can cost and still be served ahead of larger ones, which covers
+/// tiles and thumbnails up to 512x512.
+const SMALL_COST: u32 = 4;
+
+/// How urgently a pipeline should be admitted, from viewers panning and zooming interactively down
+/// to bulk downloads and harvesters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PriorityClass {
+    Interactive,
+    #[default]
+    Normal,
+    Bulk,
+}
+
+impl FromStr for PriorityClass {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "interactive" => Ok(PriorityClass::Interactive),
+            "normal" => Ok(PriorityClass::Normal),
+            "bulk" => Ok(PriorityClass::Bulk),
+            _ => Err(format!("unknown priority class: {value}")),
+        }
+    }
+}
+
+impl Display for PriorityClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PriorityClass::Interactive => write!(f, "interactive"),
+            PriorityClass::Normal => write!(f, "normal"),
+            PriorityClass::Bulk => write!(f, "bulk"),
+        }
+    }
+}
+
+/// Who a pipeline is run for, which decides where it waits in the queue.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Requester {
+    pub priority: PriorityClass,
+    /// Identifies the client, such as by its address. Clients that can't be identified share
+    /// their capacity.
+    pub client: Option<Arc<str>>,
+}
+
+/// Limits the decoding and encoding work that runs at once.
+///
+/// Each pipeline takes a share of a fixed capacity, weighted by the size of the image it produces,
+/// so a full-size render takes as much of it as many tiles. Pipelines that don't fit wait in a
+/// bounded queue, and are shed once the queue is full or they've waited longer than its deadline.
+///
+/// Waiting pipelines are admitted by [PriorityClass], then with tiles and thumbnails ahead of
+/// larger images, then for the clients using the least capacity, and finally in the order they
+/// arrived. No pipeline overtakes the one at the head of the queue, so large renders still run
+/// once enough capacity is free.
+#[derive(Clone)]
+pub struct Admission {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    capacity: u32,
+    max_queued: usize,
+    queue_timeout: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    available: u32,
+    queue: Vec<Waiter>,
+    /// The capacity used by each client's admitted pipelines.
+    running: HashMap<Option<Arc<str>>, u32>,
+    arrivals: u64,
+}
+
+struct Waiter {
+    cost: u32,
+    requester: Requester,
+    arrival: u64,
+    admitted: oneshot::Sender<Permit>,
+}
+
+/// A share of the capacity of an [Admission], given back when dropped.
+///
+/// Permits are handed to waiters through their channel, so capacity granted to a waiter that
+/// stops waiting before receiving it is given back along with the channel.
+pub struct Permit {
+    shared: Arc<Shared>,
+    /// The units of capacity held, or zero once they've been given back.
+    cost: u32,
+    client: Option<Arc<str>>,
+}
+
+impl Admission {
+    /// Run pipelines costing up to `capacity` units at once, queueing at most `max_queued` more
+    /// for up to `queue_timeout` each.
+    pub fn new(capacity: u32, max_queued: usize, queue_timeout: Duration) -> Self {
+        let capacity = capacity.max(1);
+        let state = State { available: capacity, ..State::default() };
+
+        Self {
+            shared: Arc::new(Shared {
+                capacity,
+                max_queued,
+                queue_timeout,
+                state: Mutex::new(state),
+            }),
+        }
+    }
+
+    /// The units of capacity taken by a pipeline producing an image of `size`. Images larger than
+    /// the whole capacity take all of it, rather than never running.
+    pub fn cost(&self, size: Dimensions) -> u32 {
+        let units = (size.0 as u64 * size.1 as u64).div_ceil(PIXELS_PER_UNIT);
+
+        units.clamp(1, self.shared.capacity as u64) as u32
+    }
+
+    /// How long clients should wait before retrying a request that was shed.
+    pub fn retry_after(&self) -> Duration {
+        Duration::from_secs(self.shared.queue_timeout.as_secs_f64().ceil().max(1.0) as u64)
+    }
+
+    /// Wait for `cost` units of capacity on behalf of `requester`, failing if the queue is full or
+    /// the wait outlasts its deadline.
+    pub async fn admit(
+        &self,
+        cost: u32,
+        requester: &Requester,
+    ) -> Result<Permit, TranscodingError> {
+        let cost = cost.clamp(1, self.shared.capacity);
+
+        let mut admitted = {
+            let mut state = self.shared.lock();
+            state.prune();
+
+            let (sender, mut receiver) = oneshot::channel();
+            let arrival = state.arrivals;
+            state.arrivals += 1;
+            state.queue.push(Waiter {
+                cost,
+                requester: requester.clone(),
+                arrival,
+                admitted: sender,
+            });
+            state.grant(&self.shared);
+
+            if let Ok(permit) = receiver.try_recv() {
+                return Ok(permit);
+            }
+
+            if state.queue.len() > self.shared.max_queued {
+                state.queue.retain(|waiter| waiter.arrival != arrival);
+                debug!(cost, queued = state.queue.len(), "shedding a request, the queue is full");
+                return Err(self.overloaded());
+            }
+
+            receiver
+        };
+
+        match tokio::time::timeout(self.shared.queue_timeout, &mut admitted).await {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => {
+                let mut state = self.shared.lock();
+
+                // The capacity may have been handed over just as the deadline passed.
+                if let Ok(permit) = admitted.try_recv() {
+                    return Ok(permit);
+                }
+
+                // Leaving the queue can let the pipelines behind this one start.
+                drop(admitted);
+                state.grant(&self.shared);
+
+                debug!(cost, "shedding a request, it waited too long to be admitted");
+                Err(self.overloaded())
+            }
+        }
+    }
+
+    fn overloaded(&self) -> TranscodingError {
+        METRICS.shed.add(1, &[]);
+
+        TranscodingError::Overloaded { retry_after: self.retry_after() }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("admission state was poisoned")
+    }
+}
+
+impl State {
+    /// Remove waiters that stopped waiting, such as requests whose client disconnected.
+    fn prune(&mut self) {
+        self.queue.retain(|waiter| !waiter.admitted.is_closed());
+    }
+
+    /// The position in the queue of the waiter that should be admitted next.
+    fn head(&self) -> Option<usize> {
+        self.queue
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, waiter)| {
+                let running = self
+                    .running
+                    .get(&waiter.requester.client)
+                    .copied()
+                    .unwrap_or(0);
+
+                (waiter.requester.priority, waiter.cost > SMALL_COST, running, waiter.arrival)
+            })
+            .map(|(index, _)| index)
+    }
+
+    /// Admit waiters from the head of the queue while there is capacity for them, handing each a
+    /// [Permit] for a share of `shared`.
+    fn grant(&mut self, shared: &Arc<Shared>) {
+        self.prune();
+
+        while let Some(index) = self.head() {
+            if self.queue[index].cost > self.available {
+                break;
+            }
+
+            let Waiter { cost, requester, admitted, .. } = self.queue.swap_remove(index);
+            let client = requester.client;
+            self.available -= cost;
+            *self.running.entry(client.clone()).or_default() += cost;
+
+            let permit = Permit { shared: shared.clone(), cost, client };
+            if let Err(mut permit) = admitted.send(permit) {
+                // The waiter stopped waiting since the queue was pruned.
+                permit.release(self);
+            }
+        }
+    }
+}
+
+impl Permit {
+    /// Give the capacity back to `state`, which is already locked.
+    fn release(&mut self, state: &mut State) {
+        state.available += self.cost;
+
+        if let Some(running) = state.running.get_mut(&self.client) {
+            *running -= self.cost;
+            if *running == 0 {
+                state.running.remove(&self.client);
+            }
+        }
+
+        self.cost = 0;
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if self.cost == 0 {
+            return;
+        }
+
+        let shared = self.shared.clone();
+        let mut state = shared.lock();
+        self.release(&mut state);
+        state.grant(&shared);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::FutureExt;
+
+    use super::*;
+
+    fn requester(priority: PriorityClass, client: &str) -> Requester {
+        Requester { priority, client: Some(Arc::from(client)) }
+    }
+
+    #[tokio::test]
+    async fn admits_waiters_in_order_as_capacity_is_released() {
+        let admission = Admission::new(4, 8, Duration::from_secs(5));
+        let anyone = Requester::default();
+
+        let large = admission.admit(3, &anyone).await.unwrap();
+        let mut medium = Box::pin(admission.admit(2, &anyone));
+        assert!((&mut medium).now_or_never().is_none());
+
+        // Requests don't overtake the head of the queue, even when they'd fit.
+        let mut small = Box::pin(admission.admit(1, &anyone));
+        assert!((&mut small).now_or_never().is_none());
+
+        drop(large);
+        let _medium = medium.await.unwrap();
+        let _small = small.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn admits_interactive_and_small_requests_first() {
+        let admission = Admission::new(13, 8, Duration::from_secs(5));
+        let viewer = requester(PriorityClass::Interactive, "viewer");
+        let harvester = requester(PriorityClass::Bulk, "harvester");
+        let browser = requester(PriorityClass::Normal, "browser");
+
+        let running = admission.admit(13, &browser).await.unwrap();
+        let mut render = Box::pin(admission.admit(12, &browser));
+        assert!((&mut render).now_or_never().is_none());
+        let mut download = Box::pin(admission.admit(1, &harvester));
+        assert!((&mut download).now_or_never().is_none());
+        let mut thumbnail = Box::pin(admission.admit(1, &browser));
+        assert!((&mut thumbnail).now_or_never().is_none());
+        let mut tile = Box::pin(admission.admit(1, &viewer));
+        assert!((&mut tile).now_or_never().is_none());
+
+        // The tile and thumbnail leave too little capacity for the render, which the bulk
+        // download then waits behind.
+        drop(running);
+        let _tile = (&mut tile).now_or_never().unwrap().unwrap();
+        let thumbnail = (&mut thumbnail).now_or_never().unwrap().unwrap();
+        assert!((&mut render).now_or_never().is_none());
+        assert!((&mut download).now_or_never().is_none());
+
+        drop(thumbnail);
+        let _render = (&mut render).now_or_never().unwrap().unwrap();
+        assert!((&mut download).now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn shares_capacity_between_clients() {
+        let admission = Admission::new(2, 8, Duration::from_secs(5));
+        let harvester = requester(PriorityClass::Normal, "harvester");
+        let viewer = requester(PriorityClass::Normal, "viewer");
+
+        let first = admission.admit(1, &harvester).await.unwrap();
+        let _second = admission.admit(1, &harvester).await.unwrap();
+        let mut third = Box::pin(admission.admit(1, &harvester));
+        assert!((&mut third).now_or_never().is_none());
+
+        // The viewer arrived later, but isn't using any capacity yet.
+        let mut tile = Box::pin(admission.admit(1, &viewer));
+        assert!((&mut tile).now_or_never().is_none());
+
+        drop(first);
+        let _tile = (&mut tile).now_or_never().unwrap().unwrap();
+        assert!((&mut third).now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn sheds_requests_once_the_queue_is_full() {
+        let admission = Admission::new(1, 1, Duration::from_secs(5));
+        let anyone = Requester::default();
+
+        let _running = admission.admit(1, &anyone).await.unwrap();
+        let mut queued = Box::pin(admission.admit(1, &anyone));
+        assert!((&mut queued).now_or_never().is_none());
+
+        let result = admission.admit(1, &anyone).await;
+        assert!(matches!(result, Err(TranscodingError::Overloaded { .. })));
+
+        // Waiters that give up make room for others.
+        drop(queued);
+        let mut requeued = Box::pin(admission.admit(1, &anyone));
+        assert!((&mut requeued).now_or_never().is_none());
+    }
+
+    #[tokio::test]
+    async fn sheds_requests_that_wait_too_long() {
+        let admission = Admission::new(1, 1, Duration::from_millis(20));
+        let anyone = Requester::default();
+
+        let running = admission.admit(1, &anyone).await.unwrap();
+        let result = admission.admit(1, &anyone).await;
+        assert!(matches!(result, Err(TranscodingError::Overloaded { .. })));
+
+        drop(running);
+        assert!(admission.admit(1, &anyone).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn gives_back_capacity_granted_to_waiters_that_stopped_waiting() {
+        let admission = Admission::new(2, 8, Duration::from_secs(5));
+        let viewer = requester(PriorityClass::Normal, "viewer");
+
+        let running = admission.admit(2, &viewer).await.unwrap();
+        let mut queued = Box::pin(admission.admit(1, &viewer));
+        assert!((&mut queued).now_or_never().is_none());
+
+        // The capacity is granted when the running pipeline finishes, but never received.
+        drop(running);
+        drop(queued);
+
+        let state = admission.shared.lock();
+        assert_eq!(state.available, 2);
+        assert!(state.running.is_empty());
+        drop(state);
+
+        assert!(admission.admit(2, &viewer).now_or_never().unwrap().is_ok());
+    }
+
+    #[test]
+    fn weighs_requests_by_output_size() {
+        let admission = Admission::new(64, 0, Duration::ZERO);
+
+        assert_eq!(admission.cost((256, 256)), 1);
+        assert_eq!(admission.cost((512, 300)), 3);
+        assert_eq!(admission.cost((4000, 3000)), 64);
+    }
+}
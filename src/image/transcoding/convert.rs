@@ -0,0 +1,141 @@
+use bytes::{BufMut, BytesMut};
+
+use crate::image::pixel::{BitDepth, ColorSpace, PixelFormat};
+
+/// Converts packed scanlines from one [PixelFormat] to another.
+///
+/// Grayscale pixels are expanded to RGB by repeating their value, and RGB pixels are reduced to
+/// grayscale by their luma. Alpha channels are discarded when the output has none, and added as
+/// fully opaque when the input has none.
+#[derive(Clone, Copy, Debug)]
+pub struct PixelConverter {
+    input: PixelFormat,
+    output: PixelFormat,
+}
+
+impl PixelConverter {
+    /// Create a converter between two formats, or `None` if no conversion is needed.
+    pub fn new(input: PixelFormat, output: PixelFormat) -> Option<Self> {
+        (input != output).then_some(Self { input, output })
+    }
+
+    /// The size of the output produced for `input` bytes of pixels.
+    pub fn output_len(&self, input: usize) -> usize {
+        input / self.input.bytes_per_pixel() * self.output.bytes_per_pixel()
+    }
+
+    /// Convert whole pixels from `input`, appending them to `output`.
+    pub fn convert(&self, input: &[u8], output: &mut BytesMut) {
+        output.reserve(self.output_len(input.len()));
+
+        for pixel in input.chunks_exact(self.input.bytes_per_pixel()) {
+            let mut samples = [0u16; 4];
+            for (channel, sample) in samples.iter_mut().take(self.input.channels()).enumerate() {
+                *sample = read_sample(pixel, channel, self.input.depth);
+            }
+
+            let (color, alpha) = self.split(&samples);
+            let color = match (self.input.color, self.output.color) {
+                (ColorSpace::Gray, ColorSpace::Rgb) => [color[0]; 3],
+                (ColorSpace::Rgb, ColorSpace::Gray) => [luma(color), 0, 0],
+                _ => color,
+            };
+
+            let channels = match self.output.color {
+                ColorSpace::Gray => &color[..1],
+                ColorSpace::Rgb => &color[..],
+            };
+            for &sample in channels {
+                write_sample(output, sample, self.output.depth);
+            }
+
+            if self.output.alpha {
+                write_sample(output, alpha, self.output.depth);
+            }
+        }
+    }
+
+    /// Split 16-bit input samples into their colour channels and alpha, treating pixels without
+    /// an alpha channel as opaque.
+    fn split(&self, samples: &[u16; 4]) -> ([u16; 3], u16) {
+        let color = match self.input.color {
+            ColorSpace::Gray => 1,
+            ColorSpace::Rgb => 3,
+        };
+        let alpha = if self.input.alpha {
+            samples[color]
+        } else {
+            u16::MAX
+        };
+
+        ([samples[0], samples[1], samples[2]], alpha)
+    }
+}
+
+/// Read a sample from a pixel, widened to 16 bits.
+fn read_sample(pixel: &[u8], channel: usize, depth: BitDepth) -> u16 {
+    match depth {
+        BitDepth::Eight => pixel[channel] as u16 * 257,
+        BitDepth::Sixteen => u16::from_ne_bytes([pixel[channel * 2], pixel[channel * 2 + 1]]),
+    }
+}
+
+/// Write a 16-bit sample, narrowing it to `depth`.
+fn write_sample(output: &mut BytesMut, sample: u16, depth: BitDepth) {
+    match depth {
+        BitDepth::Eight => output.put_u8(((sample as u32 * 255 + 32767) / 65535) as u8),
+        BitDepth::Sixteen => output.put_u16_ne(sample),
+    }
+}
+
+/// The BT.601 luma of an RGB pixel.
+fn luma([r, g, b]: [u16; 3]) -> u16 {
+    ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000) as u16
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn convert(input: PixelFormat, output: PixelFormat, pixels: &[u8]) -> Vec<u8> {
+        let mut buffer = BytesMut::new();
+        PixelConverter::new(input, output)
+            .unwrap()
+            .convert(pixels, &mut buffer);
+
+        buffer.to_vec()
+    }
+
+    #[test]
+    fn skips_identical_formats() {
+        assert!(PixelConverter::new(PixelFormat::RGB8, PixelFormat::RGB8).is_none());
+    }
+
+    #[test]
+    fn converts_between_color_spaces() {
+        assert_eq!(convert(PixelFormat::GRAY8, PixelFormat::RGB8, &[7, 9]), [7, 7, 7, 9, 9, 9]);
+        assert_eq!(
+            convert(PixelFormat::RGB8, PixelFormat::GRAY8, &[255, 255, 255, 0, 0, 0]),
+            [255, 0]
+        );
+    }
+
+    #[test]
+    fn adds_and_removes_alpha() {
+        let gray_alpha = PixelFormat::new(ColorSpace::Gray, true, BitDepth::Eight);
+
+        assert_eq!(convert(gray_alpha, PixelFormat::GRAY8, &[10, 0, 20, 255]), [10, 20]);
+        assert_eq!(convert(PixelFormat::RGB8, PixelFormat::RGBA8, &[1, 2, 3]), [1, 2, 3, 255]);
+    }
+
+    #[test]
+    fn narrows_16_bit_samples() {
+        let gray16 = PixelFormat::new(ColorSpace::Gray, false, BitDepth::Sixteen);
+        let pixels: Vec<u8> = [0u16, 0x8080, u16::MAX]
+            .iter()
+            .flat_map(|sample| sample.to_ne_bytes())
+            .collect();
+
+        assert_eq!(convert(gray16, PixelFormat::GRAY8, &pixels), [0, 128, 255]);
+    }
+}
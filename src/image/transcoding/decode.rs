@@ -1,46 +1,387 @@
-use bytes::{Bytes, BytesMut};
+use std::sync::Arc;
+
+use bytes::BytesMut;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
 use tokio_util::sync::CancellationToken;
-use tracing::warn;
+use tracing::{Span, debug, warn};
+
+use super::convert::PixelConverter;
+use super::encode::{ScanlineFormat, accepted_format};
+use super::pool::BufferPool;
+use super::resample::{Resampler, ResamplingFilter};
+use super::{METRICS, QUEUED_STRIPS, TranscodingError, TranscodingOptions};
+use crate::iiif::Dimension;
+use crate::image::color::{ColorManagement, ColorTransform, IccProfile, profile_color_space};
+use crate::image::info::ImageInfo;
+use crate::image::{AbsoluteRegion, BoxedImage, Dimensions, ImageDecoder};
+
+/// The number of rows in each strip of scanlines sent by decoders of images that aren't tiled.
+const DEFAULT_STRIP_HEIGHT: Dimension = 16;
 
-use super::TranscodingError;
-use crate::iiif::service::ImageParameters;
-use crate::iiif::{Dimension, Region, Scale, Size};
-use crate::image::{AbsoluteRegion, BoxedImage, Dimensions};
+/// The output the decoder has agreed to send, reported once it has opened the region and before
+/// it decodes anything.
+pub struct NegotiatedOutput {
+    pub format: ScanlineFormat,
+    /// The pool that scanline buffers are taken from, and should be recycled to once encoded.
+    pub pool: BufferPool,
+}
+
+/// The region of the source image to decode, and the size and quality to decode it at.
+#[derive(Clone, Copy)]
+pub struct DecodeTarget {
+    pub region: AbsoluteRegion,
+    pub size: Dimensions,
+    /// The most quality layers to decode, for images that have them.
+    pub quality_layers: Option<u16>,
+}
+
+/// A region opened for decoding, along with the stages that adapt its scanlines for the encoder.
+struct Decoding {
+    decoder: Box<dyn ImageDecoder>,
+    transform: Option<ColorTransform>,
+    resampler: Option<Resampler>,
+    converter: Option<PixelConverter>,
+}
 
 pub fn decode_task(
     token: CancellationToken,
-    mut image: BoxedImage,
-    absolute_region: AbsoluteRegion,
-    size: Dimensions,
-    output_channel: Sender<Bytes>,
+    image: BoxedImage,
+    target: DecodeTarget,
+    options: TranscodingOptions,
+    negotiated: oneshot::Sender<Result<NegotiatedOutput, TranscodingError>>,
+    start: oneshot::Receiver<()>,
+    output_channel: Sender<BytesMut>,
 ) -> Result<(), TranscodingError> {
-    let info = image.info();
-    let mut decoder = image.open_region(absolute_region, size);
-    let scanlines = info
-        .tiles
-        .map(|tile| {
-            if tile[0].width < info.width {
-                tile[0].height.unwrap()
-            } else {
-                16
+    let negotiation = negotiate(image, target, options);
+    let (decoding, output) = match negotiation {
+        Ok(negotiation) => negotiation,
+        Err(e) => {
+            // The error is reported to the pipeline, rather than through the encoded stream.
+            let _ = negotiated.send(Err(e));
+            return Ok(());
+        }
+    };
+
+    let Decoding { mut decoder, transform, mut resampler, converter } = decoding;
+    let pool = output.pool.clone();
+    let row_size = target.size.0 as usize * output.format.pixels.bytes_per_pixel();
+    if negotiated.send(Ok(output)).is_err() {
+        warn!("image decoding task was cancelled prematurely");
+        return Ok(());
+    }
+
+    // Decoding only starts once the pipeline is executed, which it may never be.
+    if start.blocking_recv().is_err() {
+        debug!("image transcoding was planned but never executed");
+        return Ok(());
+    }
+
+    let mut rows_sent = 0;
+
+    // Cancellation is checked between strips, so a decoder stops within a strip of the pipeline
+    // being cancelled.
+    while !token.is_cancelled() {
+        let mut buffer = pool.take();
+        if decoder.decode_to(&mut buffer)? {
+            pool.recycle(buffer);
+            break;
+        }
+
+        if let Some(transform) = &transform {
+            transform.apply(&mut buffer);
+        }
+
+        if let Some(resampler) = &mut resampler {
+            let mut resampled = pool.take();
+            resampler.push(&buffer, &mut resampled);
+            pool.recycle(std::mem::replace(&mut buffer, resampled));
+
+            // Reducing can leave a strip without any complete output rows.
+            if buffer.is_empty() {
+                pool.recycle(buffer);
+                continue;
             }
-        })
-        .unwrap();
-    // Process up to 32 scanlines at a time
-    let buffer_capacity = info.width as usize * info.height as usize * 3;
-    let mut buffer = BytesMut::with_capacity(buffer_capacity);
+        }
 
-    while !token.is_cancelled() && !decoder.decode_to(&mut buffer) {
-        let buf = std::mem::replace(&mut buffer, BytesMut::with_capacity(buffer_capacity));
+        if let Some(converter) = &converter {
+            let mut converted = pool.take();
+            converter.convert(&buffer, &mut converted);
+            pool.recycle(std::mem::replace(&mut buffer, converted));
+        }
 
-        if output_channel.blocking_send(buf.freeze()).is_err() {
+        let rows = buffer.len() / row_size;
+        if output_channel.blocking_send(buffer).is_err() {
             warn!("image decoding task was cancelled prematurely");
+            record_cancellation(rows_sent, target.size);
             return Ok(());
         }
+        rows_sent += rows;
     }
 
-    drop(buffer);
+    if token.is_cancelled() {
+        record_cancellation(rows_sent, target.size);
+    }
 
     Ok(())
 }
+
+/// Record the work left undone by a decoder that was cancelled after sending `rows_sent` rows.
+fn record_cancellation(rows_sent: usize, size: Dimensions) {
+    let remaining = (size.1 as usize).saturating_sub(rows_sent);
+    debug!(rows_sent, remaining, "image decoding was cancelled");
+
+    METRICS.abandoned_rows.add(remaining as u64, &[]);
+}
+
+/// Open the region and agree on the format of the scanlines sent to the encoder, checking that
+/// the buffers needed to send them fit within the memory budget.
+fn negotiate(
+    mut image: BoxedImage,
+    target: DecodeTarget,
+    options: TranscodingOptions,
+) -> Result<(Decoding, NegotiatedOutput), TranscodingError> {
+    let DecodeTarget { region: absolute_region, size, quality_layers } = target;
+    let info = image.info()?;
+
+    let (scale_factor, level_size) =
+        decoded_level(&info, &absolute_region, size, options.fast_decoding);
+    let span = Span::current();
+    span.record("scale_factor", scale_factor);
+    if let Some(layers) = quality_layers {
+        span.record("quality_layers", layers);
+    }
+
+    // Decoders average pixels as they decode, which is all a box filter needs. Other filters, and
+    // fast decoding, work from a resolution level of the image decoded without further scaling.
+    let resampled = options.resampling != ResamplingFilter::Box || options.linear_light;
+    let decoded_size = if resampled || options.fast_decoding {
+        level_size
+    } else {
+        size
+    };
+
+    let decoder = match quality_layers {
+        Some(layers) => image.open_region_with_layers(absolute_region, decoded_size, layers)?,
+        None => image.open_region(absolute_region, decoded_size)?,
+    };
+
+    let native_format = decoder.pixel_format();
+    let format = accepted_format(native_format);
+    let converter = PixelConverter::new(native_format, format);
+    debug!(%native_format, %format, converted = converter.is_some(), "negotiated pixel format");
+
+    // Decoders produce a row of tiles, or a batch of rows, with each call, though some produce
+    // more, such as those that decode a whole strip of a TIFF image at once.
+    let strip_height = match info.tiles.as_deref() {
+        Some([tile, ..]) if tile.width < info.width => tile.height.unwrap_or(tile.width),
+        _ => DEFAULT_STRIP_HEIGHT,
+    };
+    let strip_height = decoder
+        .rows_per_decode()
+        .map_or(strip_height, |rows| rows.max(strip_height));
+
+    let (transform, icc_profile) = color_handling(decoder.as_ref(), options.color);
+
+    // Resampling in linear light assumes sRGB samples, which they aren't when their profile is
+    // embedded rather than converted.
+    let linear_light = options.linear_light && icc_profile.is_none();
+    let resampler = (decoded_size != size).then(|| {
+        debug!(?decoded_size, filter = %options.resampling, linear_light, "resampling decoded scanlines");
+        Resampler::new(options.resampling, decoded_size, size, native_format, linear_light)
+    });
+
+    let pixel_size = native_format
+        .bytes_per_pixel()
+        .max(format.bytes_per_pixel());
+    let buffer_size = decoded_size.0.max(size.0) as usize * strip_height as usize * pixel_size;
+
+    // Buffers may be queued for the encoder, being decoded into, being resampled into, being
+    // converted into, and being encoded all at once.
+    let buffers = QUEUED_STRIPS + 2 + resampler.is_some() as usize + converter.is_some() as usize;
+    let resampled_rows = resampler.as_ref().map_or(0, Resampler::buffered_bytes);
+    let working_memory = decoder.working_memory();
+    let required = (buffers * buffer_size + resampled_rows) as u64 + working_memory;
+    debug!(strip_height, buffer_size, working_memory, required, "sized scanline buffers");
+
+    if let Some(budget) = options.memory_budget {
+        if required > budget {
+            return Err(TranscodingError::MemoryBudgetExceeded { required, budget });
+        }
+    }
+
+    let output = NegotiatedOutput {
+        format: ScanlineFormat { pixels: format, icc_profile },
+        pool: BufferPool::new(buffer_size),
+    };
+
+    Ok((Decoding { decoder, transform, resampler, converter }, output))
+}
+
+/// The scale factor and size of the resolution level of the image that `region` is decoded from:
+/// the most reduced level whose copy of the region is still at least as large as `size`, or the
+/// level below that when decoding `fast`.
+fn decoded_level(
+    info: &ImageInfo,
+    region: &AbsoluteRegion,
+    size: Dimensions,
+    fast: bool,
+) -> (u16, Dimensions) {
+    let mut scale_factors = match info.tiles.as_deref() {
+        Some([tile, ..]) if !tile.scale_factors.is_empty() => tile.scale_factors.clone(),
+        _ => vec![1],
+    };
+    scale_factors.sort_unstable();
+
+    let level_size = |factor: u16| {
+        let factor = factor as Dimension;
+        (region.width.div_ceil(factor), region.height.div_ceil(factor))
+    };
+
+    let nearest = scale_factors
+        .iter()
+        .rposition(|&factor| {
+            let (width, height) = level_size(factor);
+            width >= size.0 && height >= size.1
+        })
+        .unwrap_or(0);
+
+    let level = if fast {
+        (nearest + 1).min(scale_factors.len() - 1)
+    } else {
+        nearest
+    };
+
+    let factor = scale_factors[level];
+    (factor, level_size(factor))
+}
+
+/// Decide how the colours described by the decoder's ICC profile are handled, returning either a
+/// transform that converts them to sRGB or a profile to embed in the encoded image.
+///
+/// Profiles that can't be converted are embedded instead, as long as they describe the decoded
+/// pixels.
+fn color_handling(
+    decoder: &dyn ImageDecoder,
+    color: ColorManagement,
+) -> (Option<ColorTransform>, Option<Arc<[u8]>>) {
+    let Some(icc_profile) = decoder.icc_profile() else {
+        return (None, None);
+    };
+
+    let format = decoder.pixel_format();
+    match profile_color_space(&icc_profile) {
+        Ok(Some(space)) if space == format.color => {}
+        Ok(_) => {
+            warn!(%format, "ignoring an embedded ICC profile that doesn't describe the image");
+            return (None, None);
+        }
+        Err(e) => {
+            warn!("ignoring an invalid embedded ICC profile: {e}");
+            return (None, None);
+        }
+    }
+
+    if color == ColorManagement::Preserve {
+        return (None, Some(icc_profile));
+    }
+
+    match IccProfile::parse(&icc_profile)
+        .and_then(|profile| ColorTransform::to_srgb(&profile, format))
+    {
+        Ok(transform) => (Some(transform), None),
+        Err(e) => {
+            warn!("embedding an ICC profile that can't be converted to sRGB: {e}");
+            (None, Some(icc_profile))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use tiff::encoder::TiffEncoder;
+    use tiff::encoder::colortype::RGB8;
+
+    use super::*;
+    use crate::image::ImageReader;
+    use crate::image::codec::TiffImageReader;
+    use crate::image::info::Tile;
+    use crate::storage::FileOrStream;
+
+    /// An RGB TIFF image stored in a single strip, which its decoder decodes whole at once.
+    async fn single_strip_tiff(width: u32, height: u32) -> BoxedImage {
+        let pixels = vec![0; (width * height * 3) as usize];
+
+        let mut data = Cursor::new(vec![]);
+        let mut encoder = TiffEncoder::new(&mut data).unwrap();
+        let mut image = encoder.new_image::<RGB8>(width, height).unwrap();
+        image.rows_per_strip(height).unwrap();
+        image.write_data(&pixels).unwrap();
+
+        let stream = FileOrStream::Stream(Box::new(futures::io::Cursor::new(data.into_inner())));
+        TiffImageReader.read(None, stream).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn sizes_buffers_for_the_rows_decoded_at_once() {
+        let (width, height) = (64, 256);
+        let strip = (width * height * 3) as u64;
+        let target = DecodeTarget {
+            region: AbsoluteRegion { x: 0, y: 0, width, height },
+            size: (width, height),
+            quality_layers: None,
+        };
+
+        let image = single_strip_tiff(width, height).await;
+        let budgeted = single_strip_tiff(width, height).await;
+        tokio::task::spawn_blocking(move || {
+            // The decoder's working memory and buffers for the whole strip don't fit.
+            let options =
+                TranscodingOptions { memory_budget: Some(strip * 3), ..Default::default() };
+            assert!(matches!(
+                negotiate(budgeted, target, options),
+                Err(TranscodingError::MemoryBudgetExceeded { .. })
+            ));
+
+            // Decoding the whole strip fits in a buffer from the pool, which is reused.
+            let (mut decoding, output) =
+                negotiate(image, target, TranscodingOptions::default()).unwrap();
+            let mut buffer = output.pool.take();
+            assert!(!decoding.decoder.decode_to(&mut buffer).unwrap());
+            assert_eq!(buffer.len() as u64, strip);
+
+            let allocation = buffer.as_ptr();
+            output.pool.recycle(buffer);
+            assert_eq!(output.pool.take().as_ptr(), allocation);
+        })
+        .await
+        .unwrap();
+    }
+
+    #[test]
+    fn decodes_the_nearest_level_above_the_output_size() {
+        let info = ImageInfo {
+            width: 2000,
+            height: 2000,
+            max_width: None,
+            max_height: None,
+            max_area: None,
+            sizes: None,
+            tiles: Some(vec![Tile { scale_factors: vec![1, 2, 4, 8], width: 256, height: None }]),
+            preferred_formats: None,
+            rights: None,
+        };
+        let region = AbsoluteRegion { x: 100, y: 100, width: 1000, height: 801 };
+
+        assert_eq!(decoded_level(&info, &region, (300, 200), false), (2, (500, 401)));
+        assert_eq!(decoded_level(&info, &region, (250, 200), false), (4, (250, 201)));
+        assert_eq!(decoded_level(&info, &region, (100, 50), false), (8, (125, 101)));
+        assert_eq!(decoded_level(&info, &region, (2000, 1602), false), (1, (1000, 801)));
+
+        // Fast decoding enlarges the level below, as long as there is one.
+        assert_eq!(decoded_level(&info, &region, (300, 200), true), (4, (250, 201)));
+        assert_eq!(decoded_level(&info, &region, (100, 50), true), (8, (125, 101)));
+    }
+}
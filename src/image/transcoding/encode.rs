@@ -1,25 +1,65 @@
-use bytes::Bytes;
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio_util::sync::CancellationToken;
 
+use super::pool::BufferPool;
 use super::{SenderWriter, TranscodingError};
 use crate::image::Dimensions;
 use crate::image::info::ImageInfo;
+use crate::image::pixel::{ColorSpace, PixelFormat};
+
+/// The layout and colours of the scanlines sent to the encoder, chosen by the decoder before it
+/// sends any scanlines.
+#[derive(Clone, Debug)]
+pub struct ScanlineFormat {
+    pub pixels: PixelFormat,
+    /// An ICC profile describing the colours of the scanlines, to embed in the encoded image.
+    pub icc_profile: Option<Arc<[u8]>>,
+}
+
+/// The format the encoder accepts in place of a decoder's `native` format, which avoids a
+/// conversion when the two are the same.
+///
+/// JPEG has no alpha channel or 16-bit samples, but libjpeg can skip the alpha channel of 8-bit
+/// RGBA pixels itself.
+pub fn accepted_format(native: PixelFormat) -> PixelFormat {
+    match native.color {
+        ColorSpace::Gray => PixelFormat::GRAY8,
+        ColorSpace::Rgb if native.alpha => PixelFormat::RGBA8,
+        ColorSpace::Rgb => PixelFormat::RGB8,
+    }
+}
+
+/// The libjpeg colour space of scanlines in a format returned by [accepted_format].
+fn color_space(format: PixelFormat) -> mozjpeg::ColorSpace {
+    match format.color {
+        ColorSpace::Gray => mozjpeg::ColorSpace::JCS_GRAYSCALE,
+        ColorSpace::Rgb if format.alpha => mozjpeg::ColorSpace::JCS_EXT_RGBA,
+        ColorSpace::Rgb => mozjpeg::ColorSpace::JCS_EXT_RGB,
+    }
+}
 
 pub fn encode_task(
     cancellation_token: CancellationToken,
     output_size: Dimensions,
-    mut input_channel: Receiver<Bytes>,
+    input_format: ScanlineFormat,
+    pool: BufferPool,
+    mut input_channel: Receiver<BytesMut>,
     output_channel: Sender<Bytes>,
     info: ImageInfo,
 ) -> Result<(), TranscodingError> {
     std::panic::catch_unwind(move || {
         let (width, height) = output_size;
-        let mut compressor = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_EXT_RGB);
+        let mut compressor = mozjpeg::Compress::new(color_space(input_format.pixels));
         compressor.set_size(width as usize, height as usize);
 
         let writer = SenderWriter::new(output_channel);
         let mut output = compressor.start_compress(writer)?;
+        if let Some(icc_profile) = &input_format.icc_profile {
+            output.write_icc_profile(icc_profile);
+        }
 
         loop {
             if cancellation_token.is_cancelled() {
@@ -28,11 +68,18 @@ pub fn encode_task(
 
             if let Some(input) = input_channel.blocking_recv() {
                 output.write_scanlines(&input[..])?;
+                pool.recycle(input);
             } else {
                 break;
             }
         }
 
+        // The decoder stops sending scanlines when the pipeline is cancelled, which shouldn't be
+        // mistaken for the end of the image.
+        if cancellation_token.is_cancelled() {
+            return Ok(());
+        }
+
         output.finish()?;
 
         Ok(())
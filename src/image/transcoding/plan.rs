@@ -0,0 +1,317 @@
+use mediatype::MediaTypeBuf;
+use mediatype::names::{IMAGE, JPEG};
+
+use super::TranscodingError;
+use crate::iiif::service::ImageParameters;
+use crate::iiif::{Dimension, Format, Quality, Region, Rotation, Scale, Size};
+use crate::image::info::ImageInfo;
+use crate::image::{AbsoluteRegion, Dimensions};
+
+/// The encoders that can produce the images requested from a pipeline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoder {
+    MozJpeg,
+}
+
+impl Encoder {
+    /// The encoder that produces images in the requested `format`, if there is one.
+    fn for_format(format: Format) -> Option<Encoder> {
+        match format {
+            Format::Jpg => Some(Encoder::MozJpeg),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoder::MozJpeg => "mozjpeg",
+        }
+    }
+
+    pub fn media_type(self) -> MediaTypeBuf {
+        match self {
+            Encoder::MozJpeg => MediaTypeBuf::new(IMAGE, JPEG),
+        }
+    }
+}
+
+/// The image a request asks for, resolved against the source image before any of it is decoded.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutputPlan {
+    /// The region of the source image, clipped to its bounds.
+    pub region: AbsoluteRegion,
+    /// The size the region is scaled to.
+    pub size: Dimensions,
+    pub encoder: Encoder,
+}
+
+/// Resolve the region, size and encoder of a request, checking that the output is within the
+/// limits the source image advertises.
+pub fn plan_output(
+    params: &ImageParameters,
+    info: &ImageInfo,
+) -> Result<OutputPlan, TranscodingError> {
+    let encoder = Encoder::for_format(params.format).ok_or_else(|| {
+        TranscodingError::Unsupported(format!("images can't be encoded as {}", params.format))
+    })?;
+
+    if params.rotation != Rotation::new(0.0) {
+        return Err(TranscodingError::Unsupported(format!(
+            "images can't be rotated by {}",
+            params.rotation
+        )));
+    }
+
+    if !matches!(params.quality, Quality::Default | Quality::Color) {
+        return Err(TranscodingError::Unsupported(format!(
+            "images can't be produced in {} quality",
+            params.quality
+        )));
+    }
+
+    let region = resolve_region(&params.region, info)?;
+    let size = resolve_size(params.size, &region, info)?;
+
+    Ok(OutputPlan { region, size, encoder })
+}
+
+fn resolve_region(region: &Region, info: &ImageInfo) -> Result<AbsoluteRegion, TranscodingError> {
+    let (x, y, width, height) = match *region {
+        Region::Full => (0, 0, info.width, info.height),
+        Region::Square => {
+            let side = info.width.min(info.height);
+            ((info.width - side) / 2, (info.height - side) / 2, side, side)
+        }
+        Region::Absolute { x, y, width, height } => (x, y, width, height),
+        Region::Percentage { x, y, width, height } => {
+            let scale = |percent: f32, extent: Dimension| {
+                (percent as f64 / 100.0 * extent as f64).round() as Dimension
+            };
+
+            (
+                scale(x, info.width),
+                scale(y, info.height),
+                scale(width, info.width),
+                scale(height, info.height),
+            )
+        }
+    };
+
+    if x >= info.width || y >= info.height {
+        return Err(TranscodingError::InvalidRequest(format!(
+            "the region starts at ({x}, {y}), outside the {}x{} image",
+            info.width, info.height
+        )));
+    }
+
+    // Regions that extend past the edges of the image are cropped to them.
+    let width = width.min(info.width - x);
+    let height = height.min(info.height - y);
+    if width == 0 || height == 0 {
+        return Err(TranscodingError::InvalidRequest("the region is empty".to_string()));
+    }
+
+    Ok(AbsoluteRegion { x, y, width, height })
+}
+
+fn resolve_size(
+    size: Size,
+    region: &AbsoluteRegion,
+    info: &ImageInfo,
+) -> Result<Dimensions, TranscodingError> {
+    let (region_width, region_height) = (region.width as f64, region.height as f64);
+    let scaled = |extent: f64, factor: f64| (extent * factor).round() as Dimension;
+
+    let (width, height) = match size.scale() {
+        Scale::Max => {
+            // The largest size allowed, which is the region itself unless the image is limited
+            // or, with ^, as large as its limits allow. Rounding down keeps the size within the
+            // limits.
+            let factor = max_scale(region, info);
+            let factor = if size.upscale() && is_limited(info) {
+                factor
+            } else {
+                factor.min(1.0)
+            };
+            let floored = |extent: f64| ((extent * factor).floor() as Dimension).max(1);
+            (floored(region_width), floored(region_height))
+        }
+        Scale::Percentage(percent) => {
+            let factor = percent as f64 / 100.0;
+            (scaled(region_width, factor), scaled(region_height, factor))
+        }
+        Scale::FixedWidth(width) => {
+            (width.get(), scaled(region_height, width.get() as f64 / region_width))
+        }
+        Scale::FixedHeight(height) => {
+            (scaled(region_width, height.get() as f64 / region_height), height.get())
+        }
+        Scale::Fixed { width, height } => (width.get(), height.get()),
+        Scale::AspectPreserving { width, height } => {
+            let factor =
+                (width.get() as f64 / region_width).min(height.get() as f64 / region_height);
+            (scaled(region_width, factor), scaled(region_height, factor))
+        }
+    };
+
+    if width == 0 || height == 0 {
+        return Err(TranscodingError::InvalidRequest(format!(
+            "the region scales to an empty {width}x{height} image"
+        )));
+    }
+
+    if !size.upscale() && (width > region.width || height > region.height) {
+        return Err(TranscodingError::InvalidRequest(format!(
+            "a {width}x{height} image is larger than the {}x{} region, which needs a size \
+             prefixed with ^",
+            region.width, region.height
+        )));
+    }
+
+    let (max_width, max_height, max_area) = limits(info);
+    if width > max_width || height > max_height || width as u64 * height as u64 > max_area {
+        return Err(TranscodingError::LimitExceeded(format!(
+            "a {width}x{height} image is over the limits of the source image"
+        )));
+    }
+
+    Ok((width, height))
+}
+
+/// The largest factor that `region` can be scaled by within the limits of the image.
+fn max_scale(region: &AbsoluteRegion, info: &ImageInfo) -> f64 {
+    let (max_width, max_height, max_area) = limits(info);
+    let (width, height) = (region.width as f64, region.height as f64);
+
+    (max_width as f64 / width)
+        .min(max_height as f64 / height)
+        .min((max_area as f64 / (width * height)).sqrt())
+}
+
+/// Whether the image advertises any limit on the size it can be scaled to.
+fn is_limited(info: &ImageInfo) -> bool {
+    info.max_width.is_some() || info.max_height.is_some() || info.max_area.is_some()
+}
+
+/// The largest width, height and area that images can be scaled to. An image that only limits
+/// its width limits its height to the same size.
+fn limits(info: &ImageInfo) -> (Dimension, Dimension, u64) {
+    let max_width = info.max_width.unwrap_or(Dimension::MAX);
+    let max_height = info.max_height.or(info.max_width).unwrap_or(Dimension::MAX);
+    let max_area = info.max_area.map_or(u64::MAX, u64::from);
+
+    (max_width, max_height, max_area)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::iiif::ImageServiceRequest;
+    use crate::iiif::service::ImageServiceRequestKind;
+
+    fn info(width: Dimension, height: Dimension) -> ImageInfo {
+        ImageInfo {
+            width,
+            height,
+            max_width: None,
+            max_height: None,
+            max_area: None,
+            sizes: None,
+            tiles: None,
+            preferred_formats: None,
+            rights: None,
+        }
+    }
+
+    fn plan(request: &str, info: &ImageInfo) -> Result<OutputPlan, TranscodingError> {
+        let request: ImageServiceRequest = request.parse().unwrap();
+        let ImageServiceRequestKind::Image(params) = request.kind else {
+            panic!("expected an image request");
+        };
+
+        plan_output(&params, info)
+    }
+
+    fn region_and_size(request: &str, info: &ImageInfo) -> ((Dimension, Dimension), Dimensions) {
+        let plan = plan(request, info).unwrap();
+        ((plan.region.x, plan.region.y), (plan.size.0, plan.size.1))
+    }
+
+    #[test]
+    fn resolves_regions_and_sizes() {
+        let info = info(4000, 3000);
+
+        assert_eq!(region_and_size("i/full/max/0/default.jpg", &info), ((0, 0), (4000, 3000)));
+        assert_eq!(region_and_size("i/square/300,/0/default.jpg", &info), ((500, 0), (300, 300)));
+        assert_eq!(
+            region_and_size("i/pct:50,50,50,50/pct:10/0/default.jpg", &info),
+            ((2000, 1500), (200, 150))
+        );
+        assert_eq!(
+            region_and_size("i/1000,1000,2000,1000/,250/0/default.jpg", &info),
+            ((1000, 1000), (500, 250))
+        );
+        assert_eq!(region_and_size("i/full/!400,400/0/default.jpg", &info), ((0, 0), (400, 300)));
+
+        // Regions are cropped to the edges of the image.
+        let plan = plan("i/3000,2000,2000,2000/max/0/default.jpg", &info).unwrap();
+        assert_eq!(plan.size, (1000, 1000));
+    }
+
+    #[test]
+    fn rejects_requests_that_cant_be_produced() {
+        let info = info(4000, 3000);
+
+        for request in [
+            "i/4000,0,10,10/max/0/default.jpg",
+            "i/full/8000,/0/default.jpg",
+            "i/full/max/0/default.png",
+        ] {
+            assert!(plan(request, &info).is_err(), "{request}");
+        }
+
+        for request in [
+            "i/full/max/90/default.jpg",
+            "i/full/max/!0/default.jpg",
+            "i/full/max/0/gray.jpg",
+            "i/full/max/0/bitonal.jpg",
+        ] {
+            assert!(
+                matches!(plan(request, &info), Err(TranscodingError::Unsupported(_))),
+                "{request}"
+            );
+        }
+
+        assert!(plan("i/full/max/0/color.jpg", &info).is_ok());
+
+        assert!(plan("i/full/^8000,/0/default.jpg", &info).is_ok());
+    }
+
+    #[test]
+    fn respects_the_limits_of_the_image() {
+        let info = ImageInfo { max_width: Some(1000), ..info(4000, 3000) };
+
+        assert_eq!(plan("i/full/max/0/default.jpg", &info).unwrap().size, (1000, 750));
+        assert!(matches!(
+            plan("i/full/2000,/0/default.jpg", &info),
+            Err(TranscodingError::LimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn upscales_to_the_limits_of_the_image_with_caret_max() {
+        let limited =
+            ImageInfo { max_width: Some(1000), max_area: Some(300_000), ..info(400, 300) };
+
+        assert_eq!(plan("i/full/max/0/default.jpg", &limited).unwrap().size, (400, 300));
+        assert_eq!(plan("i/full/^max/0/default.jpg", &limited).unwrap().size, (632, 474));
+
+        // Without limits, the region is never enlarged.
+        assert_eq!(
+            plan("i/full/^max/0/default.jpg", &info(400, 300))
+                .unwrap()
+                .size,
+            (400, 300)
+        );
+    }
+}
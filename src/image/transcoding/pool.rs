@@ -0,0 +1,74 @@
+use std::sync::{Arc, Mutex};
+
+use bytes::BytesMut;
+
+/// A pool of strip-sized scanline buffers, recycled between the decoder and the encoder.
+///
+/// Buffers are only allocated when none are waiting to be reused, so a request allocates as many
+/// buffers as are in flight between its tasks at once, however large its output is.
+#[derive(Clone)]
+pub struct BufferPool {
+    buffers: Arc<Mutex<Vec<BytesMut>>>,
+    buffer_size: usize,
+}
+
+impl BufferPool {
+    pub fn new(buffer_size: usize) -> Self {
+        Self { buffers: Arc::default(), buffer_size }
+    }
+
+    /// Take an empty buffer from the pool, allocating a new one if none are free.
+    pub fn take(&self) -> BytesMut {
+        self.buffers
+            .lock()
+            .expect("buffer pool was poisoned")
+            .pop()
+            .unwrap_or_else(|| BytesMut::with_capacity(self.buffer_size))
+    }
+
+    /// Return a buffer to the pool once its contents have been consumed.
+    pub fn recycle(&self, mut buffer: BytesMut) {
+        buffer.clear();
+
+        // Buffers that have been split or shrunk can't hold a whole strip any more, and those that
+        // grew past a strip would hold on to more memory than the budget allows for.
+        if buffer.capacity() == self.buffer_size {
+            self.buffers
+                .lock()
+                .expect("buffer pool was poisoned")
+                .push(buffer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BufMut;
+
+    use super::*;
+
+    #[test]
+    fn reuses_recycled_buffers() {
+        let pool = BufferPool::new(64);
+
+        let mut buffer = pool.take();
+        buffer.put_slice(&[1; 32]);
+        let allocation = buffer.as_ptr();
+        pool.recycle(buffer);
+
+        let buffer = pool.take();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), allocation);
+    }
+
+    #[test]
+    fn drops_buffers_that_grew_past_a_strip() {
+        let pool = BufferPool::new(64);
+
+        let mut buffer = pool.take();
+        buffer.put_slice(&[1; 256]);
+        pool.recycle(buffer);
+
+        assert!(pool.buffers.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::fmt::Display;
+use std::str::FromStr;
+
+use bytes::BytesMut;
+
+use crate::image::Dimensions;
+use crate::image::color::{SrgbCurve, read_sample, write_sample};
+use crate::image::pixel::{ColorSpace, PixelFormat};
+
+/// The filter used to resample decoded scanlines to the requested size.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResamplingFilter {
+    /// Average the source pixels covered by each output pixel. Decoders average pixels as they
+    /// decode, so this needs no separate resampling stage unless it works in linear light.
+    #[default]
+    Box,
+
+    /// The Mitchell-Netravali cubic filter, which balances sharpness against ringing.
+    Mitchell,
+
+    /// A three-lobed Lanczos filter, which keeps the most detail at the cost of some ringing
+    /// around hard edges.
+    Lanczos3,
+}
+
+impl ResamplingFilter {
+    /// The distance from its centre, in source pixels at the output scale, beyond which the filter
+    /// has no weight.
+    fn support(self) -> f32 {
+        match self {
+            ResamplingFilter::Box => 0.5,
+            ResamplingFilter::Mitchell => 2.0,
+            ResamplingFilter::Lanczos3 => 3.0,
+        }
+    }
+
+    fn weight(self, x: f32) -> f32 {
+        let x = x.abs();
+
+        match self {
+            ResamplingFilter::Box if x <= 0.5 => 1.0,
+            ResamplingFilter::Mitchell if x < 1.0 => {
+                (7.0 * x.powi(3) - 12.0 * x.powi(2) + 16.0 / 3.0) / 6.0
+            }
+            ResamplingFilter::Mitchell if x < 2.0 => {
+                (-7.0 / 3.0 * x.powi(3) + 12.0 * x.powi(2) - 20.0 * x + 32.0 / 3.0) / 6.0
+            }
+            ResamplingFilter::Lanczos3 if x < 3.0 => sinc(x) * sinc(x / 3.0),
+            _ => 0.0,
+        }
+    }
+}
+
+impl FromStr for ResamplingFilter {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "box" => Ok(ResamplingFilter::Box),
+            "mitchell" => Ok(ResamplingFilter::Mitchell),
+            "lanczos3" => Ok(ResamplingFilter::Lanczos3),
+            _ => Err(format!("unknown resampling filter: {value}")),
+        }
+    }
+}
+
+impl Display for ResamplingFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ResamplingFilter::Box => write!(f, "box"),
+            ResamplingFilter::Mitchell => write!(f, "mitchell"),
+            ResamplingFilter::Lanczos3 => write!(f, "lanczos3"),
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// The source pixels that contribute to an output pixel, and how much each contributes.
+#[derive(Debug)]
+struct Contribution {
+    start: usize,
+    weights: Vec<f32>,
+}
+
+impl Contribution {
+    fn end(&self) -> usize {
+        self.start + self.weights.len()
+    }
+}
+
+/// The contributions of `input` source pixels to each of `output` pixels along one axis.
+fn contributions(filter: ResamplingFilter, input: u32, output: u32) -> Vec<Contribution> {
+    let scale = input as f32 / output as f32;
+    // When reducing, the filter is stretched to cover every source pixel under an output pixel.
+    let filter_scale = scale.max(1.0);
+    let support = filter.support() * filter_scale;
+
+    (0..output)
+        .map(|index| {
+            let centre = (index as f32 + 0.5) * scale;
+            let start = ((centre - support).floor().max(0.0) as usize).min(input as usize - 1);
+            let end = ((centre + support).ceil() as usize).clamp(start + 1, input as usize);
+
+            let mut weights: Vec<f32> = (start..end)
+                .map(|source| filter.weight((source as f32 + 0.5 - centre) / filter_scale))
+                .collect();
+
+            // Weights that fall outside the image are dropped, and the rest rescaled to compensate.
+            let total: f32 = weights.iter().sum();
+            if total != 0.0 {
+                weights.iter_mut().for_each(|weight| *weight /= total);
+            }
+
+            Contribution { start, weights }
+        })
+        .collect()
+}
+
+/// Resamples scanlines to a fixed output size as they are decoded, using a separable
+/// [ResamplingFilter].
+///
+/// Each source row is resampled horizontally as it is pushed, and kept only until every output
+/// row it contributes to has been written. Colours are premultiplied by alpha while they are
+/// filtered, and can be filtered in linear light rather than in the sRGB encoded values.
+pub struct Resampler {
+    format: PixelFormat,
+    input_width: usize,
+    columns: Vec<Contribution>,
+    rows: Vec<Contribution>,
+    space: FilterSpace,
+    /// Source rows, resampled horizontally, that later output rows still need.
+    window: VecDeque<Vec<f32>>,
+    /// The index of the source row at the front of the window.
+    window_start: usize,
+    input_row: usize,
+    output_row: usize,
+    /// Samples of the source row being pushed, in the space they're filtered in.
+    scratch: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(
+        filter: ResamplingFilter,
+        input: Dimensions,
+        output: Dimensions,
+        format: PixelFormat,
+        linear_light: bool,
+    ) -> Self {
+        Self {
+            format,
+            input_width: input.0 as usize,
+            columns: contributions(filter, input.0, output.0),
+            rows: contributions(filter, input.1, output.1),
+            space: FilterSpace {
+                color_channels: match format.color {
+                    ColorSpace::Gray => 1,
+                    ColorSpace::Rgb => 3,
+                },
+                curve: linear_light.then(SrgbCurve::new),
+            },
+            window: VecDeque::new(),
+            window_start: 0,
+            input_row: 0,
+            output_row: 0,
+            scratch: vec![0.0; input.0 as usize * format.channels()],
+        }
+    }
+
+    /// The most memory, in bytes, held by the resampler between pushes.
+    pub fn buffered_bytes(&self) -> usize {
+        let taps = self
+            .rows
+            .iter()
+            .map(|row| row.weights.len())
+            .max()
+            .unwrap_or(0);
+        let row_size = self.columns.len() * self.format.channels() * size_of::<f32>();
+
+        taps * row_size + self.scratch.len() * size_of::<f32>()
+    }
+
+    /// Push whole source rows, writing any output rows they complete to `output`.
+    pub fn push(&mut self, input: &[u8], output: &mut BytesMut) {
+        let row_size = self.input_width * self.format.bytes_per_pixel();
+
+        for row in input.chunks_exact(row_size) {
+            self.push_row(row, output);
+        }
+    }
+
+    fn push_row(&mut self, row: &[u8], output: &mut BytesMut) {
+        if self.output_row >= self.rows.len() {
+            return;
+        }
+
+        let channels = self.format.channels();
+        let sample_size = self.format.bytes_per_sample();
+        for (pixel, samples) in row
+            .chunks_exact(self.format.bytes_per_pixel())
+            .zip(self.scratch.chunks_exact_mut(channels))
+        {
+            for (channel, sample) in samples.iter_mut().enumerate() {
+                *sample = read_sample(&pixel[channel * sample_size..], self.format.depth);
+            }
+
+            self.space.enter(samples);
+        }
+
+        let mut resampled = vec![0.0; self.columns.len() * channels];
+        for (samples, column) in resampled.chunks_exact_mut(channels).zip(&self.columns) {
+            for (offset, weight) in column.weights.iter().enumerate() {
+                let source = (column.start + offset) * channels;
+
+                for (sample, value) in samples.iter_mut().zip(&self.scratch[source..]) {
+                    *sample += weight * value;
+                }
+            }
+        }
+
+        self.window.push_back(resampled);
+        self.input_row += 1;
+
+        while self.output_row < self.rows.len()
+            && self.rows[self.output_row].end() <= self.input_row
+        {
+            self.write_row(output);
+            self.output_row += 1;
+
+            let needed = self
+                .rows
+                .get(self.output_row)
+                .map_or(self.input_row, |row| row.start);
+            while self.window_start < needed && !self.window.is_empty() {
+                self.window.pop_front();
+                self.window_start += 1;
+            }
+        }
+    }
+
+    fn write_row(&self, output: &mut BytesMut) {
+        let channels = self.format.channels();
+        let sample_size = self.format.bytes_per_sample();
+        let contribution = &self.rows[self.output_row];
+
+        let mut samples = vec![0.0; self.columns.len() * channels];
+        for (offset, weight) in contribution.weights.iter().enumerate() {
+            let row = &self.window[contribution.start + offset - self.window_start];
+
+            for (sample, value) in samples.iter_mut().zip(row) {
+                *sample += weight * value;
+            }
+        }
+
+        let mut pixel = vec![0; self.format.bytes_per_pixel()];
+        for samples in samples.chunks_exact_mut(channels) {
+            self.space.leave(samples);
+
+            for (channel, sample) in samples.iter().enumerate() {
+                write_sample(
+                    &mut pixel[channel * sample_size..],
+                    sample.clamp(0.0, 1.0),
+                    self.format.depth,
+                );
+            }
+
+            output.extend_from_slice(&pixel);
+        }
+    }
+}
+
+/// The space samples are filtered in: premultiplied by alpha, and optionally in linear light.
+struct FilterSpace {
+    /// The number of colour channels in each pixel, which are followed by any alpha channel.
+    color_channels: usize,
+    curve: Option<SrgbCurve>,
+}
+
+impl FilterSpace {
+    /// Convert the samples of a decoded pixel to the space they are filtered in.
+    fn enter(&self, samples: &mut [f32]) {
+        let (color, alpha) = samples.split_at_mut(self.color_channels);
+
+        for sample in color.iter_mut() {
+            if let Some(curve) = &self.curve {
+                *sample = curve.linearize(*sample);
+            }
+
+            if let Some(alpha) = alpha.first() {
+                *sample *= alpha;
+            }
+        }
+    }
+
+    /// Convert the samples of a filtered pixel back to the space they were decoded in.
+    fn leave(&self, samples: &mut [f32]) {
+        let (color, alpha) = samples.split_at_mut(self.color_channels);
+
+        for sample in color.iter_mut() {
+            match alpha.first() {
+                Some(&alpha) if alpha > 0.0 => *sample /= alpha,
+                Some(_) => *sample = 0.0,
+                None => {}
+            }
+
+            if let Some(curve) = &self.curve {
+                *sample = curve.encode(sample.clamp(0.0, 1.0));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn resample(
+        filter: ResamplingFilter,
+        input: &[u8],
+        from: Dimensions,
+        to: Dimensions,
+        linear_light: bool,
+    ) -> Vec<u8> {
+        let mut resampler = Resampler::new(filter, from, to, PixelFormat::GRAY8, linear_light);
+        let mut output = BytesMut::new();
+
+        // Rows are pushed one at a time, as a decoder producing single-row strips would.
+        for row in input.chunks_exact(from.0 as usize) {
+            resampler.push(row, &mut output);
+        }
+
+        output.to_vec()
+    }
+
+    #[test]
+    fn keeps_uniform_images_uniform() {
+        for filter in [
+            ResamplingFilter::Box,
+            ResamplingFilter::Mitchell,
+            ResamplingFilter::Lanczos3,
+        ] {
+            let output = resample(filter, &[100; 64], (8, 8), (3, 3), false);
+            assert_eq!(output, [100; 9], "{filter} filter");
+
+            let output = resample(filter, &[100; 4], (2, 2), (5, 5), false);
+            assert_eq!(output, [100; 25], "{filter} filter");
+        }
+    }
+
+    #[test]
+    fn filters_in_linear_light() {
+        let checkerboard = [0, 255, 255, 0];
+
+        assert_eq!(resample(ResamplingFilter::Box, &checkerboard, (2, 2), (1, 1), false), [128]);
+        assert_eq!(resample(ResamplingFilter::Box, &checkerboard, (2, 2), (1, 1), true), [188]);
+    }
+
+    #[test]
+    fn premultiplies_alpha() {
+        let format = PixelFormat::new(ColorSpace::Gray, true, crate::image::pixel::BitDepth::Eight);
+        let mut resampler = Resampler::new(ResamplingFilter::Box, (2, 1), (1, 1), format, false);
+        let mut output = BytesMut::new();
+
+        // The colour of a fully transparent pixel doesn't bleed into its neighbours.
+        resampler.push(&[200, 255, 0, 0], &mut output);
+        assert_eq!(output[..], [200, 128]);
+    }
+}
@@ -0,0 +1,4 @@
+pub mod iiif;
+pub mod image;
+pub mod storage;
+pub mod telemetry;
@@ -1,25 +1,43 @@
-pub mod iiif;
-pub mod image;
 pub mod runtime;
-pub mod storage;
-pub mod telemetry;
 
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::process::ExitCode;
 use std::time::Duration;
 
 use byte_unit::Byte;
 use clap::Parser;
+use color_eyre::eyre::OptionExt;
 use hyper::body::Incoming;
-use hyper::header::{AUTHORIZATION, COOKIE};
+use hyper::header::{AUTHORIZATION, COOKIE, HeaderName};
 use hyper::{Request, Response};
 use hyper_util::service::TowerToHyperService;
-use iiif::http::HttpImageService;
-use iiif::service::ImageService;
+#[cfg(feature = "kaduceus")]
 use kaduceus::KakaduContext;
-use opendal::services::Fs;
+use laya::iiif::cache::DerivativeCache;
+use laya::iiif::http::HttpImageService;
+use laya::iiif::service::ImageService;
+use laya::image::cache::ImageCache;
+#[cfg(feature = "kaduceus")]
+use laya::image::codec::KaduceusImageReader;
+#[cfg(all(feature = "openjp2", not(feature = "kaduceus")))]
+use laya::image::codec::OpenJpegImageReader;
+use laya::image::codec::{
+    CompositeImageReader, JpegImageReader, PngImageReader, SourceFormat, TiffImageReader,
+};
+use laya::image::color::ColorManagement;
+use laya::image::transcoding::admission::{Admission, PriorityClass};
+use laya::image::transcoding::resample::ResamplingFilter;
+use laya::storage::StorageProvider;
+use laya::storage::cache::CachingStorageProvider;
+use laya::storage::containment::ContainedStorageProvider;
+use laya::storage::http::{HttpStorageOptions, HttpStorageProvider};
+use laya::storage::layout::{LayoutStorageProvider, StorageLayout};
+use laya::storage::local::LocalStorageProvider;
+#[cfg(feature = "opendal")]
+use laya::storage::opendal::{OpenDalStorageProvider, S3Credentials, S3Options};
+use laya::telemetry;
 use opentelemetry_http::HeaderExtractor;
-use storage::opendal::OpenDalStorageProvider;
 use tower::ServiceBuilder;
 use tower_http::sensitive_headers::SetSensitiveRequestHeadersLayer;
 use tower_http::timeout::TimeoutLayer;
@@ -32,7 +50,7 @@ use tracing_opentelemetry_instrumentation_sdk::http::{
     http_flavor, http_host, http_method, url_scheme, user_agent,
 };
 
-use crate::image::codec::KaduceusImageReader;
+use crate::runtime::Shutdown;
 
 #[derive(Clone, Default, Debug, clap::ValueEnum)]
 pub enum Runtime {
@@ -75,6 +93,12 @@ pub struct LayaOptions {
     #[arg(long, default_missing_value("true"))]
     dev: bool,
 
+    /// Specifies the number of seconds in-flight requests are given to finish after SIGTERM or
+    /// SIGINT, once the server stops accepting connections. The server exits with status 3 if any
+    /// are still running when it passes.
+    #[arg(long("shutdown-timeout"), default_value("25"))]
+    shutdown_timeout_secs: u64,
+
     #[command(flatten)]
     tokio_options: TokioRuntimeOptions,
 
@@ -83,23 +107,160 @@ pub struct LayaOptions {
 
     #[command(flatten)]
     storage_options: StorageOptions,
+
+    #[command(flatten)]
+    derivative_cache_options: DerivativeCacheOptions,
+
+    #[command(flatten)]
+    scheduling_options: SchedulingOptions,
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct DerivativeCacheOptions {
+    /// Specifies the maximum total size of encoded images cached in memory.
+    /// Accepts human-readable formats such as "256 MiB" or "1GB". A size of 0 disables the
+    /// memory cache. Images larger than 16 MiB are only cached on disk.
+    #[arg(
+        long("derivative-cache-memory-size"),
+        default_value("0"),
+        help_heading("Derivative Cache")
+    )]
+    memory_size: Byte,
+
+    /// A local directory used to cache encoded images.
+    /// If not specified, encoded images are not cached on disk.
+    #[arg(long("derivative-cache-path"), help_heading("Derivative Cache"))]
+    path: Option<PathBuf>,
+
+    /// Specifies the maximum total size of encoded images cached on disk.
+    #[arg(
+        long("derivative-cache-disk-size"),
+        default_value("10 GiB"),
+        help_heading("Derivative Cache")
+    )]
+    disk_size: Byte,
+}
+
+impl DerivativeCacheOptions {
+    fn cache(&self) -> color_eyre::Result<Option<DerivativeCache>> {
+        if self.memory_size.as_u64() == 0 && self.path.is_none() {
+            return Ok(None);
+        }
+
+        let cache = DerivativeCache::new(self.memory_size.as_u64());
+        Ok(Some(match &self.path {
+            Some(path) => cache.with_disk_tier(path.clone(), self.disk_size.as_u64())?,
+            None => cache,
+        }))
+    }
 }
 
 #[derive(clap::Args, Clone, Debug)]
 pub struct ImageDecoderOptions {
+    /// Specifies the number of source images whose info and parsed headers are kept in memory
+    /// for reuse by later requests. A value of 0 disables the cache.
+    #[arg(
+        long("image-cache-entries"),
+        default_value("256"),
+        help_heading("Image Cache")
+    )]
+    image_cache_entries: usize,
+
+    /// Specifies the number of opened source images kept idle by the image cache, across all of
+    /// its entries. Each holds open the file or connection it was read from.
+    #[arg(
+        long("image-cache-open-images"),
+        default_value("64"),
+        help_heading("Image Cache")
+    )]
+    image_cache_open_images: usize,
+
+    /// Embeds the ICC profiles of source images in encoded images, rather than converting their
+    /// colours to sRGB.
+    #[arg(long, default_missing_value("true"), help_heading("Color Management"))]
+    preserve_color_profiles: bool,
+
+    /// Specifies the most memory a single image request may use for buffering decoded scanlines.
+    /// Requests that need more are rejected before decoding starts. Accepts human-readable
+    /// formats such as "256 MiB" or "1GB".
+    #[arg(
+        long("decode-memory-budget"),
+        default_value("256 MiB"),
+        help_heading("Image Decoder")
+    )]
+    memory_budget: Byte,
+
+    /// Specifies the filter used to resample decoded images to the requested size: "box",
+    /// "mitchell" or "lanczos3". Filters other than "box" decode the nearest resolution level
+    /// above the requested size and resample it, which is slower but sharper.
+    #[arg(long, default_value("box"), help_heading("Resampling"))]
+    resampling_filter: ResamplingFilter,
+
+    /// Resamples images in linear light rather than as their sRGB encoded values, which keeps the
+    /// brightness of fine detail when reducing images.
+    #[arg(long, default_missing_value("true"), help_heading("Resampling"))]
+    resample_in_linear_light: bool,
+
+    /// Decodes images one resolution level below the requested size and enlarges them, which is
+    /// faster but less sharp. Intended for interactive deep zoom viewers.
+    #[arg(long, default_missing_value("true"), help_heading("Resampling"))]
+    fast_decoding: bool,
+
+    /// Specifies the most quality layers decoded for previews and thumbnails: images no larger
+    /// than --preview-max-size pixels on either side. Every layer is decoded if not specified.
+    /// Only available when JPEG 2000 images are decoded with OpenJPEG, as Kakadu decodes every
+    /// layer.
+    #[arg(long, help_heading("Quality Layers"))]
+    preview_quality_layers: Option<u16>,
+
+    /// Specifies the largest width or height, in pixels, of images that count as previews.
+    #[arg(long, default_value("256"), help_heading("Quality Layers"))]
+    preview_max_size: u32,
+
+    /// Lets clients limit the quality layers decoded for an image request with the non-standard
+    /// `layers` query parameter, such as `/full/max/0/default.jpg?layers=2`. Only available when
+    /// JPEG 2000 images are decoded with OpenJPEG, as Kakadu decodes every layer.
+    #[arg(long, default_missing_value("true"), help_heading("Quality Layers"))]
+    allow_quality_layers_parameter: bool,
+
+    /// Specifies how much decoding and encoding may run at once, counted in 256x256 tiles. Each
+    /// image request takes one unit for every 65,536 pixels of its output, so a full-size render
+    /// takes as much as many tiles.
+    #[arg(long, default_value("64"), help_heading("Admission Control"))]
+    decode_capacity: u32,
+
+    /// Specifies how many image requests may wait for decoding capacity. Requests beyond it are
+    /// rejected with 503 Service Unavailable.
+    #[arg(long, default_value("256"), help_heading("Admission Control"))]
+    decode_queue_size: usize,
+
+    /// Specifies the number of seconds an image request may wait for decoding capacity before it
+    /// is rejected with 503 Service Unavailable.
+    #[arg(
+        long("decode-queue-timeout"),
+        default_value("5"),
+        help_heading("Admission Control")
+    )]
+    decode_queue_timeout_secs: u64,
+
+    #[cfg(feature = "kaduceus")]
     #[command(flatten)]
     kakadu: KakaduOptions,
 }
 
+#[cfg(feature = "kaduceus")]
 #[derive(clap::Args, Clone, Debug)]
 pub struct KakaduOptions {
-    /// Specifies the number of threads used to run image decoding operations.
-    /// If not specified, defaults to the number of available CPU cores.
+    /// Specifies the number of threads used to open images, and the number of regions decoded at
+    /// once. If not specified, defaults to the number of available CPU cores.
     #[arg(long("kakadu-decoder-threads"), help_heading("Kakadu"))]
     decoder_threads: Option<usize>,
 
-    /// Specifies the amount of memory allocated to Kakadu in units of bytes.
-    /// Accepts human-readable formats such as "50 MB", "1024 MiB", or "1GB".
+    /// Specifies the working memory of Kakadu decoders in units of bytes, estimated from the
+    /// regions they decode and shared between every request. The estimate bounds, rather than
+    /// caps, the memory Kakadu allocates. Requests that would exceed it are rejected with 503
+    /// Service Unavailable until others finish. Accepts human-readable
+    /// formats such as "50 MB", "1024 MiB", or "1GB".
     ///
     /// CAUTION: Failing to specify this option may result in unbounded memory
     /// usage and potential application crashes during image decoding.
@@ -117,6 +278,335 @@ pub struct StorageOptions {
         help_heading("Storage")
     )]
     fs_storage_path: PathBuf,
+
+    /// Refuses to open image files that are reached through symbolic links pointing outside
+    /// of the filesystem storage path.
+    #[arg(
+        long("fs-deny-symlink-escape"),
+        default_missing_value("true"),
+        help_heading("Storage")
+    )]
+    fs_deny_symlink_escape: bool,
+
+    /// Specifies how image identifiers are mapped onto paths within storage.
+    #[arg(long("storage-layout"), default_value("flat"), help_heading("Storage"))]
+    storage_layout: StorageLayoutKind,
+
+    /// Specifies the backend that image files are read from.
+    #[arg(long("storage-backend"), default_value("fs"), help_heading("Storage"))]
+    storage_backend: StorageBackendKind,
+
+    #[cfg(feature = "opendal")]
+    #[command(flatten)]
+    s3: S3StorageOptions,
+
+    #[command(flatten)]
+    http: HttpOriginOptions,
+
+    #[command(flatten)]
+    source_cache: SourceCacheOptions,
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct SourceCacheOptions {
+    /// A local directory used to cache image files read from remote storage backends.
+    /// If not specified, remote image files are not cached.
+    #[arg(long("source-cache-path"), help_heading("Source Cache"))]
+    path: Option<PathBuf>,
+
+    /// Specifies the maximum total size of cached image files.
+    /// Accepts human-readable formats such as "500 MB" or "10GiB".
+    #[arg(
+        long("source-cache-size"),
+        default_value("10 GiB"),
+        help_heading("Source Cache")
+    )]
+    size: Byte,
+
+    /// Specifies the size of the largest image file that is cached. Larger files are always read
+    /// from remote storage. Accepts human-readable formats such as "500 MB" or "1GiB".
+    #[arg(
+        long("source-cache-max-entry-size"),
+        default_value("1 GiB"),
+        help_heading("Source Cache")
+    )]
+    max_entry_size: Byte,
+}
+
+impl SourceCacheOptions {
+    /// Wrap a remote storage backend in the source cache, if one is configured.
+    fn wrap<S: StorageProvider + 'static>(
+        &self,
+        remote: S,
+    ) -> color_eyre::Result<Box<dyn StorageProvider>> {
+        Ok(match &self.path {
+            Some(path) => Box::new(
+                CachingStorageProvider::new(remote, path.clone(), self.size.as_u64())?
+                    .with_max_entry_size(self.max_entry_size.as_u64()),
+            ),
+            None => Box::new(remote),
+        })
+    }
+}
+
+#[derive(Clone, Default, Debug, clap::ValueEnum)]
+pub enum StorageBackendKind {
+    /// Image files are read from the local filesystem beneath `--fs-storage-path`.
+    #[default]
+    Fs,
+
+    /// Image files are read from S3 or an S3-compatible service.
+    #[cfg(feature = "opendal")]
+    S3,
+
+    /// Image files are read from a web server using HTTP range requests.
+    Http,
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct HttpOriginOptions {
+    /// The URL that image identifiers are mapped to. The template must contain {id}, which is
+    /// replaced by the percent-encoded identifier, or {path}, which preserves '/' separators.
+    #[arg(long("http-url-template"), help_heading("HTTP Storage"))]
+    url_template: Option<String>,
+
+    /// Specifies the number of seconds allowed for a single request to the origin server.
+    #[arg(
+        long("http-timeout"),
+        default_value("10"),
+        help_heading("HTTP Storage")
+    )]
+    timeout_secs: u64,
+
+    /// Specifies the number of times a request that failed with a transient error is retried.
+    #[arg(long("http-retries"), default_value("3"), help_heading("HTTP Storage"))]
+    retries: u32,
+
+    /// Specifies the minimum amount of data fetched by each range request.
+    /// Accepts human-readable formats such as "512 KiB" or "1MB".
+    #[arg(
+        long("http-read-ahead"),
+        default_value("1 MiB"),
+        help_heading("HTTP Storage")
+    )]
+    read_ahead: Byte,
+}
+
+impl HttpOriginOptions {
+    fn options(&self) -> color_eyre::Result<HttpStorageOptions> {
+        Ok(HttpStorageOptions {
+            url_template: self
+                .url_template
+                .clone()
+                .ok_or_eyre("--http-url-template is required for HTTP storage")?,
+            timeout: Duration::from_secs(self.timeout_secs),
+            retries: self.retries,
+            read_ahead: self.read_ahead.as_u64(),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(feature = "opendal")]
+#[derive(clap::Args, Clone, Debug)]
+pub struct S3StorageOptions {
+    /// The endpoint of the S3 service (e.g. http://localhost:9000 for a local MinIO server).
+    /// If not specified, the AWS endpoint for the configured region is used.
+    #[arg(long("s3-endpoint"), help_heading("S3 Storage"))]
+    endpoint: Option<String>,
+
+    /// The region that S3 buckets are located in.
+    #[arg(long("s3-region"), help_heading("S3 Storage"))]
+    region: Option<String>,
+
+    /// The bucket that image identifiers are resolved against. Identifiers in the form of
+    /// s3://bucket/key can address objects in it or in buckets allowed by --s3-allow-bucket.
+    #[arg(long("s3-bucket"), help_heading("S3 Storage"))]
+    bucket: Option<String>,
+
+    /// Allows identifiers in the form of s3://bucket/key to address objects in another bucket.
+    /// May be repeated. Objects in buckets that aren't allowed are not found.
+    #[arg(long("s3-allow-bucket"), help_heading("S3 Storage"))]
+    allowed_buckets: Vec<String>,
+
+    /// A key prefix prepended to image identifiers resolved against the default bucket.
+    #[arg(long("s3-prefix"), help_heading("S3 Storage"))]
+    prefix: Option<String>,
+
+    /// Specifies where the credentials used to sign S3 requests are obtained from.
+    #[arg(
+        long("s3-credentials"),
+        default_value("default"),
+        help_heading("S3 Storage")
+    )]
+    credentials: S3CredentialSource,
+
+    /// The access key ID used when static credentials are selected.
+    #[arg(
+        long("s3-access-key-id"),
+        env("LAYA_S3_ACCESS_KEY_ID"),
+        help_heading("S3 Storage")
+    )]
+    access_key_id: Option<String>,
+
+    /// The secret access key used when static credentials are selected.
+    #[arg(
+        long("s3-secret-access-key"),
+        env("LAYA_S3_SECRET_ACCESS_KEY"),
+        hide_env_values(true),
+        help_heading("S3 Storage")
+    )]
+    secret_access_key: Option<String>,
+
+    /// An optional session token used when static credentials are selected.
+    #[arg(
+        long("s3-session-token"),
+        env("LAYA_S3_SESSION_TOKEN"),
+        hide_env_values(true),
+        help_heading("S3 Storage")
+    )]
+    session_token: Option<String>,
+
+    /// Uses path-style addressing (https://endpoint/bucket/key) instead of virtual-hosted-style
+    /// addressing. This is typically required by MinIO and other S3-compatible services.
+    #[arg(
+        long("s3-path-style"),
+        default_missing_value("true"),
+        help_heading("S3 Storage")
+    )]
+    path_style: bool,
+}
+
+#[cfg(feature = "opendal")]
+#[derive(Clone, Default, Debug, clap::ValueEnum)]
+pub enum S3CredentialSource {
+    /// Credentials are loaded from the environment, AWS configuration files, web identity tokens
+    /// or instance metadata.
+    #[default]
+    Default,
+
+    /// Credentials are provided by --s3-access-key-id and --s3-secret-access-key.
+    Static,
+
+    /// Requests are not signed.
+    Anonymous,
+}
+
+#[cfg(feature = "opendal")]
+impl S3StorageOptions {
+    fn options(&self) -> color_eyre::Result<S3Options> {
+        let credentials = match self.credentials {
+            S3CredentialSource::Default => S3Credentials::Default,
+            S3CredentialSource::Anonymous => S3Credentials::Anonymous,
+            S3CredentialSource::Static => S3Credentials::Static {
+                access_key_id: self
+                    .access_key_id
+                    .clone()
+                    .ok_or_eyre("--s3-access-key-id is required for static credentials")?,
+                secret_access_key: self
+                    .secret_access_key
+                    .clone()
+                    .ok_or_eyre("--s3-secret-access-key is required for static credentials")?,
+                session_token: self.session_token.clone(),
+            },
+        };
+
+        Ok(S3Options {
+            endpoint: self.endpoint.clone(),
+            region: self.region.clone(),
+            bucket: self.bucket.clone(),
+            allowed_buckets: self.allowed_buckets.clone(),
+            prefix: self.prefix.clone(),
+            credentials,
+            path_style: self.path_style,
+        })
+    }
+}
+
+#[derive(Clone, Default, Debug, clap::ValueEnum)]
+pub enum StorageLayoutKind {
+    /// Identifiers are used as paths verbatim.
+    #[default]
+    Flat,
+
+    /// Identifiers are mapped to paths using pairtree encoding.
+    Pairtree,
+
+    /// Identifiers name OCFL objects that are located using pairtree encoding, optionally
+    /// followed by a version such as ";v2".
+    Ocfl,
+}
+
+impl StorageOptions {
+    fn layout(&self) -> StorageLayout {
+        match self.storage_layout {
+            StorageLayoutKind::Flat => StorageLayout::Flat,
+            StorageLayoutKind::Pairtree => StorageLayout::Pairtree,
+            StorageLayoutKind::Ocfl => StorageLayout::Ocfl,
+        }
+    }
+
+    fn provider(&self) -> color_eyre::Result<Box<dyn StorageProvider>> {
+        let backend: Box<dyn StorageProvider> = match self.storage_backend {
+            StorageBackendKind::Fs => {
+                let storage = LocalStorageProvider::new(self.fs_storage_path.clone());
+                let storage = if self.fs_deny_symlink_escape {
+                    storage.with_symlink_containment()?
+                } else {
+                    storage
+                };
+
+                Box::new(ContainedStorageProvider::new(storage))
+            }
+            #[cfg(feature = "opendal")]
+            StorageBackendKind::S3 => Box::new(ContainedStorageProvider::new(
+                self.source_cache
+                    .wrap(OpenDalStorageProvider::s3(self.s3.options()?)?)?,
+            )),
+            StorageBackendKind::Http => Box::new(ContainedStorageProvider::new(
+                self.source_cache
+                    .wrap(HttpStorageProvider::new(self.http.options()?)?)?,
+            )),
+        };
+
+        Ok(Box::new(LayoutStorageProvider::new(backend, self.layout())))
+    }
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct SchedulingOptions {
+    /// Assigns a priority class ("interactive", "normal" or "bulk") to image requests for
+    /// identifiers starting with a prefix, given as CLASS=PREFIX (e.g. "bulk=harvest/"). May be
+    /// repeated, and the longest matching prefix wins.
+    #[arg(
+        long("priority-prefix"),
+        value_parser(parse_priority_prefix),
+        help_heading("Scheduling")
+    )]
+    priority_prefixes: Vec<(PriorityClass, String)>,
+
+    /// Lets requests choose their priority class with a header (e.g. "X-Request-Priority"). Only
+    /// enable this behind a proxy that sets or strips the header.
+    #[arg(long, help_heading("Scheduling"))]
+    priority_header: Option<HeaderName>,
+
+    /// Identifies clients by an address in a header (e.g. "X-Forwarded-For") rather than the
+    /// address they connect from, when the server is behind a proxy.
+    #[arg(long, help_heading("Scheduling"))]
+    client_header: Option<HeaderName>,
+
+    /// Specifies the number of trusted proxies that append an address to --client-header. Clients
+    /// are identified by the address added by the outermost of them, ignoring any they sent.
+    #[arg(long, default_value("1"), help_heading("Scheduling"))]
+    client_header_trusted_proxies: usize,
+}
+
+fn parse_priority_prefix(value: &str) -> Result<(PriorityClass, String), String> {
+    let (priority, prefix) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected CLASS=PREFIX, found {value}"))?;
+
+    Ok((priority.parse()?, prefix.to_string()))
 }
 
 #[derive(clap::Args, Clone, Debug)]
@@ -136,20 +626,113 @@ pub struct TokioRuntimeOptions {
     io_threads: usize,
 }
 
-fn main() -> color_eyre::Result<()> {
+#[cfg(not(any(feature = "kaduceus", feature = "openjp2")))]
+compile_error!("either the \"kaduceus\" or \"openjp2\" feature must be enabled to decode images");
+
+/// The exit status when in-flight requests were abandoned at shutdown.
+const EXIT_ABANDONED: u8 = 3;
+
+fn main() -> color_eyre::Result<ExitCode> {
     color_eyre::install()?;
 
     let options = LayaOptions::parse();
     let telemetry = telemetry::install_telemetry_collector(options.disable_opentelemetry);
 
-    let kdu_context = KakaduContext::default();
-    let kdu_image_reader = KaduceusImageReader::new(kdu_context);
+    // Kakadu is preferred when it is available, falling back to OpenJPEG otherwise.
+    #[cfg(feature = "kaduceus")]
+    let jpeg2000_reader = {
+        let decoder_options = &options.image_decoder_options;
+        // Kakadu decodes every layer, so the limits would be accepted but never applied.
+        if decoder_options.preview_quality_layers.is_some()
+            || decoder_options.allow_quality_layers_parameter
+        {
+            color_eyre::eyre::bail!(
+                "--preview-quality-layers and --allow-quality-layers-parameter need OpenJPEG, but \
+                 JPEG 2000 images are decoded with Kakadu, which decodes every layer"
+            );
+        }
+
+        let kakadu = &decoder_options.kakadu;
+        let reader = match kakadu.decoder_threads {
+            Some(threads) => {
+                KaduceusImageReader::new_with_decoder_threads(KakaduContext::default(), threads)
+            }
+            None => KaduceusImageReader::new(KakaduContext::default()),
+        };
+
+        match kakadu.memory_limit {
+            Some(limit) => reader.with_memory_limit(limit.as_u64()),
+            None => reader,
+        }
+    };
+    #[cfg(all(feature = "openjp2", not(feature = "kaduceus")))]
+    let jpeg2000_reader = OpenJpegImageReader;
+
+    let image_reader = CompositeImageReader::new()
+        .with_reader(SourceFormat::Jpeg2000, jpeg2000_reader)
+        .with_reader(SourceFormat::Tiff, TiffImageReader)
+        .with_reader(SourceFormat::Jpeg, JpegImageReader)
+        .with_reader(SourceFormat::Png, PngImageReader);
+
+    let storage = options.storage_options.provider()?;
+    let mut image_service = ImageService::new(storage, image_reader);
+
+    let image_cache_entries = options.image_decoder_options.image_cache_entries;
+    if image_cache_entries > 0 {
+        let open_images = options.image_decoder_options.image_cache_open_images;
+        image_service =
+            image_service.with_image_cache(ImageCache::new(image_cache_entries, open_images));
+    }
 
-    let image_service = ImageService::new(
-        OpenDalStorageProvider::new(options.storage_options.fs_storage_path.clone()),
-        kdu_image_reader,
+    image_service =
+        image_service.with_memory_budget(options.image_decoder_options.memory_budget.as_u64());
+
+    image_service = image_service.with_resampling(
+        options.image_decoder_options.resampling_filter,
+        options.image_decoder_options.resample_in_linear_light,
     );
-    let http_service = HttpImageService::new_with_prefix(image_service, &options.prefix);
+
+    if options.image_decoder_options.fast_decoding {
+        image_service = image_service.with_fast_decoding();
+    }
+
+    if let Some(layers) = options.image_decoder_options.preview_quality_layers {
+        image_service = image_service
+            .with_preview_layers(options.image_decoder_options.preview_max_size, layers);
+    }
+
+    image_service = image_service.with_admission(Admission::new(
+        options.image_decoder_options.decode_capacity,
+        options.image_decoder_options.decode_queue_size,
+        Duration::from_secs(options.image_decoder_options.decode_queue_timeout_secs),
+    ));
+
+    for (priority, prefix) in &options.scheduling_options.priority_prefixes {
+        image_service = image_service.with_priority_prefix(prefix.clone(), *priority);
+    }
+
+    if options.image_decoder_options.preserve_color_profiles {
+        image_service = image_service.with_color_management(ColorManagement::Preserve);
+    }
+
+    if let Some(cache) = options.derivative_cache_options.cache()? {
+        image_service = image_service.with_derivative_cache(cache);
+    }
+
+    let mut http_service = HttpImageService::new_with_prefix(image_service, &options.prefix);
+    if let Some(header) = &options.scheduling_options.priority_header {
+        http_service = http_service.with_priority_header(header.clone());
+    }
+
+    if let Some(header) = &options.scheduling_options.client_header {
+        let trusted_proxies = options.scheduling_options.client_header_trusted_proxies;
+        http_service = http_service.with_client_header(header.clone(), trusted_proxies);
+    }
+
+    if options.image_decoder_options.allow_quality_layers_parameter {
+        http_service = http_service.with_quality_layers_parameter();
+    }
+
     let tower_service = ServiceBuilder::new()
         .layer(SetSensitiveRequestHeadersLayer::new([AUTHORIZATION, COOKIE]))
         .layer(
@@ -191,7 +774,7 @@ fn main() -> color_eyre::Result<()> {
 
     let hyper_service = TowerToHyperService::new(tower_service);
 
-    match options.runtime {
+    let result = match options.runtime {
         #[cfg(all(feature = "rt-glommio", target_os = "linux"))]
         Runtime::Glommio => {
             todo!()
@@ -199,9 +782,13 @@ fn main() -> color_eyre::Result<()> {
 
         #[cfg(feature = "rt-tokio")]
         Runtime::Tokio => runtime::tokio::serve(options, hyper_service),
-    }
+    };
 
+    // Telemetry is flushed however the server stopped, including the spans of drained requests.
     telemetry.shutdown(Duration::from_secs(5));
 
-    Ok(())
+    match result? {
+        Shutdown::Drained => Ok(ExitCode::SUCCESS),
+        Shutdown::Abandoned { .. } => Ok(ExitCode::from(EXIT_ABANDONED)),
+    }
 }
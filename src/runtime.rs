@@ -3,3 +3,13 @@ pub mod glommio;
 
 #[cfg(feature = "rt-tokio")]
 pub mod tokio;
+
+/// How the server stopped after being asked to shut down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shutdown {
+    /// Every in-flight request finished before the drain deadline.
+    Drained,
+    /// The drain deadline passed, or a second signal cut the drain short, before every
+    /// in-flight request finished. Holds the number of connections open when draining started.
+    Abandoned { connections: usize },
+}
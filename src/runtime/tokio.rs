@@ -1,19 +1,37 @@
 use std::error::Error;
 use std::fmt::Display;
+use std::pin::pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use http_body_util::combinators::BoxBody;
 use hyper::body::{Bytes, Incoming};
-use hyper::service::Service;
+use hyper::service::{Service, service_fn};
 use hyper::{Request, Response};
 use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::GracefulShutdown;
+use laya::iiif::http::ClientAddress;
 use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::signal::unix::{SignalKind, signal};
 use tower_http::classify::{NeverClassifyEos, ServerErrorsFailureClass};
 use tower_http::trace::ResponseBody;
-use tracing::info;
+use tracing::{debug, info, warn};
 
+use super::Shutdown;
 use crate::LayaOptions;
 
-pub fn serve<S, E>(options: LayaOptions, service: S)
+/// The delay before accepting connections again after an error, doubling with each error in a
+/// row up to [MAX_ACCEPT_BACKOFF].
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Serve HTTP requests with `service` until SIGTERM or SIGINT is received, then stop accepting
+/// connections, ask clients to close the ones they have (sending HTTP/2 GOAWAY), and wait up to
+/// the shutdown timeout for their in-flight requests to finish.
+pub fn serve<S, E>(options: LayaOptions, service: S) -> std::io::Result<Shutdown>
 where
     S: Service<
             Request<Incoming>,
@@ -33,26 +51,117 @@ where
         .build()
         .expect("failed to create HTTP runtime");
 
-    let result: Result<_, std::io::Error> = rt.block_on(async move {
+    let shutdown_timeout = Duration::from_secs(options.shutdown_timeout_secs);
+    let result = rt.block_on(async move {
         info!("Starting HTTP server");
         let listener = TcpListener::bind(options.bind_address).await?;
         info!("Listening on {:?}", options.bind_address);
 
+        let builder = Builder::new(TokioExecutor::new());
+        let graceful = GracefulShutdown::new();
+        // The connections still open, counted separately as the graceful shutdown is consumed
+        // while draining them.
+        let open = Arc::new(AtomicUsize::new(0));
+        let mut signal = pin!(shutdown_signal());
+
+        let mut backoff = MIN_ACCEPT_BACKOFF;
+
         loop {
-            let (stream, _addr) = listener.accept().await?;
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = &mut signal => break,
+            };
+
+            // Errors accepting a connection, such as running out of file descriptors, are
+            // usually transient, so accepting is retried after a growing delay.
+            let (stream, address) = match accepted {
+                Ok(accepted) => {
+                    backoff = MIN_ACCEPT_BACKOFF;
+                    accepted
+                }
+                Err(e) => {
+                    warn!(?backoff, "unable to accept a connection: {e}");
+                    tokio::select! {
+                        _ = tokio::time::sleep(backoff) => {}
+                        _ = &mut signal => break,
+                    }
+
+                    backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+                    continue;
+                }
+            };
+
             let io = TokioIo::new(stream);
             let service = service.clone();
-            let handler = async move {
-                hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
-                    .serve_connection(io, service)
-                    .await
-            };
+            // Requests carry the address of their client, which identifies it to the scheduler.
+            let service = service_fn(move |mut req: Request<Incoming>| {
+                req.extensions_mut().insert(ClientAddress(address));
+                service.call(req)
+            });
+            let connection = graceful.watch(builder.serve_connection(io, service).into_owned());
+
+            open.fetch_add(1, Ordering::Relaxed);
+            let open = open.clone();
+            tokio::spawn(async move {
+                if let Err(e) = connection.await {
+                    debug!("connection closed with an error: {e}");
+                }
 
-            tokio::spawn(handler);
+                open.fetch_sub(1, Ordering::Relaxed);
+            });
         }
 
-        Ok(())
+        drop(listener);
+        info!(
+            connections = open.load(Ordering::Relaxed),
+            ?shutdown_timeout,
+            "no longer accepting connections, draining requests"
+        );
+
+        // Watched connections finish their in-flight requests and close, rather than being
+        // dropped with the runtime.
+        let drained = tokio::select! {
+            _ = graceful.shutdown() => true,
+            _ = tokio::time::sleep(shutdown_timeout) => false,
+            _ = shutdown_signal() => false,
+        };
+
+        if drained {
+            info!("drained every connection");
+            Ok(Shutdown::Drained)
+        } else {
+            let connections = open.load(Ordering::Relaxed);
+            warn!(connections, "abandoning connections that didn't drain in time");
+            Ok(Shutdown::Abandoned { connections })
+        }
     });
 
-    result.expect("failed to bind HTTP server")
+    // Decoders abandoned with their connections stop within a strip, so they aren't waited on.
+    rt.shutdown_timeout(Duration::from_secs(1));
+
+    result
+}
+
+/// Wait for the process to be asked to stop, by SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("unable to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("received SIGINT, shutting down"),
+        _ = terminate => info!("received SIGTERM, shutting down"),
+    }
 }
@@ -3,22 +3,36 @@ use std::fmt::Display;
 use std::future::Future;
 use std::path::Path;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::time::SystemTime;
 
 use futures::{AsyncRead, AsyncSeek};
-use kaduceus::AsyncSeekableRead;
 
+pub mod cache;
+pub mod containment;
+pub mod http;
+pub mod layout;
+pub mod local;
+#[cfg(feature = "opendal")]
 pub mod opendal;
 
+/// An asynchronous stream that can also seek, used to read source images.
+pub trait AsyncSeekableRead: AsyncRead + AsyncSeek {}
+
+impl<T: AsyncRead + AsyncSeek> AsyncSeekableRead for T {}
+
 pub type FileStreamProvider = Box<dyn FnOnce(&Path) -> Box<dyn AsyncSeekableRead> + Send>;
 
 /// An object stored by a storage provider.
 ///
 /// This structure represents a stored object with optional metadata
-/// such as a name and last modification time, along with its contents.
+/// such as a name, last modification time and entity tag, along with its contents.
 pub struct StorageObject {
     pub name: Option<String>,
     pub last_modified: Option<SystemTime>,
+    pub etag: Option<String>,
+    /// The size of the contents in bytes, if the provider knows it without reading them.
+    pub size: Option<u64>,
     pub content: FileOrStream,
 }
 
@@ -43,6 +57,10 @@ impl<T: StorageProvider> StorageProvider for Box<T> {
     ) -> Pin<Box<dyn Future<Output = Result<StorageObject, StorageError>> + Send>> {
         T::open(self, id)
     }
+
+    fn healthcheck(&self) -> Pin<Box<dyn Future<Output = Result<(), StorageError>> + Send>> {
+        T::healthcheck(self)
+    }
 }
 
 impl StorageProvider for Box<dyn StorageProvider> {
@@ -52,12 +70,19 @@ impl StorageProvider for Box<dyn StorageProvider> {
     ) -> Pin<Box<dyn Future<Output = Result<StorageObject, StorageError>> + Send>> {
         <dyn StorageProvider>::open(self, id)
     }
+
+    fn healthcheck(&self) -> Pin<Box<dyn Future<Output = Result<(), StorageError>> + Send>> {
+        <dyn StorageProvider>::healthcheck(self)
+    }
 }
 
 /// A path to a file encapsulated with a factory method that can provide an asynchronous stream if
 /// necessary.
 pub struct FileStream {
     pub path: Box<Path>,
+    /// The file, as opened by the storage provider. Decoders should read it through this handle
+    /// rather than opening `path` again, as whatever `path` leads to may have changed since.
+    pub file: Arc<std::fs::File>,
     pub stream_factory: FileStreamProvider,
 }
 
@@ -90,20 +115,32 @@ impl FileOrStream {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum StorageError {
     AccessDenied,
     NotFound,
+    InvalidIdentifier(String),
     Other(String),
 }
 
 impl Error for StorageError {}
 
+impl From<std::io::Error> for StorageError {
+    fn from(value: std::io::Error) -> Self {
+        match value.kind() {
+            std::io::ErrorKind::NotFound => StorageError::NotFound,
+            std::io::ErrorKind::PermissionDenied => StorageError::AccessDenied,
+            _ => StorageError::Other(value.to_string()),
+        }
+    }
+}
+
 impl Display for StorageError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             StorageError::AccessDenied => write!(f, "access was denied"),
             StorageError::NotFound => write!(f, "data could not be found"),
+            StorageError::InvalidIdentifier(reason) => write!(f, "invalid identifier: {reason}"),
             StorageError::Other(reason) => write!(f, "other: {reason}"),
         }
     }
@@ -0,0 +1,695 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::future::Future;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use futures::AsyncReadExt;
+use opentelemetry::KeyValue;
+use opentelemetry::metrics::{Counter, UpDownCounter};
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+use super::local::PositionalFileReader;
+use super::{
+    AsyncSeekableRead, FileOrStream, FileStream, StorageError, StorageObject, StorageProvider,
+};
+
+/// The extension of files holding the contents of cached objects.
+const ENTRY_EXTENSION: &str = "source";
+
+/// The extension of files holding the contents of objects that are still being downloaded.
+const PARTIAL_EXTENSION: &str = "partial";
+
+/// A [StorageProvider] that keeps copies of objects from a remote provider in a size-bounded
+/// directory on local disk.
+///
+/// Every request still opens the object from the remote provider so that its ETag and
+/// modification time can be compared against the cached copy, but the contents are only
+/// downloaded when the object is first seen or has changed. Cached objects are returned as
+/// [FileOrStream::File], so decoders can read them with positional reads rather than making many
+/// small ranged requests against the remote.
+///
+/// Requests that miss the cache are served from the remote while the object is downloaded in the
+/// background, so they don't wait for the whole object to arrive. Objects without an ETag or
+/// modification time can't be validated, and objects larger than the limit on a single entry
+/// aren't worth the space, so both bypass the cache.
+pub struct CachingStorageProvider {
+    inner: Arc<dyn StorageProvider>,
+    cache: Arc<SourceCache>,
+}
+
+impl CachingStorageProvider {
+    /// Cache objects from `inner` in `directory`, keeping at most `capacity` bytes on disk. Any
+    /// object may fill the cache, unless limited with [Self::with_max_entry_size].
+    ///
+    /// The index of cached objects is held in memory, so any entries left behind by a previous
+    /// process are removed.
+    pub fn new<S: StorageProvider + 'static>(
+        inner: S,
+        directory: PathBuf,
+        capacity: u64,
+    ) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&directory)?;
+
+        for entry in std::fs::read_dir(&directory)? {
+            let path = entry?.path();
+            let extension = path.extension().and_then(|extension| extension.to_str());
+
+            if matches!(extension, Some(ENTRY_EXTENSION | PARTIAL_EXTENSION)) {
+                std::fs::remove_file(&path)?;
+            }
+        }
+
+        info!(directory = %directory.display(), capacity, "using source cache");
+
+        Ok(Self {
+            inner: Arc::new(inner),
+            cache: Arc::new(SourceCache {
+                directory,
+                capacity,
+                max_entry_size: capacity,
+                state: Mutex::new(CacheState::default()),
+                fills: Mutex::new(HashSet::new()),
+                downloads: AtomicU64::new(0),
+                metrics: CacheMetrics::new(),
+            }),
+        })
+    }
+
+    /// Only cache objects of at most `size` bytes, reading larger ones from the remote every time.
+    pub fn with_max_entry_size(mut self, size: u64) -> Self {
+        let cache = Arc::get_mut(&mut self.cache).expect("the cache is only shared once opened");
+        cache.max_entry_size = size.min(cache.capacity);
+
+        self
+    }
+}
+
+impl StorageProvider for CachingStorageProvider {
+    fn open(
+        &self,
+        id: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<StorageObject, StorageError>> + Send>> {
+        Box::pin(open(self.inner.clone(), self.cache.clone(), id.to_string()))
+    }
+
+    fn healthcheck(&self) -> Pin<Box<dyn Future<Output = Result<(), StorageError>> + Send>> {
+        self.inner.healthcheck()
+    }
+}
+
+#[tracing::instrument(skip(inner, cache), err)]
+async fn open(
+    inner: Arc<dyn StorageProvider>,
+    cache: Arc<SourceCache>,
+    id: String,
+) -> Result<StorageObject, StorageError> {
+    let object = inner.open(&id).await?;
+    let validator = Validator::of(&object);
+
+    // Local files are already as fast to read as a cached copy would be.
+    if !validator.is_usable() || matches!(object.content, FileOrStream::File(_)) {
+        cache.metrics.record_lookup("bypass");
+        return Ok(object);
+    }
+
+    if let Some(entry) = cache.lookup(&id, &validator) {
+        match entry.open(object.name.clone(), &validator).await {
+            Ok(cached) => {
+                cache.metrics.record_lookup("hit");
+                return Ok(cached);
+            }
+            Err(error) => {
+                warn!(%error, "cached source is no longer readable");
+                cache.remove(&id);
+            }
+        }
+    }
+
+    if object.size.is_some_and(|size| size > cache.max_entry_size) {
+        cache.metrics.record_lookup("bypass");
+        return Ok(object);
+    }
+
+    cache.metrics.record_lookup("miss");
+    cache.fill(inner, id, validator);
+
+    Ok(object)
+}
+
+struct SourceCache {
+    directory: PathBuf,
+    capacity: u64,
+    /// The size of the largest object that is cached.
+    max_entry_size: u64,
+    state: Mutex<CacheState>,
+    /// Downloads in progress, keyed on the identifier and version of the object being downloaded.
+    fills: Mutex<HashSet<(String, Validator)>>,
+    downloads: AtomicU64,
+    metrics: CacheMetrics,
+}
+
+#[derive(Default)]
+struct CacheState {
+    entries: HashMap<String, CacheEntry>,
+    size: u64,
+    clock: u64,
+}
+
+#[derive(Clone, Debug)]
+struct CacheEntry {
+    path: PathBuf,
+    size: u64,
+    validator: Validator,
+    last_access: u64,
+}
+
+/// The values used to decide whether a cached copy of an object is still current.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Validator {
+    etag: Option<String>,
+    last_modified: Option<SystemTime>,
+}
+
+impl Validator {
+    fn of(object: &StorageObject) -> Self {
+        Self { etag: object.etag.clone(), last_modified: object.last_modified }
+    }
+
+    fn is_usable(&self) -> bool {
+        self.etag.is_some() || self.last_modified.is_some()
+    }
+}
+
+impl CacheEntry {
+    async fn open(
+        &self,
+        name: Option<String>,
+        validator: &Validator,
+    ) -> std::io::Result<StorageObject> {
+        let file = tokio::fs::File::open(&self.path).await?.into_std().await;
+        let download = Download { path: self.path.clone(), file: Arc::new(file), size: self.size };
+
+        Ok(download.object(name, validator))
+    }
+}
+
+/// A cached copy of an object that has just been downloaded, along with a handle to it that
+/// stays readable even if the copy is evicted before the requests waiting for it read it.
+#[derive(Clone, Debug)]
+struct Download {
+    path: PathBuf,
+    file: Arc<File>,
+    size: u64,
+}
+
+impl Download {
+    fn object(self, name: Option<String>, validator: &Validator) -> StorageObject {
+        let Download { path, file, size } = self;
+
+        StorageObject {
+            name,
+            last_modified: validator.last_modified,
+            etag: validator.etag.clone(),
+            size: Some(size),
+            content: FileOrStream::File(FileStream {
+                path: path.into_boxed_path(),
+                file: file.clone(),
+                stream_factory: Box::new(move |_| Box::new(PositionalFileReader::new(file, size))),
+            }),
+        }
+    }
+}
+
+impl SourceCache {
+    /// Find a current cache entry for `id`, marking it as recently used.
+    fn lookup(&self, id: &str, validator: &Validator) -> Option<CacheEntry> {
+        let mut state = self.state.lock().expect("source cache was poisoned");
+        state.clock += 1;
+
+        let clock = state.clock;
+        let entry = state
+            .entries
+            .get_mut(id)
+            .filter(|entry| &entry.validator == validator)?;
+        entry.last_access = clock;
+
+        Some(entry.clone())
+    }
+
+    fn remove(&self, id: &str) {
+        let mut state = self.state.lock().expect("source cache was poisoned");
+
+        if let Some(entry) = state.entries.remove(id) {
+            state.size -= entry.size;
+            self.metrics.size.add(-(entry.size as i64), &[]);
+        }
+    }
+
+    /// Download an object into the cache in the background, unless a download of the same
+    /// version of the object is already in progress, returning the task doing so.
+    ///
+    /// The object is opened again for the download, so the request that missed the cache can read
+    /// it from the remote in the meantime.
+    fn fill(
+        self: &Arc<Self>,
+        inner: Arc<dyn StorageProvider>,
+        id: String,
+        validator: Validator,
+    ) -> Option<JoinHandle<()>> {
+        let key = (id, validator);
+        if !self
+            .fills
+            .lock()
+            .expect("source cache was poisoned")
+            .insert(key.clone())
+        {
+            debug!("download already in progress");
+            return None;
+        }
+
+        let cache = self.clone();
+        Some(tokio::spawn(async move {
+            let (id, validator) = &key;
+            if let Err(error) = cache.download_from(inner.as_ref(), id, validator).await {
+                warn!(%error, "unable to cache source");
+            }
+
+            cache
+                .fills
+                .lock()
+                .expect("source cache was poisoned")
+                .remove(&key);
+        }))
+    }
+
+    /// Download the version of the object identified by `id` described by `validator` from
+    /// `inner`, failing if the object has changed or is too large to cache.
+    async fn download_from(
+        &self,
+        inner: &dyn StorageProvider,
+        id: &str,
+        validator: &Validator,
+    ) -> Result<Download, StorageError> {
+        let object = inner.open(id).await?;
+        if Validator::of(&object) != *validator {
+            return Err(StorageError::Other("source changed before it was cached".into()));
+        }
+
+        match object.content {
+            FileOrStream::Stream(stream) => self.download(id, stream, validator.clone()).await,
+            FileOrStream::File(_) => Err(StorageError::Other("source is already local".into())),
+        }
+    }
+
+    async fn download(
+        &self,
+        id: &str,
+        stream: Box<dyn AsyncSeekableRead + Send>,
+        validator: Validator,
+    ) -> Result<Download, StorageError> {
+        // Each version of an object is cached in its own file, so a download of one version
+        // can't replace the file that another version's readers are using.
+        let mut hasher = DefaultHasher::new();
+        id.hash(&mut hasher);
+        validator.hash(&mut hasher);
+
+        let name = format!("{:016x}", hasher.finish());
+        let download = self.downloads.fetch_add(1, Ordering::Relaxed);
+        let partial = self
+            .directory
+            .join(format!("{name}.{download}.{PARTIAL_EXTENSION}"));
+        let path = self.directory.join(format!("{name}.{ENTRY_EXTENSION}"));
+
+        let size = match copy_to_file(stream, &partial, self.max_entry_size).await {
+            Ok(size) => size,
+            Err(error) => {
+                let _ = tokio::fs::remove_file(&partial).await;
+                return Err(error);
+            }
+        };
+
+        tokio::fs::rename(&partial, &path).await?;
+        let file = Arc::new(tokio::fs::File::open(&path).await?.into_std().await);
+        debug!(size, path = %path.display(), "cached source");
+
+        let evicted =
+            self.insert(id, CacheEntry { path: path.clone(), size, validator, last_access: 0 });
+        for entry in evicted {
+            if let Err(error) = tokio::fs::remove_file(&entry.path).await {
+                warn!(%error, path = %entry.path.display(), "unable to remove evicted source");
+            }
+        }
+
+        Ok(Download { path, file, size })
+    }
+
+    /// Add an entry to the index and evict the least recently used entries until the cache is
+    /// within its capacity, returning the evicted entries.
+    fn insert(&self, id: &str, mut entry: CacheEntry) -> Vec<CacheEntry> {
+        let mut state = self.state.lock().expect("source cache was poisoned");
+        state.clock += 1;
+        entry.last_access = state.clock;
+
+        let mut size_change = entry.size as i64;
+        state.size += entry.size;
+
+        let mut evicted = vec![];
+        let path = entry.path.clone();
+        if let Some(previous) = state.entries.insert(id.to_string(), entry) {
+            state.size -= previous.size;
+            size_change -= previous.size as i64;
+
+            // A download of the same version replaces the previous file when it is renamed.
+            if previous.path != path {
+                evicted.push(previous);
+            }
+        }
+
+        while state.size > self.capacity {
+            let Some(oldest) = state
+                .entries
+                .iter()
+                .filter(|(key, _)| key.as_str() != id)
+                .min_by_key(|(_, entry)| entry.last_access)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+
+            let entry = state
+                .entries
+                .remove(&oldest)
+                .expect("evicted entry must exist");
+            state.size -= entry.size;
+            size_change -= entry.size as i64;
+            evicted.push(entry);
+        }
+
+        self.metrics.size.add(size_change, &[]);
+        self.metrics.evictions.add(evicted.len() as u64, &[]);
+
+        evicted
+    }
+}
+
+/// Copy the contents of a stream to a new file at `path`, failing if it is larger than
+/// `limit` bytes.
+async fn copy_to_file(
+    stream: Box<dyn AsyncSeekableRead + Send>,
+    path: &Path,
+    limit: u64,
+) -> Result<u64, StorageError> {
+    let mut reader = Box::into_pin(stream);
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut size = 0u64;
+
+    loop {
+        let count = reader.read(&mut buffer).await?;
+        if count == 0 {
+            break;
+        }
+
+        size += count as u64;
+        if size > limit {
+            return Err(StorageError::Other("source is too large to cache".into()));
+        }
+
+        file.write_all(&buffer[..count]).await?;
+    }
+
+    file.flush().await?;
+
+    Ok(size)
+}
+
+struct CacheMetrics {
+    lookups: Counter<u64>,
+    evictions: Counter<u64>,
+    size: UpDownCounter<i64>,
+}
+
+impl CacheMetrics {
+    fn new() -> Self {
+        let meter = opentelemetry::global::meter(env!("CARGO_PKG_NAME"));
+
+        Self {
+            lookups: meter
+                .u64_counter("source_cache.lookups")
+                .with_description("Source cache lookups, by result (hit, miss or bypass)")
+                .build(),
+            evictions: meter
+                .u64_counter("source_cache.evictions")
+                .with_description("Sources evicted from the cache to stay within its capacity")
+                .build(),
+            size: meter
+                .i64_up_down_counter("source_cache.size")
+                .with_description("Total size of cached sources")
+                .with_unit("By")
+                .build(),
+        }
+    }
+
+    fn record_lookup(&self, result: &'static str) {
+        self.lookups.add(1, &[KeyValue::new("result", result)]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::AtomicUsize;
+    use std::task::{Context, Poll};
+
+    use futures::io::Cursor;
+    use futures::{AsyncRead, AsyncSeek};
+
+    use super::*;
+    use crate::storage::layout::read_to_end;
+
+    /// Object contents and ETags, keyed by identifier.
+    type Objects = HashMap<String, (Vec<u8>, String)>;
+
+    /// A remote storage provider that counts how many times object contents are read.
+    #[derive(Clone, Default)]
+    struct CountingStorage {
+        objects: Arc<Mutex<Objects>>,
+        reads: Arc<AtomicUsize>,
+    }
+
+    impl CountingStorage {
+        fn put(&self, id: &str, data: &[u8], etag: &str) {
+            self.objects
+                .lock()
+                .unwrap()
+                .insert(id.to_string(), (data.to_vec(), etag.to_string()));
+        }
+
+        fn reads(&self) -> usize {
+            self.reads.load(Ordering::SeqCst)
+        }
+    }
+
+    impl StorageProvider for CountingStorage {
+        fn open(
+            &self,
+            id: &str,
+        ) -> Pin<Box<dyn Future<Output = Result<StorageObject, StorageError>> + Send>> {
+            let object = self.objects.lock().unwrap().get(id).cloned();
+            let reads = self.reads.clone();
+
+            Box::pin(async move {
+                let (data, etag) = object.ok_or(StorageError::NotFound)?;
+
+                Ok(StorageObject {
+                    name: None,
+                    last_modified: None,
+                    etag: Some(etag),
+                    size: Some(data.len() as u64),
+                    content: FileOrStream::Stream(Box::new(CountingReader {
+                        inner: Cursor::new(data),
+                        reads,
+                        counted: false,
+                    })),
+                })
+            })
+        }
+    }
+
+    struct CountingReader {
+        inner: Cursor<Vec<u8>>,
+        reads: Arc<AtomicUsize>,
+        counted: bool,
+    }
+
+    impl AsyncRead for CountingReader {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let this = self.get_mut();
+            if !this.counted {
+                this.counted = true;
+                this.reads.fetch_add(1, Ordering::SeqCst);
+            }
+
+            Pin::new(&mut this.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncSeek for CountingReader {
+        fn poll_seek(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            pos: std::io::SeekFrom,
+        ) -> Poll<std::io::Result<u64>> {
+            Pin::new(&mut self.get_mut().inner).poll_seek(cx, pos)
+        }
+    }
+
+    fn cached(remote: &CountingStorage, directory: &Path, capacity: u64) -> CachingStorageProvider {
+        CachingStorageProvider::new(remote.clone(), directory.to_path_buf(), capacity).unwrap()
+    }
+
+    /// Open `id`, then wait for any download it started to finish.
+    async fn open_and_fill(storage: &CachingStorageProvider, id: &str) -> StorageObject {
+        let object = storage.open(id).await.unwrap();
+        while !storage.cache.fills.lock().unwrap().is_empty() {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+
+        object
+    }
+
+    #[tokio::test]
+    async fn serves_misses_from_storage_and_hits_from_disk() {
+        let directory = tempfile::tempdir().unwrap();
+        let remote = CountingStorage::default();
+        remote.put("a.jp2", b"first", "\"1\"");
+
+        let storage = cached(&remote, directory.path(), 1024);
+        let object = open_and_fill(&storage, "a.jp2").await;
+        assert!(matches!(object.content, FileOrStream::Stream(_)));
+        assert_eq!(read_to_end(object).await.unwrap(), b"first");
+
+        for _ in 0..3 {
+            let object = storage.open("a.jp2").await.unwrap();
+            assert!(matches!(object.content, FileOrStream::File(_)));
+            assert_eq!(object.etag.as_deref(), Some("\"1\""));
+            assert_eq!(read_to_end(object).await.unwrap(), b"first");
+        }
+
+        // The miss and the download each read the object once.
+        assert_eq!(remote.reads(), 2);
+    }
+
+    #[tokio::test]
+    async fn refetches_changed_sources() {
+        let directory = tempfile::tempdir().unwrap();
+        let remote = CountingStorage::default();
+        remote.put("a.jp2", b"first", "\"1\"");
+
+        let storage = cached(&remote, directory.path(), 1024);
+        open_and_fill(&storage, "a.jp2").await;
+
+        remote.put("a.jp2", b"second", "\"2\"");
+        let object = open_and_fill(&storage, "a.jp2").await;
+        assert_eq!(object.etag.as_deref(), Some("\"2\""));
+        assert_eq!(read_to_end(object).await.unwrap(), b"second");
+
+        let object = storage.open("a.jp2").await.unwrap();
+        assert!(matches!(object.content, FileOrStream::File(_)));
+        assert_eq!(read_to_end(object).await.unwrap(), b"second");
+        assert_eq!(storage.cache.state.lock().unwrap().size, 6);
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used_sources() {
+        let directory = tempfile::tempdir().unwrap();
+        let remote = CountingStorage::default();
+        remote.put("a.jp2", &[0; 40], "\"a\"");
+        remote.put("b.jp2", &[1; 40], "\"b\"");
+        remote.put("c.jp2", &[2; 40], "\"c\"");
+
+        let storage = cached(&remote, directory.path(), 100);
+        open_and_fill(&storage, "a.jp2").await;
+        open_and_fill(&storage, "b.jp2").await;
+        open_and_fill(&storage, "a.jp2").await;
+        open_and_fill(&storage, "c.jp2").await;
+
+        let state = storage.cache.state.lock().unwrap();
+        assert_eq!(state.size, 80);
+        assert!(state.entries.contains_key("a.jp2"));
+        assert!(!state.entries.contains_key("b.jp2"));
+        assert!(state.entries.contains_key("c.jp2"));
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn bypasses_sources_larger_than_an_entry() {
+        let directory = tempfile::tempdir().unwrap();
+        let remote = CountingStorage::default();
+        remote.put("large.jp2", &[0; 200], "\"large\"");
+
+        let storage = cached(&remote, directory.path(), 1000).with_max_entry_size(100);
+        let object = open_and_fill(&storage, "large.jp2").await;
+
+        assert!(matches!(object.content, FileOrStream::Stream(_)));
+        assert_eq!(read_to_end(object).await.unwrap().len(), 200);
+
+        // The size is known up front, so the object is never downloaded into the cache.
+        assert_eq!(remote.reads(), 1);
+        assert_eq!(storage.cache.downloads.load(Ordering::SeqCst), 0);
+        assert_eq!(storage.cache.state.lock().unwrap().size, 0);
+    }
+
+    #[tokio::test]
+    async fn deduplicates_concurrent_misses() {
+        let directory = tempfile::tempdir().unwrap();
+        let remote = CountingStorage::default();
+        remote.put("a.jp2", &[7; 1000], "\"1\"");
+
+        let storage = cached(&remote, directory.path(), 4096);
+        let objects = futures::future::join_all((0..10).map(|_| storage.open("a.jp2"))).await;
+        open_and_fill(&storage, "a.jp2").await;
+
+        for object in objects {
+            assert_eq!(read_to_end(object.unwrap()).await.unwrap(), vec![7; 1000]);
+        }
+
+        assert_eq!(storage.cache.downloads.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn caches_each_version_in_its_own_file() {
+        let directory = tempfile::tempdir().unwrap();
+        let storage = cached(&CountingStorage::default(), directory.path(), 4096);
+        let download = |data: &[u8], etag: &str| {
+            storage.cache.download(
+                "a.jp2",
+                Box::new(Cursor::new(data.to_vec())),
+                Validator { etag: Some(etag.to_string()), last_modified: None },
+            )
+        };
+
+        // The second version is downloaded while the first still is.
+        let (first, second) =
+            futures::join!(download(b"first", "\"1\""), download(b"second", "\"2\""));
+
+        for (download, expected) in [(first, b"first".as_slice()), (second, b"second")] {
+            let mut data = vec![];
+            std::io::Read::read_to_end(&mut &*download.unwrap().file, &mut data).unwrap();
+            assert_eq!(data, expected);
+        }
+
+        // Whichever version finished last replaced the other in the cache.
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,161 @@
+use std::future::{Future, ready};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tracing::warn;
+
+use super::{StorageError, StorageObject, StorageProvider};
+
+/// A [StorageProvider] that rejects identifiers which could be used to address files outside of
+/// the storage root before they reach the underlying provider.
+///
+/// Identifiers are URL-decoded before they reach storage, so values such as
+/// `..%2F..%2Fetc%2Fpasswd` must be treated as hostile. Symbolic links that lead outside of a
+/// local filesystem root can only be refused as files are opened, so that is left to
+/// [crate::storage::local::LocalStorageProvider::with_symlink_containment].
+pub struct ContainedStorageProvider {
+    inner: Arc<dyn StorageProvider>,
+}
+
+impl ContainedStorageProvider {
+    pub fn new<S: StorageProvider + 'static>(inner: S) -> Self {
+        Self { inner: Arc::new(inner) }
+    }
+}
+
+impl StorageProvider for ContainedStorageProvider {
+    fn open(
+        &self,
+        id: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<StorageObject, StorageError>> + Send>> {
+        if let Err(e) = sanitise_identifier(id) {
+            warn!(identifier = id, "rejected a hostile storage identifier: {e}");
+            return Box::pin(ready(Err(e)));
+        }
+
+        self.inner.open(id)
+    }
+
+    fn healthcheck(&self) -> Pin<Box<dyn Future<Output = Result<(), StorageError>> + Send>> {
+        self.inner.healthcheck()
+    }
+}
+
+/// Check that an identifier is safe to use as a path relative to a storage root.
+///
+/// Identifiers are rejected if they are empty, contain NUL bytes or other control characters,
+/// are absolute paths, or contain `..` segments.
+pub fn sanitise_identifier(id: &str) -> Result<&str, StorageError> {
+    let invalid = |reason: &str| Err(StorageError::InvalidIdentifier(reason.into()));
+
+    if id.is_empty() {
+        return invalid("identifier is empty");
+    }
+
+    if id.chars().any(char::is_control) {
+        return invalid("identifier contains control characters");
+    }
+
+    if id.starts_with(['/', '\\']) || Path::new(id).has_root() || has_drive_prefix(id) {
+        return invalid("identifier is an absolute path");
+    }
+
+    if id.split(['/', '\\']).any(|segment| segment == "..") {
+        return invalid("identifier contains a parent directory segment");
+    }
+
+    Ok(id)
+}
+
+fn has_drive_prefix(id: &str) -> bool {
+    matches!(id.as_bytes(), [drive, b':', b'/' | b'\\', ..] if drive.is_ascii_alphabetic())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HOSTILE_IDENTIFIERS: &[&str] = &[
+        "",
+        "..",
+        "../etc/passwd",
+        "../../../../../../etc/passwd",
+        "images/../../etc/passwd",
+        "images/..",
+        "..\\..\\windows\\win.ini",
+        "images\\..\\..\\secret",
+        "/etc/passwd",
+        "//etc/passwd",
+        "\\\\server\\share\\file",
+        "C:\\windows\\win.ini",
+        "c:/windows/win.ini",
+        "image.jp2\0.txt",
+        "\0",
+        "image\n.jp2",
+        "image\r\n.jp2",
+        "image\t.jp2",
+        "\u{1b}[31mimage.jp2",
+        "image\u{7f}.jp2",
+        "image\u{85}.jp2",
+    ];
+
+    const BENIGN_IDENTIFIERS: &[&str] = &[
+        "image.jp2",
+        "images/page-0001.jp2",
+        "a/?#[]@%z",
+        "...",
+        "..image.jp2",
+        "image..jp2",
+        "images/.../image.jp2",
+        "%2e%2e/%2e%2e/etc/passwd",
+        "ark:/13030/xt12t3",
+        "ünïcödé.jp2",
+    ];
+
+    #[test]
+    fn rejects_hostile_identifiers() {
+        for id in HOSTILE_IDENTIFIERS {
+            assert!(
+                matches!(sanitise_identifier(id), Err(StorageError::InvalidIdentifier(_))),
+                "expected {id:?} to be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_benign_identifiers() {
+        for id in BENIGN_IDENTIFIERS {
+            assert_eq!(sanitise_identifier(id).ok(), Some(*id), "expected {id:?} to be accepted");
+        }
+    }
+
+    #[test]
+    fn rejects_decoded_request_identifiers() {
+        let request = "/..%2F..%2Fetc%2Fpasswd/info.json"
+            .parse::<crate::iiif::ImageServiceRequest>()
+            .unwrap();
+
+        assert!(sanitise_identifier(&request.identifier).is_err());
+    }
+
+    struct UnreachableStorage;
+
+    impl StorageProvider for UnreachableStorage {
+        fn open(
+            &self,
+            id: &str,
+        ) -> Pin<Box<dyn Future<Output = Result<StorageObject, StorageError>> + Send>> {
+            panic!("storage should not have been opened for {id:?}")
+        }
+    }
+
+    #[tokio::test]
+    async fn hostile_identifiers_never_reach_storage() {
+        let storage = ContainedStorageProvider::new(UnreachableStorage);
+
+        for id in HOSTILE_IDENTIFIERS {
+            assert!(matches!(storage.open(id).await, Err(StorageError::InvalidIdentifier(_))));
+        }
+    }
+}
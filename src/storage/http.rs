@@ -0,0 +1,634 @@
+use std::future::Future;
+use std::io::SeekFrom;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{AsyncRead, AsyncSeek};
+use reqwest::header::{
+    CONTENT_LENGTH, ETAG, HeaderMap, IF_MATCH, IF_UNMODIFIED_SINCE, LAST_MODIFIED, RANGE,
+};
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode};
+use tracing::{debug, warn};
+
+use super::{FileOrStream, StorageError, StorageObject, StorageProvider};
+
+/// The placeholder in a URL template that is replaced by the percent-encoded identifier.
+pub const ID_PLACEHOLDER: &str = "{id}";
+
+/// The placeholder in a URL template that is replaced by the identifier with each path segment
+/// percent-encoded, preserving `/` separators.
+pub const PATH_PLACEHOLDER: &str = "{path}";
+
+/// The maximum delay between attempts of a failed request.
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Configuration for reading image files from an HTTP(S) origin server.
+#[derive(Clone, Debug)]
+pub struct HttpStorageOptions {
+    /// The URL that identifiers are mapped to, containing `{id}` or `{path}`, e.g.
+    /// `https://images.example.org/originals/{path}`.
+    pub url_template: String,
+
+    /// The time allowed for a single request to the origin to complete.
+    pub timeout: Duration,
+
+    /// The number of times a request that failed with a transient error is retried.
+    pub retries: u32,
+
+    /// The delay before the first retry, which is doubled after each subsequent attempt.
+    pub backoff: Duration,
+
+    /// The minimum number of bytes fetched by each ranged read.
+    pub read_ahead: u64,
+}
+
+impl Default for HttpStorageOptions {
+    fn default() -> Self {
+        Self {
+            url_template: PATH_PLACEHOLDER.into(),
+            timeout: Duration::from_secs(10),
+            retries: 3,
+            backoff: Duration::from_millis(100),
+            read_ahead: 1024 * 1024,
+        }
+    }
+}
+
+/// A [StorageProvider] that reads image files from a web server using HTTP range requests.
+pub struct HttpStorageProvider {
+    origin: Arc<HttpOrigin>,
+    url_template: String,
+    read_ahead: u64,
+}
+
+struct HttpOrigin {
+    client: Client,
+    retries: u32,
+    backoff: Duration,
+}
+
+impl HttpStorageProvider {
+    pub fn new(options: HttpStorageOptions) -> Result<Self, StorageError> {
+        if !options.url_template.contains(ID_PLACEHOLDER)
+            && !options.url_template.contains(PATH_PLACEHOLDER)
+        {
+            return Err(StorageError::Other(format!(
+                "URL template must contain {ID_PLACEHOLDER} or {PATH_PLACEHOLDER}"
+            )));
+        }
+
+        let client = Client::builder()
+            .timeout(options.timeout)
+            .connect_timeout(options.timeout)
+            .build()
+            .map_err(|e| StorageError::Other(e.to_string()))?;
+
+        Ok(Self {
+            origin: Arc::new(HttpOrigin {
+                client,
+                retries: options.retries,
+                backoff: options.backoff,
+            }),
+            url_template: options.url_template,
+            read_ahead: options.read_ahead.max(1),
+        })
+    }
+
+    /// Map an identifier to the URL of its image file using the URL template.
+    fn url(&self, id: &str) -> String {
+        let path = id
+            .split('/')
+            .map(urlencoding::encode)
+            .collect::<Vec<_>>()
+            .join("/");
+
+        self.url_template
+            .replace(ID_PLACEHOLDER, &urlencoding::encode(id))
+            .replace(PATH_PLACEHOLDER, &path)
+    }
+}
+
+impl StorageProvider for HttpStorageProvider {
+    fn open(
+        &self,
+        id: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<StorageObject, StorageError>> + Send>> {
+        Box::pin(open(self.origin.clone(), self.url(id), id.to_string(), self.read_ahead))
+    }
+}
+
+#[tracing::instrument(skip(origin), err)]
+async fn open(
+    origin: Arc<HttpOrigin>,
+    url: String,
+    id: String,
+    read_ahead: u64,
+) -> Result<StorageObject, StorageError> {
+    let response = origin
+        .send(|client| client.request(Method::HEAD, &url))
+        .await
+        .map_err(|e| StorageError::Other(e.to_string()))?;
+
+    match response.status() {
+        status if status.is_success() => {}
+        StatusCode::NOT_FOUND | StatusCode::GONE => return Err(StorageError::NotFound),
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => return Err(StorageError::AccessDenied),
+        status => return Err(StorageError::Other(format!("origin responded with {status}"))),
+    }
+
+    let headers = response.headers();
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+    let length = header(CONTENT_LENGTH)
+        .and_then(|value| value.parse::<u64>().ok())
+        .ok_or_else(|| StorageError::Other("origin did not report a content length".into()))?;
+    let last_modified =
+        header(LAST_MODIFIED).and_then(|value| httpdate::parse_http_date(value).ok());
+    let etag = header(ETAG).map(str::to_string);
+    let version = ResourceVersion {
+        etag: etag.clone(),
+        last_modified: header(LAST_MODIFIED).map(str::to_string),
+    };
+
+    Ok(StorageObject {
+        name: Some(id),
+        last_modified,
+        etag,
+        size: Some(length),
+        content: FileOrStream::Stream(Box::new(HttpRangeReader::new(
+            origin, url, version, length, read_ahead,
+        ))),
+    })
+}
+
+/// The validators of the version of a resource that a reader started reading, which every range
+/// read from it must match.
+#[derive(Clone, Debug)]
+struct ResourceVersion {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl ResourceVersion {
+    /// Add preconditions to a request so the origin refuses it if the resource has changed.
+    fn precondition(&self, request: RequestBuilder) -> RequestBuilder {
+        match (&self.etag, &self.last_modified) {
+            // Weak entity tags never match `If-Match`, which uses strong comparison.
+            (Some(etag), _) if !etag.starts_with("W/") => request.header(IF_MATCH, etag),
+            (_, Some(last_modified)) => request.header(IF_UNMODIFIED_SINCE, last_modified),
+            _ => request,
+        }
+    }
+
+    /// Check that a response is of this version of the resource, for origins that ignore
+    /// preconditions.
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+        match (&self.etag, &self.last_modified) {
+            (Some(etag), _) => header(ETAG) == Some(etag.as_str()),
+            (None, Some(last_modified)) => header(LAST_MODIFIED) == Some(last_modified.as_str()),
+            (None, None) => true,
+        }
+    }
+}
+
+/// The error returned when a resource changes while it is being read.
+fn changed_while_reading() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "the resource changed at the origin while it was being read",
+    )
+}
+
+impl HttpOrigin {
+    /// Send a request, retrying with exponential backoff if it fails with a transient error.
+    async fn send<F>(&self, request: F) -> Result<Response, reqwest::Error>
+    where
+        F: Fn(&Client) -> RequestBuilder,
+    {
+        let mut attempt = 0;
+
+        loop {
+            let result = request(&self.client).send().await;
+            let retryable = match &result {
+                Ok(response) => is_retryable_status(response.status()),
+                // Other errors, such as a connection reset mid-response, may have been seen by the
+                // origin and aren't safe to assume transient.
+                Err(e) => e.is_timeout() || e.is_connect(),
+            };
+
+            if !retryable || attempt >= self.retries {
+                return result;
+            }
+
+            let delay = self
+                .backoff
+                .saturating_mul(1 << attempt.min(16))
+                .min(MAX_BACKOFF);
+            match &result {
+                Ok(response) => warn!(status = %response.status(), ?delay, "retrying request"),
+                Err(e) => warn!(error = %e, ?delay, "retrying request"),
+            }
+
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Fetch the bytes in the half-open range `start..end` of `version` of the resource at `url`,
+    /// failing if the resource has changed since.
+    async fn fetch(
+        self: Arc<Self>,
+        url: String,
+        version: ResourceVersion,
+        start: u64,
+        end: u64,
+    ) -> std::io::Result<Bytes> {
+        debug!(url, start, end, "fetching byte range");
+
+        let range = format!("bytes={start}-{}", end - 1);
+        let response = self
+            .send(|client| version.precondition(client.get(&url).header(RANGE, &range)))
+            .await
+            .map_err(std::io::Error::other)?;
+
+        if response.status() == StatusCode::PRECONDITION_FAILED
+            || (response.status().is_success() && !version.matches(response.headers()))
+        {
+            warn!(url, "resource changed at the origin while it was being read");
+            return Err(changed_while_reading());
+        }
+
+        match response.status() {
+            StatusCode::PARTIAL_CONTENT => {}
+            // The origin ignored the range and sent the entire resource, which is still usable
+            // when reading from the start.
+            StatusCode::OK if start == 0 => {}
+            status => {
+                return Err(std::io::Error::other(format!(
+                    "origin responded to a range request with {status}"
+                )));
+            }
+        }
+
+        response.bytes().await.map_err(std::io::Error::other)
+    }
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+type RangeFuture = Pin<Box<dyn Future<Output = std::io::Result<Bytes>> + Send>>;
+
+/// A seekable reader over a remote resource that fetches data on demand with HTTP range
+/// requests.
+///
+/// Each request fetches at least `read_ahead` bytes from the current position, so that the many
+/// small sequential reads made by decoders are served from a local buffer. Every request is
+/// conditional on the resource being the version that was first opened, so an image that
+/// changes while it is read fails to decode rather than being assembled from both versions.
+pub struct HttpRangeReader {
+    origin: Arc<HttpOrigin>,
+    url: String,
+    version: ResourceVersion,
+    length: u64,
+    position: u64,
+    read_ahead: u64,
+    buffer: Bytes,
+    buffer_start: u64,
+    pending: Option<(u64, RangeFuture)>,
+}
+
+impl HttpRangeReader {
+    fn new(
+        origin: Arc<HttpOrigin>,
+        url: String,
+        version: ResourceVersion,
+        length: u64,
+        read_ahead: u64,
+    ) -> Self {
+        Self {
+            origin,
+            url,
+            version,
+            length,
+            position: 0,
+            read_ahead,
+            buffer: Bytes::new(),
+            buffer_start: 0,
+            pending: None,
+        }
+    }
+
+    /// Get the buffered bytes available at the current position, if any.
+    fn buffered(&self) -> Option<&[u8]> {
+        let offset = self.position.checked_sub(self.buffer_start)?;
+
+        if offset < self.buffer.len() as u64 {
+            Some(&self.buffer[offset as usize..])
+        } else {
+            None
+        }
+    }
+}
+
+impl AsyncRead for HttpRangeReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.position >= this.length || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+
+            if let Some(available) = this.buffered() {
+                let count = available.len().min(buf.len());
+                buf[..count].copy_from_slice(&available[..count]);
+                this.position += count as u64;
+
+                return Poll::Ready(Ok(count));
+            }
+
+            if this.pending.is_none() {
+                let start = this.position;
+                let end = this
+                    .length
+                    .min(start.saturating_add(this.read_ahead.max(buf.len() as u64)));
+                let future =
+                    this.origin
+                        .clone()
+                        .fetch(this.url.clone(), this.version.clone(), start, end);
+
+                this.pending = Some((start, Box::pin(future)));
+            }
+
+            let (start, future) = this.pending.as_mut().expect("range request is pending");
+            let start = *start;
+            let result = ready!(future.as_mut().poll(cx));
+            this.pending = None;
+
+            let data = result?;
+            if data.is_empty() {
+                return Poll::Ready(Err(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            this.buffer = data;
+            this.buffer_start = start;
+        }
+    }
+}
+
+impl AsyncSeek for HttpRangeReader {
+    fn poll_seek(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<std::io::Result<u64>> {
+        let this = self.get_mut();
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => this.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => this.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                this.position = position;
+                Poll::Ready(Ok(position))
+            }
+            None => Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use futures::{AsyncReadExt, AsyncSeekExt};
+    use http_body_util::Full;
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{Request, Response};
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::storage::AsyncSeekableRead;
+
+    const LAST_MODIFIED_DATE: &str = "Wed, 21 Oct 2015 07:28:00 GMT";
+
+    struct StubOrigin {
+        data: Vec<u8>,
+        etag: Mutex<&'static str>,
+        failures: AtomicUsize,
+        requests: AtomicUsize,
+        ignores_preconditions: AtomicBool,
+    }
+
+    impl StubOrigin {
+        fn respond(&self, req: Request<Incoming>) -> Response<Full<Bytes>> {
+            self.requests.fetch_add(1, Ordering::SeqCst);
+
+            let response = Response::builder();
+            if self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                return response.status(503).body(Full::default()).unwrap();
+            }
+
+            if req.uri().path() != "/images/a%20b/c.jp2" {
+                return response.status(404).body(Full::default()).unwrap();
+            }
+
+            let etag = *self.etag.lock().unwrap();
+            let response = response
+                .header("etag", etag)
+                .header("last-modified", LAST_MODIFIED_DATE);
+
+            let precondition = req.headers().get(IF_MATCH);
+            if !self.ignores_preconditions.load(Ordering::SeqCst)
+                && precondition.is_some_and(|value| value != etag)
+            {
+                return response.status(412).body(Full::default()).unwrap();
+            }
+
+            if req.method() == Method::HEAD {
+                return response
+                    .header("content-length", self.data.len())
+                    .body(Full::default())
+                    .unwrap();
+            }
+
+            let range = req.headers()[RANGE].to_str().unwrap();
+            let (start, end) = range
+                .strip_prefix("bytes=")
+                .and_then(|range| range.split_once('-'))
+                .unwrap();
+            let start: usize = start.parse().unwrap();
+            let end: usize = end.parse::<usize>().unwrap().min(self.data.len() - 1);
+
+            response
+                .status(206)
+                .body(Full::new(Bytes::copy_from_slice(&self.data[start..=end])))
+                .unwrap()
+        }
+    }
+
+    async fn serve(failures: usize) -> (SocketAddr, Arc<StubOrigin>) {
+        let origin = Arc::new(StubOrigin {
+            data: (0..=255u8).cycle().take(10_000).collect(),
+            etag: Mutex::new("\"abc123\""),
+            failures: AtomicUsize::new(failures),
+            requests: AtomicUsize::new(0),
+            ignores_preconditions: AtomicBool::new(false),
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server_origin = origin.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let origin = server_origin.clone();
+                let service = service_fn(move |req| {
+                    let response = origin.respond(req);
+                    async move { Ok::<_, Infallible>(response) }
+                });
+
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        (addr, origin)
+    }
+
+    fn provider(addr: SocketAddr) -> HttpStorageProvider {
+        HttpStorageProvider::new(HttpStorageOptions {
+            url_template: format!("http://{addr}/images/{PATH_PLACEHOLDER}"),
+            backoff: Duration::from_millis(1),
+            read_ahead: 4096,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn reader(object: StorageObject) -> Pin<Box<dyn AsyncSeekableRead + Send>> {
+        match object.content {
+            FileOrStream::Stream(stream) => Box::into_pin(stream),
+            FileOrStream::File(_) => panic!("expected a stream"),
+        }
+    }
+
+    #[test]
+    fn url_template_encodes_identifiers() {
+        let provider = HttpStorageProvider::new(HttpStorageOptions {
+            url_template: "https://example.org/{path}?id={id}".into(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(
+            provider.url("a b/c?.jp2"),
+            "https://example.org/a%20b/c%3F.jp2?id=a%20b%2Fc%3F.jp2"
+        );
+    }
+
+    #[test]
+    fn url_template_requires_placeholder() {
+        let options = HttpStorageOptions {
+            url_template: "https://example.org/".into(),
+            ..Default::default()
+        };
+
+        assert!(HttpStorageProvider::new(options).is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_metadata_and_ranges() {
+        let (addr, origin) = serve(0).await;
+        let object = provider(addr).open("a b/c.jp2").await.unwrap();
+
+        assert_eq!(object.etag.as_deref(), Some("\"abc123\""));
+        assert_eq!(object.last_modified, httpdate::parse_http_date(LAST_MODIFIED_DATE).ok());
+
+        let mut reader = reader(object);
+        let mut data = vec![];
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, origin.data);
+
+        // One HEAD request, followed by three 4096 byte reads.
+        assert_eq!(origin.requests.load(Ordering::SeqCst), 4);
+
+        let mut tail = [0u8; 4];
+        reader.seek(SeekFrom::End(-4)).await.unwrap();
+        reader.read_exact(&mut tail).await.unwrap();
+        assert_eq!(tail[..], origin.data[origin.data.len() - 4..]);
+
+        let mut middle = [0u8; 16];
+        reader.seek(SeekFrom::Start(5000)).await.unwrap();
+        reader.read_exact(&mut middle).await.unwrap();
+        assert_eq!(middle[..], origin.data[5000..5016]);
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures() {
+        let (addr, origin) = serve(2).await;
+        let object = provider(addr).open("a b/c.jp2").await.unwrap();
+
+        let mut data = vec![];
+        reader(object).read_to_end(&mut data).await.unwrap();
+
+        assert_eq!(data, origin.data);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_retries() {
+        let (addr, _) = serve(10).await;
+
+        assert!(matches!(provider(addr).open("a b/c.jp2").await, Err(StorageError::Other(_))));
+    }
+
+    #[tokio::test]
+    async fn missing_resources_are_not_found() {
+        let (addr, _) = serve(0).await;
+
+        assert!(matches!(provider(addr).open("missing.jp2").await, Err(StorageError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn fails_if_the_resource_changes_while_reading() {
+        for ignores_preconditions in [false, true] {
+            let (addr, origin) = serve(0).await;
+            origin
+                .ignores_preconditions
+                .store(ignores_preconditions, Ordering::SeqCst);
+
+            let mut reader = reader(provider(addr).open("a b/c.jp2").await.unwrap());
+            let mut data = [0u8; 16];
+            reader.read_exact(&mut data).await.unwrap();
+
+            *origin.etag.lock().unwrap() = "\"def456\"";
+            reader.seek(SeekFrom::Start(8000)).await.unwrap();
+            let error = reader.read_exact(&mut data).await.unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::AsyncReadExt;
+
+use super::local::PositionalFileReader;
+use super::{FileOrStream, StorageError, StorageObject, StorageProvider};
+
+pub mod ocfl;
+pub mod pairtree;
+
+/// The directory structure used to locate images within a [StorageProvider].
+#[derive(Clone, Debug, Default)]
+pub enum StorageLayout {
+    /// Identifiers are used as storage paths verbatim.
+    #[default]
+    Flat,
+
+    /// Identifiers are mapped to paths beneath [pairtree::PAIRTREE_ROOT] using pairtree encoding.
+    Pairtree,
+
+    /// Identifiers name OCFL objects that are located by pairtree encoding, and the image file is
+    /// resolved through the object's `inventory.json`. Identifiers select a version of the object
+    /// with an [ocfl::VERSION_SEPARATOR] suffix, and the head version is used otherwise.
+    Ocfl,
+}
+
+/// A [StorageProvider] that maps identifiers onto the paths of an underlying provider according
+/// to a [StorageLayout].
+pub struct LayoutStorageProvider {
+    inner: Arc<dyn StorageProvider>,
+    layout: StorageLayout,
+    inventories: Arc<ocfl::InventoryCache>,
+}
+
+impl LayoutStorageProvider {
+    pub fn new<S: StorageProvider + 'static>(inner: S, layout: StorageLayout) -> Self {
+        Self { inner: Arc::new(inner), layout, inventories: Default::default() }
+    }
+}
+
+impl StorageProvider for LayoutStorageProvider {
+    fn open(
+        &self,
+        id: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<StorageObject, StorageError>> + Send>> {
+        match &self.layout {
+            StorageLayout::Flat => self.inner.open(id),
+            StorageLayout::Pairtree => {
+                self.inner
+                    .open(&format!("{}/{}", pairtree::PAIRTREE_ROOT, pairtree::path(id)))
+            }
+            StorageLayout::Ocfl => {
+                Box::pin(ocfl::open(self.inner.clone(), self.inventories.clone(), id.to_string()))
+            }
+        }
+    }
+
+    fn healthcheck(&self) -> Pin<Box<dyn Future<Output = Result<(), StorageError>> + Send>> {
+        self.inner.healthcheck()
+    }
+}
+
+/// Read the entire contents of a [StorageObject] into memory.
+pub(crate) async fn read_to_end(object: StorageObject) -> Result<Vec<u8>, StorageError> {
+    match object.content {
+        FileOrStream::File(file) => {
+            let mut reader = PositionalFileReader::for_file(file.file)?;
+            let mut buffer = vec![];
+
+            tokio::task::spawn_blocking(move || {
+                std::io::Read::read_to_end(&mut reader, &mut buffer).map(|_| buffer)
+            })
+            .await
+            .map_err(|e| StorageError::Other(e.to_string()))?
+            .map_err(StorageError::from)
+        }
+        FileOrStream::Stream(stream) => {
+            let mut buffer = vec![];
+            Box::into_pin(stream).read_to_end(&mut buffer).await?;
+
+            Ok(buffer)
+        }
+    }
+}
@@ -0,0 +1,570 @@
+//! Resolution of image files stored within
+//! [OCFL](https://ocfl.io/1.1/spec/) objects.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use chrono::{DateTime, Timelike};
+use serde::Deserialize;
+use tracing::debug;
+
+use super::{pairtree, read_to_end};
+use crate::storage::{StorageError, StorageObject, StorageProvider};
+
+/// The name of the inventory file found at the root of every OCFL object.
+const INVENTORY_FILE: &str = "inventory.json";
+
+/// Separates the OCFL object identifier from the logical path of a file within the object, e.g.
+/// `ark:/13030/xt12t3::images/0001.jp2`. If the separator is omitted, the object must contain
+/// exactly one file.
+pub const LOGICAL_PATH_SEPARATOR: &str = "::";
+
+/// Separates an identifier from the version of its OCFL object that is served, e.g.
+/// `ark:/13030/xt12t3::images/0001.jp2;v2`. If the version is omitted, the head version is used.
+pub const VERSION_SEPARATOR: char = ';';
+
+/// The digest algorithms an inventory sidecar may be named after, in order of preference.
+const SIDECAR_ALGORITHMS: [&str; 2] = ["sha512", "sha256"];
+
+/// The number of parsed inventories kept by an [InventoryCache].
+const DEFAULT_INVENTORY_CACHE_CAPACITY: usize = 1024;
+
+#[derive(Debug, Deserialize)]
+struct Inventory {
+    id: String,
+    #[serde(rename = "digestAlgorithm")]
+    digest_algorithm: String,
+    head: String,
+    manifest: HashMap<String, Vec<String>>,
+    versions: HashMap<String, InventoryVersion>,
+}
+
+#[derive(Debug, Deserialize)]
+struct InventoryVersion {
+    created: Option<String>,
+    state: HashMap<String, Vec<String>>,
+}
+
+/// A logical file within an OCFL object version, resolved to its content path.
+#[derive(Debug, PartialEq)]
+struct ResolvedFile {
+    logical_path: String,
+    content_path: String,
+    digest: String,
+    created: Option<SystemTime>,
+}
+
+impl Inventory {
+    fn resolve(
+        &self,
+        logical_path: Option<&str>,
+        version: Option<&str>,
+    ) -> Result<ResolvedFile, StorageError> {
+        let version_name = version.unwrap_or(&self.head);
+        let version = self
+            .versions
+            .get(version_name)
+            .ok_or(StorageError::NotFound)?;
+
+        let mut candidates = version.state.iter().flat_map(|(digest, logical_paths)| {
+            logical_paths.iter().map(move |path| (digest, path))
+        });
+
+        let (digest, logical_path) = match logical_path {
+            Some(logical_path) => candidates
+                .find(|(_, path)| *path == logical_path)
+                .ok_or(StorageError::NotFound)?,
+            None => match (candidates.next(), candidates.next()) {
+                (Some(file), None) => file,
+                (None, _) => return Err(StorageError::NotFound),
+                (Some(_), Some(_)) => {
+                    return Err(StorageError::Other(format!(
+                        "OCFL object {} has multiple files, a logical path must be provided",
+                        self.id
+                    )));
+                }
+            },
+        };
+
+        let content_path = self
+            .manifest
+            .get(digest)
+            .and_then(|paths| paths.first())
+            .ok_or_else(|| {
+                StorageError::Other(format!(
+                    "OCFL object {} has no manifest entry for {}",
+                    self.id, digest
+                ))
+            })?;
+
+        // Content paths are relative to the object root, so they must not leave it.
+        if !is_within_object(content_path) {
+            return Err(StorageError::Other(format!(
+                "OCFL object {} has an invalid content path: {content_path}",
+                self.id
+            )));
+        }
+
+        let created = version
+            .created
+            .as_deref()
+            .and_then(|created| DateTime::parse_from_rfc3339(created).ok())
+            .and_then(|created| created.with_nanosecond(0))
+            .map(SystemTime::from);
+
+        Ok(ResolvedFile {
+            logical_path: logical_path.clone(),
+            content_path: content_path.clone(),
+            digest: format!("{}:{}", self.digest_algorithm, digest),
+            created,
+        })
+    }
+}
+
+/// Whether `content_path` names a file beneath the object root, rather than an absolute path or
+/// one that leaves the object through `..` segments.
+fn is_within_object(content_path: &str) -> bool {
+    !content_path.starts_with('/')
+        && !content_path.contains('\\')
+        && content_path
+            .split('/')
+            .all(|segment| !segment.is_empty() && segment != "." && segment != "..")
+}
+
+/// Split the version an identifier selects from its end, if it has one, e.g. `v2` from
+/// `ark:/13030/xt12t3;v2`. Suffixes that aren't OCFL version names are kept in the identifier.
+fn split_version(id: &str) -> (&str, Option<&str>) {
+    match id.rsplit_once(VERSION_SEPARATOR) {
+        Some((id, version))
+            if version.len() > 1
+                && version.starts_with('v')
+                && version[1..].bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            (id, Some(version))
+        }
+        _ => (id, None),
+    }
+}
+
+/// The digest of an inventory, read from the sidecar file stored alongside it.
+#[derive(Clone, Debug, PartialEq)]
+struct InventoryDigest {
+    algorithm: &'static str,
+    digest: String,
+    last_modified: Option<SystemTime>,
+}
+
+impl InventoryDigest {
+    /// Parse a sidecar file, which holds the digest followed by the name of the inventory file.
+    fn parse(
+        algorithm: &'static str,
+        sidecar: &[u8],
+        last_modified: Option<SystemTime>,
+    ) -> Option<Self> {
+        let digest = std::str::from_utf8(sidecar)
+            .ok()?
+            .split_whitespace()
+            .next()?;
+
+        Some(Self { algorithm, digest: digest.to_ascii_lowercase(), last_modified })
+    }
+}
+
+/// A cache of parsed inventories, keyed on the root of their object and validated against the
+/// digest in the inventory's sidecar file.
+///
+/// Reading the sidecar is still a round trip to storage, but it saves reading and parsing an
+/// inventory that can grow with every version of its object.
+pub(super) struct InventoryCache {
+    state: Mutex<InventoryCacheState>,
+    capacity: usize,
+}
+
+#[derive(Default)]
+struct InventoryCacheState {
+    entries: HashMap<String, CachedInventory>,
+    clock: u64,
+}
+
+struct CachedInventory {
+    digest: String,
+    inventory: Arc<Inventory>,
+    last_access: u64,
+}
+
+impl Default for InventoryCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_INVENTORY_CACHE_CAPACITY)
+    }
+}
+
+impl InventoryCache {
+    pub(super) fn new(capacity: usize) -> Self {
+        Self { state: Mutex::new(InventoryCacheState::default()), capacity }
+    }
+
+    fn get(&self, object_root: &str, digest: &str) -> Option<Arc<Inventory>> {
+        let mut state = self.state.lock().expect("inventory cache was poisoned");
+        state.clock += 1;
+
+        let clock = state.clock;
+        let entry = state
+            .entries
+            .get_mut(object_root)
+            .filter(|entry| entry.digest == digest)?;
+        entry.last_access = clock;
+
+        Some(entry.inventory.clone())
+    }
+
+    fn insert(&self, object_root: &str, digest: String, inventory: Arc<Inventory>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let mut state = self.state.lock().expect("inventory cache was poisoned");
+        if state.entries.len() >= self.capacity && !state.entries.contains_key(object_root) {
+            let oldest = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_access)
+                .map(|(root, _)| root.clone());
+
+            if let Some(root) = oldest {
+                state.entries.remove(&root);
+            }
+        }
+
+        state.clock += 1;
+
+        let last_access = state.clock;
+        state
+            .entries
+            .insert(object_root.to_string(), CachedInventory { digest, inventory, last_access });
+    }
+}
+
+/// Read the digest of an object's inventory from its sidecar file, if the object has one.
+async fn inventory_digest(
+    storage: &dyn StorageProvider,
+    object_root: &str,
+) -> Result<Option<InventoryDigest>, StorageError> {
+    for algorithm in SIDECAR_ALGORITHMS {
+        let sidecar = match storage
+            .open(&format!("{object_root}/{INVENTORY_FILE}.{algorithm}"))
+            .await
+        {
+            Ok(sidecar) => sidecar,
+            Err(StorageError::NotFound) => continue,
+            Err(e) => return Err(e),
+        };
+
+        let last_modified = sidecar.last_modified;
+        let digest = InventoryDigest::parse(algorithm, &read_to_end(sidecar).await?, last_modified)
+            .ok_or_else(|| StorageError::Other("invalid OCFL inventory sidecar".to_string()))?;
+
+        return Ok(Some(digest));
+    }
+
+    Ok(None)
+}
+
+/// Load the inventory of the object at `object_root`, reusing a cached copy if its digest still
+/// matches the sidecar.
+async fn load_inventory(
+    storage: &dyn StorageProvider,
+    inventories: &InventoryCache,
+    object_root: &str,
+) -> Result<(Arc<Inventory>, Option<InventoryDigest>), StorageError> {
+    let digest = inventory_digest(storage, object_root).await?;
+    if let Some(inventory) = digest
+        .as_ref()
+        .and_then(|digest| inventories.get(object_root, &digest.digest))
+    {
+        return Ok((inventory, digest));
+    }
+
+    let inventory = storage
+        .open(&format!("{object_root}/{INVENTORY_FILE}"))
+        .await?;
+    let inventory: Arc<Inventory> = serde_json::from_slice(&read_to_end(inventory).await?)
+        .map(Arc::new)
+        .map_err(|e| StorageError::Other(format!("invalid OCFL inventory: {e}")))?;
+
+    match &digest {
+        Some(digest) => inventories.insert(object_root, digest.digest.clone(), inventory.clone()),
+        None => debug!(object_root, "OCFL inventory has no sidecar, so it can't be cached"),
+    }
+
+    Ok((inventory, digest))
+}
+
+/// Open the file identified by `id` from the OCFL object stored beneath the pairtree path of its
+/// object identifier, in the version `id` selects or the head version.
+///
+/// The file is validated by the digest of the inventory that resolved it, and considered modified
+/// when the inventory was last written. Objects without an inventory sidecar fall back to the
+/// digest of the file and the creation time of its version.
+#[tracing::instrument(skip(storage, inventories), err)]
+pub(super) async fn open(
+    storage: Arc<dyn StorageProvider>,
+    inventories: Arc<InventoryCache>,
+    id: String,
+) -> Result<StorageObject, StorageError> {
+    let (id, version) = split_version(&id);
+    let (object_id, logical_path) = match id.split_once(LOGICAL_PATH_SEPARATOR) {
+        Some((object_id, logical_path)) => (object_id, Some(logical_path)),
+        None => (id, None),
+    };
+
+    let object_root = pairtree::path(object_id);
+    let (inventory, digest) = load_inventory(storage.as_ref(), &inventories, &object_root).await?;
+    if inventory.id != object_id {
+        return Err(StorageError::NotFound);
+    }
+
+    let file = inventory.resolve(logical_path, version)?;
+    let object = storage
+        .open(&format!("{object_root}/{}", file.content_path))
+        .await?;
+
+    let (etag, last_modified) = match digest {
+        Some(InventoryDigest { algorithm, digest, last_modified }) => {
+            (format!("\"{algorithm}:{digest}\""), last_modified.or(file.created))
+        }
+        None => (format!("\"{}\"", file.digest), file.created),
+    };
+
+    Ok(StorageObject {
+        name: Some(file.logical_path),
+        last_modified,
+        etag: Some(etag),
+        size: object.size,
+        content: object.content,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::future::Future;
+    use std::pin::Pin;
+
+    use futures::io::Cursor;
+
+    use super::*;
+    use crate::storage::FileOrStream;
+
+    const INVENTORY: &str = r#"{
+        "digestAlgorithm": "sha512",
+        "head": "v2",
+        "id": "ark:/12345/bcd987",
+        "manifest": {
+            "4d27c8": [ "v1/content/empty.txt" ],
+            "7dcc35": [ "v1/content/foo/bar.xml" ],
+            "ffccf6": [ "v2/content/image.jp2" ]
+        },
+        "type": "https://ocfl.io/1.1/spec/#inventory",
+        "versions": {
+            "v1": {
+                "created": "2018-01-01T01:01:01Z",
+                "state": {
+                    "4d27c8": [ "empty.txt" ],
+                    "7dcc35": [ "foo/bar.xml" ]
+                }
+            },
+            "v2": {
+                "created": "2018-02-02T02:02:02.5Z",
+                "state": {
+                    "ffccf6": [ "image.jp2" ]
+                }
+            }
+        }
+    }"#;
+
+    fn inventory() -> Inventory {
+        serde_json::from_str(INVENTORY).expect("failed to parse test inventory")
+    }
+
+    #[test]
+    fn resolves_single_file_in_head_version() {
+        let file = inventory().resolve(None, None).unwrap();
+
+        assert_eq!(file.logical_path, "image.jp2");
+        assert_eq!(file.content_path, "v2/content/image.jp2");
+        assert_eq!(file.digest, "sha512:ffccf6");
+        assert_eq!(
+            file.created,
+            DateTime::parse_from_rfc3339("2018-02-02T02:02:02Z")
+                .ok()
+                .map(SystemTime::from)
+        );
+    }
+
+    #[test]
+    fn resolves_logical_path_in_selected_version() {
+        let file = inventory()
+            .resolve(Some("foo/bar.xml"), Some("v1"))
+            .unwrap();
+
+        assert_eq!(file.content_path, "v1/content/foo/bar.xml");
+        assert_eq!(file.digest, "sha512:7dcc35");
+    }
+
+    #[test]
+    fn requires_logical_path_for_multiple_files() {
+        assert!(matches!(inventory().resolve(None, Some("v1")), Err(StorageError::Other(_))));
+    }
+
+    #[test]
+    fn missing_files_and_versions_are_not_found() {
+        assert!(matches!(
+            inventory().resolve(Some("foo/bar.xml"), None),
+            Err(StorageError::NotFound)
+        ));
+        assert!(matches!(inventory().resolve(None, Some("v3")), Err(StorageError::NotFound)));
+    }
+
+    #[test]
+    fn rejects_content_paths_outside_the_object() {
+        for content_path in [
+            "../other/v1/content/image.jp2",
+            "/etc/passwd",
+            "v1/../../image.jp2",
+        ] {
+            let mut inventory = inventory();
+            inventory
+                .manifest
+                .insert("ffccf6".to_string(), vec![content_path.to_string()]);
+
+            assert!(
+                matches!(inventory.resolve(None, None), Err(StorageError::Other(_))),
+                "{content_path}"
+            );
+        }
+    }
+
+    #[test]
+    fn splits_versions_from_identifiers() {
+        assert_eq!(split_version("ark:/12345/bcd987;v1"), ("ark:/12345/bcd987", Some("v1")));
+        assert_eq!(
+            split_version("ark:/12345/bcd987::foo.jp2;v02"),
+            ("ark:/12345/bcd987::foo.jp2", Some("v02"))
+        );
+        assert_eq!(split_version("ark:/12345/bcd987"), ("ark:/12345/bcd987", None));
+        assert_eq!(split_version("ark:/12345/a;b"), ("ark:/12345/a;b", None));
+    }
+
+    /// Stores files in memory, counting how many times each is opened.
+    #[derive(Default)]
+    struct MemoryStorage {
+        files: Mutex<HashMap<String, Vec<u8>>>,
+        opens: Mutex<HashMap<String, usize>>,
+    }
+
+    impl MemoryStorage {
+        fn put(&self, path: &str, data: &str) {
+            self.files
+                .lock()
+                .unwrap()
+                .insert(path.to_string(), data.as_bytes().to_vec());
+        }
+
+        fn opens(&self, path: &str) -> usize {
+            self.opens.lock().unwrap().get(path).copied().unwrap_or(0)
+        }
+    }
+
+    impl StorageProvider for Arc<MemoryStorage> {
+        fn open(
+            &self,
+            id: &str,
+        ) -> Pin<Box<dyn Future<Output = Result<StorageObject, StorageError>> + Send>> {
+            *self
+                .opens
+                .lock()
+                .unwrap()
+                .entry(id.to_string())
+                .or_default() += 1;
+            let data = self.files.lock().unwrap().get(id).cloned();
+
+            Box::pin(async move {
+                Ok(StorageObject {
+                    name: None,
+                    last_modified: None,
+                    etag: None,
+                    size: None,
+                    content: FileOrStream::Stream(Box::new(Cursor::new(
+                        data.ok_or(StorageError::NotFound)?,
+                    ))),
+                })
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn caches_inventories_until_their_digest_changes() {
+        let storage = Arc::new(MemoryStorage::default());
+        let root = pairtree::path("ark:/12345/bcd987");
+        let inventory_path = format!("{root}/{INVENTORY_FILE}");
+        storage.put(&inventory_path, INVENTORY);
+        storage.put(&format!("{inventory_path}.sha512"), "AB12 inventory.json\n");
+        storage.put(&format!("{root}/v2/content/image.jp2"), "image");
+
+        let inventories = Arc::new(InventoryCache::new(8));
+        let provider: Arc<dyn StorageProvider> = Arc::new(storage.clone());
+        let open =
+            || super::open(provider.clone(), inventories.clone(), "ark:/12345/bcd987".to_string());
+
+        for _ in 0..2 {
+            let object = open().await.unwrap();
+            assert_eq!(object.etag.as_deref(), Some("\"sha512:ab12\""));
+        }
+        assert_eq!(storage.opens(&inventory_path), 1);
+
+        // A new version of the object replaces the inventory and its sidecar.
+        storage.put(&format!("{inventory_path}.sha512"), "cd34 inventory.json\n");
+        let object = open().await.unwrap();
+        assert_eq!(object.etag.as_deref(), Some("\"sha512:cd34\""));
+        assert_eq!(storage.opens(&inventory_path), 2);
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_file_digests_without_a_sidecar() {
+        let storage = Arc::new(MemoryStorage::default());
+        let root = pairtree::path("ark:/12345/bcd987");
+        storage.put(&format!("{root}/{INVENTORY_FILE}"), INVENTORY);
+        storage.put(&format!("{root}/v2/content/image.jp2"), "image");
+
+        let provider: Arc<dyn StorageProvider> = Arc::new(storage.clone());
+        let object = open(provider, Default::default(), "ark:/12345/bcd987".to_string())
+            .await
+            .unwrap();
+
+        assert_eq!(object.etag.as_deref(), Some("\"sha512:ffccf6\""));
+        assert!(object.last_modified.is_some());
+    }
+
+    #[tokio::test]
+    async fn opens_the_version_selected_by_the_identifier() {
+        let storage = Arc::new(MemoryStorage::default());
+        let root = pairtree::path("ark:/12345/bcd987");
+        storage.put(&format!("{root}/{INVENTORY_FILE}"), INVENTORY);
+        storage.put(&format!("{root}/v1/content/foo/bar.xml"), "xml");
+        storage.put(&format!("{root}/v2/content/image.jp2"), "image");
+
+        let provider: Arc<dyn StorageProvider> = Arc::new(storage.clone());
+        let open = |id: &str| open(provider.clone(), Default::default(), id.to_string());
+
+        let object = open("ark:/12345/bcd987::foo/bar.xml;v1").await.unwrap();
+        assert_eq!(object.name.as_deref(), Some("foo/bar.xml"));
+
+        let object = open("ark:/12345/bcd987").await.unwrap();
+        assert_eq!(object.name.as_deref(), Some("image.jp2"));
+
+        assert!(matches!(
+            open("ark:/12345/bcd987::foo/bar.xml").await,
+            Err(StorageError::NotFound)
+        ));
+    }
+}
@@ -0,0 +1,88 @@
+//! Identifier to path mapping as described by the
+//! [Pairtree specification](https://datatracker.ietf.org/doc/html/draft-kunze-pairtree-01).
+
+use std::fmt::Write;
+
+/// The directory beneath the storage root that contains the pairtree.
+pub const PAIRTREE_ROOT: &str = "pairtree_root";
+
+/// The number of characters in each directory name of a pairtree path.
+const SHORTY_LENGTH: usize = 2;
+
+/// Encode an identifier into a "cleaned" pairtree name, which is safe to use as a single path
+/// component.
+///
+/// Characters outside of visible ASCII and those reserved by the specification are hex-encoded
+/// as `^hh`, after which `/`, `:` and `.` are substituted with `=`, `+` and `,` respectively.
+pub fn encode(id: &str) -> String {
+    let mut encoded = String::with_capacity(id.len());
+
+    for byte in id.bytes() {
+        match byte {
+            b'/' => encoded.push('='),
+            b':' => encoded.push('+'),
+            b'.' => encoded.push(','),
+            b'"' | b'*' | b'+' | b',' | b'<' | b'=' | b'>' | b'?' | b'\\' | b'^' | b'|' => {
+                let _ = write!(encoded, "^{byte:02x}");
+            }
+            0x21..=0x7E => encoded.push(byte as char),
+            _ => {
+                let _ = write!(encoded, "^{byte:02x}");
+            }
+        }
+    }
+
+    encoded
+}
+
+/// Map an identifier to its pairtree path, relative to the pairtree root.
+///
+/// The path consists of the encoded identifier split into two character directories, followed
+/// by an encapsulating directory named after the full encoded identifier. For example, the
+/// identifier `ark:/13030/xt12t3` maps to `ar/k+/=1/30/30/=x/t1/2t/3/ark+=13030=xt12t3`.
+pub fn path(id: &str) -> String {
+    let encoded = encode(id);
+    let mut path = String::with_capacity(encoded.len() * 2 + encoded.len() / SHORTY_LENGTH);
+
+    // Encoded identifiers only contain ASCII, so they can safely be split on byte boundaries.
+    for shorty in encoded.as_bytes().chunks(SHORTY_LENGTH) {
+        path.push_str(std::str::from_utf8(shorty).expect("encoded identifier is ASCII"));
+        path.push('/');
+    }
+
+    path.push_str(&encoded);
+    path
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_substitutes_path_characters() {
+        assert_eq!(encode("ark:/13030/xt12t3"), "ark+=13030=xt12t3");
+        assert_eq!(
+            encode("http://n2t.info/urn:nbn:se:kb:repos-1"),
+            "http+==n2t,info=urn+nbn+se+kb+repos-1"
+        );
+    }
+
+    #[test]
+    fn encode_hex_encodes_reserved_characters() {
+        assert_eq!(encode("what-the-*@?#!^!?"), "what-the-^2a@^3f#!^5e!^3f");
+        assert_eq!(encode("a b"), "a^20b");
+        assert_eq!(encode("é"), "^c3^a9");
+    }
+
+    #[test]
+    fn encode_neutralises_relative_segments() {
+        assert_eq!(encode("../../etc/passwd"), ",,=,,=etc=passwd");
+    }
+
+    #[test]
+    fn path_splits_into_shorties() {
+        assert_eq!(path("abcd"), "ab/cd/abcd");
+        assert_eq!(path("abcde"), "ab/cd/e/abcde");
+        assert_eq!(path("ark:/13030/xt12t3"), "ar/k+/=1/30/30/=x/t1/2t/3/ark+=13030=xt12t3");
+    }
+}
@@ -0,0 +1,483 @@
+use std::fs::File;
+use std::future::Future;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{AsyncRead, AsyncSeek};
+use tracing::warn;
+
+use super::{FileOrStream, FileStream, StorageError, StorageObject, StorageProvider};
+
+/// A [StorageProvider] that serves files from a directory on the local filesystem.
+///
+/// Unlike the opendal filesystem backend, objects are returned as [FileOrStream::File] so that
+/// decoders can read directly from the file rather than through an asynchronous stream.
+pub struct LocalStorageProvider {
+    root: PathBuf,
+    /// A handle to the root directory that files are opened relative to, if symbolic links are
+    /// prevented from leading outside of it.
+    contained_root: Option<Arc<File>>,
+}
+
+impl LocalStorageProvider {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root, contained_root: None }
+    }
+
+    /// Refuse to open any file whose path leads outside of the root directory once symbolic links
+    /// are followed.
+    ///
+    /// Paths are resolved by the kernel as the file is opened, so a symbolic link swapped in
+    /// while a file is being opened can't lead outside of the root either. On Linux symbolic links
+    /// that stay beneath the root are followed, but on other platforms, and kernels without
+    /// `openat2`, no symbolic links are followed at all.
+    pub fn with_symlink_containment(self) -> std::io::Result<Self> {
+        if cfg!(not(unix)) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "symbolic link containment is only supported on Unix",
+            ));
+        }
+
+        let root = File::open(&self.root)?;
+        Ok(Self { contained_root: Some(Arc::new(root)), ..self })
+    }
+}
+
+impl StorageProvider for LocalStorageProvider {
+    fn open(
+        &self,
+        id: &str,
+    ) -> Pin<Box<dyn Future<Output = Result<StorageObject, StorageError>> + Send>> {
+        Box::pin(open(self.root.clone(), self.contained_root.clone(), id.to_string()))
+    }
+
+    fn healthcheck(&self) -> Pin<Box<dyn Future<Output = Result<(), StorageError>> + Send>> {
+        let root = self.root.clone();
+
+        Box::pin(async move {
+            match tokio::fs::metadata(&root).await? {
+                metadata if metadata.is_dir() => Ok(()),
+                _ => Err(StorageError::Other(format!("{} is not a directory", root.display()))),
+            }
+        })
+    }
+}
+
+#[tracing::instrument(skip(contained_root), err)]
+async fn open(
+    root: PathBuf,
+    contained_root: Option<Arc<File>>,
+    id: String,
+) -> Result<StorageObject, StorageError> {
+    let path = root.join(&id);
+    let file = {
+        let (path, id) = (path.clone(), id.clone());
+
+        tokio::task::spawn_blocking(move || match contained_root {
+            Some(root) => open_beneath(&root, Path::new(&id)),
+            None => File::open(path),
+        })
+        .await
+        .map_err(|e| StorageError::Other(e.to_string()))?
+    };
+
+    let file = file.inspect_err(|e| {
+        if e.kind() == std::io::ErrorKind::PermissionDenied {
+            warn!(identifier = id, "refused to open a file outside of storage: {e}");
+        }
+    })?;
+    let metadata = file.metadata()?;
+
+    if !metadata.is_file() {
+        return Err(StorageError::NotFound);
+    }
+
+    let file = Arc::new(file);
+    let length = metadata.len();
+    let last_modified = modified_time(&metadata);
+    let etag = last_modified
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map(|mtime| format!("\"{:x}-{:x}\"", mtime.as_secs(), length));
+
+    Ok(StorageObject {
+        name: Some(id),
+        last_modified,
+        etag,
+        size: Some(length),
+        content: FileOrStream::File(FileStream {
+            path: path.into_boxed_path(),
+            file: file.clone(),
+            stream_factory: Box::new(move |_| Box::new(PositionalFileReader::new(file, length))),
+        }),
+    })
+}
+
+/// The error returned when resolving a path would lead outside of the storage root.
+fn escaped_root() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::PermissionDenied,
+        "the path leads outside of the storage root",
+    )
+}
+
+/// Open `path` relative to the directory `root`, without following symbolic links out of it.
+#[cfg(unix)]
+fn open_beneath(root: &File, path: &Path) -> std::io::Result<File> {
+    #[cfg(target_os = "linux")]
+    match openat2_beneath(root, path) {
+        // Kernels before 5.6 don't have `openat2`, and older seccomp profiles deny it.
+        Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EPERM)) => {}
+        result => return result,
+    }
+
+    open_nofollow(root, path)
+}
+
+#[cfg(not(unix))]
+fn open_beneath(_: &File, _: &Path) -> std::io::Result<File> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Open `path` with `openat2`, letting the kernel refuse to resolve it outside of `root`.
+#[cfg(target_os = "linux")]
+fn openat2_beneath(root: &File, path: &Path) -> std::io::Result<File> {
+    use std::ffi::CString;
+    use std::os::fd::{AsRawFd, FromRawFd};
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: `open_how` is plain data, for which all zeroes is a valid value.
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (libc::O_RDONLY | libc::O_CLOEXEC) as u64;
+    how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS;
+
+    // SAFETY: `path` and `how` outlive the call, and `how` is passed along with its size.
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            root.as_raw_fd(),
+            path.as_ptr(),
+            &how as *const libc::open_how,
+            size_of::<libc::open_how>(),
+        )
+    };
+
+    if fd < 0 {
+        let error = std::io::Error::last_os_error();
+        return match error.raw_os_error() {
+            Some(libc::EXDEV | libc::ELOOP) => Err(escaped_root()),
+            _ => Err(error),
+        };
+    }
+
+    // SAFETY: the descriptor was just opened, and nothing else owns it.
+    Ok(unsafe { File::from_raw_fd(fd as i32) })
+}
+
+/// Open `path` one component at a time relative to `root`, refusing to follow any symbolic link.
+#[cfg(unix)]
+fn open_nofollow(root: &File, path: &Path) -> std::io::Result<File> {
+    use std::ffi::CString;
+    use std::os::fd::{AsRawFd, FromRawFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Component;
+
+    let mut components = path
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .peekable();
+    let mut directory: Option<File> = None;
+
+    while let Some(component) = components.next() {
+        let Component::Normal(name) = component else {
+            return Err(escaped_root());
+        };
+
+        let last = components.peek().is_none();
+        let flags = libc::O_RDONLY
+            | libc::O_CLOEXEC
+            | libc::O_NOFOLLOW
+            | if last { 0 } else { libc::O_DIRECTORY };
+
+        let name = CString::new(name.as_bytes())?;
+        let parent = directory.as_ref().unwrap_or(root);
+
+        // SAFETY: `name` outlives the call, and `parent` is an open directory.
+        let fd = unsafe { libc::openat(parent.as_raw_fd(), name.as_ptr(), flags) };
+        if fd < 0 {
+            let error = std::io::Error::last_os_error();
+            return match error.raw_os_error() {
+                Some(libc::ELOOP) => Err(escaped_root()),
+                Some(libc::ENOTDIR) if !last => Err(escaped_root()),
+                _ => Err(error),
+            };
+        }
+
+        // SAFETY: the descriptor was just opened, and nothing else owns it.
+        let file = unsafe { File::from_raw_fd(fd) };
+        if last {
+            return Ok(file);
+        }
+
+        directory = Some(file);
+    }
+
+    Err(std::io::ErrorKind::NotFound.into())
+}
+
+/// The number of bytes read ahead of the position by each asynchronous read.
+const READ_AHEAD: usize = 64 * 1024;
+
+type PendingRead = tokio::task::JoinHandle<std::io::Result<(u64, Vec<u8>)>>;
+
+/// A seekable reader over a local file that uses positional reads (`pread`) rather than
+/// moving a shared file cursor, so any number of readers can share one open file.
+///
+/// Blocking callers, such as decoders running in [tokio::task::spawn_blocking], should read
+/// through [std::io::Read], which reads the file directly. Reads through [AsyncRead] are handed
+/// off to the blocking thread pool so they never block an executor, and read ahead of the
+/// position so that the many small reads made by decoders don't each pay for the hand-off.
+pub struct PositionalFileReader {
+    file: Arc<File>,
+    length: u64,
+    position: u64,
+    /// Data read ahead by asynchronous reads, and the offset it was read from.
+    buffer: Vec<u8>,
+    buffer_start: u64,
+    pending: Option<PendingRead>,
+}
+
+impl PositionalFileReader {
+    pub fn new(file: Arc<File>, length: u64) -> Self {
+        Self { file, length, position: 0, buffer: vec![], buffer_start: 0, pending: None }
+    }
+
+    /// Read the whole of `file`, taking its length from its metadata.
+    pub fn for_file(file: Arc<File>) -> std::io::Result<Self> {
+        let length = file.metadata()?.len();
+
+        Ok(Self::new(file, length))
+    }
+
+    /// Get the data read ahead that is available at the current position, if any.
+    fn buffered(&self) -> Option<&[u8]> {
+        let offset = self.position.checked_sub(self.buffer_start)?;
+
+        if offset < self.buffer.len() as u64 {
+            Some(&self.buffer[offset as usize..])
+        } else {
+            None
+        }
+    }
+
+    fn seek_to(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+impl std::io::Read for PositionalFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = read_at(&self.file, buf, self.position)?;
+        self.position += count as u64;
+
+        Ok(count)
+    }
+}
+
+impl std::io::Seek for PositionalFileReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.seek_to(pos)
+    }
+}
+
+impl AsyncRead for PositionalFileReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.position >= this.length || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+
+            if let Some(available) = this.buffered() {
+                let count = available.len().min(buf.len());
+                buf[..count].copy_from_slice(&available[..count]);
+                this.position += count as u64;
+
+                return Poll::Ready(Ok(count));
+            }
+
+            let pending = this.pending.get_or_insert_with(|| {
+                let file = this.file.clone();
+                let offset = this.position;
+                let size = (this.length - offset).min(READ_AHEAD.max(buf.len()) as u64) as usize;
+
+                tokio::task::spawn_blocking(move || {
+                    let mut data = vec![0; size];
+                    let count = read_at(&file, &mut data, offset)?;
+                    data.truncate(count);
+
+                    Ok((offset, data))
+                })
+            });
+
+            let result = ready!(Pin::new(pending).poll(cx));
+            this.pending = None;
+
+            let (offset, data) = result??;
+            if data.is_empty() {
+                // The file was truncated after its length was read.
+                return Poll::Ready(Ok(0));
+            }
+
+            this.buffer = data;
+            this.buffer_start = offset;
+        }
+    }
+}
+
+impl AsyncSeek for PositionalFileReader {
+    fn poll_seek(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<std::io::Result<u64>> {
+        Poll::Ready(self.get_mut().seek_to(pos))
+    }
+}
+
+/// Get the last modification time of a file, truncated to whole seconds as in HTTP dates.
+fn modified_time(metadata: &std::fs::Metadata) -> Option<SystemTime> {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map(|mtime| UNIX_EPOCH + Duration::from_secs(mtime.as_secs()))
+}
+
+#[cfg(test)]
+mod test {
+    use futures::{AsyncReadExt, AsyncSeekExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn opens_local_files() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("image.jp2"), b"0123456789").unwrap();
+
+        let storage = LocalStorageProvider::new(root.path().to_path_buf());
+        let object = storage.open("image.jp2").await.unwrap();
+
+        assert!(object.etag.is_some());
+        assert!(object.last_modified.is_some());
+
+        let FileOrStream::File(file) = object.content else {
+            panic!("expected a local file")
+        };
+        assert_eq!(&*file.path, root.path().join("image.jp2"));
+
+        let mut reader = Box::into_pin((file.stream_factory)(&file.path));
+        let mut data = [0u8; 4];
+        reader.seek(SeekFrom::Start(3)).await.unwrap();
+        reader.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"3456");
+
+        reader.seek(SeekFrom::End(-2)).await.unwrap();
+        let mut tail = vec![];
+        reader.read_to_end(&mut tail).await.unwrap();
+        assert_eq!(tail, b"89");
+
+        assert!(matches!(storage.open("missing.jp2").await, Err(StorageError::NotFound)));
+    }
+
+    /// Create a storage root holding `image.jp2`, along with a symbolic link to it and a symbolic
+    /// link to a directory outside of the root holding `secret.jp2`.
+    #[cfg(unix)]
+    fn linked_root() -> (tempfile::TempDir, tempfile::TempDir) {
+        let outside = tempfile::tempdir().unwrap();
+        let root = tempfile::tempdir().unwrap();
+
+        std::fs::write(outside.path().join("secret.jp2"), b"secret").unwrap();
+        std::fs::write(root.path().join("image.jp2"), b"image").unwrap();
+        std::fs::create_dir(root.path().join("images")).unwrap();
+        std::fs::write(root.path().join("images/page.jp2"), b"page").unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("escape")).unwrap();
+        std::os::unix::fs::symlink("image.jp2", root.path().join("alias.jp2")).unwrap();
+
+        (root, outside)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn refuses_symlinks_outside_of_root() {
+        let (root, _outside) = linked_root();
+        let storage = LocalStorageProvider::new(root.path().to_path_buf())
+            .with_symlink_containment()
+            .unwrap();
+
+        assert!(storage.open("image.jp2").await.is_ok());
+        assert!(storage.open("images/page.jp2").await.is_ok());
+        assert!(matches!(
+            storage.open("escape/secret.jp2").await,
+            Err(StorageError::AccessDenied)
+        ));
+        assert!(matches!(storage.open("missing.jp2").await, Err(StorageError::NotFound)));
+
+        // Without containment, symbolic links are followed wherever they lead.
+        let storage = LocalStorageProvider::new(root.path().to_path_buf());
+        assert!(storage.open("escape/secret.jp2").await.is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_every_symlink_without_openat2() {
+        let (root, _outside) = linked_root();
+        let root_dir = File::open(root.path()).unwrap();
+        let denied = |path: &str| {
+            open_nofollow(&root_dir, Path::new(path))
+                .err()
+                .map(|e| e.kind())
+                == Some(std::io::ErrorKind::PermissionDenied)
+        };
+
+        assert!(open_nofollow(&root_dir, Path::new("./images/page.jp2")).is_ok());
+        assert!(denied("escape/secret.jp2"));
+        assert!(denied("alias.jp2"));
+        assert!(denied("../image.jp2"));
+    }
+}
@@ -1,24 +1,260 @@
+use std::collections::HashMap;
 use std::future::Future;
-use std::path::PathBuf;
+use std::path::Path;
 use std::pin::Pin;
 use std::sync::Arc;
 
 use chrono::Timelike;
 use hyper::Uri;
+use opendal::Operator;
 use opendal::layers::TracingLayer;
 use opendal::services::{Fs, S3};
-use opendal::{Builder, Operator};
-use tracing::info;
+use tracing::{debug, info, warn};
 
 use super::{FileOrStream, StorageError, StorageObject, StorageProvider};
 
+/// The scheme of identifiers that address an object in a specific S3 bucket, e.g.
+/// `s3://bucket/path/to/image.jp2`.
+const S3_SCHEME: &str = "s3";
+
+/// A [StorageProvider] backed by an [opendal] [Operator].
+///
+/// Operators are constructed once and shared between requests so that connections to remote
+/// services can be reused.
 pub struct OpenDalStorageProvider {
-    path: PathBuf,
+    backend: Arc<OpenDalBackend>,
+}
+
+enum OpenDalBackend {
+    /// A single operator serving every identifier, such as a local filesystem root.
+    Operator(Operator),
+
+    /// An S3 operator for each bucket that identifiers may address, keyed by bucket name.
+    S3 { options: S3Options, operators: HashMap<String, Operator> },
+}
+
+/// Configuration for reading image files from S3 or an S3-compatible service such as MinIO.
+#[derive(Clone, Debug, Default)]
+pub struct S3Options {
+    /// The endpoint of the S3 service, e.g. `http://localhost:9000`. If not specified, the AWS
+    /// endpoint for the configured region is used.
+    pub endpoint: Option<String>,
+
+    /// The region the buckets are located in.
+    pub region: Option<String>,
+
+    /// The bucket that identifiers are resolved against, unless they are an `s3://` URI.
+    pub bucket: Option<String>,
+
+    /// Other buckets that `s3://` URI identifiers may address. Identifiers addressing any other
+    /// bucket are not found, so clients can't read every bucket the credentials can reach.
+    pub allowed_buckets: Vec<String>,
+
+    /// A key prefix prepended to identifiers resolved against the default bucket.
+    pub prefix: Option<String>,
+
+    /// Where credentials used to sign requests are obtained from.
+    pub credentials: S3Credentials,
+
+    /// Use path-style (`https://endpoint/bucket/key`) rather than virtual-hosted-style
+    /// (`https://bucket.endpoint/key`) addressing.
+    pub path_style: bool,
+}
+
+/// The source of credentials used to sign requests to S3.
+#[derive(Clone, Debug, Default)]
+pub enum S3Credentials {
+    /// Load credentials from the environment, AWS configuration files, web identity tokens or
+    /// instance metadata, in that order.
+    #[default]
+    Default,
+
+    /// Use the provided static credentials.
+    Static { access_key_id: String, secret_access_key: String, session_token: Option<String> },
+
+    /// Send unsigned requests, for use with public buckets.
+    Anonymous,
 }
 
 impl OpenDalStorageProvider {
-    pub(crate) fn new(path: PathBuf) -> Self {
-        Self { path }
+    /// Create a provider that serves files relative to the local directory `root`.
+    pub fn fs(root: &Path) -> Result<Self, StorageError> {
+        let root = root
+            .to_str()
+            .ok_or_else(|| StorageError::Other("storage root is not valid UTF-8".into()))?;
+
+        Ok(Self::from_operator(Operator::new(Fs::default().root(root))?.finish()))
+    }
+
+    /// Create a provider that serves objects from S3, constructing an operator for the default
+    /// bucket and each of the allowed buckets up front.
+    pub fn s3(options: S3Options) -> Result<Self, StorageError> {
+        Ok(Self { backend: Arc::new(OpenDalBackend::s3(options)?) })
+    }
+
+    /// Create a provider that serves every identifier from `operator`.
+    pub fn from_operator(operator: Operator) -> Self {
+        Self { backend: Arc::new(OpenDalBackend::Operator(operator.layer(TracingLayer))) }
+    }
+}
+
+impl OpenDalBackend {
+    fn s3(options: S3Options) -> Result<Self, StorageError> {
+        let mut operators = HashMap::new();
+        for bucket in options.bucket.iter().chain(&options.allowed_buckets) {
+            if !operators.contains_key(bucket) {
+                info!(bucket = bucket, "creating S3 operator");
+                operators.insert(bucket.clone(), options.operator(bucket)?);
+            }
+        }
+
+        Ok(OpenDalBackend::S3 { options, operators })
+    }
+
+    /// Find the operator responsible for the identifier `id` and the path of the identified
+    /// object within it.
+    fn resolve(&self, id: &str) -> Result<(Operator, String), StorageError> {
+        match self {
+            OpenDalBackend::Operator(operator) => Ok((operator.clone(), id.to_string())),
+            OpenDalBackend::S3 { options, .. } => {
+                let (bucket, key) = options.resolve(id)?;
+                Ok((self.bucket_operator(&bucket)?, key))
+            }
+        }
+    }
+
+    /// Get the operator for an S3 bucket, which is only found if the bucket is the default or
+    /// one of the allowed buckets.
+    fn bucket_operator(&self, bucket: &str) -> Result<Operator, StorageError> {
+        let OpenDalBackend::S3 { operators, .. } = self else {
+            return Err(StorageError::Other("storage backend is not S3".into()));
+        };
+
+        match operators.get(bucket) {
+            Some(operator) => Ok(operator.clone()),
+            None => {
+                debug!(bucket = bucket, "refusing to open an object from a bucket not allowed");
+                Err(StorageError::NotFound)
+            }
+        }
+    }
+}
+
+impl S3Options {
+    /// Map an identifier to an S3 bucket and key.
+    ///
+    /// Identifiers in the form `s3://region/bucket/key`, which earlier releases used, are still
+    /// recognised when the host is the name of an AWS region, although the region is ignored as it
+    /// always was.
+    fn resolve(&self, id: &str) -> Result<(String, String), StorageError> {
+        let uri = id.parse::<Uri>().ok();
+        if let Some(uri) = uri.filter(|uri| uri.scheme_str() == Some(S3_SCHEME)) {
+            let host = uri
+                .host()
+                .ok_or(StorageError::Other("invalid S3 URI specification".into()))?;
+            let path = uri.path().trim_start_matches('/');
+
+            let (bucket, key) = match path.split_once('/') {
+                Some((bucket, key)) if is_region_name(host) => {
+                    warn!(
+                        identifier = id,
+                        "identifier uses the deprecated s3://region/bucket/key form, use \
+                         s3://bucket/key instead"
+                    );
+                    (bucket, key)
+                }
+                _ => (host, path),
+            };
+
+            if bucket.is_empty() || key.is_empty() {
+                return Err(StorageError::Other("invalid S3 bucket/key specification".into()));
+            }
+
+            return Ok((bucket.to_string(), key.to_string()));
+        }
+
+        let bucket = self
+            .bucket
+            .clone()
+            .ok_or(StorageError::Other("no default S3 bucket is configured".into()))?;
+        let key = match self
+            .prefix
+            .as_deref()
+            .map(|prefix| prefix.trim_matches('/'))
+        {
+            Some(prefix) if !prefix.is_empty() => format!("{prefix}/{id}"),
+            _ => id.to_string(),
+        };
+
+        Ok((bucket, key))
+    }
+
+    fn operator(&self, bucket: &str) -> Result<Operator, StorageError> {
+        let mut builder = S3::default().bucket(bucket);
+
+        if let Some(endpoint) = &self.endpoint {
+            builder = builder.endpoint(endpoint);
+        }
+
+        if let Some(region) = &self.region {
+            builder = builder.region(region);
+        }
+
+        if !self.path_style {
+            builder = builder.enable_virtual_host_style();
+        }
+
+        builder = match &self.credentials {
+            S3Credentials::Default => builder,
+            S3Credentials::Static { access_key_id, secret_access_key, session_token } => {
+                let builder = builder
+                    .disable_config_load()
+                    .disable_ec2_metadata()
+                    .access_key_id(access_key_id)
+                    .secret_access_key(secret_access_key);
+
+                match session_token {
+                    Some(token) => builder.session_token(token),
+                    None => builder,
+                }
+            }
+            S3Credentials::Anonymous => builder
+                .disable_config_load()
+                .disable_ec2_metadata()
+                .allow_anonymous(),
+        };
+
+        Ok(Operator::new(builder)?.layer(TracingLayer).finish())
+    }
+}
+
+/// Whether `name` looks like the name of an AWS region, such as `eu-west-2` or `us-gov-east-1`.
+fn is_region_name(name: &str) -> bool {
+    const AREAS: &[&str] = &["af", "ap", "ca", "cn", "eu", "il", "me", "mx", "sa", "us"];
+    const DIRECTIONS: &[&str] = &[
+        "north",
+        "south",
+        "east",
+        "west",
+        "central",
+        "northeast",
+        "northwest",
+        "southeast",
+        "southwest",
+    ];
+
+    let parts: Vec<&str> = name.split('-').collect();
+    match parts.as_slice() {
+        [area, qualifiers @ .., direction, number] => {
+            AREAS.contains(area)
+                && qualifiers
+                    .iter()
+                    .all(|part| ["gov", "iso", "isob"].contains(part))
+                && DIRECTIONS.contains(direction)
+                && !number.is_empty()
+                && number.bytes().all(|byte| byte.is_ascii_digit())
+        }
+        _ => false,
     }
 }
 
@@ -26,6 +262,7 @@ impl From<opendal::Error> for StorageError {
     fn from(value: opendal::Error) -> Self {
         match value.kind() {
             opendal::ErrorKind::NotFound => StorageError::NotFound,
+            opendal::ErrorKind::PermissionDenied => StorageError::AccessDenied,
             _ => StorageError::Other(value.to_string()),
         }
     }
@@ -36,46 +273,36 @@ impl StorageProvider for OpenDalStorageProvider {
         &self,
         id: &str,
     ) -> Pin<Box<dyn Future<Output = Result<StorageObject, StorageError>> + Send + 'static>> {
-        Box::pin(open(self.path.clone(), id.to_string()))
+        let resolved = self.backend.resolve(id);
+
+        Box::pin(async move {
+            let (operator, path) = resolved?;
+            open(operator, path).await
+        })
     }
-}
 
-#[tracing::instrument]
-async fn open(local_root: PathBuf, path: String) -> Result<StorageObject, StorageError> {
-    let (operator, path) = match path.parse::<Uri>() {
-        Ok(uri) if uri.scheme_str() == Some("s3") => {
-            let (region, bucket_and_path) = uri
-                .path()
-                .split_once('/')
-                .ok_or(StorageError::Other("invalid S3 URI specification".into()))?;
-            let (bucket, bucket_key) = bucket_and_path
-                .split_once('/')
-                .ok_or(StorageError::Other("invalid S3 bucket/key specification".into()))?;
-
-            info!(
-                region = region,
-                bucket = bucket,
-                bucket_key = bucket_key,
-                "recognised image path as S3 object"
-            );
-
-            (
-                Operator::new(S3::default().region(region).bucket(bucket))?
-                    .layer(TracingLayer)
-                    .finish(),
-                bucket_key.to_string(),
-            )
-        }
-        _ => (
-            Operator::new(
-                Fs::default().root(local_root.to_str().expect("invalid root path provided")),
-            )?
-            .layer(TracingLayer)
-            .finish(),
-            path.to_string(),
-        ),
-    };
+    fn healthcheck(&self) -> Pin<Box<dyn Future<Output = Result<(), StorageError>> + Send>> {
+        let operator = match self.backend.as_ref() {
+            OpenDalBackend::Operator(operator) => Ok(Some(operator.clone())),
+            OpenDalBackend::S3 { options, .. } => options
+                .bucket
+                .as_deref()
+                .map(|bucket| self.backend.bucket_operator(bucket))
+                .transpose(),
+        };
+
+        Box::pin(async move {
+            if let Some(operator) = operator? {
+                operator.check().await?;
+            }
+
+            Ok(())
+        })
+    }
+}
 
+#[tracing::instrument(skip(operator))]
+async fn open(operator: Operator, path: String) -> Result<StorageObject, StorageError> {
     let stat = operator.stat(&path).await?;
     let reader = operator
         .reader(&path)
@@ -89,5 +316,121 @@ async fn open(local_root: PathBuf, path: String) -> Result<StorageObject, Storag
         last_modified: stat
             .last_modified()
             .map(|utc| utc.with_nanosecond(0).unwrap().into()),
+        etag: stat.etag().map(str::to_string),
+        size: Some(stat.content_length()),
     })
 }
+
+#[cfg(test)]
+mod test {
+    use opendal::services::Memory;
+
+    use super::*;
+    use crate::storage::layout::read_to_end;
+
+    fn s3_options() -> S3Options {
+        S3Options {
+            endpoint: Some("http://127.0.0.1:9000".into()),
+            region: Some("us-east-1".into()),
+            bucket: Some("images".into()),
+            allowed_buckets: vec!["other".into()],
+            prefix: Some("/masters/".into()),
+            credentials: S3Credentials::Anonymous,
+            path_style: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn opens_objects_from_operator() {
+        let operator = Operator::new(Memory::default()).unwrap().finish();
+        operator.write("a/image.jp2", vec![1, 2, 3]).await.unwrap();
+
+        let storage = OpenDalStorageProvider::from_operator(operator);
+        let object = storage.open("a/image.jp2").await.unwrap();
+
+        assert_eq!(object.name.as_deref(), Some("a/image.jp2"));
+        assert_eq!(read_to_end(object).await.unwrap(), vec![1, 2, 3]);
+        assert!(matches!(storage.open("a/missing.jp2").await, Err(StorageError::NotFound)));
+    }
+
+    #[test]
+    fn resolves_identifiers_against_default_bucket() {
+        let (bucket, key) = s3_options().resolve("a/image.jp2").unwrap();
+
+        assert_eq!(bucket, "images");
+        assert_eq!(key, "masters/a/image.jp2");
+    }
+
+    #[test]
+    fn resolves_s3_uri_identifiers() {
+        let (bucket, key) = s3_options().resolve("s3://other/a/image.jp2").unwrap();
+
+        assert_eq!(bucket, "other");
+        assert_eq!(key, "a/image.jp2");
+        assert!(s3_options().resolve("s3://other/").is_err());
+    }
+
+    #[test]
+    fn resolves_deprecated_s3_uri_identifiers() {
+        let (bucket, key) = s3_options()
+            .resolve("s3://eu-west-2/other/a/image.jp2")
+            .unwrap();
+
+        assert_eq!(bucket, "other");
+        assert_eq!(key, "a/image.jp2");
+
+        // Buckets that merely contain dashes aren't mistaken for regions.
+        let (bucket, key) = s3_options().resolve("s3://my-images-2/a.jp2").unwrap();
+        assert_eq!((bucket.as_str(), key.as_str()), ("my-images-2", "a.jp2"));
+        assert!(is_region_name("us-gov-east-1"));
+    }
+
+    #[test]
+    fn requires_default_bucket_for_plain_identifiers() {
+        let options = S3Options { bucket: None, ..s3_options() };
+
+        assert!(options.resolve("image.jp2").is_err());
+    }
+
+    #[test]
+    fn only_opens_allowed_buckets() {
+        let backend = OpenDalBackend::s3(s3_options()).unwrap();
+
+        backend.resolve("a.jp2").unwrap();
+        backend.resolve("s3://images/b.jp2").unwrap();
+        backend.resolve("s3://other/c.jp2").unwrap();
+        assert!(matches!(backend.resolve("s3://unlisted/d.jp2"), Err(StorageError::NotFound)));
+
+        let OpenDalBackend::S3 { operators, .. } = backend else {
+            unreachable!()
+        };
+        assert_eq!(operators.len(), 2);
+    }
+
+    /// Requires a local MinIO server, e.g. `docker run -p 9000:9000 minio/minio server /data`,
+    /// with a bucket named `images` that is accessible by the `minioadmin` user.
+    #[tokio::test]
+    #[ignore]
+    async fn opens_objects_from_minio() {
+        let storage = OpenDalStorageProvider::s3(S3Options {
+            credentials: S3Credentials::Static {
+                access_key_id: "minioadmin".into(),
+                secret_access_key: "minioadmin".into(),
+                session_token: None,
+            },
+            prefix: None,
+            ..s3_options()
+        })
+        .unwrap();
+
+        let operator = storage.backend.bucket_operator("images").unwrap();
+        operator
+            .write("laya-test.jp2", vec![4, 5, 6])
+            .await
+            .unwrap();
+
+        let object = storage.open("laya-test.jp2").await.unwrap();
+        assert_eq!(read_to_end(object).await.unwrap(), vec![4, 5, 6]);
+        assert!(storage.healthcheck().await.is_ok());
+    }
+}
@@ -6,10 +6,12 @@ use opentelemetry::global::{self};
 use opentelemetry::trace::TracerProvider;
 use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
 use opentelemetry_aws::trace::{XrayIdGenerator, XrayPropagator};
-use opentelemetry_otlp::LogExporter;
+use opentelemetry_otlp::{LogExporter, MetricExporter};
 use opentelemetry_resource_detectors::{OsResourceDetector, ProcessResourceDetector};
 use opentelemetry_sdk::logs::SdkLoggerProvider;
 use opentelemetry_sdk::logs::log_processor_with_async_runtime::BatchLogProcessor;
+use opentelemetry_sdk::metrics::SdkMeterProvider;
+use opentelemetry_sdk::metrics::periodic_reader_with_async_runtime::PeriodicReader;
 use opentelemetry_sdk::resource::EnvResourceDetector;
 use opentelemetry_sdk::trace::span_processor_with_async_runtime::BatchSpanProcessor;
 use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
@@ -39,6 +41,7 @@ pub struct TelemetryHandle {
     rt: Runtime,
     tracing_provider: Option<SdkTracerProvider>,
     logger_provider: Option<SdkLoggerProvider>,
+    meter_provider: Option<SdkMeterProvider>,
 }
 
 impl TelemetryHandle {
@@ -51,6 +54,10 @@ impl TelemetryHandle {
             .logger_provider
             .take()
             .and_then(|provider| provider.shutdown().ok());
+        let _ = self
+            .meter_provider
+            .take()
+            .and_then(|provider| provider.shutdown().ok());
 
         self.rt.shutdown_timeout(timeout);
     }
@@ -65,7 +72,7 @@ pub fn install_telemetry_collector(disable_otel: bool) -> TelemetryHandle {
 
     let _rt = rt.enter();
 
-    let (tracer_provider, logger_provider) = if !disable_otel {
+    let (tracer_provider, logger_provider, meter_provider) = if !disable_otel {
         global::set_text_map_propagator(XrayPropagator::new());
 
         let exporter = opentelemetry_otlp::SpanExporter::builder()
@@ -90,9 +97,21 @@ pub fn install_telemetry_collector(disable_otel: bool) -> TelemetryHandle {
             .with_log_processor(BatchLogProcessor::builder(log_exporter, runtime::Tokio).build())
             .build();
 
-        (Some(tracer_provider), Some(logger_provider))
+        let metric_exporter = MetricExporter::builder()
+            .with_http()
+            .build()
+            .expect("Failed to create metric exporter");
+
+        let meter_provider = SdkMeterProvider::builder()
+            .with_resource(resource())
+            .with_reader(PeriodicReader::builder(metric_exporter, runtime::Tokio).build())
+            .build();
+
+        global::set_meter_provider(meter_provider.clone());
+
+        (Some(tracer_provider), Some(logger_provider), Some(meter_provider))
     } else {
-        (None, None)
+        (None, None, None)
     };
 
     let formatter = std::env::var("LAYA_LOG_FORMATTER").unwrap_or("pretty".into());
@@ -127,5 +146,10 @@ pub fn install_telemetry_collector(disable_otel: bool) -> TelemetryHandle {
         )
         .init();
 
-    TelemetryHandle { rt, tracing_provider: tracer_provider.clone(), logger_provider }
+    TelemetryHandle {
+        rt,
+        tracing_provider: tracer_provider.clone(),
+        logger_provider,
+        meter_provider,
+    }
 }